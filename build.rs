@@ -1,5 +1,29 @@
 use static_files::resource_dir;
+use std::process::Command;
+
+/// Run a command and return the first line of its output
+fn command_output(cmd: &str, args: &[&str]) -> String {
+    Command::new(cmd)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .and_then(|s| s.lines().next().map(|l| l.trim().to_string()))
+        .unwrap_or("unknown".to_string())
+}
 
 fn main() -> std::io::Result<()> {
+    /* Build information exposed as proxy_build_info */
+    let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+    println!(
+        "cargo:rustc-env=PROXY_RUSTC_VERSION={}",
+        command_output(&rustc, &["--version"])
+    );
+    println!(
+        "cargo:rustc-env=PROXY_GIT_HASH={}",
+        command_output("git", &["rev-parse", "--short", "HEAD"])
+    );
+
     resource_dir("./static").build()
-}
\ No newline at end of file
+}
@@ -5,6 +5,8 @@
 
 typedef struct MetricProxyClient MetricProxyClient;
 
+typedef struct MetricProxyNamespace MetricProxyNamespace;
+
 typedef struct MetricProxyValue MetricProxyValue;
 
 #ifdef __cplusplus
@@ -13,20 +15,80 @@ extern "C" {
 
 struct MetricProxyClient *metric_proxy_init(void);
 
+struct MetricProxyClient *metric_proxy_init_with(const char *path, uint64_t period_ms);
+
 int metric_proxy_release(struct MetricProxyClient *pclient);
 
+int metric_proxy_enable_process_stats(struct MetricProxyClient *pclient);
+
+int metric_proxy_enable_cgroup_stats(struct MetricProxyClient *pclient);
+
+struct MetricProxyNamespace *metric_proxy_namespace_new(struct MetricProxyClient *pclient,
+                                                        const char *prefix);
+
+struct MetricProxyValue *metric_proxy_namespace_counter_new(struct MetricProxyNamespace *pns,
+                                                            const char *name,
+                                                            const char *doc);
+
+struct MetricProxyValue *metric_proxy_namespace_updown_new(struct MetricProxyNamespace *pns,
+                                                           const char *name,
+                                                           const char *doc);
+
+struct MetricProxyValue *metric_proxy_namespace_gauge_new(struct MetricProxyNamespace *pns,
+                                                          const char *name,
+                                                          const char *doc);
+
+struct MetricProxyValue *metric_proxy_namespace_lastvalue_new(struct MetricProxyNamespace *pns,
+                                                              const char *name,
+                                                              const char *doc);
+
+int metric_proxy_namespace_free(struct MetricProxyNamespace *pns);
+
+const char *metric_proxy_last_error(void);
+
 struct MetricProxyValue *metric_proxy_counter_new(struct MetricProxyClient *pclient,
                                                   const char *name,
                                                   const char *doc);
 
+struct MetricProxyValue *metric_proxy_counter_new_labeled(struct MetricProxyClient *pclient,
+                                                          const char *name,
+                                                          const char *doc,
+                                                          const char *const *label_keys,
+                                                          const char *const *label_values,
+                                                          size_t n);
+
 int metric_proxy_counter_inc(struct MetricProxyValue *pcounter, double value);
 
+struct MetricProxyValue *metric_proxy_updown_new(struct MetricProxyClient *pclient,
+                                                 const char *name,
+                                                 const char *doc);
+
+int metric_proxy_counter_dec(struct MetricProxyValue *pcounter, double value);
+
+double metric_proxy_counter_read(struct MetricProxyClient *pclient, const char *name);
+
+double metric_proxy_query_node_metric(struct MetricProxyClient *pclient, const char *name);
+
+int metric_proxy_annotate(struct MetricProxyClient *pclient, const char *label);
+
 struct MetricProxyValue *metric_proxy_gauge_new(struct MetricProxyClient *pclient,
                                                 const char *name,
                                                 const char *doc);
 
 int metric_proxy_gauge_set(struct MetricProxyValue *pcounter, double value);
 
+double metric_proxy_gauge_get(struct MetricProxyValue *pcounter);
+
+int metric_proxy_gauge_observe_weighted(struct MetricProxyValue *pcounter,
+                                        double value,
+                                        double weight);
+
+struct MetricProxyValue *metric_proxy_lastvalue_new(struct MetricProxyClient *pclient,
+                                                    const char *name,
+                                                    const char *doc);
+
+int metric_proxy_lastvalue_set(struct MetricProxyValue *pvalue, double value);
+
 #ifdef __cplusplus
 } // extern "C"
 #endif // __cplusplus
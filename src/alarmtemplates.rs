@@ -0,0 +1,150 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::proxy_common::ProxyErr;
+
+/*******************
+ * ALARM TEMPLATES *
+ *******************/
+
+/// An alarm as written in the alarms file
+#[derive(Deserialize)]
+struct AlarmTemplateDef {
+    name: String,
+    /// Regex matched against the jobid
+    target: String,
+    metric: String,
+    operation: String,
+    value: f64,
+}
+
+pub(crate) struct AlarmTemplate {
+    pub name: String,
+    target: Regex,
+    pub metric: String,
+    pub operation: String,
+    pub value: f64,
+}
+
+/// Alarms instantiated in every job whose id matches their target
+///
+/// The configuration is a JSON array such as:
+/// [{"name": "swap", "target": ".*", "metric": "proxy_swap_used_bytes",
+///   "operation": ">", "value": 0}]
+#[derive(Default)]
+pub(crate) struct AlarmTemplates {
+    templates: Vec<AlarmTemplate>,
+}
+
+impl AlarmTemplates {
+    pub(crate) fn from_file(path: &Path) -> Result<AlarmTemplates, ProxyErr> {
+        let data = std::fs::read_to_string(path).map_err(|e| {
+            ProxyErr::new(format!(
+                "Failed to read alarms file {} : {}",
+                path.to_string_lossy(),
+                e
+            ))
+        })?;
+
+        let defs: Vec<AlarmTemplateDef> = serde_json::from_str(&data)
+            .map_err(|e| ProxyErr::new(format!("Bad alarms file : {}", e)))?;
+
+        let mut templates: Vec<AlarmTemplate> = Vec::new();
+
+        for d in defs {
+            let target = Regex::new(&d.target)
+                .map_err(|e| ProxyErr::new(format!("Bad alarm target {} : {}", d.target, e)))?;
+
+            if !matches!(d.operation.as_str(), "=" | "<" | ">" | "<=" | ">=" | "!=") {
+                return Err(ProxyErr::new(format!(
+                    "Bad operator {} for alarm {}",
+                    d.operation, d.name
+                )));
+            }
+
+            log::info!(
+                "Alarm template {} on jobs {} : {} {} {}",
+                d.name,
+                d.target,
+                d.metric,
+                d.operation,
+                d.value
+            );
+
+            templates.push(AlarmTemplate {
+                name: d.name,
+                target,
+                metric: d.metric,
+                operation: d.operation,
+                value: d.value,
+            });
+        }
+
+        Ok(AlarmTemplates { templates })
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> Option<&AlarmTemplate> {
+        self.templates.get(idx)
+    }
+
+    /// Indices of the templates applying to a job
+    pub(crate) fn matching(&self, jobid: &str) -> Vec<usize> {
+        self.templates
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.target.is_match(jobid))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_alarms(name: &str, data: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("proxy-alarms-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn templates_match_jobs_by_id() {
+        let path = write_alarms(
+            "ok",
+            r#"[{"name": "swap", "target": ".*", "metric": "proxy_swap_used_bytes", "operation": ">", "value": 0},
+                {"name": "slow", "target": "^12", "metric": "proxy_cpu_usage", "operation": "<=", "value": 10}]"#,
+        );
+
+        let templates = AlarmTemplates::from_file(&path).unwrap();
+        assert_eq!(templates.matching("1234"), vec![0, 1]);
+        assert_eq!(templates.matching("4321"), vec![0]);
+        assert_eq!(templates.get(1).unwrap().metric, "proxy_cpu_usage");
+        assert!(templates.get(2).is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        for (name, data) in [
+            (
+                "op",
+                r#"[{"name": "a", "target": ".*", "metric": "m", "operation": "~", "value": 0}]"#,
+            ),
+            (
+                "regex",
+                r#"[{"name": "a", "target": "(", "metric": "m", "operation": ">", "value": 0}]"#,
+            ),
+            ("json", r#"{"name": "a"}"#),
+        ] {
+            let path = write_alarms(name, data);
+            assert!(AlarmTemplates::from_file(&path).is_err(), "{}", name);
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert!(AlarmTemplates::from_file(Path::new("/nonexistent/alarms.json")).is_err());
+    }
+}
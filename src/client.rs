@@ -0,0 +1,488 @@
+use std::error::Error;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::proxy_common::{CgroupStats, ProcessStats, ProxyErr};
+use crate::proxywireprotocol::{labeled_name, CounterType};
+use crate::{MetricProxyClient, MetricProxyNamespace, MetricProxyValue};
+
+/*********************
+ * SAFE RUST CLIENT  *
+ *********************/
+
+/// Handle on the metric proxy client of this process
+///
+/// The underlying connection is shared by all handles, the job is
+/// released when a handle is released or the last one is dropped.
+///
+/// Values are pushed every period as NUL separated JSON commands:
+///
+/// ```
+/// use std::io::{BufRead, BufReader};
+/// use std::os::unix::net::UnixListener;
+/// use std::time::Duration;
+///
+/// use proxyclient::client::Client;
+///
+/// /* A mock proxy socket collecting the commands of the client */
+/// let path = std::env::temp_dir().join(format!("proxy-doc-client-{}", std::process::id()));
+/// let listener = UnixListener::bind(&path).unwrap();
+///
+/// let client = Client::with_settings(path.to_str().unwrap(), Duration::from_millis(10));
+/// assert!(client.connected());
+///
+/// let calls = client.counter("calls", "Number of calls").unwrap();
+/// calls.inc(2.0).unwrap();
+///
+/// let (stream, _) = listener.accept().unwrap();
+/// let mut commands = BufReader::new(stream)
+///     .split(0)
+///     .map(|c| serde_json::from_slice::<serde_json::Value>(&c.unwrap()).unwrap());
+///
+/// assert!(commands.next().unwrap().get("JobDesc").is_some());
+///
+/// let desc = commands.find(|c| c["Desc"]["name"] == "calls").unwrap();
+/// assert_eq!(desc["Desc"]["doc"], "Number of calls");
+///
+/// let value = commands.find(|c| c["Value"]["name"] == "calls").unwrap();
+/// assert_eq!(value["Value"]["value"]["Counter"]["value"], 2.0);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct Client {
+    inner: Arc<MetricProxyClient>,
+}
+
+/// A monotonic counter created with `Client::counter`
+pub struct Counter {
+    value: Arc<MetricProxyValue>,
+}
+
+/// A counter created with `Client::updown_counter` which may decrease
+pub struct UpDownCounter {
+    value: Arc<MetricProxyValue>,
+}
+
+/// A gauge created with `Client::gauge`
+///
+/// ```
+/// # use std::io::{BufRead, BufReader};
+/// # use std::os::unix::net::UnixListener;
+/// # use std::time::Duration;
+/// use proxyclient::client::Client;
+///
+/// # let path = std::env::temp_dir().join(format!("proxy-doc-gauge-{}", std::process::id()));
+/// # let listener = UnixListener::bind(&path).unwrap();
+/// let client = Client::with_settings(path.to_str().unwrap(), Duration::from_millis(10));
+///
+/// let load = client.gauge("load", "Current load").unwrap();
+/// load.set(4.0).unwrap();
+///
+/// /* The proxy aggregates the values set during each period */
+/// # let (stream, _) = listener.accept().unwrap();
+/// # let mut commands = BufReader::new(stream)
+/// #     .split(0)
+/// #     .map(|c| serde_json::from_slice::<serde_json::Value>(&c.unwrap()).unwrap());
+/// let value = commands.find(|c| c["Value"]["name"] == "load").unwrap();
+/// let gauge = &value["Value"]["value"]["Gauge"];
+/// assert_eq!((gauge["min"].as_f64(), gauge["max"].as_f64()), (Some(4.0), Some(4.0)));
+/// assert_eq!((gauge["hits"].as_f64(), gauge["total"].as_f64()), (Some(1.0), Some(4.0)));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct Gauge {
+    value: Arc<MetricProxyValue>,
+}
+
+/// A value created with `Client::last_value`, only the newest set is kept
+pub struct LastValue {
+    value: Arc<MetricProxyValue>,
+}
+
+/// Metrics isolated from the rest of the process created with `Client::namespace`
+pub struct Namespace {
+    inner: Arc<MetricProxyNamespace>,
+}
+
+impl Client {
+    /// Connect to the proxy (PROXY_PATH) and register the job
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Client {
+        Client::started(MetricProxyClient::new())
+    }
+
+    /// Connect to the proxy listening on `path` and push values every `period`
+    ///
+    /// There is a single client per process, if it is already connected
+    /// with other settings a warning is logged and it is used as is.
+    pub fn with_settings(path: &str, period: Duration) -> Client {
+        Client::started(MetricProxyClient::new_with(path.to_string(), period))
+    }
+
+    fn started(inner: Arc<MetricProxyClient>) -> Client {
+        inner.handles.fetch_add(1, Ordering::SeqCst);
+        let client = Client { inner };
+
+        if let Ok(start) = client.inner.new_counter(
+            "has_started".to_string(),
+            "Number of calls to metric_proxy_init".to_string(),
+        ) {
+            let _ = start.inc(1.0);
+        }
+
+        client
+    }
+
+    /// Is the client connected to a proxy
+    pub fn connected(&self) -> bool {
+        self.inner.running()
+    }
+
+    fn check_connected(&self) -> Result<(), Box<dyn Error>> {
+        if !self.connected() {
+            return Err(ProxyErr::newboxed("Not connected to Metric Proxy"));
+        }
+        Ok(())
+    }
+
+    /// Create (or get) a counter
+    pub fn counter(&self, name: &str, doc: &str) -> Result<Counter, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self.inner.new_counter(name.to_string(), doc.to_string())?;
+        Ok(Counter { value })
+    }
+
+    /// Create (or get) a counter named `name{k="v",...}`
+    pub fn counter_labeled(
+        &self,
+        name: &str,
+        doc: &str,
+        labels: &[(String, String)],
+    ) -> Result<Counter, Box<dyn Error>> {
+        self.counter(&labeled_name(name.to_string(), labels), doc)
+    }
+
+    /// Create (or get) a counter which can be decremented
+    pub fn updown_counter(&self, name: &str, doc: &str) -> Result<UpDownCounter, Box<dyn Error>> {
+        self.check_connected()?;
+        let value =
+            self.inner
+                .push_entry(name.to_string(), doc.to_string(), CounterType::newupdown())?;
+        Ok(UpDownCounter { value })
+    }
+
+    /// Create (or get) a gauge
+    pub fn gauge(&self, name: &str, doc: &str) -> Result<Gauge, Box<dyn Error>> {
+        self.check_connected()?;
+        let value =
+            self.inner
+                .push_entry(name.to_string(), doc.to_string(), CounterType::newgauge())?;
+        Ok(Gauge { value })
+    }
+
+    /// Create (or get) a value keeping only the last set
+    pub fn last_value(&self, name: &str, doc: &str) -> Result<LastValue, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self.inner.push_entry(
+            name.to_string(),
+            doc.to_string(),
+            CounterType::newlastvalue(),
+        )?;
+        Ok(LastValue { value })
+    }
+
+    /// Namespace whose metrics are named `prefix__name`, for libraries
+    /// which must not collide with the metrics of the application
+    pub fn namespace(&self, prefix: &str) -> Result<Namespace, Box<dyn Error>> {
+        Ok(Namespace {
+            inner: Arc::new(MetricProxyNamespace::new(self.inner.clone(), prefix)?),
+        })
+    }
+
+    /// Current value of a metric as aggregated by the proxy for this job
+    pub fn read(&self, name: &str) -> Result<f64, Box<dyn Error>> {
+        match self.read_all(name)?.first() {
+            Some((_, value)) => Ok(*value),
+            None => Err(ProxyErr::newboxed(format!("No such metric {}", name))),
+        }
+    }
+
+    /// Current value of a metric as aggregated by the proxy for the whole node
+    pub fn read_node(&self, name: &str) -> Result<f64, Box<dyn Error>> {
+        self.check_connected()?;
+
+        /* Make sure the proxy has our latest values */
+        self.inner.dump_values()?;
+
+        match self.inner.get_node_values(name)?.first() {
+            Some(c) => Ok(c.ctype.value()),
+            None => Err(ProxyErr::newboxed(format!("No such metric {}", name))),
+        }
+    }
+
+    /// Current values of the metrics matching pattern, a trailing '*' matches any suffix
+    pub fn read_all(&self, pattern: &str) -> Result<Vec<(String, f64)>, Box<dyn Error>> {
+        self.check_connected()?;
+
+        /* Make sure the proxy has our latest values */
+        self.inner.dump_values()?;
+
+        Ok(self
+            .inner
+            .get_values(pattern)?
+            .into_iter()
+            .map(|c| (c.name, c.ctype.value()))
+            .collect())
+    }
+
+    /// Tag the trace of the job with a label at the current time
+    pub fn annotate(&self, label: &str) -> Result<(), Box<dyn Error>> {
+        self.check_connected()?;
+        self.inner.annotate(label)
+    }
+
+    /// Push statistics of this process as app_process_* gauges every period
+    pub fn enable_process_stats(&self) -> Result<(), Box<dyn Error>> {
+        self.check_connected()?;
+        ProcessStats::read()?;
+
+        let client = self.inner.clone();
+
+        thread::spawn(move || {
+            while client.running() {
+                if let Err(e) = client.dump_process_stats() {
+                    log::error!("Failed to push process statistics : {}", e);
+                    break;
+                }
+                thread::sleep(client.period);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Push the resource usage of the cgroup of this process (the job)
+    /// as job_cgroup_* gauges every period, fails without readable cgroup
+    pub fn enable_cgroup_stats(&self) -> Result<(), Box<dyn Error>> {
+        self.check_connected()?;
+        CgroupStats::read()?;
+
+        let client = self.inner.clone();
+
+        thread::spawn(move || {
+            while client.running() {
+                if let Err(e) = client.dump_cgroup_stats() {
+                    log::error!("Failed to push cgroup statistics : {}", e);
+                    break;
+                }
+                thread::sleep(client.period);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Flush all values and end the job in the proxy
+    pub fn release(&self) -> Result<(), Box<dyn Error>> {
+        if let Ok(done) = self.inner.new_counter(
+            "has_finished".to_string(),
+            "Number of calls to metric_proxy_release".to_string(),
+        ) {
+            let _ = done.inc(1.0);
+        }
+
+        *self.inner.running.lock().unwrap() = false;
+
+        self.inner.dump_values()?;
+
+        /* Values are flushed, the proxy can now finalize the job */
+        self.inner.send_jobend()?;
+
+        self.inner.release_shm();
+
+        Ok(())
+    }
+
+    /// Give the client to C code (see `metric_proxy_init`)
+    pub(crate) fn into_raw(self) -> *mut MetricProxyClient {
+        let this = ManuallyDrop::new(self);
+        /* The Arc is moved out, the client is not released */
+        let inner = unsafe { std::ptr::read(&this.inner) };
+        Arc::into_raw(inner) as *mut MetricProxyClient
+    }
+
+    /// Use a client given to C code without taking ownership
+    ///
+    /// # Safety
+    ///
+    /// The pointer must come from `Client::into_raw`
+    pub(crate) unsafe fn borrow_raw(pclient: *mut MetricProxyClient) -> ManuallyDrop<Client> {
+        ManuallyDrop::new(Client {
+            inner: unsafe { Arc::from_raw(pclient) },
+        })
+    }
+}
+
+impl Namespace {
+    fn check_connected(&self) -> Result<(), Box<dyn Error>> {
+        if !self.inner.client.running() {
+            return Err(ProxyErr::newboxed("Not connected to Metric Proxy"));
+        }
+        Ok(())
+    }
+
+    /// Create (or get) a counter of the namespace
+    pub fn counter(&self, name: &str, doc: &str) -> Result<Counter, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self
+            .inner
+            .client
+            .new_counter(self.inner.name(name), doc.to_string())?;
+        Ok(Counter { value })
+    }
+
+    /// Create (or get) a counter of the namespace which can be decremented
+    pub fn updown_counter(&self, name: &str, doc: &str) -> Result<UpDownCounter, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self.inner.client.push_entry(
+            self.inner.name(name),
+            doc.to_string(),
+            CounterType::newupdown(),
+        )?;
+        Ok(UpDownCounter { value })
+    }
+
+    /// Create (or get) a gauge of the namespace
+    pub fn gauge(&self, name: &str, doc: &str) -> Result<Gauge, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self.inner.client.push_entry(
+            self.inner.name(name),
+            doc.to_string(),
+            CounterType::newgauge(),
+        )?;
+        Ok(Gauge { value })
+    }
+
+    /// Create (or get) a value of the namespace keeping only the last set
+    pub fn last_value(&self, name: &str, doc: &str) -> Result<LastValue, Box<dyn Error>> {
+        self.check_connected()?;
+        let value = self.inner.client.push_entry(
+            self.inner.name(name),
+            doc.to_string(),
+            CounterType::newlastvalue(),
+        )?;
+        Ok(LastValue { value })
+    }
+
+    /// Give the namespace to C code
+    pub(crate) fn into_raw(self) -> *mut MetricProxyNamespace {
+        Arc::into_raw(self.inner) as *mut MetricProxyNamespace
+    }
+
+    /// Use a namespace given to C code without taking ownership
+    ///
+    /// # Safety
+    ///
+    /// The pointer must come from `Namespace::into_raw`
+    pub(crate) unsafe fn borrow_raw(pns: *mut MetricProxyNamespace) -> ManuallyDrop<Namespace> {
+        ManuallyDrop::new(Namespace {
+            inner: unsafe { Arc::from_raw(pns) },
+        })
+    }
+
+    /// Take back a namespace given to C code
+    ///
+    /// # Safety
+    ///
+    /// The pointer must come from `Namespace::into_raw` and is no longer valid
+    pub(crate) unsafe fn from_raw(pns: *mut MetricProxyNamespace) -> Namespace {
+        Namespace {
+            inner: unsafe { Arc::from_raw(pns) },
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        /* Other handles of the process still use the client */
+        if self.inner.handles.fetch_sub(1, Ordering::SeqCst) == 1 && self.connected() {
+            let _ = self.release();
+        }
+    }
+}
+
+impl Counter {
+    /// Add `value` to the counter
+    pub fn inc(&self, value: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.inc(value)?)
+    }
+}
+
+impl UpDownCounter {
+    /// Add `value` (possibly negative) to the counter
+    pub fn inc(&self, value: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.inc(value)?)
+    }
+
+    /// Remove `value` from the counter
+    pub fn dec(&self, value: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.inc(-value)?)
+    }
+}
+
+impl Gauge {
+    /// Set the current value of the gauge
+    pub fn set(&self, value: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.set(value)?)
+    }
+
+    /// Average of the values set since the last flush to the proxy, this
+    /// is local (no round-trip) and None once flushed until set again
+    pub fn local_value(&self) -> Option<f64> {
+        self.value.local_value()
+    }
+
+    /// Observe a value counting for `weight` hits in the average of the
+    /// gauge, for instance the time it was held for a time-weighted mean
+    pub fn observe_weighted(&self, value: f64, weight: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.observe_weighted(value, weight)?)
+    }
+}
+
+impl LastValue {
+    /// Set the value, the proxy keeps the newest one
+    pub fn set(&self, value: f64) -> Result<(), Box<dyn Error>> {
+        Ok(self.value.set(value)?)
+    }
+}
+
+/// Metric handles are given to C code as a pointer on their value
+macro_rules! raw_metric_handle {
+    ($handle:ident) => {
+        impl $handle {
+            /// Give the metric to C code
+            pub(crate) fn into_raw(self) -> *mut MetricProxyValue {
+                Arc::into_raw(self.value) as *mut MetricProxyValue
+            }
+
+            /// Use a metric given to C code without taking ownership
+            ///
+            /// # Safety
+            ///
+            /// The pointer must come from `into_raw` of the same handle type
+            pub(crate) unsafe fn borrow_raw(
+                pvalue: *mut MetricProxyValue,
+            ) -> ManuallyDrop<$handle> {
+                ManuallyDrop::new($handle {
+                    value: unsafe { Arc::from_raw(pvalue) },
+                })
+            }
+        }
+    };
+}
+
+raw_metric_handle!(Counter);
+raw_metric_handle!(UpDownCounter);
+raw_metric_handle!(Gauge);
+raw_metric_handle!(LastValue);
@@ -1,43 +1,100 @@
+use lazy_static::lazy_static;
 use retry::{delay::Fixed, retry};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 use std::thread::sleep;
 use std::time::Duration;
 
 use crate::proxywireprotocol::{
-    ApiResponse, CounterSnapshot, CounterType, JobDesc, JobProfile, ValueAlarm, ValueAlarmTrigger,
+    add_label, has_label, AlarmEvent, AlarmWindow, ApiResponse, CounterSnapshot, CounterType,
+    JobDesc, JobProfile, ValueAlarm, ValueAlarmTrigger, JOB_LIFECYCLE_METRICS,
 };
-use crate::{ftio, proxy_common};
+use crate::{ftio, proxy_common, proxywireprotocol};
 
+use crate::alarmtemplates::AlarmTemplates;
+#[cfg(feature = "otlp")]
+use crate::otlp::OtlpPusher;
 use crate::profiles::ProfileView;
-use crate::trace::{Trace, TraceView};
+use crate::relabel::Relabeler;
+use crate::trace::{Trace, TraceTimeUnit, TraceView};
 
-use super::proxy_common::{hostname, ProxyErr};
+use super::proxy_common::{hostname, peer_url, proxy_url, unix_ts, DirWatcher, ProxyErr};
 
-use crate::ftio::FtioClient;
+use crate::ftio::{FtioClient, FtioCommand};
 
 use crate::scrapper::{ProxyScraper, ProxyScraperSnapshot};
+use crate::systemmetrics::SystemMetricsLevel;
 
 /***********************
  * PROMETHEUS EXPORTER *
  ***********************/
 
+/// Maximum number of series (label sets) per basename (0 = unlimited)
+static MAX_SERIES_PER_METRIC: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of series rejected because of MAX_SERIES_PER_METRIC
+static DROPPED_SERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Bumped on each change of a value, the cursor of /metrics/delta
+static METRIC_VERSION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Identifies this run in delta tokens, those of a previous run are ignored
+    static ref DELTA_EPOCH: u64 = unix_ts();
+}
+
+/// Did a value change, newer timestamps alone do not count
+fn value_changed(before: &CounterType, after: &CounterType) -> bool {
+    match (before, after) {
+        (CounterType::Counter { value: a, .. }, CounterType::Counter { value: b, .. })
+        | (CounterType::UpDown { value: a, .. }, CounterType::UpDown { value: b, .. })
+        | (CounterType::LastValue { value: a, .. }, CounterType::LastValue { value: b, .. }) => {
+            a != b
+        }
+        _ => before != after,
+    }
+}
+
 /// This is a refcounted reference to a counter and
 /// its documentation this allows to lock at counter
 /// granularity if needed
 struct ExporterEntry {
     value: Arc<RwLock<CounterSnapshot>>,
+    /// Unix timestamp in ms of the last set or accumulate
+    last_update: AtomicU64,
+    /// METRIC_VERSION at the last change of the value
+    version: AtomicU64,
 }
 
 impl ExporterEntry {
     fn new(value: CounterSnapshot) -> ExporterEntry {
         ExporterEntry {
             value: Arc::new(RwLock::new(value)),
+            last_update: AtomicU64::new(unix_ts()),
+            version: AtomicU64::new(METRIC_VERSION.fetch_add(1, Ordering::SeqCst) + 1),
         }
     }
+
+    fn touch(&self) {
+        self.last_update.store(unix_ts(), Ordering::Relaxed);
+    }
+
+    /// Record a change of the value, called with its write lock held
+    /// so that a reader holding the read lock sees a stable version
+    fn bump(&self) {
+        let version = METRIC_VERSION.fetch_add(1, Ordering::SeqCst) + 1;
+        self.version.store(version, Ordering::SeqCst);
+    }
+
+    fn updated_since(&self, ts: u64) -> bool {
+        self.last_update.load(Ordering::Relaxed) >= ts
+    }
 }
 
 /// This is a group of values used to have counters with the
@@ -61,6 +118,8 @@ struct ExporterEntryGroup {
     doc: String,
     /// List of values (stored with their full name including the {XXX})
     ht: RwLock<HashMap<String, ExporterEntry>>,
+    /// Was the series cap reached (to warn only once)
+    capped: AtomicBool,
 }
 
 impl ExporterEntryGroup {
@@ -70,9 +129,16 @@ impl ExporterEntryGroup {
             basename,
             doc,
             ht: RwLock::new(HashMap::new()),
+            capped: AtomicBool::new(false),
         }
     }
 
+    /// Would a new series exceed the cardinality cap for a group of len series
+    fn cap_reached(len: usize) -> bool {
+        let max = MAX_SERIES_PER_METRIC.load(Ordering::Relaxed);
+        max > 0 && len >= max
+    }
+
     /// Get the basename of the ExporterEntryGroup
     fn basename(name: String) -> String {
         let spl: Vec<&str> = name.split('{').collect();
@@ -82,10 +148,16 @@ impl ExporterEntryGroup {
     #[allow(unused)]
     /// Set a value in the ExporterEntryGroup
     fn set(&self, value: CounterSnapshot) -> Result<(), ProxyErr> {
-        match self.ht.write().unwrap().get_mut(&value.name) {
+        /* Each entry has its own lock, the list is only read */
+        match self.ht.read().unwrap().get(&value.name) {
             Some(v) => {
                 let mut val = v.value.write().unwrap();
+                let changed = value_changed(&val.ctype, &value.ctype);
                 *val = value;
+                v.touch();
+                if changed {
+                    v.bump();
+                }
                 Ok(())
             }
             None => Err(ProxyErr::new("Failed to set counter")),
@@ -96,16 +168,25 @@ impl ExporterEntryGroup {
     ///
     /// This will sum up data
     fn accumulate(&self, snapshot: &CounterSnapshot, merge: bool) -> Result<(), ProxyErr> {
-        match self.ht.write().unwrap().get_mut(&snapshot.name) {
+        let ht = self.ht.read().unwrap();
+
+        match ht.get(&snapshot.name) {
             Some(v) => {
                 let mut val = v.value.write().unwrap();
+                let before = val.ctype.clone();
                 if merge {
                     val.merge(snapshot)?;
                 } else {
                     val.set(snapshot)?;
                 }
+                v.touch();
+                if value_changed(&before, &val.ctype) {
+                    v.bump();
+                }
                 Ok(())
             }
+            /* Values of series dropped by the cardinality cap */
+            None if ExporterEntryGroup::cap_reached(ht.len()) => Ok(()),
             None => Err(ProxyErr::new(
                 format!("Failed to accumulate {} {:?}", snapshot.name, snapshot).as_str(),
             )),
@@ -127,40 +208,147 @@ impl ExporterEntryGroup {
     }
 
     /// Insert a new value in the counter list
-    fn push(&self, snapshot: CounterSnapshot) -> Result<(), ProxyErr> {
+    fn push(&self, mut snapshot: CounterSnapshot) -> Result<(), ProxyErr> {
         let name = snapshot.name.to_string();
         if self.ht.read().unwrap().contains_key(&name) {
             return Ok(());
-        } else {
-            if name.contains('{') && !name.contains('}') {
-                return Err(ProxyErr::new(
-                    format!("Bad metric name '{}' unmatched brackets", name).as_str(),
-                ));
+        }
+
+        if name.contains('{') && !name.contains('}') {
+            return Err(ProxyErr::new(
+                format!("Bad metric name '{}' unmatched brackets", name).as_str(),
+            ));
+        }
+
+        /* Check again under the write lock, a concurrent push may have
+        inserted (and already accumulated in) the same series */
+        let mut ht = self.ht.write().unwrap();
+
+        if ht.contains_key(&name) {
+            return Ok(());
+        }
+
+        if ExporterEntryGroup::cap_reached(ht.len()) {
+            DROPPED_SERIES.fetch_add(1, Ordering::Relaxed);
+            if !self.capped.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "{} reached --max-series-per-metric, new series are dropped (first is {})",
+                    self.basename,
+                    name
+                );
             }
-            let new = ExporterEntry::new(snapshot);
-            self.ht.write().unwrap().insert(name, new);
+            return Ok(());
         }
 
+        /* Series pushed without a doc (labeled copies, TTL re-push) share the one of the group */
+        if snapshot.doc.is_empty() {
+            snapshot.doc = self.doc.to_string();
+        }
+
+        ht.insert(name, ExporterEntry::new(snapshot));
+
         Ok(())
     }
 
     #[allow(unused)]
     /// Generate the prometheus data from the couter list
-    fn serialize(&self) -> Result<String, ProxyErr> {
+    ///
+    /// If an exemplar (trace id, timestamp) is passed it is appended
+    /// to counter lines following the OpenMetrics format
+    ///
+    /// Without full the values with no data (zero counters, gauges never
+    /// set) are skipped as well as the group itself if none is left
+    fn serialize(&self, exemplar: &Option<(String, f64)>, full: bool) -> Result<String, ProxyErr> {
         let mut ret: String = String::new();
 
+        if !full && !self.hasdata() {
+            return Ok(ret);
+        }
+
         ret += format!("# HELP {} {}\n", self.basename, self.doc).as_str();
-        ret += format!("# TYPE {} counter\n", self.basename).as_str();
+
+        /* Up/down counters may decrease, rates must not see resets */
+        let updown =
+            self.ht.read().unwrap().values().next().is_some_and(|e| {
+                matches!(e.value.read().unwrap().ctype, CounterType::UpDown { .. })
+            });
+        let ptype = if updown { "gauge" } else { "counter" };
+        ret += format!("# TYPE {} {}\n", self.basename, ptype).as_str();
 
         for (_, exporter_counter) in self.ht.read().unwrap().iter() {
             // Acquire the Mutex for this specific ExporterEntry
             let value = exporter_counter.value.read().unwrap();
-            ret += value.serialize().as_str();
+            if !full && !value.hasdata() {
+                continue;
+            }
+            if let Some((trace_id, trace_ts)) = exemplar {
+                ret += value.serialize_with_exemplar(trace_id, *trace_ts).as_str();
+            } else {
+                ret += value.serialize().as_str();
+            }
         }
 
         Ok(ret)
     }
 
+    /// Documentation and type of the group, None when it has no value
+    fn meta(&self) -> Option<MetricMeta> {
+        let ht = self.ht.read().unwrap();
+        let first = ht.values().next()?;
+        let kind = first.value.read().unwrap().ctype.kind();
+
+        Some(MetricMeta {
+            name: self.basename.to_string(),
+            doc: self.doc.to_string(),
+            kind: kind.to_string(),
+        })
+    }
+
+    /// Drop the values not updated since the given timestamp
+    /// returns the number of removed values
+    fn expire(&self, deadline: u64) -> usize {
+        let mut ht = self.ht.write().unwrap();
+        let before = ht.len();
+        ht.retain(|_, v| v.updated_since(deadline));
+        before - ht.len()
+    }
+
+    /// Drop the values whose name matches, returns the number of removed values
+    fn remove_matching(&self, pred: &dyn Fn(&str) -> bool) -> usize {
+        let mut ht = self.ht.write().unwrap();
+        let before = ht.len();
+        ht.retain(|k, _| !pred(k));
+        before - ht.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ht.read().unwrap().is_empty()
+    }
+
+    /// Does any value of the group hold data
+    fn hasdata(&self) -> bool {
+        self.ht
+            .read()
+            .unwrap()
+            .values()
+            .any(|v| v.value.read().unwrap().hasdata())
+    }
+
+    /// Values changed after the given METRIC_VERSION
+    fn changed_since(&self, version: u64) -> Vec<CounterSnapshot> {
+        let mut ret: Vec<CounterSnapshot> = Vec::new();
+
+        for (_, exporter_counter) in self.ht.read().unwrap().iter() {
+            /* Version is read under the value lock, see bump */
+            let value = exporter_counter.value.read().unwrap();
+            if exporter_counter.version.load(Ordering::SeqCst) > version {
+                ret.push(value.clone());
+            }
+        }
+
+        ret
+    }
+
     /// Clone the current the counter list as a vector of CounterSnapshot
     fn snapshot(&self, full: bool) -> Result<Vec<CounterSnapshot>, ProxyErr> {
         let mut ret: Vec<CounterSnapshot> = Vec::new();
@@ -169,7 +357,7 @@ impl ExporterEntryGroup {
             // Acquire the Mutex for this specific ExporterEntry
             let value = exporter_counter.value.read().unwrap().clone();
             if value.hasdata() || full {
-                ret.push(value.clone());
+                ret.push(value);
             }
         }
 
@@ -177,17 +365,42 @@ impl ExporterEntryGroup {
     }
 }
 
+/// Metrics changed since a token and the token for the next call (see /metrics/delta)
+#[derive(Serialize)]
+pub(crate) struct MetricsDelta {
+    pub token: String,
+    pub metrics: Vec<CounterSnapshot>,
+}
+
+/// Documentation and type of a metric (see /metrics/meta)
+#[allow(unused)]
+#[derive(Serialize)]
+pub(crate) struct MetricMeta {
+    pub name: String,
+    pub doc: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
 /// An exporter is the central metric storage structure
 /// It holds a hashmap of ExporterEntryGroup which themselves
 /// store the various counter values.
 ///
 /// It is also host for the alarms which are applied to the
 /// various metrics using the `check_alarms` call.
+///
+/// Locks are always taken in the same order: the group list (`ht`),
+/// then the series list of a group and finally the value of a series.
+/// Values are updated under read locks of both lists, write locks on
+/// the lists are only taken to insert or remove.
 pub(crate) struct Exporter {
     /// List of metrics stored by basename in ExporterEntryGroup
     ht: RwLock<HashMap<String, ExporterEntryGroup>>,
     /// List of alarms each refering to a counter
     alarms: RwLock<HashMap<String, ValueAlarm>>,
+    /// Trace of the job attached to this exporter (if any)
+    /// used to generate exemplars pointing to trace frames
+    trace: RwLock<Option<Weak<Trace>>>,
 }
 
 impl Exporter {
@@ -195,6 +408,33 @@ impl Exporter {
         Exporter {
             ht: RwLock::new(HashMap::new()),
             alarms: RwLock::new(HashMap::new()),
+            trace: RwLock::new(None),
+        }
+    }
+
+    pub(crate) fn set_trace(&self, trace: &Arc<Trace>) {
+        *self.trace.write().unwrap() = Some(Arc::downgrade(trace));
+    }
+
+    /// Returns the jobid and the last write timestamp of the attached trace
+    fn exemplar(&self) -> Option<(String, f64)> {
+        let trace = self.trace.read().unwrap().as_ref()?.upgrade()?;
+        Some((trace.desc().jobid.to_string(), trace.lastwrite()))
+    }
+
+    /// Record an annotation in the attached trace
+    /// returns false if there is none (traces are only written by the aggregator)
+    pub(crate) fn annotate(&self, ts: u64, label: &str) -> Result<bool, Box<dyn Error>> {
+        let trace = self
+            .trace
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|t| t.upgrade());
+
+        match trace {
+            Some(trace) => trace.annotate(ts, label.to_string()).map(|_| true),
+            None => Ok(false),
         }
     }
 
@@ -244,11 +484,15 @@ impl Exporter {
 
         let basename = ExporterEntryGroup::basename(value.name.to_string());
 
+        /* Most pushes are for existing groups, do not block readers for them */
+        if let Some(ncnt) = self.ht.read().unwrap().get(basename.as_str()) {
+            return ncnt.push(value.clone());
+        }
+
         let mut ht = self.ht.write().unwrap();
 
         if let Some(ncnt) = ht.get(basename.as_str()) {
             ncnt.push(value.clone())?;
-            return Ok(());
         } else {
             let ncnt = ExporterEntryGroup::new(basename.to_owned(), value.doc.to_string());
             ncnt.push(value.clone())?;
@@ -259,11 +503,23 @@ impl Exporter {
     }
 
     #[allow(unused)]
-    pub(crate) fn serialize(&self) -> Result<String, ProxyErr> {
+    pub(crate) fn serialize(&self, exemplars: bool) -> Result<String, ProxyErr> {
+        self.serialize_full(exemplars, true)
+    }
+
+    /// Prometheus text of the exporter
+    ///
+    /// With full every declared metric is listed, including counters still
+    /// at zero and gauges never set. Otherwise only metrics which hold data
+    /// are listed, as in counters(false).
+    #[allow(unused)]
+    pub(crate) fn serialize_full(&self, exemplars: bool, full: bool) -> Result<String, ProxyErr> {
         let mut ret: String = String::new();
 
+        let exemplar = if exemplars { self.exemplar() } else { None };
+
         for (_, exporter_counter) in self.ht.read().unwrap().iter() {
-            ret += exporter_counter.serialize()?.as_str();
+            ret += exporter_counter.serialize(&exemplar, full)?.as_str();
         }
 
         ret += "# EOF\n";
@@ -271,29 +527,100 @@ impl Exporter {
         Ok(ret)
     }
 
-    pub(crate) fn profile(&self, desc: &JobDesc, full: bool) -> Result<JobProfile, ProxyErr> {
-        let mut ret = JobProfile {
-            desc: desc.clone(),
-            counters: Vec::new(),
-        };
+    /// Documentation and type of each metric without their values
+    pub(crate) fn meta(&self) -> Vec<MetricMeta> {
+        let mut ret: Vec<MetricMeta> = self
+            .ht
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|g| g.meta())
+            .collect();
+
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+
+        ret
+    }
+
+    /// Remove the metrics not updated during the ttl ms before now
+    /// returns the number of removed metrics
+    pub(crate) fn expire(&self, now: u64, ttl: u64) -> usize {
+        let deadline = now.saturating_sub(ttl);
+        let mut ht = self.ht.write().unwrap();
+
+        let removed = ht.values().map(|g| g.expire(deadline)).sum();
+        ht.retain(|_, g| !g.is_empty());
+
+        removed
+    }
+
+    /// Remove the series whose full name matches
+    /// returns the number of removed series
+    pub(crate) fn remove_matching(&self, pred: &dyn Fn(&str) -> bool) -> usize {
+        let mut ht = self.ht.write().unwrap();
+
+        let removed = ht.values().map(|g| g.remove_matching(pred)).sum();
+        ht.retain(|_, g| !g.is_empty());
+
+        removed
+    }
+
+    /// All counters of the exporter (only those with data unless full)
+    pub(crate) fn counters(&self, full: bool) -> Result<Vec<CounterSnapshot>, ProxyErr> {
+        let mut ret: Vec<CounterSnapshot> = Vec::new();
 
         for (_, exporter_counter) in self.ht.read().unwrap().iter() {
             let snaps = exporter_counter.snapshot(full)?;
-            ret.counters.extend(snaps);
+            ret.extend(snaps);
         }
 
         Ok(ret)
     }
 
+    /// Values changed since the token of a previous call
+    ///
+    /// An empty, malformed or stale (previous run) token returns every
+    /// value. Removed values are not reported.
+    #[allow(unused)]
+    pub(crate) fn delta(&self, token: &str) -> MetricsDelta {
+        let since = token
+            .split_once('.')
+            .filter(|(epoch, _)| u64::from_str_radix(epoch, 16).ok() == Some(*DELTA_EPOCH))
+            .and_then(|(_, version)| u64::from_str_radix(version, 16).ok())
+            .unwrap_or(0);
+
+        /* Taken first, values changed while listing are sent again next time */
+        let version = METRIC_VERSION.load(Ordering::SeqCst);
+
+        let mut metrics: Vec<CounterSnapshot> = Vec::new();
+        for (_, group) in self.ht.read().unwrap().iter() {
+            metrics.extend(group.changed_since(since));
+        }
+
+        MetricsDelta {
+            token: format!("{:x}.{:x}", *DELTA_EPOCH, version),
+            metrics,
+        }
+    }
+
+    pub(crate) fn profile(&self, desc: &JobDesc, full: bool) -> Result<JobProfile, ProxyErr> {
+        Ok(JobProfile {
+            desc: desc.clone(),
+            counters: self.counters(full)?,
+        })
+    }
+
     pub(crate) fn add_alarm(
         &self,
         name: String,
         metric: String,
         op: String,
         value: f64,
+        history_len: usize,
+        window: Option<AlarmWindow>,
     ) -> Result<(), ProxyErr> {
         let cnt: Arc<RwLock<CounterSnapshot>> = self.get(&metric)?;
-        let alarm = ValueAlarm::new(&name, cnt, op, value)?;
+        let alarm = ValueAlarm::new(&name, cnt, op, value, history_len)?.with_window(window);
 
         log::info!("Adding new alarm {}", alarm);
 
@@ -333,6 +660,16 @@ impl Exporter {
 
         ret
     }
+
+    /// Transitions of each alarm
+    pub(crate) fn alarm_history(&self) -> HashMap<String, Vec<AlarmEvent>> {
+        self.alarms
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, a)| (k.to_string(), a.history()))
+            .collect()
+    }
 }
 
 /// This structure is used to manage the job refcounting
@@ -349,6 +686,8 @@ struct PerJobRefcount {
     /// A job from a scrapper is not a local one
     /// It is used to only blame node-local metrics to local jobs
     islocal: bool,
+    /// Alarm templates waiting for their metric to be instantiated
+    pending_alarms: Vec<usize>,
 }
 
 impl Drop for PerJobRefcount {
@@ -363,6 +702,153 @@ impl PerJobRefcount {
     }
 }
 
+/// Counter value after an update, sent to /stream subscribers
+#[derive(Serialize, Clone)]
+pub(crate) struct CounterUpdate {
+    pub job: String,
+    pub counter: CounterSnapshot,
+}
+
+/// Annotation of a job forwarded to the root proxy (POST /annotate)
+#[allow(unused)]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JobAnnotation {
+    pub job: String,
+    /// Unix timestamp in us
+    pub ts: u64,
+    pub label: String,
+}
+
+/// Maximum number of updates queued per stream before dropping
+const STREAM_QUEUE_LEN: usize = 4096;
+
+/// Maximum number of live update streams, each one holds a webserver thread
+const MAX_STREAMS: usize = 32;
+
+/// Sending side of a live update stream
+type StreamSender = Arc<SyncSender<CounterUpdate>>;
+
+/// Settings of the ExporterFactory (mostly from the command line)
+pub(crate) struct ExporterFactoryOptions {
+    /// Store profiles and traces (false with -i)
+    pub aggregate: bool,
+    /// Max size of a trace file in bytes
+    pub max_trace_size: usize,
+    /// Max number of samples per metric kept in memory (0 = unbounded)
+    pub trace_window: usize,
+    /// Timestamp new traces in nanoseconds instead of seconds
+    pub trace_nanoseconds: bool,
+    /// Seconds after their last write the traces of finished jobs are removed (0 = never)
+    pub trace_retention: u64,
+    /// Only write the counters which changed in trace frames
+    pub trace_delta: bool,
+    /// Scrape period in ms
+    pub period: u64,
+    /// Number of branches for the aggregation tree
+    pub branches: u64,
+    /// Level of node metrics collected by the system scraper
+    pub system_metrics: SystemMetricsLevel,
+    /// Prefix of system metrics (and all metrics if prefix_all)
+    pub metric_prefix: String,
+    /// Also apply the prefix to client supplied metrics
+    pub prefix_all: bool,
+    /// Drop metrics not updated for this many seconds (0 = never)
+    pub metric_ttl: u64,
+    /// Connect and read timeout of network scrapes
+    pub scrape_timeout: Duration,
+    /// Consecutive failures before a scrape is evicted
+    pub scrape_failures: u32,
+    /// How long evicted network scrapes are probed before being dropped (0 = drop at once)
+    pub scrape_retry_max: Duration,
+    /// Spread network scrapes of equal period with a random phase
+    pub scrape_jitter: bool,
+    /// Renaming rules for client metrics
+    pub relabel: Relabeler,
+    /// Number of transitions kept per alarm
+    pub alarm_history: usize,
+    /// JSON file of alarms added to every matching job (see AlarmTemplates)
+    pub alarms_file: Option<PathBuf>,
+    /// Maximum number of series per metric basename (0 = unlimited)
+    pub max_series_per_metric: usize,
+    /// Significant digits of values in the Prometheus output (0 = exact)
+    pub metric_precision: usize,
+    /// SQLite database storing profiles instead of files (needs the sqlite feature)
+    pub profile_db: Option<PathBuf>,
+    /// Also store the profile of each scraped proxy (node) for every job
+    pub keep_node_profiles: bool,
+    /// Save profiles in binary (.profile.bin) instead of JSON
+    pub binary_profiles: bool,
+    /// Also expose the metrics of each job in main with a jobid label
+    pub label_jobs_in_main: bool,
+    /// Label the metrics scraped from child proxies with their hostname
+    pub label_source_host: bool,
+    /// OTLP/HTTP collector receiving the job metrics every period (needs the otlp feature)
+    pub otlp_endpoint: Option<String>,
+    /// Command run to compute FTIO models when the FTIO server is unreachable
+    pub ftio_command: FtioCommand,
+}
+
+impl Default for ExporterFactoryOptions {
+    fn default() -> Self {
+        ExporterFactoryOptions {
+            aggregate: true,
+            max_trace_size: 1024 * 1024 * 32,
+            trace_window: 0,
+            trace_nanoseconds: false,
+            trace_retention: 0,
+            trace_delta: false,
+            period: 1000,
+            branches: 2,
+            system_metrics: SystemMetricsLevel::Full,
+            metric_prefix: "proxy_".to_string(),
+            prefix_all: false,
+            metric_ttl: 0,
+            scrape_timeout: Duration::from_secs(5),
+            scrape_failures: 3,
+            scrape_retry_max: Duration::from_secs(600),
+            scrape_jitter: true,
+            relabel: Relabeler::default(),
+            alarm_history: 32,
+            alarms_file: None,
+            max_series_per_metric: 0,
+            metric_precision: 6,
+            profile_db: None,
+            keep_node_profiles: false,
+            binary_profiles: false,
+            label_jobs_in_main: false,
+            label_source_host: false,
+            otlp_endpoint: None,
+            ftio_command: FtioCommand::default(),
+        }
+    }
+}
+
+/// Longest delay (ms) between two probes of an evicted scrape
+const SCRAPE_RETRY_MAX_BACKOFF: u64 = 60_000;
+
+/// A network scrape evicted after failures
+struct EvictedScrape {
+    scrape: ProxyScraper,
+    /// Unix ts (ms) of the eviction
+    since: u64,
+    /// Unix ts (ms) of the next probe
+    next_probe: u64,
+    /// Current delay (ms) between probes, doubled after each failure
+    backoff: u64,
+}
+
+impl EvictedScrape {
+    fn new(scrape: ProxyScraper) -> EvictedScrape {
+        let now = unix_ts();
+        EvictedScrape {
+            scrape,
+            since: now,
+            next_probe: now + 1000,
+            backoff: 1000,
+        }
+    }
+}
+
 /// This is the central pivot for metric and job management
 /// in the metric proxy all operations pass trough here
 /// and they are then dispatched to individual exporter instances
@@ -384,6 +870,8 @@ pub(crate) struct ExporterFactory {
     scrapes: Mutex<HashMap<String, ProxyScraper>>,
     /// Pending scrapes to be backpushed
     pending_scrapes: Mutex<Vec<(String, ProxyScraper)>>,
+    /// Network scrapes evicted after failures, probed until they answer again
+    evicted_scrapes: Mutex<HashMap<String, EvictedScrape>>,
     /// Instance of the profile manager
     /// in charge of listing and loading profiles
     pub profile_store: Arc<ProfileView>,
@@ -395,21 +883,78 @@ pub(crate) struct ExporterFactory {
     max_trace_size: usize,
     /// This is where the traces are stored
     pub trace_store: Arc<TraceView>,
+    /// Seconds after their last write the traces of finished jobs are removed (0 = never)
+    trace_retention: u64,
     /// Client to FTIO server
     pub ftio_client: Arc<FtioClient>,
     pub root_proxy: Arc<RwLock<Option<String>>>,
+    /// All the roots this proxy joined (the first one is root_proxy)
+    pub root_proxies: Arc<RwLock<Vec<String>>>,
     pub web_url: Arc<RwLock<Option<String>>>,
     pub period: Arc<RwLock<u64>>,
     pub branches: u64,
     pub instrumentation: Arc<dyn Instrumentation>,
+    /// Prefix of system metrics
+    metric_prefix: String,
+    /// Prefix client metrics too
+    prefix_all: bool,
+    /// Metric TTL in seconds (0 = never expire), can change at runtime
+    metric_ttl: AtomicU64,
+    /// Connect and read timeout of network scrapes, can change at runtime
+    scrape_timeout: RwLock<Duration>,
+    /// Level of the system scrape, can change at runtime
+    system_metrics: Mutex<SystemMetricsLevel>,
+    /// Consecutive failures before a scrape is evicted
+    scrape_failures: u32,
+    /// How long evicted network scrapes are probed before being dropped
+    scrape_retry_max: Duration,
+    /// Spread network scrapes of equal period with a random phase
+    pub scrape_jitter: bool,
+    /// Renaming rules applied to client metrics
+    relabel: Relabeler,
+    /// Number of transitions kept per alarm
+    alarm_history: usize,
+    /// Alarms added to every matching job
+    alarm_templates: AlarmTemplates,
+    /// Live update subscribers with their optional job filter
+    streams: Mutex<Vec<(Option<String>, StreamSender)>>,
+    /// Cleared when the proxy is shutting down
+    running: AtomicBool,
+    /// Set after the first complete scrape pass
+    ready: AtomicBool,
+    /// Set once in-flight jobs have been flushed
+    shutdown_done: Mutex<bool>,
+    /// Wakes the shutdown callers waiting for the flush
+    shutdown_cv: Condvar,
+    /// Number of UNIX clients currently connected
+    clients_connected: AtomicI64,
+    /// Keep the profile of each scraped proxy for every job
+    keep_node_profiles: bool,
+    /// Cumulative profile of running jobs per scraped proxy (jobid -> node -> profile)
+    node_profiles: Mutex<HashMap<String, HashMap<String, JobProfile>>>,
+    /// Unix ts (ms) when a job (other than main and node) was last seen
+    last_job_seen: AtomicU64,
+    /// Also expose the metrics of each job in main with a jobid label
+    label_jobs_in_main: bool,
+    /// Label the metrics scraped from child proxies with their hostname
+    label_source_host: bool,
 }
 
 impl ExporterFactory {
     /// This function if the mainloop of the scrapting thread
     /// It runs infinitely every 1 second checking all scrapes
     fn run_scrapping(&self) {
-        loop {
+        let mut last_alarm_check: u64 = 0;
+        let mut last_folds: u64 = 0;
+        let mut last_dropped: u64 = 0;
+        let mut last_processed: u64 = 0;
+        let mut last_reclaimed: u64 = 0;
+        let mut last_trace_gc: u64 = unix_ts();
+
+        while self.is_running() {
             let mut to_delete: Vec<String> = Vec::new();
+            /* Readiness needs a pass where every scrape succeeded */
+            let mut pass_ok = true;
 
             /* Scrape all the candidates */
             if let Ok(scrapes) = self.scrapes.lock().as_mut() {
@@ -426,6 +971,19 @@ impl ExporterFactory {
                     }
 
                     if let Err(e) = res {
+                        pass_ok = false;
+                        let failures = v.failed();
+                        if failures < self.scrape_failures {
+                            log::warn!(
+                                "Failed to scrape {} ({}/{} failures) : {}",
+                                k,
+                                failures,
+                                self.scrape_failures,
+                                e
+                            );
+                            continue;
+                        }
+
                         if let Some(target_url) = v.get_url_if_proxy() {
                             log::error!(
                                 "Failed to scrape proxy {} : {}! Notifying the root server.",
@@ -455,30 +1013,264 @@ impl ExporterFactory {
                     }
                 }
 
-                /* Remove failed scrapes */
+                /* Remove failed scrapes, network ones are probed until they come back */
                 for k in to_delete {
-                    scrapes.remove(&k);
+                    if let Some(scrape) = scrapes.remove(&k) {
+                        if let Some((last_success, _)) = scrape.status() {
+                            self.update_scrape_status(&k, last_success, false);
+                            if !self.scrape_retry_max.is_zero() {
+                                log::info!(
+                                    "Retrying {} for up to {} s",
+                                    k,
+                                    self.scrape_retry_max.as_secs()
+                                );
+                                self.evicted_scrapes
+                                    .lock()
+                                    .unwrap()
+                                    .insert(k, EvictedScrape::new(scrape));
+                            }
+                        }
+                    }
+                }
+
+                if pass_ok {
+                    self.ready.store(true, Ordering::SeqCst);
+                }
+            }
+
+            self.retry_evicted_scrapes();
+
+            /* Alarms are checked every period to record their transitions */
+            if unix_ts() - last_alarm_check >= *self.period.read().unwrap() {
+                self.apply_alarm_templates();
+                self.check_alarms();
+                (last_folds, last_reclaimed) =
+                    self.update_trace_metrics(last_folds, last_reclaimed);
+                last_dropped = self.update_series_metrics(last_dropped);
+                self.update_scrape_metrics();
+                if self.aggregator {
+                    last_processed = self.update_aggregation_metrics(last_processed);
                 }
+                last_alarm_check = unix_ts();
+            }
+
+            /* Traces are swept once a minute */
+            if self.trace_retention > 0 && unix_ts() - last_trace_gc >= 60 * 1000 {
+                self.trace_gc();
+                last_trace_gc = unix_ts();
             }
 
             sleep(Duration::from_millis(10));
         }
     }
 
+    /// Mainloop of the thread removing metrics older than ttl seconds
+    fn run_expiry(&self) {
+        while self.is_running() {
+            let ttl = self.metric_ttl();
+            sleep(Duration::from_secs(ttl.clamp(1, 60)));
+
+            /* The TTL may have changed (or been disabled) while sleeping */
+            let ttl_ms = self.metric_ttl() * 1000;
+            if ttl_ms == 0 {
+                continue;
+            }
+
+            let removed = self.expire_metrics(unix_ts(), ttl_ms);
+
+            if removed > 0 {
+                log::debug!("Removed {} stale metrics", removed);
+            }
+        }
+    }
+
+    /// Remove the job metrics not updated during the ttl ms before now,
+    /// the main and node exporters are cumulative and never expire
+    fn expire_metrics(&self, now: u64, ttl_ms: u64) -> usize {
+        let jobs: Vec<Arc<Exporter>> = self
+            .perjob
+            .lock()
+            .unwrap()
+            .values()
+            .map(|j| j.exporter.clone())
+            .filter(|e| !Arc::ptr_eq(e, &self.main) && !Arc::ptr_eq(e, &self.pernode))
+            .collect();
+
+        jobs.iter().map(|e| e.expire(now, ttl_ms)).sum()
+    }
+
+    /// Mainloop of the thread loading the profiles as soon as they are written,
+    /// files failing to parse are retried by the pass of run_scrapping
+    fn run_profile_watch(&self, watcher: DirWatcher) {
+        while self.is_running() {
+            let period = Duration::from_millis(*self.period.read().unwrap());
+
+            match watcher.wait(period) {
+                Ok(true) => {
+                    if let Err(e) = self.profile_store.refresh_profiles() {
+                        log::error!("Failed to load new profiles : {}", e);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("Stopped watching the profile directory : {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    /// Mainloop of the thread pushing the job metrics to an OTLP collector
+    /// the push interval is the scrape period
+    fn run_otlp(&self, pusher: OtlpPusher) {
+        while self.is_running() {
+            sleep(Duration::from_millis(*self.period.read().unwrap()));
+
+            if let Err(e) = pusher.push(&self.profiles(false)) {
+                log::warn!("Failed to push metrics to {} : {}", pusher.url(), e);
+            }
+        }
+    }
+
+    /// Probe the evicted scrapes which are due, reinstate those answering
+    /// again and drop those unreachable for more than --scrape-retry-max
+    fn retry_evicted_scrapes(&self) {
+        let now = unix_ts();
+
+        let due: Vec<(String, EvictedScrape)> = {
+            let mut evicted = self.evicted_scrapes.lock().unwrap();
+            let keys: Vec<String> = evicted
+                .iter()
+                .filter(|(_, v)| v.next_probe <= now)
+                .map(|(k, _)| k.to_string())
+                .collect();
+            keys.into_iter()
+                .filter_map(|k| evicted.remove(&k).map(|v| (k, v)))
+                .collect()
+        };
+
+        /* Probes may block up to the scrape timeout, run them in parallel
+        without the lock so that down hosts delay a pass by one timeout at most */
+        let probed: Vec<(String, EvictedScrape, Result<(), String>)> = std::thread::scope(|s| {
+            due.into_iter()
+                .map(|(k, evicted)| {
+                    s.spawn(move || {
+                        let res = evicted.scrape.probe().map_err(|e| e.to_string());
+                        (k, evicted, res)
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .filter_map(|h| h.join().ok())
+                .collect()
+        });
+
+        for (k, mut evicted, res) in probed {
+            match res {
+                Ok(_) => {
+                    log::info!("{} answers again, restarting its scrape", k);
+                    evicted.scrape.reinstate();
+                    /* It may have been joined again in the meantime */
+                    self.scrapes
+                        .lock()
+                        .unwrap()
+                        .entry(k)
+                        .or_insert(evicted.scrape);
+                }
+                Err(e) if unix_ts() - evicted.since >= self.scrape_retry_max.as_millis() as u64 => {
+                    log::error!(
+                        "Dropping {} unreachable for {} s : {}",
+                        k,
+                        self.scrape_retry_max.as_secs(),
+                        e
+                    );
+                }
+                Err(e) => {
+                    log::debug!("{} is still unreachable : {}", k, e);
+                    evicted.backoff = (evicted.backoff * 2).min(SCRAPE_RETRY_MAX_BACKOFF);
+                    evicted.next_probe = unix_ts() + evicted.backoff;
+                    self.evicted_scrapes.lock().unwrap().insert(k, evicted);
+                }
+            }
+        }
+    }
+
     #[allow(unused)]
     /// Add a new scrape to the scrape list
+    /// Scrape url every period ms, a non zero gauge_interval (ms) merges
+    /// the gauge samples of Prometheus targets over that interval
     pub(crate) fn add_scrape(
         factory: Arc<ExporterFactory>,
         url: &String,
         period: u64,
-    ) -> Result<(), Box<dyn Error>> {
-        let new = ProxyScraper::new(url, period, factory.clone())?;
-        factory
-            .scrapes
+        gauge_interval: u64,
+    ) -> Result<String, Box<dyn Error>> {
+        let key = ExporterFactory::add_scrape_unless(
+            factory,
+            url,
+            period,
+            gauge_interval,
+            &HashSet::new(),
+        )?;
+        Ok(key.unwrap_or_default())
+    }
+
+    /// Same as add_scrape but leaves the scrapes of the keys in keep untouched
+    /// returns None if the target is one of these scrapes
+    pub(crate) fn add_scrape_unless(
+        factory: Arc<ExporterFactory>,
+        url: &String,
+        period: u64,
+        gauge_interval: u64,
+        keep: &HashSet<String>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut new = ProxyScraper::new(url, period, factory.clone())?;
+        new.set_gauge_interval(gauge_interval);
+        /* The target URL is the key to pass to remove_scrape */
+        let key = new.url().to_string();
+        if keep.contains(&key) {
+            return Ok(None);
+        }
+        factory.scrapes.lock().unwrap().insert(key.clone(), new);
+        Ok(Some(key))
+    }
+
+    #[allow(unused)]
+    /// Pause or resume the scrape of a target given as with /join (host:port)
+    /// returns the key of the scrape
+    pub(crate) fn set_scrape_paused(&self, target: &str, paused: bool) -> Result<String, ProxyErr> {
+        let base = |url: &str| -> String {
+            /* Targets may be given with or without their http:// or https:// scheme */
+            let url = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+            url.strip_suffix("/job")
+                .or_else(|| url.strip_suffix("/metrics"))
+                .unwrap_or(url)
+                .to_string()
+        };
+
+        let target = base(target);
+
+        for (k, v) in self.scrapes.lock().unwrap().iter_mut() {
+            if *k == target || base(k) == target {
+                v.set_paused(paused);
+                return Ok(k.to_string());
+            }
+        }
+
+        Err(ProxyErr::new(format!("No such scrape {}", target)))
+    }
+
+    #[allow(unused)]
+    /// Base URLs of the proxies scraped by this one (its children in the tree)
+    pub(crate) fn child_proxies(&self) -> Vec<String> {
+        self.scrapes
             .lock()
             .unwrap()
-            .insert(new.url().to_string(), new);
-        Ok(())
+            .values()
+            .filter_map(|s| s.get_url_if_proxy())
+            .map(|u| u.strip_suffix("/job").unwrap_or(u).to_string())
+            .collect()
     }
 
     #[allow(unused)]
@@ -487,12 +1279,22 @@ impl ExporterFactory {
         factory: Arc<ExporterFactory>,
         url: &String,
     ) -> Result<(), Box<dyn Error>> {
-        match factory.scrapes.lock().unwrap().remove(url) {
-            Some(_) => Ok(()),
-            None => Err(ProxyErr::newboxed(format!(
+        /* An evicted scrape is no longer retried */
+        let evicted = factory
+            .evicted_scrapes
+            .lock()
+            .unwrap()
+            .remove(url)
+            .is_some();
+        let removed = factory.scrapes.lock().unwrap().remove(url).is_some();
+
+        if removed || evicted {
+            Ok(())
+        } else {
+            Err(ProxyErr::newboxed(format!(
                 "No such scrape {} to remove",
                 url
-            ))),
+            )))
         }
     }
 
@@ -520,19 +1322,15 @@ impl ExporterFactory {
         my_server_address: &String,
         period: u64,
     ) -> Result<(), ProxyErr> {
-        let mut pivot_url = root_server.to_string() + "/pivot?from=" + my_server_address;
-
-        if !pivot_url.starts_with("http") {
-            pivot_url = format!("http://{}", pivot_url);
-        }
+        let pivot_url =
+            peer_url(root_server, my_server_address) + "/pivot?from=" + my_server_address;
 
         /* We add some delay as the root server may get smashed */
         let resp = retry(Fixed::from_millis(2000).take(5), || {
             ApiResponse::query(&pivot_url)
         })?;
 
-        let target_url = "http://".to_string()
-            + resp.operation.as_str()
+        let target_url = peer_url(&resp.operation, my_server_address)
             + "/join?to="
             + my_server_address
             + "&period="
@@ -558,15 +1356,15 @@ impl ExporterFactory {
         my_server_address: &String,
         target_address: &String,
     ) -> Result<(), ProxyErr> {
-        let mut target_address = target_address.to_string();
-        if target_address.starts_with("http://") {
-            target_address = target_address.replace("http://", "");
-        }
+        let mut target_address = match target_address.split_once("://") {
+            Some((_, address)) => address.to_string(),
+            None => target_address.to_string(),
+        };
         if target_address.ends_with("/job") {
             target_address = target_address.replace("/job", "");
         }
 
-        let mut pivot_url = root_server.to_string()
+        let pivot_url = peer_url(root_server, my_server_address)
             + "/remove?from="
             + my_server_address
             + "&target="
@@ -574,10 +1372,6 @@ impl ExporterFactory {
 
         println!("pivot_url: {}", pivot_url);
 
-        if !pivot_url.starts_with("http") {
-            pivot_url = format!("http://{}", pivot_url);
-        }
-
         println!(
             "Notifying root server {} about failed proxy {}, we are {}",
             root_server, target_address, my_server_address
@@ -598,12 +1392,8 @@ impl ExporterFactory {
             }
             let response: Vec<&str> = resp.operation.split('&').collect();
 
-            let target_url = "http://".to_string()
-                + my_server_address
-                + "/join?to="
-                + response[0]
-                + "&period="
-                + response[1];
+            let target_url =
+                proxy_url(my_server_address) + "/join?to=" + response[0] + "&period=" + response[1];
 
             match ApiResponse::query(&target_url) {
                 Ok(_) => {
@@ -639,10 +1429,7 @@ impl ExporterFactory {
             *p = period;
         }
 
-        let mut root_url = root.to_string();
-        if !root_url.starts_with("http") {
-            root_url = format!("http://{}", root_url);
-        }
+        let root_url = peer_url(root, my_server_address);
 
         let mut web_url = my_server_address.to_string();
 
@@ -671,17 +1458,55 @@ impl ExporterFactory {
 
     pub(crate) fn new(
         profile_prefix: PathBuf,
-        aggregate: bool,
-        max_trace_size: usize,
-        period: u64,
-        branches: u64,
+        options: ExporterFactoryOptions,
         instrumentation: Arc<dyn Instrumentation>,
     ) -> Result<Arc<ExporterFactory>, Box<dyn Error>> {
-        let main_jobdesc = JobDesc {
-            jobid: "main".to_string(),
-            command: "Sum of all Jobs".to_string(),
-            size: 0,
-            nodelist: "".to_string(),
+        let ExporterFactoryOptions {
+            aggregate,
+            max_trace_size,
+            trace_window,
+            trace_nanoseconds,
+            trace_retention,
+            trace_delta,
+            period,
+            branches,
+            system_metrics,
+            metric_prefix,
+            prefix_all,
+            metric_ttl,
+            scrape_timeout,
+            scrape_failures,
+            scrape_retry_max,
+            scrape_jitter,
+            relabel,
+            alarm_history,
+            alarms_file,
+            max_series_per_metric,
+            metric_precision,
+            profile_db,
+            keep_node_profiles,
+            binary_profiles,
+            label_jobs_in_main,
+            label_source_host,
+            otlp_endpoint,
+            ftio_command,
+        } = options;
+
+        #[cfg(not(feature = "otlp"))]
+        if otlp_endpoint.is_some() {
+            return Err(ProxyErr::newboxed(
+                "OTLP export requires the 'otlp' feature",
+            ));
+        }
+
+        MAX_SERIES_PER_METRIC.store(max_series_per_metric, Ordering::Relaxed);
+        proxywireprotocol::set_metric_precision(metric_precision);
+
+        let main_jobdesc = JobDesc {
+            jobid: "main".to_string(),
+            command: "Sum of all Jobs".to_string(),
+            size: 0,
+            nodelist: "".to_string(),
             partition: "".to_string(),
             cluster: "".to_string(),
             run_dir: "".to_string(),
@@ -701,7 +1526,20 @@ impl ExporterFactory {
             end_time: 0,
         };
 
-        let trace_store = Arc::new(TraceView::new(&profile_prefix)?);
+        let alarm_templates = match &alarms_file {
+            Some(path) => AlarmTemplates::from_file(path)?,
+            None => AlarmTemplates::default(),
+        };
+
+        let trace_unit = if trace_nanoseconds {
+            TraceTimeUnit::Nanoseconds
+        } else {
+            TraceTimeUnit::Seconds
+        };
+        let trace_store = Arc::new(
+            TraceView::new(&profile_prefix, trace_window, trace_unit, trace_delta)?
+                .with_ftio_command(ftio_command),
+        );
         let ftio_client = Arc::new(FtioClient::new());
 
         if which::which("admire_proxy_zmq").is_ok() {
@@ -758,16 +1596,45 @@ impl ExporterFactory {
             perjob: Mutex::new(HashMap::new()),
             scrapes: Mutex::new(HashMap::new()),
             pending_scrapes: Mutex::new(Vec::new()),
-            profile_store: Arc::new(ProfileView::new(&profile_prefix)?),
+            evicted_scrapes: Mutex::new(HashMap::new()),
+            profile_store: Arc::new(ProfileView::new(
+                &profile_prefix,
+                profile_db.as_deref(),
+                binary_profiles,
+            )?),
             trace_store: trace_store.clone(),
+            trace_retention,
             aggregator: aggregate,
             max_trace_size,
             ftio_client: ftio_client.clone(),
             root_proxy: Arc::new(RwLock::new(None)),
+            root_proxies: Arc::new(RwLock::new(Vec::new())),
             web_url: Arc::new(RwLock::new(None)),
             period: Arc::new(RwLock::new(period)),
             branches,
             instrumentation,
+            metric_prefix,
+            prefix_all,
+            metric_ttl: AtomicU64::new(metric_ttl),
+            scrape_timeout: RwLock::new(scrape_timeout),
+            system_metrics: Mutex::new(system_metrics),
+            scrape_failures,
+            scrape_retry_max,
+            scrape_jitter,
+            relabel,
+            alarm_history,
+            alarm_templates,
+            streams: Mutex::new(Vec::new()),
+            running: AtomicBool::new(true),
+            ready: AtomicBool::new(false),
+            shutdown_done: Mutex::new(false),
+            shutdown_cv: Condvar::new(),
+            clients_connected: AtomicI64::new(0),
+            keep_node_profiles,
+            node_profiles: Mutex::new(HashMap::new()),
+            last_job_seen: AtomicU64::new(unix_ts()),
+            label_jobs_in_main,
+            label_source_host,
         });
 
         let scrape_ref = ret.clone();
@@ -776,11 +1643,43 @@ impl ExporterFactory {
             scrape_ref.run_scrapping();
         });
 
+        let expiry_ref = ret.clone();
+        // Start the stale metric sweeping thread (idle while the TTL is 0)
+        std::thread::spawn(move || {
+            expiry_ref.run_expiry();
+        });
+
+        if aggregate {
+            match ret.profile_store.watcher() {
+                Ok(Some(watcher)) => {
+                    let watch_ref = ret.clone();
+                    // Start the thread loading profiles as they are written
+                    std::thread::spawn(move || {
+                        watch_ref.run_profile_watch(watcher);
+                    });
+                }
+                Ok(None) => {}
+                /* The periodic pass still loads the profiles */
+                Err(e) => log::warn!("Profiles will be loaded periodically : {}", e),
+            }
+        }
+
+        #[cfg(feature = "otlp")]
+        if let Some(endpoint) = otlp_endpoint {
+            let pusher = OtlpPusher::new(&endpoint, scrape_timeout)?;
+            let otlp_ref = ret.clone();
+            // Start the OTLP push thread
+            std::thread::spawn(move || {
+                otlp_ref.run_otlp(pusher);
+            });
+        }
+
         ret.insert_ftio_exporter(trace_store.clone(), &main_jobdesc.jobid)?;
         ret.insert_ftio_exporter(trace_store.clone(), &nodejob_desc.jobid)?;
 
         /* This creates a job entry for the cumulative job */
         let main_job = PerJobRefcount {
+            pending_alarms: ret.alarm_templates.matching(&main_jobdesc.jobid),
             desc: main_jobdesc,
             exporter: ret.main.clone(),
             counter: 1,
@@ -793,6 +1692,7 @@ impl ExporterFactory {
 
         /* This creates a job entry for the pernode job */
         let node_job = PerJobRefcount {
+            pending_alarms: ret.alarm_templates.matching(&nodejob_desc.jobid),
             desc: nodejob_desc,
             exporter: ret.pernode.clone(),
             counter: 1,
@@ -804,12 +1704,25 @@ impl ExporterFactory {
             .insert(node_job.desc.jobid.to_string(), node_job);
 
         /* Now insert the default system scrape */
-        let systemurl = "/system".to_string();
-        if let Ok(sys_metrics) =
-            ProxyScraper::new(&systemurl, proxy_common::get_proxy_period(), ret.clone())
-        {
-            ret.scrapes.lock().unwrap().insert(systemurl, sys_metrics);
-        }
+        ExporterFactory::set_system_metrics(ret.clone(), system_metrics)?;
+
+        /* Expose the version of this proxy */
+        let build_info = CounterSnapshot::new(
+            ret.metric_name("build_info", true),
+            &[
+                ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+                ("git".to_string(), env!("PROXY_GIT_HASH").to_string()),
+                ("rustc".to_string(), env!("PROXY_RUSTC_VERSION").to_string()),
+            ],
+            "Build information of the proxy (always 1)".to_string(),
+            CounterType::Gauge {
+                min: 1.0,
+                max: 1.0,
+                hits: 1.0,
+                total: 1.0,
+            },
+        );
+        ret.main.push(&build_info)?;
 
         /* Now insert tracing events */
         ret.insert_tracing(ret.main.clone(), main_job_trace)?;
@@ -824,6 +1737,7 @@ impl ExporterFactory {
         trace: Option<Arc<Trace>>,
     ) -> Result<(), Box<dyn Error>> {
         if let Some(trace) = trace {
+            exporter.set_trace(&trace);
             if let Ok(main_trace_scraper) = ProxyScraper::newtrace(exporter, trace) {
                 self.pending_scrapes
                     .lock()
@@ -851,6 +1765,522 @@ impl ExporterFactory {
         Ok(())
     }
 
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    #[allow(unused)]
+    /// Time since the last job (main and node excluded) left, zero while jobs run
+    pub(crate) fn idle_for(&self) -> Duration {
+        let has_jobs = self.perjob.lock().unwrap().values().any(|j| {
+            !Arc::ptr_eq(&j.exporter, &self.main) && !Arc::ptr_eq(&j.exporter, &self.pernode)
+        });
+
+        let now = unix_ts();
+
+        if has_jobs {
+            self.last_job_seen.store(now, Ordering::SeqCst);
+            return Duration::ZERO;
+        }
+
+        Duration::from_millis(now.saturating_sub(self.last_job_seen.load(Ordering::SeqCst)))
+    }
+
+    /// Metric TTL in seconds (0 = never expire)
+    pub(crate) fn metric_ttl(&self) -> u64 {
+        self.metric_ttl.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_metric_ttl(&self, ttl: u64) {
+        log::info!("Metric TTL set to {} s", ttl);
+        self.metric_ttl.store(ttl, Ordering::Relaxed);
+    }
+
+    /// Should metrics scraped from child proxies carry a source_host label
+    pub(crate) fn label_source_host(&self) -> bool {
+        self.label_source_host
+    }
+
+    /// Size (bytes) past which traces are folded
+    pub(crate) fn max_trace_size(&self) -> usize {
+        self.max_trace_size
+    }
+
+    /// Timeout of network scrapes
+    pub(crate) fn scrape_timeout(&self) -> Duration {
+        *self.scrape_timeout.read().unwrap()
+    }
+
+    /// Applies to the next request of existing scrapes
+    pub(crate) fn set_scrape_timeout(&self, timeout: Duration) {
+        log::info!("Scrape timeout set to {:?}", timeout);
+        *self.scrape_timeout.write().unwrap() = timeout;
+    }
+
+    /// Level of the system metrics scrape
+    pub(crate) fn system_metrics(&self) -> SystemMetricsLevel {
+        *self.system_metrics.lock().unwrap()
+    }
+
+    /// Replace the system scrape with one at the given level (None removes it)
+    pub(crate) fn set_system_metrics(
+        factory: Arc<ExporterFactory>,
+        level: SystemMetricsLevel,
+    ) -> Result<(), ProxyErr> {
+        let mut current = factory.system_metrics.lock().unwrap();
+        let mut scrapes = factory.scrapes.lock().unwrap();
+
+        scrapes.remove("/system");
+
+        if level != SystemMetricsLevel::None {
+            let sys_metrics =
+                ProxyScraper::newsystem(level, proxy_common::get_proxy_period(), factory.clone())?;
+            scrapes.insert(sys_metrics.url().to_string(), sys_metrics);
+        }
+
+        log::info!("System metrics level set to {:?}", level);
+        *current = level;
+
+        Ok(())
+    }
+
+    /// Store profiles and traces (false with -i)
+    pub(crate) fn is_aggregator(&self) -> bool {
+        self.aggregator
+    }
+
+    pub(crate) fn metric_prefix(&self) -> &str {
+        &self.metric_prefix
+    }
+
+    #[allow(unused)]
+    /// Ready once a scrape pass succeeded and profiles can be stored
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst) && self.profile_store.storage_available()
+    }
+
+    #[allow(unused)]
+    /// Stop scraping and serving, then persist all in-flight jobs
+    /// Concurrent callers block until the first one is done flushing
+    pub(crate) fn shutdown(&self) {
+        /* Only the first caller flushes, the lock is not held while scraping */
+        if !self.running.swap(false, Ordering::SeqCst) {
+            let done = self.shutdown_done.lock().unwrap();
+            let _done = self.shutdown_cv.wait_while(done, |done| !*done).unwrap();
+            return;
+        }
+
+        log::info!("Shutting down, flushing in-flight jobs");
+
+        /* Final aggregation pass, waits for the scrapping thread to release the list */
+        if let Ok(scrapes) = self.scrapes.lock().as_mut() {
+            for (k, v) in scrapes.iter_mut() {
+                if let Err(e) = v.scrape() {
+                    log::debug!("Failed final scrape of {} : {}", k, e);
+                }
+            }
+        }
+
+        if self.aggregator {
+            let ht = self.perjob.lock().unwrap();
+
+            for job in ht.values() {
+                /* Main and node jobs are never saved as profiles */
+                if Arc::ptr_eq(&job.exporter, &self.main)
+                    || Arc::ptr_eq(&job.exporter, &self.pernode)
+                {
+                    continue;
+                }
+
+                if let Err(e) = self.flush_job(job) {
+                    log::error!("Failed to flush job {} : {}", job.desc.jobid, e);
+                }
+            }
+        }
+
+        *self.shutdown_done.lock().unwrap() = true;
+        self.shutdown_cv.notify_all();
+    }
+
+    #[allow(unused)]
+    fn flush_job(&self, job: &PerJobRefcount) -> Result<(), Box<dyn Error>> {
+        let mut desc = job.desc.clone();
+        desc.end_time = unix_ts();
+
+        let snap = job.exporter.profile(&desc, false)?;
+        self.profile_store.saveprofile(snap, &desc)?;
+        self.trace_store.done(&desc)?;
+
+        Ok(())
+    }
+
+    #[allow(unused)]
+    /// Register a new live update stream optionally filtered on a job
+    /// fails once MAX_STREAMS streams are open
+    pub(crate) fn subscribe(
+        &self,
+        job: Option<String>,
+    ) -> Result<Receiver<CounterUpdate>, ProxyErr> {
+        let mut streams = self.streams.lock().unwrap();
+
+        if streams.len() >= MAX_STREAMS {
+            return Err(ProxyErr::new(format!(
+                "Too many streams, at most {} can be open",
+                MAX_STREAMS
+            )));
+        }
+
+        let (tx, rx) = sync_channel(STREAM_QUEUE_LEN);
+        streams.push((job, Arc::new(tx)));
+        Ok(rx)
+    }
+
+    /// Send the current value of a counter to the matching streams
+    /// Updates are dropped when a stream is full and closed streams are removed
+    pub(crate) fn notify_update(&self, job: &str, exporter: &Arc<Exporter>, name: &str) {
+        /* Only collect the subscribers under the lock, clients may be slow */
+        let subscribers: Vec<StreamSender> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(filter, _)| filter.as_ref().is_none_or(|f| f == job))
+            .map(|(_, tx)| tx.clone())
+            .collect();
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let counter = match exporter.get(&name.to_string()) {
+            Ok(c) => c.read().unwrap().clone(),
+            Err(_) => return,
+        };
+
+        let closed: Vec<StreamSender> = subscribers
+            .into_iter()
+            .filter(|tx| {
+                let update = CounterUpdate {
+                    job: job.to_string(),
+                    counter: counter.clone(),
+                };
+                matches!(tx.try_send(update), Err(TrySendError::Disconnected(_)))
+            })
+            .collect();
+
+        if !closed.is_empty() {
+            self.streams
+                .lock()
+                .unwrap()
+                .retain(|(_, tx)| !closed.iter().any(|c| Arc::ptr_eq(c, tx)));
+        }
+    }
+
+    /// Name under which a metric is exposed, system metrics are always prefixed
+    /// (--prefix-all keeps the job lifecycle counters for did_complete)
+    pub(crate) fn metric_name(&self, name: &str, system: bool) -> String {
+        if system || (self.prefix_all && !JOB_LIFECYCLE_METRICS.contains(&name)) {
+            format!("{}{}", self.metric_prefix, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Update an internal metric of the proxy in the main exporter
+    fn self_metric(&self, name: &str, doc: &str, ctype: CounterType, merge: bool) {
+        let snapshot = CounterSnapshot {
+            name: self.metric_name(name, true),
+            doc: doc.to_string(),
+            ctype,
+        };
+
+        /* Pushed empty so that the first update is not counted twice */
+        let empty = CounterSnapshot {
+            ctype: match snapshot.ctype {
+                CounterType::Counter { .. } => CounterType::newcounter(),
+                CounterType::Gauge { .. } => CounterType::newgauge(),
+                CounterType::LastValue { .. } => CounterType::newlastvalue(),
+                CounterType::UpDown { .. } => CounterType::newupdown(),
+            },
+            ..snapshot.clone()
+        };
+
+        if self.main.push(&empty).is_ok() {
+            if let Err(e) = self.main.accumulate(&snapshot, merge) {
+                log::debug!("Failed to update {} : {}", snapshot.name, e);
+            }
+        }
+    }
+
+    /// Count a command received from a UNIX client
+    #[allow(unused)]
+    pub(crate) fn count_command(&self, success: bool) {
+        let one = CounterType::Counter {
+            ts: unix_ts(),
+            value: 1.0,
+        };
+
+        self.self_metric(
+            "commands_total",
+            "Number of commands received from clients",
+            one.clone(),
+            true,
+        );
+
+        if !success {
+            self.self_metric(
+                "commands_failed_total",
+                "Number of client commands which failed to be processed",
+                one,
+                true,
+            );
+        }
+    }
+
+    /// Expose the disk usage of the traces in the main exporter
+    /// returns the fold count to pass on the next call
+    fn update_trace_metrics(&self, last_folds: u64, last_reclaimed: u64) -> (u64, u64) {
+        let stats = self.trace_store.stats();
+
+        for (name, doc, value) in [
+            (
+                "trace_bytes",
+                "Total size in bytes of the trace files",
+                stats.total_bytes as f64,
+            ),
+            ("traces", "Number of traces", stats.traces as f64),
+        ] {
+            self.self_metric(
+                name,
+                doc,
+                CounterType::Gauge {
+                    min: value,
+                    max: value,
+                    hits: 1.0,
+                    total: value,
+                },
+                false,
+            );
+        }
+
+        if stats.folds > last_folds {
+            self.self_metric(
+                "trace_folds_total",
+                "Number of times a trace reached --max-trace-size and was folded",
+                CounterType::Counter {
+                    ts: unix_ts(),
+                    value: (stats.folds - last_folds) as f64,
+                },
+                true,
+            );
+        }
+
+        if stats.reclaimed_bytes > last_reclaimed {
+            self.self_metric(
+                "trace_reclaimed_bytes_total",
+                "Bytes of trace files removed by --trace-retention or /trace/gc",
+                CounterType::Counter {
+                    ts: unix_ts(),
+                    value: (stats.reclaimed_bytes - last_reclaimed) as f64,
+                },
+                true,
+            );
+        }
+
+        if let Some((success, duration)) = stats.ftio_last {
+            for (name, doc, value) in [
+                (
+                    "ftio_last_run_seconds",
+                    "Duration of the last FTIO run",
+                    duration.as_secs_f64(),
+                ),
+                (
+                    "ftio_last_run_success",
+                    "1 if the last FTIO run produced models, 0 if it failed",
+                    if success { 1.0 } else { 0.0 },
+                ),
+            ] {
+                self.self_metric(
+                    name,
+                    doc,
+                    CounterType::Gauge {
+                        min: value,
+                        max: value,
+                        hits: 1.0,
+                        total: value,
+                    },
+                    false,
+                );
+            }
+        }
+
+        (stats.folds, stats.reclaimed_bytes)
+    }
+
+    /// Remove the traces of finished jobs not written for --trace-retention seconds
+    /// traces of running jobs (including main and node) or without a saved profile are kept
+    ///
+    /// Returns None without retention (0 means traces are kept)
+    pub(crate) fn trace_gc(&self) -> Option<u64> {
+        if self.trace_retention == 0 {
+            return None;
+        }
+
+        let running: HashSet<String> = self.perjob.lock().unwrap().keys().cloned().collect();
+
+        Some(self.trace_store.gc(self.trace_retention, |jobid| {
+            running.contains(jobid) || !self.profile_store.has_profile(jobid)
+        }))
+    }
+
+    /// Expose the number of series dropped by the cardinality cap
+    /// returns the count to pass on the next call
+    fn update_series_metrics(&self, last_dropped: u64) -> u64 {
+        let dropped = DROPPED_SERIES.load(Ordering::Relaxed);
+
+        if dropped > last_dropped {
+            self.self_metric(
+                "dropped_series_total",
+                "Number of series dropped as --max-series-per-metric was reached",
+                CounterType::Counter {
+                    ts: unix_ts(),
+                    value: (dropped - last_dropped) as f64,
+                },
+                true,
+            );
+        }
+
+        dropped
+    }
+
+    /// Load the profile files written since the last pass and expose
+    /// how many were waiting, returns the count to pass on the next call
+    fn update_aggregation_metrics(&self, last_processed: u64) -> u64 {
+        let pending = self.profile_store.pending_files();
+
+        if pending > 0 {
+            if let Err(e) = self.profile_store.refresh_profiles() {
+                log::error!("Failed to load pending profiles : {}", e);
+            }
+        }
+
+        self.self_metric(
+            "aggregation_pending_files",
+            "Number of profile files waiting to be loaded",
+            CounterType::Gauge {
+                min: pending as f64,
+                max: pending as f64,
+                hits: 1.0,
+                total: pending as f64,
+            },
+            false,
+        );
+
+        let processed = self.profile_store.processed();
+
+        if processed > last_processed {
+            self.self_metric(
+                "aggregation_processed_total",
+                "Number of profile files loaded",
+                CounterType::Counter {
+                    ts: unix_ts(),
+                    value: (processed - last_processed) as f64,
+                },
+                true,
+            );
+        }
+
+        processed
+    }
+
+    /// Export the state of a network scrape (an evicted one is down)
+    fn update_scrape_status(&self, target: &str, last_success: u64, up: bool) {
+        for (name, doc, value) in [
+            (
+                "scrape_last_success_timestamp",
+                "Unix timestamp in seconds of the last successful scrape",
+                last_success as f64 / 1000.0,
+            ),
+            (
+                "scrape_up",
+                "1 if the last scrape succeeded, 0 if it failed or the target was evicted",
+                if up { 1.0 } else { 0.0 },
+            ),
+        ] {
+            self.self_metric(
+                &format!("{}{{target=\"{}\"}}", name, target),
+                doc,
+                CounterType::Gauge {
+                    min: value,
+                    max: value,
+                    hits: 1.0,
+                    total: value,
+                },
+                false,
+            );
+        }
+    }
+
+    fn update_scrape_metrics(&self) {
+        let status: Vec<(String, u64, bool)> = self
+            .scrapes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(k, v)| v.status().map(|(ts, up)| (k.to_string(), ts, up)))
+            .collect();
+
+        for (target, last_success, up) in status {
+            self.update_scrape_status(&target, last_success, up);
+        }
+
+        let retrying = self.evicted_scrapes.lock().unwrap().len() as f64;
+
+        self.self_metric(
+            "scrapes_retrying",
+            "Number of evicted scrapes probed until they answer again",
+            CounterType::Gauge {
+                min: retrying,
+                max: retrying,
+                hits: 1.0,
+                total: retrying,
+            },
+            false,
+        );
+    }
+
+    /// Count a UNIX client refused because of --max-clients
+    #[allow(unused)]
+    pub(crate) fn client_refused(&self) {
+        self.self_metric(
+            "clients_refused_total",
+            "Number of UNIX clients refused as --max-clients was reached",
+            CounterType::Counter {
+                ts: unix_ts(),
+                value: 1.0,
+            },
+            true,
+        );
+    }
+
+    /// Track UNIX clients as they connect (1) and leave (-1)
+    #[allow(unused)]
+    pub(crate) fn client_connected(&self, delta: i64) {
+        let count = (self.clients_connected.fetch_add(delta, Ordering::SeqCst) + delta) as f64;
+
+        self.self_metric(
+            "clients_connected",
+            "Number of clients connected to the UNIX socket",
+            CounterType::Gauge {
+                min: count,
+                max: count,
+                hits: 1.0,
+                total: count,
+            },
+            false,
+        );
+    }
+
     pub(crate) fn get_main(&self) -> Arc<Exporter> {
         self.main.clone()
     }
@@ -899,6 +2329,7 @@ impl ExporterFactory {
                     exporter: Arc::new(Exporter::new()),
                     counter: 1,
                     islocal: tobesaved,
+                    pending_alarms: self.alarm_templates.matching(&desc.jobid),
                 };
 
                 /* Add the trace scrapping */
@@ -929,10 +2360,18 @@ impl ExporterFactory {
 
     #[allow(unused)]
     pub(crate) fn profiles(&self, full: bool) -> Vec<JobProfile> {
+        self.profiles_filtered(full, |_| true)
+    }
+
+    /// Profiles of the jobs whose desc matches pred
+    pub(crate) fn profiles_filtered<F>(&self, full: bool, pred: F) -> Vec<JobProfile>
+    where
+        F: Fn(&JobDesc) -> bool,
+    {
         let mut ret: Vec<JobProfile> = Vec::new();
 
         if let Ok(ht) = self.perjob.lock() {
-            for v in ht.values() {
+            for v in ht.values().filter(|v| pred(&v.desc)) {
                 if let Ok(p) = v.profile(full) {
                     ret.push(p);
                 }
@@ -951,6 +2390,79 @@ impl ExporterFactory {
         Err(ProxyErr::new("No such Job ID"))
     }
 
+    /// Record the cumulative profile of a job as scraped from a proxy
+    pub(crate) fn node_profile(&self, node: &str, profile: &JobProfile) {
+        if !self.keep_node_profiles {
+            return;
+        }
+
+        self.node_profiles
+            .lock()
+            .unwrap()
+            .entry(profile.desc.jobid.to_string())
+            .or_default()
+            .insert(node.to_string(), profile.clone());
+    }
+
+    /// Profile of a running or stored job for each proxy which contributed to it
+    pub(crate) fn node_profiles_of(
+        &self,
+        jobid: &str,
+    ) -> Result<HashMap<String, JobProfile>, Box<dyn Error>> {
+        if let Some(nodes) = self.node_profiles.lock().unwrap().get(jobid) {
+            return Ok(nodes.clone());
+        }
+
+        self.profile_store.get_node_profiles(jobid)
+    }
+
+    /// Record an annotation in the trace of a job, proxies without
+    /// a trace for the job forward it to their root
+    #[allow(unused)]
+    pub(crate) fn annotate(&self, annotation: JobAnnotation) -> Result<(), Box<dyn Error>> {
+        let exporter = self
+            .perjob
+            .lock()
+            .unwrap()
+            .get(&annotation.job)
+            .map(|j| j.exporter.clone());
+
+        if let Some(exporter) = exporter {
+            if exporter.annotate(annotation.ts, &annotation.label)? {
+                return Ok(());
+            }
+        }
+
+        let root = match self.root_proxy.read().unwrap().clone() {
+            Some(root) => root,
+            None => {
+                log::debug!(
+                    "No trace to record annotation {} of {}",
+                    annotation.label,
+                    annotation.job
+                );
+                return Ok(());
+            }
+        };
+
+        /* Do not hold the client while the root answers */
+        std::thread::spawn(move || {
+            let res = proxy_common::peer_client(proxy_common::PEER_TIMEOUT).and_then(|client| {
+                proxy_common::with_proxy_auth(client.post(root.to_string() + "/annotate"))
+                    .json(&annotation)
+                    .send()
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| ProxyErr::new(e.to_string()))
+            });
+
+            if let Err(e) = res {
+                log::warn!("Failed to forward annotation to {} : {}", root, e);
+            }
+        });
+
+        Ok(())
+    }
+
     pub(crate) fn relax_job(&self, desc: &JobDesc) -> Result<(), Box<dyn Error>> {
         let mut ht: std::sync::MutexGuard<'_, HashMap<String, PerJobRefcount>> =
             self.perjob.lock().unwrap();
@@ -964,15 +2476,24 @@ impl ExporterFactory {
             );
             assert!(0 <= job_entry.counter);
             if job_entry.counter == 0 {
+                let nodes = self.node_profiles.lock().unwrap().remove(&desc.jobid);
+
                 /* Serialize */
                 if let Some(perjob) = ht.get(&desc.jobid) {
                     if self.aggregator {
                         let snap = perjob.exporter.profile(desc, false)?;
                         self.profile_store.saveprofile(snap, desc)?;
+                        if let Some(nodes) = nodes {
+                            self.profile_store.save_node_profiles(desc, &nodes)?;
+                        }
                         self.trace_store.done(desc)?;
                     }
                     /* Delete */
                     ht.remove(&desc.jobid);
+                    if self.label_jobs_in_main {
+                        self.main
+                            .remove_matching(&|name| has_label(name, "jobid", &desc.jobid));
+                    }
                 }
             }
         } else {
@@ -989,9 +2510,16 @@ impl ExporterFactory {
         doc: &str,
         ctype: CounterType,
         perjob_exporter: Option<Arc<Exporter>>,
+        jobid: Option<&str>,
     ) -> Result<(), ProxyErr> {
+        let name = match self.relabel.apply(name) {
+            Some(n) => n,
+            /* Dropped by a relabel rule */
+            None => return Ok(()),
+        };
+
         let snapshot = CounterSnapshot {
-            name: name.to_string(),
+            name: self.metric_name(&name, false),
             doc: doc.to_string(),
             ctype,
         };
@@ -1002,6 +2530,10 @@ impl ExporterFactory {
             e.push(&snapshot)?;
         }
 
+        if let Some(labeled) = self.job_labeled(&snapshot, jobid) {
+            self.main.push(&labeled)?;
+        }
+
         Ok(())
     }
 
@@ -1009,25 +2541,72 @@ impl ExporterFactory {
     pub(crate) fn accumulate(
         &self,
         name: &str,
+        doc: &str,
         ctype: CounterType,
         perjob_exporter: Option<Arc<Exporter>>,
+        jobid: Option<&str>,
     ) -> Result<(), ProxyErr> {
+        let name = match self.relabel.apply(name) {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+
+        /* The doc is kept by the series created when the value is pushed again */
         let snapshot = CounterSnapshot {
-            name: name.to_string(),
-            doc: "".to_string(),
+            name: self.metric_name(&name, false),
+            doc: doc.to_string(),
             ctype,
         };
 
-        self.get_main().accumulate(&snapshot, false)?;
-        self.get_node().accumulate(&snapshot, false)?;
+        self.accumulate_into(&self.main, &snapshot)?;
+        self.accumulate_into(&self.pernode, &snapshot)?;
+        self.notify_update("main", &self.main, &snapshot.name);
+
+        if let Some(labeled) = self.job_labeled(&snapshot, jobid) {
+            /* The Desc may have been sent before the JobDesc, the series then starts here */
+            if self.main.accumulate(&labeled, false).is_err() {
+                self.main.push(&labeled)?;
+            }
+        }
 
         if let Some(e) = perjob_exporter {
-            e.accumulate(&snapshot, false)?;
+            self.accumulate_into(&e, &snapshot)?;
+            if let Some(jobid) = jobid {
+                self.notify_update(jobid, &e, &snapshot.name);
+            }
         }
 
         Ok(())
     }
 
+    /// Copy of the snapshot with a jobid label for main (--label-jobs-in-main)
+    fn job_labeled(
+        &self,
+        snapshot: &CounterSnapshot,
+        jobid: Option<&str>,
+    ) -> Option<CounterSnapshot> {
+        match jobid {
+            Some(jobid) if self.label_jobs_in_main && !jobid.is_empty() => Some(CounterSnapshot {
+                name: add_label(&snapshot.name, "jobid", jobid),
+                ..snapshot.clone()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Accumulate in an exporter, when metrics expire the value
+    /// of an expired metric restarts from this snapshot
+    fn accumulate_into(
+        &self,
+        exporter: &Exporter,
+        snapshot: &CounterSnapshot,
+    ) -> Result<(), ProxyErr> {
+        match exporter.accumulate(snapshot, false) {
+            Err(_) if self.metric_ttl() > 0 => exporter.push(snapshot),
+            res => res,
+        }
+    }
+
     #[allow(unused)]
     pub(crate) fn add_alarm(
         &self,
@@ -1036,6 +2615,7 @@ impl ExporterFactory {
         metric: String,
         op: String,
         value: f64,
+        window: Option<AlarmWindow>,
     ) -> Result<(), ProxyErr> {
         let perjobht = self.perjob.lock().unwrap();
 
@@ -1044,11 +2624,48 @@ impl ExporterFactory {
             target_job
         )))?;
 
-        perjob.exporter.add_alarm(name, metric, op, value)?;
+        perjob
+            .exporter
+            .add_alarm(name, metric, op, value, self.alarm_history, window)?;
 
         Ok(())
     }
 
+    /// Instantiate the alarm templates of the jobs where their metric appeared
+    fn apply_alarm_templates(&self) {
+        let mut ht = self.perjob.lock().unwrap();
+
+        for job in ht.values_mut().filter(|j| !j.pending_alarms.is_empty()) {
+            let exporter = job.exporter.clone();
+            let jobid = &job.desc.jobid;
+
+            job.pending_alarms.retain(|idx| {
+                let t = match self.alarm_templates.get(*idx) {
+                    Some(t) => t,
+                    None => return false,
+                };
+
+                /* Wait for the job to push the metric */
+                if exporter.get(&t.metric).is_err() {
+                    return true;
+                }
+
+                if let Err(e) = exporter.add_alarm(
+                    t.name.to_string(),
+                    t.metric.to_string(),
+                    t.operation.to_string(),
+                    t.value,
+                    self.alarm_history,
+                    None,
+                ) {
+                    log::error!("Failed to add alarm {} to {} : {}", t.name, jobid, e);
+                }
+
+                false
+            });
+        }
+    }
+
     #[allow(unused)]
     pub(crate) fn check_alarms(&self) -> HashMap<String, Vec<ValueAlarmTrigger>> {
         let mut ret: HashMap<String, Vec<ValueAlarmTrigger>> = HashMap::new();
@@ -1063,6 +2680,20 @@ impl ExporterFactory {
         ret
     }
 
+    #[allow(unused)]
+    pub(crate) fn alarm_history(
+        &self,
+        jobid: &String,
+    ) -> Result<HashMap<String, Vec<AlarmEvent>>, ProxyErr> {
+        let perjobht = self.perjob.lock().unwrap();
+
+        let perjob = perjobht
+            .get(jobid)
+            .ok_or(ProxyErr::new(format!("Failed to locate job {}", jobid)))?;
+
+        Ok(perjob.exporter.alarm_history())
+    }
+
     #[allow(unused)]
     pub(crate) fn list_alarms(&self) -> HashMap<String, Vec<ValueAlarmTrigger>> {
         let mut ret: HashMap<String, Vec<ValueAlarmTrigger>> = HashMap::new();
@@ -1084,6 +2715,18 @@ impl ExporterFactory {
         ret
     }
 
+    /// Alarms active at their last check, unlike check_alarms
+    /// this does not add samples to the windowed alarms
+    pub(crate) fn active_alarms(&self) -> HashMap<String, Vec<ValueAlarmTrigger>> {
+        let mut ret = self.list_alarms();
+
+        for alarms in ret.values_mut() {
+            alarms.retain(|a| a.active);
+        }
+
+        ret
+    }
+
     pub(crate) fn get_local_job_exporters(
         &self,
     ) -> Result<Vec<Arc<Exporter>>, Box<dyn Error + '_>> {
@@ -1214,3 +2857,314 @@ impl Instrumentation for ExperimentInstrumentation {
         *guard = Some(name.to_string());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(name: &str) -> CounterSnapshot {
+        CounterSnapshot {
+            name: name.to_string(),
+            doc: "doc".to_string(),
+            ctype: CounterType::newcounter(),
+        }
+    }
+
+    #[test]
+    fn namespaced_series_share_a_group() {
+        assert_eq!(
+            ExporterEntryGroup::basename("lib__calls{rank=\"0\"}".to_string()),
+            "lib__calls"
+        );
+
+        let exporter = Exporter::new();
+        exporter.push(&counter("lib__calls{rank=\"0\"}")).unwrap();
+        exporter.push(&counter("lib__calls{rank=\"1\"}")).unwrap();
+        exporter.push(&counter("calls")).unwrap();
+
+        let ht = exporter.ht.read().unwrap();
+        assert_eq!(ht.len(), 2);
+        assert_eq!(ht["lib__calls"].ht.read().unwrap().len(), 2);
+        assert_eq!(ht["calls"].ht.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn profile_counters_carry_their_docs() {
+        let exporter = Exporter::new();
+        exporter.push(&counter("calls")).unwrap();
+
+        /* Labeled copies and series re-pushed after expiry come without a doc */
+        let mut labeled = counter("calls{job=\"1\"}");
+        labeled.doc = "".to_string();
+        exporter.push(&labeled).unwrap();
+
+        let mut expired = counter("bytes");
+        expired.doc = "".to_string();
+        exporter.push(&counter("bytes{rank=\"0\"}")).unwrap();
+        exporter.push(&expired).unwrap();
+
+        let profile = exporter.profile(&JobDesc::new(), true).unwrap();
+        assert_eq!(profile.counters.len(), 4);
+        assert!(profile.counters.iter().all(|c| c.doc == "doc"));
+    }
+
+    #[test]
+    fn updown_counters_are_gauges_and_keep_zero() {
+        let exporter = Exporter::new();
+        let mut inflight = counter("inflight");
+        inflight.ctype = CounterType::newupdown();
+        exporter.push(&inflight).unwrap();
+
+        /* Never updated, nothing to expose */
+        let text = exporter.serialize_full(false, false).unwrap();
+        assert!(!text.contains("inflight"), "{}", text);
+
+        /* Back to 0 after an increment and a decrement */
+        for value in [1.0, -1.0] {
+            inflight.ctype = CounterType::UpDown { ts: 10, value };
+            exporter.accumulate(&inflight, false).unwrap();
+        }
+
+        let text = exporter.serialize_full(false, false).unwrap();
+        assert!(text.contains("# TYPE inflight gauge\n"), "{}", text);
+        assert!(text.contains("inflight 0\n"), "{}", text);
+
+        exporter.push(&counter("calls")).unwrap();
+        let text = exporter.serialize_full(false, true).unwrap();
+        assert!(text.contains("# TYPE calls counter\n"), "{}", text);
+    }
+
+    /// Held by the tests depending on the process wide series cap
+    static SERIES_CAP: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn series_past_the_cap_are_dropped() {
+        let _cap = SERIES_CAP.lock().unwrap_or_else(|e| e.into_inner());
+        MAX_SERIES_PER_METRIC.store(3, Ordering::Relaxed);
+        let dropped = DROPPED_SERIES.load(Ordering::Relaxed);
+
+        /* Reaching the cap used to deadlock accumulate, run it with a timeout */
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let exporter = Exporter::new();
+
+            for i in 0..5 {
+                let mut snap = counter(&format!("capped{{rank=\"{}\"}}", i));
+                exporter.push(&snap).unwrap();
+                snap.ctype = CounterType::Counter { ts: 10, value: 1.0 };
+                exporter.accumulate(&snap, true).unwrap();
+            }
+
+            let ht = exporter.ht.read().unwrap();
+            tx.send(ht["capped"].ht.read().unwrap().len()).unwrap();
+        });
+
+        let kept = rx.recv_timeout(Duration::from_secs(5));
+        MAX_SERIES_PER_METRIC.store(0, Ordering::Relaxed);
+
+        assert_eq!(kept.expect("Pushing past the cap did not complete"), 3);
+        assert!(DROPPED_SERIES.load(Ordering::Relaxed) >= dropped + 2);
+    }
+
+    const STRESS_THREADS: usize = 16;
+    const STRESS_ROUNDS: usize = 500;
+
+    /// Exporter after threads pushed, accumulated and serialized concurrently
+    fn stress_exporter() -> Arc<Exporter> {
+        let exporter = Arc::new(Exporter::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        for t in 0..STRESS_THREADS {
+            let exporter = exporter.clone();
+            let tx = tx.clone();
+
+            std::thread::spawn(move || {
+                for i in 0..STRESS_ROUNDS {
+                    /* Threads share groups and series, new ones keep appearing */
+                    let mut snap =
+                        counter(&format!("stress_{}{{rank=\"{}\"}}", i % 4, (t + i) % 32));
+                    exporter.push(&snap).unwrap();
+                    snap.ctype = CounterType::Counter { ts: 10, value: 1.0 };
+                    exporter.accumulate(&snap, true).unwrap();
+
+                    match i % 3 {
+                        0 => {
+                            exporter.serialize_full(false, true).unwrap();
+                        }
+                        1 => {
+                            exporter.profile(&JobDesc::new(), false).unwrap();
+                        }
+                        _ => {
+                            exporter.delta("");
+                        }
+                    }
+                }
+                tx.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..STRESS_THREADS {
+            rx.recv_timeout(Duration::from_secs(30))
+                .expect("Concurrent exporter accesses did not complete");
+        }
+
+        exporter
+    }
+
+    #[test]
+    fn concurrent_push_accumulate_and_serialize_complete() {
+        let _cap = SERIES_CAP.lock().unwrap_or_else(|e| e.into_inner());
+
+        /* Every accumulate landed */
+        let exporter = stress_exporter();
+        let profile = exporter.profile(&JobDesc::new(), true).unwrap();
+        let total: f64 = profile.counters.iter().map(|c| c.ctype.value()).sum();
+        assert_eq!(total, (STRESS_THREADS * STRESS_ROUNDS) as f64);
+
+        /* Same with half of the series over the cap */
+        MAX_SERIES_PER_METRIC.store(16, Ordering::Relaxed);
+        let exporter = stress_exporter();
+        MAX_SERIES_PER_METRIC.store(0, Ordering::Relaxed);
+
+        let ht = exporter.ht.read().unwrap();
+        assert_eq!(ht.len(), 4);
+        assert!(ht.values().all(|g| g.ht.read().unwrap().len() == 16));
+    }
+
+    #[test]
+    fn alarm_templates_apply_to_new_jobs() {
+        let prefix = std::env::temp_dir().join(format!("proxy-alarmjobs-{}", std::process::id()));
+        std::fs::create_dir_all(&prefix).unwrap();
+
+        let alarms = prefix.join("alarms.json");
+        std::fs::write(
+            &alarms,
+            r#"[{"name": "busy", "target": "^job-", "metric": "calls", "operation": ">", "value": 1}]"#,
+        )
+        .unwrap();
+
+        let factory = ExporterFactory::new(
+            prefix.clone(),
+            ExporterFactoryOptions {
+                aggregate: false,
+                system_metrics: SystemMetricsLevel::None,
+                alarms_file: Some(alarms),
+                ..Default::default()
+            },
+            Arc::new(NoInstrumentation),
+        )
+        .unwrap();
+
+        let mut matched = JobDesc::new();
+        matched.jobid = "job-1".to_string();
+        let mut other = JobDesc::new();
+        other.jobid = "other".to_string();
+
+        /* Jobs are created after startup, the alarm waits for its metric */
+        for desc in [&matched, &other] {
+            let exporter = factory.resolve_job(desc, false);
+            exporter.push(&counter("calls")).unwrap();
+        }
+        factory.apply_alarm_templates();
+
+        let alarms = factory.list_alarms();
+        assert_eq!(alarms["job-1"].len(), 1);
+        assert_eq!(alarms["job-1"][0].name, "busy");
+        assert!(alarms["other"].is_empty());
+
+        factory.shutdown();
+        std::fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn custom_prefix_applies_to_serialized_names() {
+        for prefix_all in [false, true] {
+            let prefix = std::env::temp_dir().join(format!(
+                "proxy-prefix-{}-{}",
+                std::process::id(),
+                prefix_all
+            ));
+            let factory = ExporterFactory::new(
+                prefix.clone(),
+                ExporterFactoryOptions {
+                    aggregate: false,
+                    system_metrics: SystemMetricsLevel::None,
+                    metric_prefix: "site_".to_string(),
+                    prefix_all,
+                    ..Default::default()
+                },
+                Arc::new(NoInstrumentation),
+            )
+            .unwrap();
+
+            for name in ["calls{rank=\"0\"}", "calls{rank=\"1\"}", "has_started"] {
+                factory
+                    .push(
+                        name,
+                        "doc",
+                        CounterType::Counter { ts: 1, value: 2.0 },
+                        None,
+                        None,
+                    )
+                    .unwrap();
+            }
+
+            let text = factory.main.serialize(false).unwrap();
+
+            /* System metrics always take the prefix */
+            assert!(text.contains("# TYPE site_build_info counter\n"));
+            assert!(!text.contains("proxy_build_info"));
+
+            /* Both series stay in the group of the prefixed basename */
+            let calls = if prefix_all { "site_calls" } else { "calls" };
+            assert_eq!(
+                text.matches(&format!("# TYPE {} counter\n", calls)).count(),
+                1
+            );
+            for rank in 0..2 {
+                assert!(text.contains(&format!("\n{}{{rank=\"{}\"}} 1 2\n", calls, rank)));
+            }
+
+            /* The job lifecycle counters are never prefixed */
+            assert!(text.contains("# TYPE has_started counter\n"));
+
+            factory.shutdown();
+            let _ = std::fs::remove_dir_all(&prefix);
+        }
+    }
+
+    #[test]
+    fn stale_job_metrics_expire() {
+        let prefix = std::env::temp_dir().join(format!("proxy-expiry-{}", std::process::id()));
+        let factory = ExporterFactory::new(
+            prefix.clone(),
+            ExporterFactoryOptions {
+                aggregate: false,
+                system_metrics: SystemMetricsLevel::None,
+                ..Default::default()
+            },
+            Arc::new(NoInstrumentation),
+        )
+        .unwrap();
+
+        let mut desc = JobDesc::new();
+        desc.jobid = "job-1".to_string();
+        let job = factory.resolve_job(&desc, false);
+        job.push(&counter("calls")).unwrap();
+        factory.main.push(&counter("calls")).unwrap();
+        let main_len = factory.main.counters(true).unwrap().len();
+
+        /* Updated within the TTL */
+        let now = unix_ts();
+        assert_eq!(factory.expire_metrics(now, 1000), 0);
+        assert_eq!(job.counters(true).unwrap().len(), 1);
+
+        /* The clock moved past the TTL, main keeps its build info */
+        assert_eq!(factory.expire_metrics(now + 2000, 1000), 1);
+        assert!(job.counters(true).unwrap().is_empty());
+        assert_eq!(factory.main.counters(true).unwrap().len(), main_len);
+
+        factory.shutdown();
+        let _ = std::fs::remove_dir_all(&prefix);
+    }
+}
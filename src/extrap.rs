@@ -307,6 +307,8 @@ struct ExtrapSample {
     callpath: Option<String>,
     size: i32,
     value: f64,
+    /// Index of the command in a bundle (see ExtrapModel::bundle)
+    command: Option<usize>,
 }
 
 impl ExtrapSample {
@@ -316,6 +318,7 @@ impl ExtrapSample {
             callpath,
             size,
             value,
+            command: None,
         }
     }
 
@@ -329,6 +332,10 @@ impl ExtrapSample {
 
         ret.push_param("size", self.size as f64);
 
+        if let Some(command) = self.command {
+            ret.push_param("command", command as f64);
+        }
+
         ret
     }
 }
@@ -427,10 +434,41 @@ impl ExtrapModel {
         true
     }
 
+    /// Merge the models of several commands, the samples of the i-th
+    /// model get an extra `command` parameter set to i
+    pub(crate) fn bundle(models: Vec<ExtrapModel>) -> ExtrapModel {
+        let mut ret = ExtrapModel {
+            profiles: Vec::new(),
+            samples: Vec::new(),
+        };
+
+        for (idx, mut model) in models.into_iter().enumerate() {
+            for s in model.samples.iter_mut() {
+                s.command = Some(idx);
+            }
+            ret.profiles.append(&mut model.profiles);
+            ret.samples.append(&mut model.samples);
+        }
+
+        ret
+    }
+
     pub(crate) fn to_jsonl(&self) -> Vec<ExtrapJsonlSample> {
         self.samples.iter().map(|v| v.to_jsonl_sample()).collect()
     }
 
+    /// The model as Extra-P JSON lines
+    pub(crate) fn to_jsonl_string(&self) -> Result<String, Box<dyn Error>> {
+        let mut ret = String::new();
+
+        for s in self.to_jsonl().iter() {
+            ret += &serde_json::to_string(&s)?;
+            ret += "\n";
+        }
+
+        Ok(ret)
+    }
+
     pub(crate) fn serialize(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
         log::info!(
             "Serializing ExtraP MODEL to {}",
@@ -438,13 +476,7 @@ impl ExtrapModel {
         );
         let mut fd = File::create(path)?;
 
-        let samples = self.to_jsonl();
-
-        for s in samples.iter() {
-            let data = serde_json::to_vec(&s)?;
-            fd.write_all(&data)?;
-            fd.write_all("\n".as_bytes())?;
-        }
+        fd.write_all(self.to_jsonl_string()?.as_bytes())?;
 
         Ok(())
     }
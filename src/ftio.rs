@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use crate::trace::{Trace, TraceExport};
@@ -176,6 +177,25 @@ impl FtioArguments {
     }
 }
 
+/// Command run when the FTIO server is unreachable, the trace is passed on stdin
+#[derive(Debug, Clone)]
+pub(crate) struct FtioCommand {
+    pub path: String,
+    pub args: Vec<String>,
+    /// The process is killed if it runs longer
+    pub timeout: Duration,
+}
+
+impl Default for FtioCommand {
+    fn default() -> Self {
+        Self {
+            path: "admire_proxy_invoke_ftio".to_string(),
+            args: vec!["-n".to_string(), "10".to_string()],
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct Payload {
     argv: Vec<String>,
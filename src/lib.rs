@@ -3,32 +3,48 @@ use elf::endian::AnyEndian;
 use elf::segment::ProgramHeader;
 use lazy_static::lazy_static;
 use proc_maps::{get_process_maps, maps_contain_addr, MapRange};
+use regex::Regex;
+use std::cell::RefCell;
 use std::env;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::io::{BufRead, BufReader};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::ptr;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{error::Error, io::Write};
 mod proxy_common;
 mod squeue;
 use elf::ElfBytes;
 use proxy_common::ProxyErr;
-use proxy_common::{get_proxy_path, init_log};
+use proxy_common::{
+    expand_path, get_proxy_path, init_log, unix_socket_connect, CgroupStats, ProcessStats,
+};
 
 mod proxywireprotocol;
+mod shm;
+
+pub mod client;
+use client::{Client, Counter, Gauge, LastValue, Namespace, UpDownCounter};
 use libc::{c_ulonglong, signal, user, SIGPIPE, SIG_IGN};
-use proxywireprotocol::{CounterType, CounterValue, JobDesc, ProxyCommand, ValueDesc};
+use proxywireprotocol::{
+    CounterSnapshot, CounterType, CounterValue, JobDesc, ProxyCommand, ProxyResponse, ShmSlot,
+    ValueDesc,
+};
+use shm::ShmSegment;
 
 use std::collections::{HashMap, HashSet};
 
 use std::thread;
 
+use std::sync::atomic::AtomicUsize;
 use std::sync::Once;
 
 pub struct MetricProxyValue {
     value: Mutex<CounterValue>,
+    /// Slot of a counter read by the proxy from shared memory
+    shm: Option<(Arc<ShmSegment>, usize)>,
 }
 
 impl MetricProxyValue {
@@ -38,6 +54,14 @@ impl MetricProxyValue {
                 name,
                 value: CounterType::newcounter(),
             }),
+            shm: None,
+        }
+    }
+
+    fn newshmcounter(name: String, segment: Arc<ShmSegment>, slot: usize) -> MetricProxyValue {
+        MetricProxyValue {
+            shm: Some((segment, slot)),
+            ..MetricProxyValue::newcounter(name)
         }
     }
 
@@ -51,6 +75,16 @@ impl MetricProxyValue {
                 hits,
                 total: _,
             } => hits > 0.0,
+            CounterType::LastValue { ts, value: _ } => ts > 0,
+            CounterType::UpDown { ts: _, value } => value != 0.0,
+        }
+    }
+
+    /// Value never registered in the client, updates on it are dropped
+    fn newdetached(name: String, ctype: CounterType) -> MetricProxyValue {
+        MetricProxyValue {
+            value: Mutex::new(CounterValue { name, value: ctype }),
+            shm: None,
         }
     }
 
@@ -60,14 +94,50 @@ impl MetricProxyValue {
                 name,
                 value: CounterType::newgauge(),
             }),
+            shm: None,
+        }
+    }
+
+    fn newupdown(name: String) -> MetricProxyValue {
+        MetricProxyValue {
+            value: Mutex::new(CounterValue {
+                name,
+                value: CounterType::newupdown(),
+            }),
+            shm: None,
+        }
+    }
+
+    fn newlastvalue(name: String) -> MetricProxyValue {
+        MetricProxyValue {
+            value: Mutex::new(CounterValue {
+                name,
+                value: CounterType::newlastvalue(),
+            }),
+            shm: None,
         }
     }
 
     fn inc(&self, increment: f64) -> Result<(), ProxyErr> {
+        /* Shared counters are never sent, the proxy reads them */
+        if let Some((segment, slot)) = &self.shm {
+            if increment < 0.0 {
+                return Err(ProxyErr::new("Counters cannot be decremented"));
+            }
+            segment.add(*slot, increment);
+            return Ok(());
+        }
+
         let mut tval = self.value.lock().unwrap();
 
         match &mut tval.value {
             CounterType::Counter { ts: _, value } => {
+                if increment < 0.0 {
+                    return Err(ProxyErr::new("Counters cannot be decremented"));
+                }
+                *value += increment;
+            }
+            CounterType::UpDown { ts: _, value } => {
                 *value += increment;
             }
             _ => {
@@ -81,6 +151,15 @@ impl MetricProxyValue {
     fn set(&self, value: f64) -> Result<(), ProxyErr> {
         let mut tval = self.value.lock().unwrap();
 
+        /* A last value keeps the time it was set at */
+        if let CounterType::LastValue { .. } = tval.value {
+            tval.value = CounterType::LastValue {
+                ts: proxy_common::unix_ts_us(),
+                value,
+            };
+            return Ok(());
+        }
+
         let new = CounterType::Gauge {
             min: value,
             max: value,
@@ -92,17 +171,114 @@ impl MetricProxyValue {
 
         Ok(())
     }
+
+    /// Value not sent to the proxy yet, None if nothing was set since the last flush
+    fn local_value(&self) -> Option<f64> {
+        let tval = self.value.lock().unwrap();
+
+        if tval.value.hasdata() {
+            Some(tval.value.value())
+        } else {
+            None
+        }
+    }
+
+    /// Add an observation to a gauge counting for `weight` hits (for
+    /// instance the time it was held) so that its average is weighted
+    fn observe_weighted(&self, value: f64, weight: f64) -> Result<(), ProxyErr> {
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(ProxyErr::new("Weights must be positive"));
+        }
+
+        self.set_weighted(value, weight)
+    }
+
+    /// Merge an observation of `weight` hits in a gauge
+    fn set_weighted(&self, value: f64, weight: f64) -> Result<(), ProxyErr> {
+        let mut tval = self.value.lock().unwrap();
+
+        if !matches!(tval.value, CounterType::Gauge { .. }) {
+            return Err(ProxyErr::new("Weighted observations are only for gauges"));
+        }
+
+        let observation = CounterType::Gauge {
+            min: value,
+            max: value,
+            hits: weight,
+            total: value * weight,
+        };
+
+        /* The first observation of the period sets the bounds */
+        if tval.value.hasdata() {
+            tval.value.merge(&observation)
+        } else {
+            tval.value = observation;
+            Ok(())
+        }
+    }
+}
+
+/// Separator between the prefix of a namespace and the names of its metrics
+const NAMESPACE_SEPARATOR: &str = "__";
+
+/// Metrics of a library prefixed with `prefix__` (see `Client::namespace`)
+pub struct MetricProxyNamespace {
+    client: Arc<MetricProxyClient>,
+    prefix: String,
+}
+
+impl MetricProxyNamespace {
+    /// Prefixes cannot contain the separator (nor end with '_') so that
+    /// two namespaces never produce the same name, there is no '{' either
+    /// as it would be taken as the start of labels
+    fn new(client: Arc<MetricProxyClient>, prefix: &str) -> Result<MetricProxyNamespace, ProxyErr> {
+        if !NAMESPACE_PREFIX.is_match(prefix) {
+            return Err(ProxyErr::new(format!(
+                "Invalid namespace '{}' it must match [a-zA-Z][a-zA-Z0-9]*(_[a-zA-Z0-9]+)*",
+                prefix
+            )));
+        }
+
+        Ok(MetricProxyNamespace {
+            client,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn name(&self, name: &str) -> String {
+        format!("{}{}{}", self.prefix, NAMESPACE_SEPARATOR, name)
+    }
 }
 
 static mut PROXY_INSTANCE: Option<Arc<MetricProxyClient>> = None;
 
+/// How long to wait for the proxy to answer a Get
+const GET_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct MetricProxyClient {
+    sock_path: String,
     period: Duration,
     running: Arc<Mutex<bool>>,
     stream: Mutex<Option<UnixStream>>,
     counters: RwLock<HashMap<String, Arc<MetricProxyValue>>>,
     functions: RwLock<HashMap<String, Arc<MetricProxyValue>>>,
+    /// Names of the counters whose Desc reached the proxy
+    sent_descs: Mutex<HashSet<String>>,
+    /// Doc and type of the counters, described again after a reconnection
+    descs: Mutex<HashMap<String, (String, CounterType)>>,
     maps: Vec<MapRange>,
+    /// Only functions from DSOs containing one of these are counted (PROXY_FUNC_DSO_FILTER)
+    dso_filter: Vec<String>,
+    /// Addresses of the functions outside of the DSO filter
+    filtered_funcs: RwLock<HashSet<usize>>,
+    /// Counters shared with the proxy (PROXY_SHM=1)
+    shm: RwLock<Option<Arc<ShmSegment>>>,
+    /// Metrics matching one of these are never created (PROXY_METRIC_DENY)
+    deny: Vec<Regex>,
+    /// Add the file:line of functions to their counters (PROXY_FUNC_SOURCE_LINES=1)
+    func_source_lines: bool,
+    /// Live `Client` handles on this client, the last one dropped releases the job
+    handles: AtomicUsize,
 }
 
 impl Drop for MetricProxyClient {
@@ -118,10 +294,35 @@ lazy_static! {
 }
 
 impl MetricProxyClient {
+    /// Client configured from the environment (PROXY_PATH and PROXY_PERIOD)
     fn new() -> Arc<MetricProxyClient> {
+        let sock_path = env::var("PROXY_PATH")
+            .map(|p| expand_path(&p))
+            .unwrap_or(get_proxy_path());
+        let period = Duration::from_millis(proxy_common::get_proxy_period());
+        MetricProxyClient::new_with(sock_path, period)
+    }
+
+    /// There is a single client per process, it is created on first call
+    fn new_with(sock_path: String, period: Duration) -> Arc<MetricProxyClient> {
         unsafe {
             if let Some(client) = PROXY_INSTANCE.clone() {
-                return client;
+                if client.sock_path == sock_path && client.period == period {
+                    return client;
+                }
+
+                /* The library constructor already tried the environment settings,
+                a client which failed to connect can be replaced */
+                if client.running() {
+                    log::warn!(
+                        "Metric proxy client already connected to {} every {} ms, ignoring {} every {} ms",
+                        client.sock_path,
+                        client.period.as_millis(),
+                        sock_path,
+                        period.as_millis()
+                    );
+                    return client;
+                }
             }
         }
 
@@ -135,13 +336,13 @@ impl MetricProxyClient {
 
         let mut can_run: bool = true;
 
-        let sock_path = env::var("PROXY_PATH").unwrap_or(get_proxy_path());
         let path = Path::new(&sock_path);
 
-        let tsock = if !path.exists() {
+        /* Abstract sockets have no file to check for */
+        let tsock = if !sock_path.starts_with('@') && !path.exists() {
             None
         } else {
-            match UnixStream::connect(path) {
+            match unix_socket_connect(&sock_path) {
                 Ok(v) => Some(v),
                 Err(e) => {
                     log::error!("Failed to connect : {}", e);
@@ -155,15 +356,43 @@ impl MetricProxyClient {
             log::warn!("Not Connected to Metric Proxy");
         }
 
-        let period: Duration = Duration::from_millis(proxy_common::get_proxy_period());
+        let dso_filter: Vec<String> = env::var("PROXY_FUNC_DSO_FILTER")
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+
+        let deny: Vec<Regex> = env::var("PROXY_METRIC_DENY")
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| match Regex::new(v) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    log::warn!("Ignoring invalid PROXY_METRIC_DENY pattern {} : {}", v, e);
+                    None
+                }
+            })
+            .collect();
 
         let client = MetricProxyClient {
+            sock_path,
             period,
             running: Arc::new(Mutex::new(can_run)),
             stream: Mutex::new(tsock),
             counters: RwLock::new(HashMap::new()),
             functions: RwLock::new(HashMap::new()),
+            sent_descs: Mutex::new(HashSet::new()),
+            descs: Mutex::new(HashMap::new()),
             maps: get_process_maps(std::process::id() as i32).unwrap(),
+            dso_filter,
+            filtered_funcs: RwLock::new(HashSet::new()),
+            shm: RwLock::new(None),
+            deny,
+            func_source_lines: env::var("PROXY_FUNC_SOURCE_LINES").is_ok_and(|v| v == "1"),
+            handles: AtomicUsize::new(0),
         };
 
         let pclient = Arc::new(client);
@@ -173,10 +402,18 @@ impl MetricProxyClient {
         if pclient.running() {
             /* Send initial jobdesc  */
             pclient.send_jobdesc().ok();
+
+            if env::var("PROXY_SHM").is_ok_and(|v| v == "1") {
+                if let Err(e) = pclient.setup_shm() {
+                    log::warn!("Shared counters disabled : {}", e);
+                }
+            }
+
             thread::spawn(move || {
                 while rclient.running() {
-                    if rclient.dump_values().is_err() {
-                        break;
+                    /* The next dump reconnects after a write error */
+                    if let Err(e) = rclient.dump_values() {
+                        log::debug!("Failed to send values : {}", e);
                     }
                     thread::sleep(rclient.period);
                 }
@@ -191,6 +428,66 @@ impl MetricProxyClient {
         pclient
     }
 
+    /// Create the shared counters segment and make sure the proxy can read it
+    fn setup_shm(&self) -> Result<(), Box<dyn Error>> {
+        let slots: usize = match env::var("PROXY_SHM_SLOTS") {
+            Ok(v) => v.parse()?,
+            Err(_) => shm::SHM_DEFAULT_SLOTS,
+        };
+
+        let mut path = match env::var("PROXY_SHM_DIR") {
+            Ok(dir) => std::path::PathBuf::from(dir),
+            Err(_) if Path::new("/dev/shm").is_dir() => std::path::PathBuf::from("/dev/shm"),
+            Err(_) => env::temp_dir(),
+        };
+        path.push(format!("proxy_counters_{}", std::process::id()));
+
+        let segment = Arc::new(ShmSegment::create(&path, slots)?);
+
+        /* An empty registration checks that the proxy mapped the segment */
+        self.register_shm(&segment, Vec::new())?;
+
+        log::info!(
+            "Sharing up to {} counters in {}",
+            slots,
+            path.to_string_lossy()
+        );
+
+        *self.shm.write().unwrap() = Some(segment);
+
+        Ok(())
+    }
+
+    fn register_shm(
+        &self,
+        segment: &ShmSegment,
+        slots: Vec<ShmSlot>,
+    ) -> Result<(), Box<dyn Error>> {
+        let cmd = ProxyCommand::ShmRegister {
+            path: segment.path().to_string_lossy().to_string(),
+            slots,
+        };
+
+        match self.request(&cmd)? {
+            ProxyResponse::Error(e) => Err(ProxyErr::newboxed(e)),
+            ProxyResponse::Values(_) => Ok(()),
+        }
+    }
+
+    /// Counters go to shared memory until the segment is full
+    fn shm_slot(&self) -> Option<(Arc<ShmSegment>, usize)> {
+        let shm = self.shm.read().unwrap();
+        let segment = shm.as_ref()?;
+        segment.alloc().map(|slot| (segment.clone(), slot))
+    }
+
+    /// The proxy has mapped the segment, the file is no longer needed
+    fn release_shm(&self) {
+        if let Some(segment) = self.shm.read().unwrap().as_ref() {
+            let _ = std::fs::remove_file(segment.path());
+        }
+    }
+
     fn text_offset(dso: &str) -> Option<usize> {
         let path = std::path::PathBuf::from(dso);
 
@@ -276,10 +573,8 @@ impl MetricProxyClient {
                 .collect();
         }
 
-        for command in values_to_send {
-            self.send(&command)?;
-        }
-        Ok(())
+        /* All the values go out in a single write */
+        self.send_all(&values_to_send)
     }
 
     fn running(&self) -> bool {
@@ -287,27 +582,175 @@ impl MetricProxyClient {
     }
 
     fn send(&self, cmd: &ProxyCommand) -> Result<(), Box<dyn Error>> {
-        let mut stream_lock = self.stream.lock().unwrap();
+        self.send_all(std::slice::from_ref(cmd))
+    }
 
-        if let Some(mut stream) = stream_lock.as_mut() {
-            serde_json::to_writer(&mut stream, cmd)?;
-            let null_byte: [u8; 1] = [0_u8; 1];
-            stream.write_all(&null_byte)?;
+    /// Send NUL terminated commands with a single write on the socket
+    /// (serde_json writes each token separately on an unbuffered stream)
+    fn send_all(&self, cmds: &[ProxyCommand]) -> Result<(), Box<dyn Error>> {
+        if cmds.is_empty() {
+            return Ok(());
+        }
 
+        let mut buff: Vec<u8> = Vec::new();
+        for cmd in cmds {
+            serde_json::to_writer(&mut buff, cmd)?;
+            buff.push(0);
             log::debug!("Sending {:?}", cmd);
-        } else {
-            *self.running.lock().unwrap() = false;
-            return Err(ProxyErr::newboxed("Not connected to UNIX socket"));
+        }
+
+        let mut stream_lock = self.stream.lock().unwrap();
+
+        let stream = self.connected(&mut stream_lock)?;
+
+        if let Err(e) = stream.write_all(&buff) {
+            /* After a partial write the proxy may only have part of a command */
+            *stream_lock = None;
+            return Err(e.into());
         }
 
         Ok(())
     }
 
+    /// Stream to the proxy, reconnecting if a previous write failed
+    fn connected<'a>(
+        &self,
+        stream: &'a mut Option<UnixStream>,
+    ) -> Result<&'a mut UnixStream, Box<dyn Error>> {
+        /* Clients which never connected do not retry */
+        if stream.is_none() && self.running() {
+            *stream = Some(self.reconnect()?);
+        }
+
+        stream
+            .as_mut()
+            .ok_or_else(|| -> Box<dyn Error> { ProxyErr::newboxed("Not connected to UNIX socket") })
+    }
+
+    /// Connect again, the proxy sees a new client so the job and the
+    /// counters are described again, shared counters are no longer read
+    fn reconnect(&self) -> Result<UnixStream, Box<dyn Error>> {
+        let mut stream = unix_socket_connect(&self.sock_path)?;
+
+        let mut cmds = vec![ProxyCommand::JobDesc(JOBDESC.clone())];
+
+        let descs = self.descs.lock().unwrap();
+        let mut sent_descs = self.sent_descs.lock().unwrap();
+        sent_descs.clear();
+
+        for (name, (doc, ctype)) in descs.iter() {
+            cmds.push(ProxyCommand::Desc(ValueDesc {
+                name: name.to_string(),
+                doc: doc.to_string(),
+                ctype: ctype.clone(),
+            }));
+        }
+
+        let mut buff: Vec<u8> = Vec::new();
+        for cmd in cmds.iter() {
+            serde_json::to_writer(&mut buff, cmd)?;
+            buff.push(0);
+        }
+        stream.write_all(&buff)?;
+
+        sent_descs.extend(descs.keys().cloned());
+
+        if self.shm.read().unwrap().is_some() {
+            log::warn!("Shared counters are not read by the proxy after a reconnection");
+        }
+
+        log::info!("Reconnected to {}", self.sock_path);
+
+        Ok(stream)
+    }
+
+    /// Ask the proxy for the current values matching name (a trailing '*' matches any suffix)
+    fn get_values(&self, name: &str) -> Result<Vec<CounterSnapshot>, Box<dyn Error>> {
+        self.query_values(&ProxyCommand::Get {
+            name: name.to_string(),
+        })
+    }
+
+    /// Same as get_values for the values aggregated on the node by the proxy
+    fn get_node_values(&self, name: &str) -> Result<Vec<CounterSnapshot>, Box<dyn Error>> {
+        self.query_values(&ProxyCommand::GetNode {
+            name: name.to_string(),
+        })
+    }
+
+    fn query_values(&self, cmd: &ProxyCommand) -> Result<Vec<CounterSnapshot>, Box<dyn Error>> {
+        match self.request(cmd)? {
+            ProxyResponse::Values(values) => Ok(values),
+            ProxyResponse::Error(e) => Err(ProxyErr::newboxed(e)),
+        }
+    }
+
+    /// Send a command and wait for the response of the proxy
+    fn request(&self, cmd: &ProxyCommand) -> Result<ProxyResponse, Box<dyn Error>> {
+        /* The stream stays locked until the response so that it is not interleaved */
+        let mut stream_lock = self.stream.lock().unwrap();
+
+        let stream = self.connected(&mut stream_lock)?;
+
+        let mut buff = serde_json::to_vec(cmd)?;
+        buff.push(0);
+        if let Err(e) = stream.write_all(&buff) {
+            *stream_lock = None;
+            return Err(e.into());
+        }
+
+        stream.set_read_timeout(Some(GET_TIMEOUT))?;
+
+        let mut data: Vec<u8> = Vec::new();
+        let res = BufReader::new(&*stream).read_until(0, &mut data);
+
+        /* A late response would answer the next request, reconnect instead */
+        if let Err(e) = res {
+            *stream_lock = None;
+            return Err(e.into());
+        }
+
+        if data.pop() != Some(0) {
+            *stream_lock = None;
+            return Err(ProxyErr::newboxed("Proxy closed the connection"));
+        }
+
+        Ok(serde_json::from_slice::<ProxyResponse>(&data)?)
+    }
+
     fn send_jobdesc(&self) -> Result<(), Box<dyn Error>> {
         let desc = ProxyCommand::JobDesc(JOBDESC.clone());
         self.send(&desc)
     }
 
+    fn annotate(&self, label: &str) -> Result<(), Box<dyn Error>> {
+        /* Values preceding the annotation are flushed first */
+        self.dump_values()?;
+
+        let cmd = ProxyCommand::Annotation {
+            ts: proxy_common::unix_ts_us(),
+            label: label.to_string(),
+        };
+
+        match self.request(&cmd)? {
+            ProxyResponse::Error(e) => Err(ProxyErr::newboxed(e)),
+            ProxyResponse::Values(_) => Ok(()),
+        }
+    }
+
+    fn send_jobend(&self) -> Result<(), Box<dyn Error>> {
+        let end = ProxyCommand::JobEnd {
+            jobid: JOBDESC.jobid.clone(),
+            end_time: proxy_common::unix_ts(),
+        };
+        self.send(&end)?;
+
+        /* The proxy forgets the job, counters pushed after it are described again */
+        self.sent_descs.lock().unwrap().clear();
+
+        Ok(())
+    }
+
     fn push_entry(
         &self,
         name: String,
@@ -316,11 +759,11 @@ impl MetricProxyClient {
     ) -> Result<Arc<MetricProxyValue>, Box<dyn Error>> {
         let counter: Arc<MetricProxyValue>;
 
-        let command = ProxyCommand::Desc(ValueDesc {
-            name: name.to_string(),
-            doc,
-            ctype: ctype.clone(),
-        });
+        /* Denied metrics are handed a value that is never sent */
+        if self.deny.iter().any(|r| r.is_match(&name)) {
+            log::trace!("Metric {} is denied", name);
+            return Ok(Arc::new(MetricProxyValue::newdetached(name, ctype)));
+        }
 
         /* First try to add the counters */
         {
@@ -330,12 +773,23 @@ impl MetricProxyClient {
 
             if foundcounter.is_none() {
                 counter = match ctype {
-                    CounterType::Counter { .. } => {
-                        Arc::new(MetricProxyValue::newcounter(name.to_string()))
-                    }
+                    CounterType::Counter { .. } => match self.shm_slot() {
+                        Some((segment, slot)) => Arc::new(MetricProxyValue::newshmcounter(
+                            name.to_string(),
+                            segment,
+                            slot,
+                        )),
+                        None => Arc::new(MetricProxyValue::newcounter(name.to_string())),
+                    },
                     CounterType::Gauge { .. } => {
                         Arc::new(MetricProxyValue::newgauge(name.to_string()))
                     }
+                    CounterType::LastValue { .. } => {
+                        Arc::new(MetricProxyValue::newlastvalue(name.to_string()))
+                    }
+                    CounterType::UpDown { .. } => {
+                        Arc::new(MetricProxyValue::newupdown(name.to_string()))
+                    }
                 };
                 ht.insert(name.to_string(), counter.clone());
             } else {
@@ -343,11 +797,72 @@ impl MetricProxyClient {
             }
         }
 
+        /* The proxy only needs the Desc once, a failed send is retried on next push */
+        if self.sent_descs.lock().unwrap().contains(&name) {
+            return Ok(counter);
+        }
+
+        self.descs
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), (doc.to_string(), ctype.clone()));
+
+        let command = ProxyCommand::Desc(ValueDesc {
+            name: name.to_string(),
+            doc,
+            ctype,
+        });
+
         self.send(&command)?;
 
+        /* The Desc must reach the proxy before it reads the slot */
+        if let Some((segment, slot)) = &counter.shm {
+            self.register_shm(
+                segment,
+                vec![ShmSlot {
+                    name: name.to_string(),
+                    slot: *slot,
+                }],
+            )?;
+        }
+
+        self.sent_descs.lock().unwrap().insert(name);
+
         Ok(counter)
     }
 
+    /// Publish the statistics of the instrumented process as app_process_* gauges
+    fn dump_process_stats(&self) -> Result<(), Box<dyn Error>> {
+        let stats = ProcessStats::read()?;
+
+        for (name, doc, value) in stats.values() {
+            let gauge = self.push_entry(
+                format!("app_process_{}", name),
+                doc.to_string(),
+                CounterType::newgauge(),
+            )?;
+            gauge.set(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish the resource usage of the cgroup of the process as job_cgroup_* gauges
+    fn dump_cgroup_stats(&self) -> Result<(), Box<dyn Error>> {
+        let stats = CgroupStats::read()?;
+
+        for (name, doc, value) in stats.values() {
+            let gauge = self.push_entry(
+                format!("job_cgroup_{}", name),
+                doc.to_string(),
+                CounterType::newgauge(),
+            )?;
+            gauge.set(value)?;
+        }
+
+        Ok(())
+    }
+
     fn new_counter(
         &self,
         name: String,
@@ -356,15 +871,16 @@ impl MetricProxyClient {
         self.push_entry(name, doc, CounterType::newcounter())
     }
 
-    fn new_gauge(
-        &mut self,
-        name: String,
-        doc: String,
-    ) -> Result<Arc<MetricProxyValue>, Box<dyn Error>> {
-        self.push_entry(name, doc, CounterType::newgauge())
+    /// Only keep characters valid in a metric name
+    fn metric_token(s: &str) -> String {
+        s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
     }
 
-    fn addr2line(addr: usize, dso: &str) -> String {
+    /// Returns the locus of addr and, when asked for and known, its file:line
+    /// (the locus then ends with the sanitized file:line)
+    fn addr2line(addr: usize, dso: &str, source_lines: bool) -> (String, Option<String>) {
         let mut command = std::process::Command::new("addr2line");
         command.arg("-fe").arg(dso).arg(format!("0x{:x}", addr));
 
@@ -376,14 +892,32 @@ impl MetricProxyClient {
 
             if lines.len() > 1 {
                 if lines[0].contains("??") {
-                    return format!("{:#x}_{}", addr, clean_dso);
-                } else {
-                    return format!("{}_{}", lines[0], clean_dso);
+                    return (format!("{:#x}_{}", addr, clean_dso), None);
                 }
+
+                /* Second line is file:line, possibly followed by a discriminator */
+                let source = lines[1]
+                    .split(" (")
+                    .next()
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| source_lines && !s.is_empty() && !s.starts_with("??"));
+
+                return match source {
+                    Some(src) => (
+                        format!(
+                            "{}_{}__{}",
+                            lines[0],
+                            clean_dso,
+                            MetricProxyClient::metric_token(&src)
+                        ),
+                        Some(src),
+                    ),
+                    None => (format!("{}_{}", lines[0], clean_dso), None),
+                };
             }
         }
 
-        format!("{:#x}{}", addr, clean_dso)
+        (format!("{:#x}{}", addr, clean_dso), None)
     }
 
     fn new_func(
@@ -391,7 +925,24 @@ impl MetricProxyClient {
         this_fn: usize,
         callsite: usize,
     ) -> Result<Arc<MetricProxyValue>, Box<dyn Error>> {
-        let func: String = format!("{}@{}", this_fn, callsite);
+        self.new_func_metric(this_fn, callsite, false)
+    }
+
+    /// Counter of the calls to a function from a callsite or, if time is set,
+    /// of the seconds spent in it (func_time__, callees included)
+    fn new_func_metric(
+        &self,
+        this_fn: usize,
+        callsite: usize,
+        time: bool,
+    ) -> Result<Arc<MetricProxyValue>, Box<dyn Error>> {
+        let (prefix, what) = if time {
+            ("func_time__", "Seconds spent in")
+        } else {
+            ("func__", "Number of calls to")
+        };
+
+        let func: String = format!("{}{}@{}", prefix, this_fn, callsite);
 
         if let Ok(funcs) = self.functions.read() {
             if let Some(prev) = funcs.get(&func) {
@@ -399,16 +950,29 @@ impl MetricProxyClient {
             }
         }
 
+        if self.filtered_funcs.read().unwrap().contains(&this_fn) {
+            return Err(ProxyErr::newboxed("Function is outside of the DSO filter"));
+        }
+
         let (addr, dso) = self.dso_local_offset(this_fn);
 
-        let locus = MetricProxyClient::addr2line(addr, &dso);
+        if !self.dso_filter.is_empty() && !self.dso_filter.iter().any(|f| dso.contains(f.as_str()))
+        {
+            log::trace!("Not counting {:#x} from {}", this_fn, dso);
+            self.filtered_funcs.write().unwrap().insert(this_fn);
+            return Err(ProxyErr::newboxed("Function is outside of the DSO filter"));
+        }
+
+        let (locus, source) = MetricProxyClient::addr2line(addr, &dso, self.func_source_lines);
 
         log::trace!("CALLSITE {}", locus);
 
-        if let Ok(c) = self.new_counter(
-            format!("func__{}", locus.clone()),
-            format!("Number of calls to {}", locus),
-        ) {
+        let doc = match source {
+            Some(src) => format!("{} {} at {}", what, locus, src),
+            None => format!("{} {}", what, locus),
+        };
+
+        if let Ok(c) = self.new_counter(format!("{}{}", prefix, locus), doc) {
             self.functions
                 .write()
                 .as_mut()
@@ -428,19 +992,40 @@ impl MetricProxyClient {
 /// An opaque object representing the metric client
 #[no_mangle]
 pub extern "C" fn metric_proxy_init() -> *mut MetricProxyClient {
-    let client = MetricProxyClient::new();
-    let client = Arc::into_raw(client) as *mut MetricProxyClient;
-
-    let pclient: &mut MetricProxyClient = unsafe { &mut *(client) };
+    Client::new().into_raw()
+}
 
-    if let Ok(start) = pclient.new_counter(
-        "has_started".to_string(),
-        "Number of calls to metric_proxy_init".to_string(),
-    ) {
-        let _ = start.inc(1.0);
+/// This intanciates the metric client with explicit settings
+/// instead of PROXY_PATH and PROXY_PERIOD
+///
+/// There is a single client per process, if it is already connected
+/// with other settings a warning is logged and it is returned as is.
+///
+/// # Arguments
+///
+/// - path: path of the proxy UNIX socket ('@' prefix for an abstract socket)
+/// - period_ms: period in milliseconds between two pushes of the values
+///
+/// # Return
+///
+/// An opaque object representing the metric client, NULL if the arguments are invalid
+///
+/// # Safety
+///
+/// path must be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_init_with(
+    path: *const std::os::raw::c_char,
+    period_ms: u64,
+) -> *mut MetricProxyClient {
+    if path.is_null() || period_ms == 0 {
+        return std::ptr::null_mut();
     }
 
-    client
+    match unwrap_c_string(path) {
+        Ok(path) => Client::with_settings(&path, Duration::from_millis(period_ms)).into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
 }
 
 /// Release the metric proxy
@@ -456,81 +1041,473 @@ pub unsafe extern "C" fn metric_proxy_release(pclient: *mut MetricProxyClient) -
     let zero: std::ffi::c_int = 0;
     let one: std::ffi::c_int = 1;
 
-    /* Get the client back and drop it */
-    let client: &mut MetricProxyClient = unsafe { &mut *(pclient) };
-
-    if let Ok(done) = client.new_counter(
-        "has_finished".to_string(),
-        "Number of calls to metric_proxy_release".to_string(),
-    ) {
-        let _ = done.inc(1.0);
+    if pclient.is_null() {
+        return one;
     }
 
-    *client.running.lock().unwrap() = false;
+    let client = unsafe { Client::borrow_raw(pclient) };
 
-    if client.dump_values().is_err() {
+    if client.release().is_err() {
         return one;
     }
 
     zero
 }
 
-fn unwrap_c_string(pcstr: *const std::os::raw::c_char) -> Result<String, Box<dyn Error>> {
-    // Convert the `char*` to a Rust CStr
-    let cstr = unsafe { CStr::from_ptr(pcstr) };
-
-    // Convert the CStr to a Rust &str (Unicode)
-    match cstr.to_str() {
-        Ok(e) => Ok(e.to_string()),
-        Err(e) => Err(Box::new(e)),
-    }
-}
-
-/* Counters */
-
-/// Create a new Cointer from the metric client
+/// Start polling statistics of the current process (RSS, threads, fds, ...)
+/// they are pushed every period as app_process_* gauges
 ///
 /// # Arguments
 ///
 /// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
-/// - name : name of the counter
-/// - doc: documentation of the counter
 ///
-/// # Returns
+/// # Return
 ///
-/// - Opaque pointer to a Counter instance
+/// 0 on success, 1 if the client is not running or the platform has no /proc
 ///
 /// # Safety
 ///
-/// Only correct pointers are returned by previous functions should be returned.
-/// Doing otherwise may crash.
+/// Only pointer returned by `metric_proxy_init` should be passed.
 #[no_mangle]
-pub unsafe extern "C" fn metric_proxy_counter_new(
+pub unsafe extern "C" fn metric_proxy_enable_process_stats(
     pclient: *mut MetricProxyClient,
-    name: *const std::os::raw::c_char,
-    doc: *const std::os::raw::c_char,
-) -> *mut MetricProxyValue {
-    let rname = unwrap_c_string(name);
-    let rdoc = unwrap_c_string(doc);
+) -> std::ffi::c_int {
+    let zero: std::ffi::c_int = 0;
+    let one: std::ffi::c_int = 1;
+
+    if pclient.is_null() {
+        return one;
+    }
+
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    if client.enable_process_stats().is_err() {
+        return one;
+    }
+
+    zero
+}
+
+/// Start polling the resource usage of the cgroup of the process (memory,
+/// CPU time, processes) pushed every period as job_cgroup_* gauges
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+///
+/// # Return
+///
+/// 0 on success, 1 if the client is not running or no cgroup can be read
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_init` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_enable_cgroup_stats(
+    pclient: *mut MetricProxyClient,
+) -> std::ffi::c_int {
+    let zero: std::ffi::c_int = 0;
+    let one: std::ffi::c_int = 1;
+
+    if pclient.is_null() {
+        return one;
+    }
 
-    if rname.is_err() || rdoc.is_err() || pclient.is_null() {
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    if client.enable_cgroup_stats().is_err() {
+        return one;
+    }
+
+    zero
+}
+
+fn unwrap_c_string(pcstr: *const std::os::raw::c_char) -> Result<String, Box<dyn Error>> {
+    // Convert the `char*` to a Rust CStr
+    let cstr = unsafe { CStr::from_ptr(pcstr) };
+
+    // Convert the CStr to a Rust &str (Unicode)
+    match cstr.to_str() {
+        Ok(e) => Ok(e.to_string()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+thread_local! {
+    /// Reason of the last NULL returned by the C API on this thread
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+    /// Functions entered by this thread (address and entry time)
+    static FUNC_STACK: RefCell<Vec<(usize, Instant)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// name="value" label, quotes, backslashes and newlines escaped in the value
+const LABEL_PAIR: &str = r#"[a-zA-Z_][a-zA-Z0-9_]*="([^"\\\n]|\\[\\"n])*""#;
+
+lazy_static! {
+    /// Prometheus metric name with an optional {name="value",...} label suffix
+    static ref METRIC_NAME: Regex = Regex::new(&format!(
+        r"^[a-zA-Z_:][a-zA-Z0-9_:]*(\{{({0}(,{0})*)?\}})?$",
+        LABEL_PAIR
+    ))
+    .unwrap();
+    static ref LABEL_NAME: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+    /// Namespace prefix without double, leading or trailing underscores
+    static ref NAMESPACE_PREFIX: Regex =
+        Regex::new(r"^[a-zA-Z][a-zA-Z0-9]*(_[a-zA-Z0-9]+)*$").unwrap();
+}
+
+fn set_last_error(msg: String) {
+    log::error!("{}", msg);
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(msg.replace('\0', "")).unwrap_or_default());
+}
+
+/// Names which would produce an unparseable /metrics output are refused
+fn validate_metric_name(name: &str) -> Result<(), ProxyErr> {
+    if METRIC_NAME.is_match(name) {
+        Ok(())
+    } else {
+        Err(ProxyErr::new(format!(
+            "Invalid metric name '{}' it must match [a-zA-Z_:][a-zA-Z0-9_:]* optionally followed by {{name=\"value\",...}}",
+            name
+        )))
+    }
+}
+
+/// Docs end up on a single # HELP line of the /metrics output
+fn validate_metric_doc(doc: &str) -> Result<(), ProxyErr> {
+    if doc.contains(['\n', '\r']) {
+        Err(ProxyErr::new(format!(
+            "Invalid metric doc '{}' it must fit on one line",
+            doc.escape_default()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Arguments of the metric constructors, on error NULL is to be returned
+/// and the reason is kept for `metric_proxy_last_error`
+fn unwrap_metric_args<T>(
+    pclient: *mut T,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> Option<(String, String)> {
+    if pclient.is_null() || name.is_null() || doc.is_null() {
+        set_last_error("NULL client, name or doc passed to the metric proxy".to_string());
+        return None;
+    }
+
+    let (rname, rdoc) = match (unwrap_c_string(name), unwrap_c_string(doc)) {
+        (Ok(n), Ok(d)) => (n, d),
+        _ => {
+            set_last_error("Metric name and doc must be valid UTF-8".to_string());
+            return None;
+        }
+    };
+
+    if let Err(e) = validate_metric_name(&rname).and(validate_metric_doc(&rdoc)) {
+        set_last_error(e.to_string());
+        return None;
+    }
+
+    Some((rname, rdoc))
+}
+
+/* Namespaces */
+
+/// Create a namespace in which the names of the metrics are prefixed with
+/// `prefix__` so that libraries of a process do not collide in the proxy
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - prefix: name of the namespace matching [a-zA-Z][a-zA-Z0-9]*(_[a-zA-Z0-9]+)*
+///
+/// # Returns
+///
+/// - Opaque pointer to the namespace, NULL on error (see `metric_proxy_last_error`)
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_init` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_new(
+    pclient: *mut MetricProxyClient,
+    prefix: *const std::os::raw::c_char,
+) -> *mut MetricProxyNamespace {
+    if pclient.is_null() || prefix.is_null() {
+        set_last_error("NULL client or prefix passed to the metric proxy".to_string());
         return std::ptr::null_mut();
     }
 
-    let client: &mut MetricProxyClient = unsafe { &mut *(pclient) };
+    let rprefix = match unwrap_c_string(prefix) {
+        Ok(p) => p,
+        Err(_) => {
+            set_last_error("Namespace prefix must be valid UTF-8".to_string());
+            return std::ptr::null_mut();
+        }
+    };
 
-    if !*client.running.lock().unwrap() {
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.namespace(&rprefix) {
+        Ok(ns) => ns.into_raw(),
+        Err(e) => {
+            set_last_error(e.to_string());
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a counter in a namespace, see `metric_proxy_counter_new`
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_namespace_new` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_counter_new(
+    pns: *mut MetricProxyNamespace,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pns, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let ns = unsafe { Namespace::borrow_raw(pns) };
+
+    match ns.counter(&rname, &rdoc) {
+        Ok(c) => c.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create an up/down counter in a namespace, see `metric_proxy_updown_new`
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_namespace_new` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_updown_new(
+    pns: *mut MetricProxyNamespace,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pns, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let ns = unsafe { Namespace::borrow_raw(pns) };
+
+    match ns.updown_counter(&rname, &rdoc) {
+        Ok(v) => v.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a gauge in a namespace, see `metric_proxy_gauge_new`
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_namespace_new` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_gauge_new(
+    pns: *mut MetricProxyNamespace,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pns, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let ns = unsafe { Namespace::borrow_raw(pns) };
+
+    match ns.gauge(&rname, &rdoc) {
+        Ok(g) => g.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a last value in a namespace, see `metric_proxy_lastvalue_new`
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_namespace_new` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_lastvalue_new(
+    pns: *mut MetricProxyNamespace,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pns, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let ns = unsafe { Namespace::borrow_raw(pns) };
+
+    match ns.last_value(&rname, &rdoc) {
+        Ok(v) => v.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a namespace, its metrics remain valid
+///
+/// The namespace holds a reference on the client which is only
+/// flushed and closed once all its namespaces are freed
+///
+/// # Return
+///
+/// 0 on success, 1 if pns is NULL
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_namespace_new` should be passed, and only once.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_namespace_free(
+    pns: *mut MetricProxyNamespace,
+) -> std::ffi::c_int {
+    if pns.is_null() {
+        return 1;
+    }
+
+    drop(unsafe { Namespace::from_raw(pns) });
+
+    0
+}
+
+/// Reason of the last NULL returned by a metric constructor on this thread
+///
+/// # Return
+///
+/// A message (empty if no error occured) valid until the next failing call on this thread
+#[no_mangle]
+pub extern "C" fn metric_proxy_last_error() -> *const std::os::raw::c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/* Counters */
+
+/// Create a new Cointer from the metric client
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name : name of the counter
+/// - doc: documentation of the counter
+///
+/// # Returns
+///
+/// - Opaque pointer to a Counter instance
+///
+/// # Safety
+///
+/// Only correct pointers are returned by previous functions should be returned.
+/// Doing otherwise may crash.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_counter_new(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pclient, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.counter(&rname, &rdoc) {
+        Ok(c) => c.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a new Counter with labels from the metric client
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name : name of the counter (without labels)
+/// - doc: documentation of the counter
+/// - label_keys: array of `n` label names
+/// - label_values: array of `n` label values (quotes are escaped)
+/// - n: number of labels
+///
+/// # Returns
+///
+/// - Opaque pointer to a Counter instance named `name{k="v",...}`
+///
+/// # Safety
+///
+/// Only correct pointers are returned by previous functions should be returned.
+/// Both label arrays must hold at least `n` valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_counter_new_labeled(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+    label_keys: *const *const std::os::raw::c_char,
+    label_values: *const *const std::os::raw::c_char,
+    n: libc::size_t,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pclient, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    if n > 0 && (label_keys.is_null() || label_values.is_null()) {
+        set_last_error(format!("NULL label arrays for {}", rname));
         return std::ptr::null_mut();
     }
 
-    let rname = rname.unwrap();
-    let rdoc = rdoc.unwrap();
+    let mut labels: Vec<(String, String)> = Vec::with_capacity(n);
 
-    if let Ok(c) = client.new_counter(rname, rdoc) {
-        return Arc::into_raw(c) as *mut MetricProxyValue;
+    for i in 0..n {
+        let key = unwrap_c_string(unsafe { *label_keys.add(i) });
+        let value = unwrap_c_string(unsafe { *label_values.add(i) });
+
+        match (key, value) {
+            (Ok(k), Ok(v)) if LABEL_NAME.is_match(&k) => labels.push((k, v)),
+            (Ok(k), Ok(_)) => {
+                set_last_error(format!("Invalid label name {} for {}", k, rname));
+                return std::ptr::null_mut();
+            }
+            _ => {
+                set_last_error(format!("Label {} of {} is not valid UTF-8", i, rname));
+                return std::ptr::null_mut();
+            }
+        }
     }
 
-    std::ptr::null_mut()
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.counter_labeled(&rname, &rdoc, &labels) {
+        Ok(c) => c.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
 }
 
 #[no_mangle]
@@ -598,6 +1575,41 @@ pub extern "C" fn __cyg_profile_func_enter(this_fn: *mut (), call_site: *mut ())
 
         if let Ok(cnt) = client.new_func(this_fn, call_site) {
             let _ = cnt.inc(1.0);
+            let _ = FUNC_STACK.try_with(|stack| stack.borrow_mut().push((this_fn, Instant::now())));
+        }
+    }
+}
+
+/// Callback function for leaving a function, the time since it was
+/// entered goes to its func_time__ counter
+#[no_mangle]
+pub extern "C" fn __cyg_profile_func_exit(this_fn: *mut (), call_site: *mut ()) {
+    log::trace!("<== FUNC EXIT {:p} && {:p}", this_fn, call_site);
+
+    let this_fn: usize = this_fn as usize;
+
+    /* Frames above it were left without an exit (longjmp, exceptions) */
+    let entered = FUNC_STACK
+        .try_with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let pos = stack.iter().rposition(|(f, _)| *f == this_fn)?;
+            let start = stack[pos].1;
+            stack.truncate(pos);
+            Some(start)
+        })
+        .ok()
+        .flatten();
+
+    let start = match entered {
+        Some(start) => start,
+        None => return,
+    };
+
+    unsafe {
+        if let Some(client) = PROXY_INSTANCE.clone() {
+            if let Ok(cnt) = client.new_func_metric(this_fn, call_site as usize, true) {
+                let _ = cnt.inc(start.elapsed().as_secs_f64());
+            }
         }
     }
 }
@@ -624,7 +1636,7 @@ pub unsafe extern "C" fn metric_proxy_counter_inc(
         return one;
     }
 
-    let counter: &mut MetricProxyValue = unsafe { &mut *(pcounter) };
+    let counter = unsafe { Counter::borrow_raw(pcounter) };
 
     if counter.inc(value).is_err() {
         return one;
@@ -633,51 +1645,213 @@ pub unsafe extern "C" fn metric_proxy_counter_inc(
     zero
 }
 
-/* Gauges  */
-
-/// Create a new Gauge from the metric client
+/// Create a new up/down counter from the metric client, unlike
+/// a counter it can be decremented (for example in-flight requests)
 ///
 /// # Arguments
 ///
 /// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
-/// - name : name of the gauge
-/// - doc: documentation of the gauge
+/// - name : name of the counter
+/// - doc: documentation of the counter
 ///
 /// # Returns
 ///
-/// - Opaque pointer to a Gauge instance
+/// - Opaque pointer to an up/down counter, to use with `metric_proxy_counter_inc`
+///   and `metric_proxy_counter_dec`
 ///
 /// # Safety
 ///
 /// Only correct pointers are returned by previous functions should be returned.
 /// Doing otherwise may crash.
 #[no_mangle]
-pub unsafe extern "C" fn metric_proxy_gauge_new(
+pub unsafe extern "C" fn metric_proxy_updown_new(
     pclient: *mut MetricProxyClient,
     name: *const std::os::raw::c_char,
     doc: *const std::os::raw::c_char,
 ) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pclient, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.updown_counter(&rname, &rdoc) {
+        Ok(v) => v.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// This decrements the value of an up/down counter in the proxy
+/// (monotonic counters from `metric_proxy_counter_new` fail)
+///
+/// # Arguments
+///
+/// - pcounter: the counter to update (as returned by `metric_proxy_updown_new`)
+/// - value: the value to remove from current value
+///
+/// # Safety
+/// If a wrong pointer is passed behavior is undefined (and may crash)
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_counter_dec(
+    pcounter: *mut MetricProxyValue,
+    value: std::ffi::c_double,
+) -> std::ffi::c_int {
+    let zero: std::ffi::c_int = 0;
+    let one: std::ffi::c_int = 1;
+
+    if pcounter.is_null() {
+        return one;
+    }
+
+    let counter = unsafe { UpDownCounter::borrow_raw(pcounter) };
+
+    if counter.dec(value).is_err() {
+        return one;
+    }
+
+    zero
+}
+
+/// Read the current value of a metric as aggregated by the proxy
+/// for this job (values of this process are flushed first)
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name: full name of the metric (as exposed by the proxy)
+///
+/// # Return
+///
+/// The value of the metric or NaN if it could not be read
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_init` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_counter_read(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+) -> std::ffi::c_double {
     let rname = unwrap_c_string(name);
-    let rdoc = unwrap_c_string(doc);
 
-    if rname.is_err() || rdoc.is_err() || pclient.is_null() {
-        return std::ptr::null_mut();
+    if rname.is_err() || pclient.is_null() {
+        return f64::NAN;
     }
 
-    let client: &mut MetricProxyClient = unsafe { &mut *(pclient) };
+    let client = unsafe { Client::borrow_raw(pclient) };
 
-    if !*client.running.lock().unwrap() {
-        return std::ptr::null_mut();
+    client.read(&rname.unwrap()).unwrap_or(f64::NAN)
+}
+
+/// Read the current value of a metric as aggregated by the proxy over
+/// the whole node (all its jobs), values of this process are flushed first
+///
+/// Unlike `metric_proxy_gauge_get` this is a round-trip to the proxy
+/// which blocks until it answers (at most 5 seconds)
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name: full name of the metric (as exposed by the proxy)
+///
+/// # Return
+///
+/// The value of the metric or NaN if it could not be read
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_init` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_query_node_metric(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+) -> std::ffi::c_double {
+    let rname = unwrap_c_string(name);
+
+    if rname.is_err() || pclient.is_null() {
+        return f64::NAN;
     }
 
-    let rname = rname.unwrap();
-    let rdoc = rdoc.unwrap();
+    let client = unsafe { Client::borrow_raw(pclient) };
 
-    if let Ok(c) = client.new_gauge(rname, rdoc) {
-        return Arc::into_raw(c) as *mut MetricProxyValue;
+    client.read_node(&rname.unwrap()).unwrap_or(f64::NAN)
+}
+
+/// Tag the trace of the job with a label at the current time
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - label: the annotation (for instance "checkpoint start")
+///
+/// # Return
+///
+/// 0 on success, 1 otherwise
+///
+/// # Safety
+///
+/// Only pointer returned by `metric_proxy_init` should be passed.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_annotate(
+    pclient: *mut MetricProxyClient,
+    label: *const std::os::raw::c_char,
+) -> std::ffi::c_int {
+    let rlabel = unwrap_c_string(label);
+
+    if rlabel.is_err() || pclient.is_null() {
+        return 1;
     }
 
-    std::ptr::null_mut()
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.annotate(&rlabel.unwrap()) {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
+}
+
+/* Gauges  */
+
+/// Create a new Gauge from the metric client
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name : name of the gauge
+/// - doc: documentation of the gauge
+///
+/// # Returns
+///
+/// - Opaque pointer to a Gauge instance
+///
+/// # Safety
+///
+/// Only correct pointers are returned by previous functions should be returned.
+/// Doing otherwise may crash.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_gauge_new(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pclient, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.gauge(&rname, &rdoc) {
+        Ok(g) => g.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// This set the value of a Gauge in the proxy
@@ -702,7 +1876,7 @@ pub unsafe extern "C" fn metric_proxy_gauge_set(
         return one;
     }
 
-    let gauge: &mut MetricProxyValue = unsafe { &mut *(pcounter) };
+    let gauge = unsafe { Gauge::borrow_raw(pcounter) };
 
     if gauge.set(value).is_err() {
         return one;
@@ -710,3 +1884,193 @@ pub unsafe extern "C" fn metric_proxy_gauge_set(
 
     zero
 }
+
+/// Add a weighted observation to a Gauge, the value counts for `weight`
+/// hits so the gauge reports a weighted (for instance time-weighted) average
+///
+/// # Arguments
+///
+/// - pcounter: the gauge to update (as returned by `metric_proxy_gauge_new`)
+/// - value: the observed value
+/// - weight: the weight of the observation (positive), such as the time it was held
+///
+/// # Safety
+/// If a wrong pointer is passed behavior is undefined (and may crash)
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_gauge_observe_weighted(
+    pcounter: *mut MetricProxyValue,
+    value: std::ffi::c_double,
+    weight: std::ffi::c_double,
+) -> std::ffi::c_int {
+    let zero: std::ffi::c_int = 0;
+    let one: std::ffi::c_int = 1;
+
+    if pcounter.is_null() {
+        return one;
+    }
+
+    let gauge = unsafe { Gauge::borrow_raw(pcounter) };
+
+    if gauge.observe_weighted(value, weight).is_err() {
+        return one;
+    }
+
+    zero
+}
+
+/// Read the local value of a Gauge, the average of what was set since
+/// the values were last flushed to the proxy (every period)
+///
+/// This only locks the gauge, see `metric_proxy_query_node_metric` for
+/// the value aggregated by the proxy (a round-trip on the socket)
+///
+/// # Arguments
+///
+/// - pcounter: the gauge to read (as returned by `metric_proxy_gauge_new`)
+///
+/// # Return
+///
+/// The value or NaN if nothing was set since the last flush
+///
+/// # Safety
+/// If a wrong pointer is passed behavior is undefined (and may crash)
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_gauge_get(
+    pcounter: *mut MetricProxyValue,
+) -> std::ffi::c_double {
+    if pcounter.is_null() {
+        return f64::NAN;
+    }
+
+    let gauge = unsafe { Gauge::borrow_raw(pcounter) };
+
+    gauge.local_value().unwrap_or(f64::NAN)
+}
+
+/* Last values */
+
+/// Create a new LastValue from the metric client, unlike a gauge
+/// only the last value set is kept (with the time it was set at)
+///
+/// # Arguments
+///
+/// - pclient: a pointer to the metric client as returned by `metric_proxy_init`
+/// - name : name of the value
+/// - doc: documentation of the value
+///
+/// # Returns
+///
+/// - Opaque pointer to a LastValue instance
+///
+/// # Safety
+///
+/// Only correct pointers are returned by previous functions should be returned.
+/// Doing otherwise may crash.
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_lastvalue_new(
+    pclient: *mut MetricProxyClient,
+    name: *const std::os::raw::c_char,
+    doc: *const std::os::raw::c_char,
+) -> *mut MetricProxyValue {
+    let (rname, rdoc) = match unwrap_metric_args(pclient, name, doc) {
+        Some(v) => v,
+        None => return std::ptr::null_mut(),
+    };
+
+    let client = unsafe { Client::borrow_raw(pclient) };
+
+    match client.last_value(&rname, &rdoc) {
+        Ok(v) => v.into_raw(),
+        Err(e) => {
+            set_last_error(format!("Failed to create {} : {}", rname, e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// This set the value of a LastValue in the proxy
+/// This refers to a value previously created with `metric_proxy_lastvalue_new`
+///
+/// # Arguments
+///
+/// - pvalue: the value to update (as returned by `metric_proxy_lastvalue_new`)
+/// - value: the value to set
+///
+/// # Safety
+/// If a wrong pointer is passed behavior is undefined (and may crash)
+#[no_mangle]
+pub unsafe extern "C" fn metric_proxy_lastvalue_set(
+    pvalue: *mut MetricProxyValue,
+    value: std::ffi::c_double,
+) -> std::ffi::c_int {
+    let zero: std::ffi::c_int = 0;
+    let one: std::ffi::c_int = 1;
+
+    if pvalue.is_null() {
+        return one;
+    }
+
+    let last = unsafe { LastValue::borrow_raw(pvalue) };
+
+    if last.set(value).is_err() {
+        return one;
+    }
+
+    zero
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_gauge_gives_time_weighted_mean() {
+        let gauge = MetricProxyValue::newgauge("g".to_string());
+        assert_eq!(gauge.local_value(), None);
+
+        /* 10 held 1 s then 20 held 3 s */
+        gauge.observe_weighted(10.0, 1.0).unwrap();
+        gauge.observe_weighted(20.0, 3.0).unwrap();
+        assert_eq!(gauge.local_value(), Some(17.5));
+
+        match gauge.value.lock().unwrap().value {
+            CounterType::Gauge { min, max, hits, .. } => {
+                assert_eq!((min, max, hits), (10.0, 20.0, 4.0));
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(gauge.observe_weighted(1.0, -1.0).is_err());
+        assert!(MetricProxyValue::newcounter("c".to_string())
+            .observe_weighted(1.0, 1.0)
+            .is_err());
+    }
+
+    #[test]
+    fn metric_names_need_well_formed_labels() {
+        for name in [
+            "calls",
+            "ns:calls{}",
+            r#"calls{rank="0"}"#,
+            r#"calls{rank="0",path="a\"b\\c\n"}"#,
+        ] {
+            assert!(validate_metric_name(name).is_ok(), "{}", name);
+        }
+
+        for name in [
+            "0calls",
+            r#"calls{bad label"}"#,
+            r#"calls{rank=0}"#,
+            r#"calls{rank="0",}"#,
+            r#"calls{rank="0"}x"#,
+            r#"calls{rank="a"b"}"#,
+            r#"calls{0rank="0"}"#,
+            "calls{rank=\"a\nb\"}",
+        ] {
+            assert!(validate_metric_name(name).is_err(), "{}", name);
+        }
+
+        assert!(validate_metric_doc("Number of calls").is_ok());
+        assert!(validate_metric_doc("Number\nof calls").is_err());
+    }
+}
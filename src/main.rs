@@ -1,6 +1,9 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, sleep};
@@ -10,7 +13,7 @@ mod proxy_common;
 use proxy_common::{get_proxy_path, init_log};
 
 mod exporter;
-use exporter::ExporterFactory;
+use exporter::{ExporterFactory, ExporterFactoryOptions};
 
 mod proxy;
 use proxy::UnixProxy;
@@ -20,37 +23,78 @@ mod squeue;
 mod webserver;
 use webserver::Web;
 
+mod alarmtemplates;
 mod ftio;
 mod extrap;
 mod icc;
+#[cfg(feature = "otlp")]
+mod otlp;
+#[cfg(feature = "sqlite")]
+mod profiledb;
 mod profiles;
 mod proxywireprotocol;
+mod relabel;
 mod scrapper;
+mod shm;
 mod systemmetrics;
 mod trace;
 
 extern crate clap;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Parser};
 
 use crate::exporter::{ExperimentInstrumentation, Instrumentation, NoInstrumentation};
+use crate::ftio::FtioCommand;
 #[cfg(feature = "admire")]
 use crate::icc::IccInterface;
+use crate::proxy_common::ProxyErr;
+use crate::relabel::Relabeler;
+use crate::systemmetrics::SystemMetricsLevel;
 
 extern crate ctrlc;
 
 /// ADMIRE project Instrumentation Proxy
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 struct Args {
+    /// TOML file whose keys are long options (e.g. sampling_period = 500), options given on the command line
+    /// replace the file ones (lists included) and --FLAG=false turns off a flag set in the file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     // Port number of the HTTP server
     #[arg(short, long, default_value_t = 1337)]
     port: u32,
 
-    // Path of the UNIX proxy for the gateway
+    /// Address the HTTP server binds to (127.0.0.1 for loopback only, :: for IPv6)
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: IpAddr,
+
+    /// PEM certificate to serve HTTPS (requires --tls-key)
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key to serve HTTPS (requires --tls-cert)
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    // Path of the UNIX proxy for the gateway (prefix with '@' for an abstract socket), ~ and $VAR are expanded
     #[arg(short, long)]
     unix: Option<String>,
 
+    /// Permissions of the UNIX socket in octal (ignored for abstract sockets)
+    #[arg(long, default_value = "600", value_parser = parse_mode)]
+    socket_mode: u32,
+
+    /// Maximum number of UNIX clients handled at once, others are refused (0 = unlimited)
+    #[arg(long, default_value_t = 1024)]
+    max_clients: usize,
+
+    /// Connections queued on the UNIX socket before the proxy accepts them
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(i32).range(0..))]
+    listen_backlog: i32,
+
     /// If set the proxy will attempt to connect to the ADMIRE intelligent controller (needs admire feature)
     #[arg(short, long, default_value_t = false)]
     connect_to_intelligent_controller: bool,
@@ -60,18 +104,48 @@ struct Args {
     inhibit_profile_agreggation: bool,
 
     /// Subservers to be scrapped (optionnal comma separated list) use ADDR\@[PERIOD in ms] to set the scraping period
+    /// and ADDR\@PERIOD\@[INTERVAL in ms] to merge the gauges of a Prometheus target over INTERVAL
     #[arg(short, long, value_delimiter = ',')]
     sub_proxies: Option<Vec<String>>,
 
+    /// File listing scrape targets (one ADDR\@[PERIOD in ms] per line) re-read every --sd-interval
+    #[arg(long)]
+    sd_file: Option<PathBuf>,
+
+    /// Command printing scrape targets (one ADDR\@[PERIOD in ms] per line) run every --sd-interval
+    #[arg(long)]
+    sd_command: Option<String>,
+
+    /// Period in seconds of the service discovery (--sd-file and --sd-command)
+    #[arg(long, default_value_t = 30)]
+    sd_interval: u64,
+
     /// Address of the proxy to pivot on to build a proxy tree use ADDR\@[PERIOD in ms] to set the scraping period
-    #[arg(short, long)]
-    root_proxy: Option<String>,
+    /// A comma separated list registers to several roots for redundancy (the first joined is the primary)
+    #[arg(short, long, value_delimiter = ',')]
+    root_proxy: Option<Vec<String>>,
 
     /// Maximum trace size to maintain in the file-system in MB (default 32MB)
     #[arg(short, long)]
     max_trace_size: Option<f64>,
 
-    /// Root directory for the proxy (optionnal default ~/.proxyprofiles/)
+    /// Maximum number of samples per metric kept in memory for traces (0 = unbounded)
+    #[arg(long, default_value_t = 86400)]
+    trace_window: usize,
+
+    /// Timestamp new traces in nanoseconds (instead of seconds with a ms resolution)
+    #[arg(long, default_value_t = false)]
+    trace_nanoseconds: bool,
+
+    /// Remove the traces of finished jobs this many seconds after their last write (0 = keep them)
+    #[arg(long, default_value_t = 0)]
+    trace_retention: u64,
+
+    /// Only store the counters which changed since the previous frame in traces
+    #[arg(long, default_value_t = false)]
+    trace_delta: bool,
+
+    /// Root directory for the proxy (optionnal default $PROXY_PROFILE_DIR or ~/.proxyprofiles/), ~ and $VAR are expanded
     #[arg(short, long)]
     target_prefix: Option<PathBuf>,
 
@@ -83,6 +157,110 @@ struct Args {
     #[arg(short, long, default_value_t = 2)]
     branches: u64,
 
+    /// Level of node metrics collected by the system scraper
+    #[arg(long, value_enum, default_value_t = SystemMetricsLevel::Full)]
+    system_metrics: SystemMetricsLevel,
+
+    /// Require this bearer token on mutating endpoints (also honoured via PROXY_AUTH_TOKEN)
+    #[arg(long)]
+    auth_token: Option<String>,
+
+    /// Also require the bearer token on read-only endpoints (/metrics, /job, /trace)
+    #[arg(long, default_value_t = false)]
+    auth_read: bool,
+
+    /// Prefix prepended to the name of system metrics
+    #[arg(long, default_value = "proxy_")]
+    metric_prefix: String,
+
+    /// Also prepend the metric prefix to client supplied metrics
+    #[arg(long, default_value_t = false)]
+    prefix_all: bool,
+
+    /// Drop job metrics not updated for this many seconds, the cumulative main and node metrics never expire (0 = never)
+    #[arg(long, default_value_t = 0)]
+    metric_ttl: u64,
+
+    /// Timeout in seconds for connecting and reading from a scrape target
+    #[arg(long, default_value_t = 5)]
+    scrape_timeout: u64,
+
+    /// Number of consecutive failed scrapes before a target is evicted
+    #[arg(long, default_value_t = 3)]
+    scrape_failures: u32,
+
+    /// Seconds an evicted proxy or Prometheus target is probed before being dropped (0 = drop at once)
+    #[arg(long, default_value_t = 600)]
+    scrape_retry_max: u64,
+
+    /// Scrape targets exactly on their period boundary (no random phase)
+    #[arg(long, default_value_t = false)]
+    no_scrape_jitter: bool,
+
+    /// JSON file of ordered regex rules renaming or dropping client metrics
+    #[arg(long)]
+    relabel_config: Option<PathBuf>,
+
+    /// Number of state changes kept per alarm
+    #[arg(long, default_value_t = 32)]
+    alarm_history: usize,
+
+    /// JSON file of alarms added to every job whose id matches their target regex
+    #[arg(long)]
+    alarms_file: Option<PathBuf>,
+
+    /// Maximum number of series (label sets) kept per metric, further ones are dropped (0 = unlimited)
+    #[arg(long, default_value_t = 10000)]
+    max_series_per_metric: usize,
+
+    /// Milliseconds the /metrics text output is served from a cache (0 = always regenerated)
+    #[arg(long, default_value_t = 200)]
+    metrics_cache_ms: u64,
+
+    /// Significant digits of non integer values in the Prometheus output (0 = exact)
+    #[arg(long, default_value_t = 6)]
+    metric_precision: usize,
+
+    /// Store profiles in this SQLite database instead of one file per job (requires the sqlite feature)
+    #[arg(long)]
+    profile_db: Option<PathBuf>,
+
+    /// Push the job metrics every period to this OTLP/HTTP collector (requires the otlp feature)
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Command computing FTIO models when the FTIO server is unreachable (trace on stdin)
+    #[arg(long, default_value = "admire_proxy_invoke_ftio")]
+    ftio_command: String,
+
+    /// Arguments of --ftio-command (split on whitespace)
+    #[arg(long, default_value = "-n 10", allow_hyphen_values = true)]
+    ftio_args: String,
+
+    /// Seconds after which a running FTIO command is killed
+    #[arg(long, default_value_t = 300)]
+    ftio_timeout: u64,
+
+    /// Also store the profile of each scraped proxy (node) of a job, see /job/pernode
+    #[arg(long, default_value_t = false)]
+    keep_per_node_profiles: bool,
+
+    /// Save profiles in a compact binary format (.profile.bin) instead of JSON, both are read
+    #[arg(long, default_value_t = false)]
+    binary_profiles: bool,
+
+    /// Also expose the metrics of each running job in /metrics with a jobid label
+    #[arg(long, default_value_t = false)]
+    label_jobs_in_main: bool,
+
+    /// Label the metrics scraped from child proxies with source_host="<child hostname>"
+    #[arg(long, default_value_t = false)]
+    label_source_host: bool,
+
+    /// Exit once no job has been running for this many seconds (0 = never)
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+
     /// Duration to run instrumentation in seconds (default 0 = disabled)
     #[arg(long, default_value_t = 0)]
     instrumentation: u64,
@@ -98,39 +276,316 @@ struct Args {
     root_url_dir: Option<PathBuf>,
 }
 
-fn parse_period(arg: &String, default_period: u64) -> (String, u64) {
+fn parse_mode(arg: &str) -> Result<u32, String> {
+    u32::from_str_radix(arg.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("{} is not an octal mode : {}", arg, e))
+}
+
+impl Args {
+    /// Command where flags also take an explicit value (--FLAG=false)
+    fn command_with_flag_values() -> Command {
+        Args::command().mut_args(|a| match a.get_action() {
+            ArgAction::SetTrue => a
+                .action(ArgAction::Set)
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("true"),
+            _ => a,
+        })
+    }
+
+    fn parse_matches(argv: &[OsString]) -> (Args, ArgMatches) {
+        let matches = Args::command_with_flag_values().get_matches_from(argv);
+        match Args::from_arg_matches(&matches) {
+            Ok(args) => (args, matches),
+            Err(e) => e.exit(),
+        }
+    }
+
+    /// Ids of the options given on the command line
+    fn command_line_ids(matches: &ArgMatches) -> HashSet<String> {
+        matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    /// Parse the command line merged with the --config file if any
+    ///
+    /// The file only provides the options which are not on the command line
+    /// so that the precedence is command line, then file, then defaults
+    fn load() -> Result<Args, ProxyErr> {
+        let cli: Vec<OsString> = env::args_os().collect();
+        let (args, matches) = Args::parse_matches(&cli);
+
+        let config = match &args.config {
+            Some(path) => path,
+            None => return Ok(args),
+        };
+
+        let given = Args::command_line_ids(&matches);
+
+        let mut argv: Vec<OsString> = cli.iter().take(1).cloned().collect();
+        argv.extend(
+            Args::config_file_args(config, &given)?
+                .into_iter()
+                .map(OsString::from),
+        );
+        argv.extend(cli.iter().skip(1).cloned());
+
+        Ok(Args::parse_matches(&argv).0)
+    }
+
+    /// Convert the keys of a TOML file to long options
+    /// skipping the options given on the command line
+    fn config_file_args(path: &Path, given: &HashSet<String>) -> Result<Vec<String>, ProxyErr> {
+        let data = std::fs::read_to_string(path).map_err(|e| {
+            ProxyErr::new(format!(
+                "Failed to read config file {} : {}",
+                path.to_string_lossy(),
+                e
+            ))
+        })?;
+
+        let table: toml::Table =
+            toml::from_str(&data).map_err(|e| ProxyErr::new(format!("Bad config file : {}", e)))?;
+
+        let command = Args::command();
+        let mut ret: Vec<String> = Vec::new();
+
+        for (key, value) in table {
+            let id = key.replace('-', "_");
+
+            let long = command
+                .get_arguments()
+                .filter(|a| a.get_id().as_str() == id && id != "config")
+                .find_map(|a| a.get_long());
+
+            let long = match long {
+                Some(l) => l,
+                None => {
+                    log::warn!("Ignoring unknown key {} in config file", key);
+                    continue;
+                }
+            };
+
+            if given.contains(&id) {
+                continue;
+            }
+
+            let value = match value {
+                /* Flags all default to false */
+                toml::Value::Boolean(true) => {
+                    ret.push(format!("--{}", long));
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Array(a) => a
+                    .iter()
+                    .map(|v| match v {
+                        toml::Value::String(s) => s.to_string(),
+                        v => v.to_string(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(","),
+                v => {
+                    log::warn!(
+                        "Ignoring unsupported value {} for {} in config file",
+                        v,
+                        key
+                    );
+                    continue;
+                }
+            };
+
+            ret.push(format!("--{}={}", long, value));
+        }
+
+        Ok(ret)
+    }
+}
+
+fn parse_period(arg: &String, default_period: u64) -> (String, u64, u64) {
     let mut spl = arg.split('@');
 
     let url = spl.next();
     let stime = spl.next();
 
     if url.is_none() || stime.is_none() {
-        return (arg.to_string(), 100);
+        return (arg.to_string(), 100, 0);
     }
 
+    let gauge_interval = match spl.next().map(str::parse::<u64>) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            log::error!("Failed to parse gauge interval in {} : {}", arg, e);
+            0
+        }
+        None => 0,
+    };
+
     match str::parse::<u64>(stime.unwrap()) {
-        Ok(v) => (url.unwrap().to_string(), v),
+        Ok(v) => (url.unwrap().to_string(), v, gauge_interval),
         Err(e) => {
             log::error!("Failed to parse scrape time in {} : {}", arg, e);
-            (arg.to_string(), default_period)
+            (arg.to_string(), default_period, 0)
+        }
+    }
+}
+
+/// Scrape targets listed by --sd-file and --sd-command, one per line
+/// ('#' starts a comment), fails if any of the sources cannot be read
+fn read_sd_targets(
+    file: Option<&Path>,
+    command: Option<&str>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut data = String::new();
+
+    if let Some(file) = file {
+        data += &std::fs::read_to_string(file)?;
+        data.push('\n');
+    }
+
+    if let Some(command) = command {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(ProxyErr::newboxed(format!(
+                "{} exited with {}",
+                command, output.status
+            )));
+        }
+
+        data += &String::from_utf8_lossy(&output.stdout);
+    }
+
+    Ok(data
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or("").trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+/// Mainloop of the service discovery adding the new targets
+/// and removing the ones which are no longer listed
+fn run_service_discovery(
+    factory: Arc<ExporterFactory>,
+    file: Option<PathBuf>,
+    command: Option<String>,
+    interval: Duration,
+    default_period: u64,
+    static_keys: HashSet<String>,
+) {
+    /* Key of the scrape -> listed target */
+    let mut current: HashMap<String, String> = HashMap::new();
+    /* Listed targets already scraped as a --sub-proxies */
+    let mut shadowed: HashSet<String> = HashSet::new();
+
+    while factory.is_running() {
+        /* Keep the current targets if the sources cannot be read */
+        match read_sd_targets(file.as_deref(), command.as_deref()) {
+            Ok(targets) => {
+                /* Remove first as a target listed with a new period has the same key */
+                current.retain(|key, target| {
+                    if targets.contains(target) {
+                        return true;
+                    }
+
+                    log::info!("Scrape {} is no longer listed, removing it", key);
+                    if let Err(e) = ExporterFactory::remove_scrape(factory.clone(), key) {
+                        log::debug!("{}", e);
+                    }
+                    false
+                });
+                shadowed.retain(|target| targets.contains(target));
+
+                for target in targets.iter() {
+                    if shadowed.contains(target) || current.values().any(|t| t == target) {
+                        continue;
+                    }
+
+                    let (url, freq, gauge_interval) = parse_period(target, default_period);
+                    log::info!("Discovered scrape {} every {} ms", url, freq);
+
+                    match ExporterFactory::add_scrape_unless(
+                        factory.clone(),
+                        &url,
+                        freq,
+                        gauge_interval,
+                        &static_keys,
+                    ) {
+                        Ok(Some(key)) => {
+                            current.insert(key, target.to_string());
+                        }
+                        Ok(None) => {
+                            log::info!("{} is already scraped as a sub-proxy", url);
+                            shadowed.insert(target.to_string());
+                        }
+                        Err(e) => log::error!("Failed to add discovered scrape {} : {}", url, e),
+                    }
+                }
+            }
+            Err(e) => log::error!("Failed to read discovered targets : {}", e),
+        }
+
+        sleep(interval);
+    }
+}
+
+/// Notify the roots that we leave and flush in-flight jobs
+/// returns false if the proxy is already leaving
+fn graceful_leave(factory: &ExporterFactory) -> bool {
+    static LEAVING: AtomicBool = AtomicBool::new(false);
+
+    if LEAVING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    if let Some(my_url) = factory.web_url.read().unwrap().clone() {
+        // Only child proxies have joined roots to notify
+        for root_url in factory.root_proxies.read().unwrap().iter() {
+            let leave_url = format!(
+                "{}/leave?from={}",
+                proxy_common::proxy_url(root_url),
+                my_url
+            );
+            log::info!("Sending graceful leave to {}", leave_url);
+            if let Ok(client) = proxy_common::peer_client(proxy_common::PEER_TIMEOUT) {
+                let _ = proxy_common::with_proxy_auth(client.get(&leave_url)).send();
+            }
         }
     }
+
+    factory.shutdown();
+
+    true
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     init_log();
 
-    let args = Args::parse();
+    let args = Args::load()?;
 
     /* Make sure it is globally visible */
     env::set_var("PROXY_PERIOD", format!("{}", args.sampling_period));
 
+    /* The token is also used when talking to other proxies */
+    if let Some(token) = &args.auth_token {
+        env::set_var("PROXY_AUTH_TOKEN", token);
+    }
+    let auth_token = proxy_common::get_proxy_auth_token();
+
     let profile_prefix = if let Some(prefix) = args.target_prefix {
-        prefix
+        PathBuf::from(proxy_common::expand_path(&prefix.to_string_lossy()))
     } else {
-        let mut d = dirs::home_dir().unwrap();
-        d.push(".proxyprofiles");
-        d
+        proxy_common::default_profile_prefix()?
     };
 
     let max_trace_size = if let Some(max_size) = args.max_trace_size {
@@ -156,39 +611,123 @@ fn main() -> Result<(), Box<dyn Error>> {
     let root_url_file = profile_prefix.join("root.url");
 
     // The central storage is the exporter
-    let factory = ExporterFactory::new(
-        profile_prefix,
-        !args.inhibit_profile_agreggation,
-        max_trace_size as usize,
-        args.sampling_period,
-        args.branches,
-        instrumentation.clone()
-    )?;
+    let options = ExporterFactoryOptions {
+        aggregate: !args.inhibit_profile_agreggation,
+        max_trace_size: max_trace_size as usize,
+        trace_window: args.trace_window,
+        trace_nanoseconds: args.trace_nanoseconds,
+        trace_retention: args.trace_retention,
+        trace_delta: args.trace_delta,
+        period: args.sampling_period,
+        branches: args.branches,
+        system_metrics: args.system_metrics,
+        metric_prefix: args.metric_prefix.clone(),
+        prefix_all: args.prefix_all,
+        metric_ttl: args.metric_ttl,
+        scrape_timeout: Duration::from_secs(args.scrape_timeout),
+        scrape_failures: args.scrape_failures.max(1),
+        scrape_retry_max: Duration::from_secs(args.scrape_retry_max),
+        scrape_jitter: !args.no_scrape_jitter,
+        relabel: match &args.relabel_config {
+            Some(path) => Relabeler::from_file(path)?,
+            None => Relabeler::default(),
+        },
+        alarm_history: args.alarm_history,
+        alarms_file: args.alarms_file.clone(),
+        max_series_per_metric: args.max_series_per_metric,
+        metric_precision: args.metric_precision,
+        profile_db: args.profile_db.clone(),
+        keep_node_profiles: args.keep_per_node_profiles,
+        binary_profiles: args.binary_profiles,
+        label_jobs_in_main: args.label_jobs_in_main,
+        label_source_host: args.label_source_host,
+        otlp_endpoint: args.otlp_endpoint.clone(),
+        ftio_command: FtioCommand {
+            path: args.ftio_command.clone(),
+            args: args
+                .ftio_args
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            timeout: Duration::from_secs(args.ftio_timeout),
+        },
+    };
+
+    let factory = ExporterFactory::new(profile_prefix, options, instrumentation.clone())?;
+
+    /* Keys of the static scrapes which are never removed by the service discovery */
+    let mut static_keys: HashSet<String> = HashSet::new();
 
     if let Some(urls) = args.sub_proxies {
         for url in urls.iter() {
-            let (url, freq) = parse_period(url, args.sampling_period);
+            let (url, freq, gauge_interval) = parse_period(url, args.sampling_period);
             log::info!("Inserting scrape {} every {} second(s)", url, freq);
-            if let Err(e) = ExporterFactory::add_scrape(factory.clone(), &url, freq) {
-                log::error!("Failed add scrape : {}", e);
+            match ExporterFactory::add_scrape(factory.clone(), &url, freq, gauge_interval) {
+                Ok(key) => {
+                    static_keys.insert(key);
+                }
+                Err(e) => log::error!("Failed add scrape : {}", e),
             }
         }
     }
 
+    if args.sd_file.is_some() || args.sd_command.is_some() {
+        let sd_factory = factory.clone();
+        let sd_file = args.sd_file.clone();
+        let sd_command = args.sd_command.clone();
+        let interval = Duration::from_secs(args.sd_interval.max(1));
+        let period = args.sampling_period;
+        thread::spawn(move || {
+            run_service_discovery(
+                sd_factory,
+                sd_file,
+                sd_command,
+                interval,
+                period,
+                static_keys,
+            )
+        });
+    }
+
     let unix = if let Some(unix) = args.unix {
-        unix.clone()
+        proxy_common::expand_path(&unix)
     } else {
         get_proxy_path()
     };
 
     // Create the UNIX proxy with a reference to the exporter
-    let proxy = UnixProxy::new(unix, factory.clone())?;
+    let proxy = UnixProxy::new(
+        unix.clone(),
+        args.socket_mode,
+        args.max_clients,
+        args.listen_backlog,
+        factory.clone(),
+    )?;
 
     // Run the proxy detached with a ref to the exporter data
     thread::spawn(move || proxy.run());
 
     // Start the webserver part with a reference to the exporter
-    let web = Web::new(args.port, factory.clone());
+    /* Plain HTTP unless both the certificate and the key are given */
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+        (None, None) => None,
+        _ => {
+            log::error!("Both --tls-cert and --tls-key are needed to enable TLS");
+            exit(1);
+        }
+    };
+
+    let web = Web::new(
+        args.port,
+        args.bind,
+        tls,
+        factory.clone(),
+        auth_token,
+        args.auth_read,
+        unix,
+    )
+    .with_metrics_cache(Duration::from_millis(args.metrics_cache_ms));
 
     let web_url = web.url();
 
@@ -197,11 +736,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     // Resolve the effective root proxy: CLI flag > env var > auto-discovery file > none (I am root)
-    let effective_root: Option<String> = if args.root_proxy.is_some() {
+    let effective_root: Option<Vec<String>> = if args.root_proxy.is_some() {
         args.root_proxy.clone()
     } else if let Ok(env_root) = env::var("PROXY_ROOT_URL") {
         log::info!("Using root proxy from PROXY_ROOT_URL: {}", env_root);
-        Some(env_root)
+        Some(env_root.split(',').map(|s| s.trim().to_string()).collect())
     } else if args.auto_root {
         let search_file = if let Some(dir) = &args.root_url_dir {
             dir.join("root.url")
@@ -212,7 +751,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             Ok(url) => {
                 let url = url.trim().to_string();
                 log::info!("Auto-discovered root proxy from {}: {}", search_file.display(), url);
-                Some(url)
+                Some(vec![url])
             }
             Err(e) => {
                 log::warn!("--auto-root set but could not read {}: {}", search_file.display(), e);
@@ -243,43 +782,61 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    // Install graceful-leave handler: on SIGTERM/SIGINT notify root before exiting
+    // Install graceful-leave handler: on SIGTERM/SIGINT notify root and flush jobs before exiting
     {
         let factory_sh = factory.clone();
-        let running = Arc::new(AtomicBool::new(true));
-        let running_clone = running.clone();
         ctrlc::set_handler(move || {
-            if running_clone.swap(false, Ordering::SeqCst) {
-                if let (Some(root_url), Some(my_url)) = (
-                    factory_sh.root_proxy.read().unwrap().clone(),
-                    factory_sh.web_url.read().unwrap().clone(),
-                ) {
-                    // Only child proxies need to notify root (root_url != my_url)
-                    if root_url != my_url {
-                        let leave_url = format!("http://{}/leave?from={}", root_url, my_url);
-                        log::info!("Sending graceful leave to {}", leave_url);
-                        let _ = reqwest::blocking::get(&leave_url);
-                    }
-                }
+            if graceful_leave(&factory_sh) {
                 exit(0);
             }
         })
         .unwrap_or_else(|e| log::warn!("Failed to install signal handler: {}", e));
     }
 
+    // Leave the same way once all jobs have drained for the idle timeout
+    if args.idle_timeout > 0 {
+        let idle_factory = factory.clone();
+        let timeout = args.idle_timeout;
+        thread::spawn(move || loop {
+            sleep(Duration::from_secs(1));
+            if idle_factory.idle_for() >= Duration::from_secs(timeout) {
+                log::info!("No job ran for {} seconds, leaving", timeout);
+                if graceful_leave(&idle_factory) {
+                    exit(0);
+                }
+            }
+        });
+    }
+
+    let join_factory = factory.clone();
     thread::spawn(move || {
         /* Wait for the webserver to start before joining */
         sleep(Duration::from_secs(3));
-        if let Some(root) = effective_root {
-            let (url, period) = parse_period(&root, args.sampling_period);
+        if let Some(roots) = effective_root {
+            for root in roots.iter() {
+                let (url, period, _) = parse_period(root, args.sampling_period);
 
-            if let Err(e) = ExporterFactory::set_data(factory.clone(), &url, &web_url, period) {
-                log::error!("Failed to set data: {}", e);
-                exit(1);
+                if let Err(e) = ExporterFactory::join(&url, &web_url, period) {
+                    log::error!("Failed to register in root server {}: {}", url, e);
+                    continue;
+                }
+
+                /* The first joined root is the primary one, notified on scrape failures */
+                if join_factory.root_proxies.read().unwrap().is_empty() {
+                    if let Err(e) =
+                        ExporterFactory::set_data(join_factory.clone(), &url, &web_url, period)
+                    {
+                        log::error!("Failed to set data: {}", e);
+                        exit(1);
+                    }
+                }
+
+                join_factory.root_proxies.write().unwrap().push(url);
             }
 
-            if let Err(e) = ExporterFactory::join(&url, &web_url, period) {
-                log::error!("Failed to register in root server {}: {}", url, e);
+            /* Redundant roots tolerate failures as long as one parent is reachable */
+            if join_factory.root_proxies.read().unwrap().is_empty() {
+                log::error!("Could not register in any root server");
                 exit(1);
             }
         }
@@ -303,5 +860,65 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     web.run_blocking();
 
+    /* Wait for in-flight jobs to be flushed */
+    factory.shutdown();
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Args {
+        let argv: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        Args::parse_matches(&argv).0
+    }
+
+    #[test]
+    fn command_line_replaces_config_file() {
+        let path = env::temp_dir().join(format!("proxy-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "sub_proxies = [\"a:1\", \"b:2\"]\ntrace_delta = true\nsd_interval = 10\n",
+        )
+        .unwrap();
+
+        let (_, matches) = Args::parse_matches(&[
+            OsString::from("proxy_v2"),
+            OsString::from("--sub-proxies=c:3"),
+            OsString::from("--trace-delta=false"),
+        ]);
+        let given = Args::command_line_ids(&matches);
+
+        let file = Args::config_file_args(&path, &given).unwrap();
+        assert_eq!(file, vec!["--sd-interval=10".to_string()]);
+
+        let mut argv = vec!["proxy_v2"];
+        argv.extend(file.iter().map(String::as_str));
+        argv.extend(["--sub-proxies=c:3", "--trace-delta=false"]);
+        let args = parse(&argv);
+
+        assert_eq!(args.sub_proxies, Some(vec!["c:3".to_string()]));
+        assert!(!args.trace_delta);
+        assert_eq!(args.sd_interval, 10);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flags_take_optional_values() {
+        assert!(parse(&["proxy_v2", "--trace-delta"]).trace_delta);
+        assert!(parse(&["proxy_v2", "--trace-delta=true"]).trace_delta);
+        assert!(!parse(&["proxy_v2", "--trace-delta=false"]).trace_delta);
+        assert!(!parse(&["proxy_v2"]).trace_delta);
+    }
+
+    #[test]
+    fn negative_listen_backlog_is_rejected() {
+        assert!(Args::command_with_flag_values()
+            .try_get_matches_from(["proxy_v2", "--listen-backlog=-1"])
+            .is_err());
+        assert_eq!(parse(&["proxy_v2", "--listen-backlog=0"]).listen_backlog, 0);
+    }
+}
@@ -0,0 +1,205 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::proxy_common::{hostname, peer_client, proxy_url, unix_ts_ns, ProxyErr};
+use crate::proxywireprotocol::{CounterSnapshot, CounterType, JobProfile};
+
+/***************
+ * OTLP EXPORT *
+ ***************/
+
+/// OTLP path of the metric service
+const OTLP_METRICS_PATH: &str = "/v1/metrics";
+
+/// Cumulative aggregation temporality in OTLP
+const OTLP_CUMULATIVE: u32 = 2;
+
+/// Split the labels of a series name such as a{b="c",d="e"}
+fn split_labels(name: &str) -> (String, Vec<(String, String)>) {
+    let (base, labels) = match name.split_once('{') {
+        Some((base, rest)) => (base, rest.strip_suffix('}').unwrap_or(rest)),
+        None => return (name.to_string(), Vec::new()),
+    };
+
+    let mut ret: Vec<(String, String)> = Vec::new();
+    let mut chars = labels.chars().peekable();
+
+    loop {
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+
+        if key.is_empty() || chars.next() != Some('"') {
+            break;
+        }
+
+        /* Values are quoted with \" escapes */
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some(e) = chars.next() {
+                        value.push(e);
+                    }
+                }
+                '"' => break,
+                c => value.push(c),
+            }
+        }
+
+        ret.push((key, value));
+    }
+
+    (base.to_string(), ret)
+}
+
+fn attributes(pairs: &[(String, String)]) -> Value {
+    Value::Array(
+        pairs
+            .iter()
+            .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+            .collect(),
+    )
+}
+
+/// A metric (basename) with the data points of its series
+struct OtlpMetric {
+    doc: String,
+    /// Sum (monotonic or not) or Gauge
+    sum: Option<bool>,
+    points: Vec<Value>,
+}
+
+/// Push the job profiles to an OpenTelemetry collector
+///
+/// Requests use OTLP/HTTP with the JSON encoding, each job is a resource
+/// with host.name and jobid attributes. Counters are monotonic sums,
+/// up/down counters non monotonic sums and the others gauges.
+pub(crate) struct OtlpPusher {
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl OtlpPusher {
+    pub(crate) fn new(endpoint: &str, timeout: Duration) -> Result<OtlpPusher, ProxyErr> {
+        let mut url = proxy_url(endpoint.trim_end_matches('/'));
+
+        if !url.ends_with(OTLP_METRICS_PATH) {
+            url += OTLP_METRICS_PATH;
+        }
+
+        log::info!("Pushing metrics to OTLP collector {}", url);
+
+        Ok(OtlpPusher {
+            url,
+            client: peer_client(timeout)?,
+        })
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn point(snap: &CounterSnapshot, labels: &[(String, String)], start: u64, now: u64) -> Value {
+        let mut point = json!({
+            "attributes": attributes(labels),
+            "timeUnixNano": now.to_string(),
+            "asDouble": snap.ctype.value(),
+        });
+
+        /* Sums are cumulative since the start of the job */
+        if let CounterType::Counter { .. } | CounterType::UpDown { .. } = snap.ctype {
+            point["startTimeUnixNano"] = Value::String(start.to_string());
+        }
+
+        point
+    }
+
+    fn resource_metrics(profile: &JobProfile, now: u64) -> Value {
+        let start = profile.desc.start_time.saturating_mul(1_000_000);
+        let mut metrics: BTreeMap<String, OtlpMetric> = BTreeMap::new();
+
+        for snap in profile.counters.iter() {
+            let (name, labels) = split_labels(&snap.name);
+
+            let sum = match snap.ctype {
+                CounterType::Counter { .. } => Some(true),
+                CounterType::UpDown { .. } => Some(false),
+                CounterType::Gauge { .. } | CounterType::LastValue { .. } => None,
+            };
+
+            metrics
+                .entry(name)
+                .or_insert_with(|| OtlpMetric {
+                    doc: snap.doc.to_string(),
+                    sum,
+                    points: Vec::new(),
+                })
+                .points
+                .push(OtlpPusher::point(snap, &labels, start, now));
+        }
+
+        let metrics: Vec<Value> = metrics
+            .into_iter()
+            .map(|(name, m)| match m.sum {
+                Some(monotonic) => json!({
+                    "name": name,
+                    "description": m.doc,
+                    "sum": {
+                        "aggregationTemporality": OTLP_CUMULATIVE,
+                        "isMonotonic": monotonic,
+                        "dataPoints": m.points,
+                    },
+                }),
+                None => json!({
+                    "name": name,
+                    "description": m.doc,
+                    "gauge": {"dataPoints": m.points},
+                }),
+            })
+            .collect();
+
+        let resource = [
+            ("host.name".to_string(), hostname()),
+            ("jobid".to_string(), profile.desc.jobid.to_string()),
+        ];
+
+        json!({
+            "resource": {"attributes": attributes(&resource)},
+            "scopeMetrics": [{
+                "scope": {"name": "proxy_v2"},
+                "metrics": metrics,
+            }],
+        })
+    }
+
+    /// Push the current value of every series of the given jobs
+    pub(crate) fn push(&self, profiles: &[JobProfile]) -> Result<(), ProxyErr> {
+        let now = unix_ts_ns();
+
+        let request = json!({
+            "resourceMetrics": profiles
+                .iter()
+                .filter(|p| !p.counters.is_empty())
+                .map(|p| OtlpPusher::resource_metrics(p, now))
+                .collect::<Vec<Value>>(),
+        });
+
+        let resp = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .map_err(|e| ProxyErr::new(format!("Failed to reach {} : {}", self.url, e)))?;
+
+        if !resp.status().is_success() {
+            return Err(ProxyErr::new(format!(
+                "{} answered {}",
+                self.url,
+                resp.status()
+            )));
+        }
+
+        Ok(())
+    }
+}
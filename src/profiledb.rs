@@ -0,0 +1,170 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::proxywireprotocol::{CounterSnapshot, JobDesc, JobProfile};
+
+/********************
+ * SQLITE PROFILES  *
+ ********************/
+
+const DESC_COLUMNS: &str =
+    "jobid, command, size, nodelist, partition, cluster, run_dir, start_time, end_time";
+
+/// Profile storage in a single SQLite database
+///
+/// Desc fields are stored as columns and counters as a JSON blob,
+/// commands are indexed by their md5 (as for Extra-P models)
+pub(crate) struct ProfileDb {
+    conn: Mutex<Connection>,
+}
+
+fn command_hash(command: &str) -> String {
+    format!("{:x}", md5::compute(command))
+}
+
+fn desc_from_row(row: &Row) -> rusqlite::Result<JobDesc> {
+    Ok(JobDesc {
+        jobid: row.get(0)?,
+        command: row.get(1)?,
+        size: row.get(2)?,
+        nodelist: row.get(3)?,
+        partition: row.get(4)?,
+        cluster: row.get(5)?,
+        run_dir: row.get(6)?,
+        start_time: row.get::<_, i64>(7)? as u64,
+        end_time: row.get::<_, i64>(8)? as u64,
+    })
+}
+
+impl ProfileDb {
+    pub(crate) fn open(path: &Path) -> Result<ProfileDb, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS profiles (
+                jobid TEXT PRIMARY KEY,
+                command TEXT NOT NULL,
+                cmd_hash TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                nodelist TEXT NOT NULL,
+                partition TEXT NOT NULL,
+                cluster TEXT NOT NULL,
+                run_dir TEXT NOT NULL,
+                start_time INTEGER NOT NULL,
+                end_time INTEGER NOT NULL,
+                counters TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS profiles_cmd_hash ON profiles(cmd_hash);",
+        )?;
+
+        log::info!("Storing profiles in {}", path.to_string_lossy());
+
+        Ok(ProfileDb {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub(crate) fn save(&self, profile: &JobProfile) -> Result<(), Box<dyn Error>> {
+        let desc = &profile.desc;
+        let counters = serde_json::to_string(&profile.counters)?;
+
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO profiles
+                (jobid, command, cmd_hash, size, nodelist, partition,
+                 cluster, run_dir, start_time, end_time, counters)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                desc.jobid,
+                desc.command,
+                command_hash(&desc.command),
+                desc.size,
+                desc.nodelist,
+                desc.partition,
+                desc.cluster,
+                desc.run_dir,
+                desc.start_time as i64,
+                desc.end_time as i64,
+                counters
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, jobid: &str) -> Result<Option<JobProfile>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, counters FROM profiles WHERE jobid = ?1",
+            DESC_COLUMNS
+        ))?;
+
+        let row = stmt
+            .query_row(params![jobid], |row| {
+                Ok((desc_from_row(row)?, row.get::<_, String>(9)?))
+            })
+            .optional()?;
+
+        match row {
+            Some((desc, counters)) => {
+                let counters: Vec<CounterSnapshot> = serde_json::from_str(&counters)?;
+                Ok(Some(JobProfile { desc, counters }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Descs of all stored jobs (counters are not loaded)
+    pub(crate) fn descs(&self) -> Result<Vec<JobDesc>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM profiles", DESC_COLUMNS))?;
+        let ret = stmt
+            .query_map([], desc_from_row)?
+            .collect::<Result<Vec<JobDesc>, _>>()?;
+        Ok(ret)
+    }
+
+    /// Descs of the jobs which ran the given command
+    pub(crate) fn descs_by_command(&self, command: &str) -> Result<Vec<JobDesc>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM profiles WHERE cmd_hash = ?1 AND command = ?2",
+            DESC_COLUMNS
+        ))?;
+        let ret = stmt
+            .query_map(params![command_hash(command), command], desc_from_row)?
+            .collect::<Result<Vec<JobDesc>, _>>()?;
+        Ok(ret)
+    }
+
+    pub(crate) fn gather_by_command(
+        &self,
+    ) -> Result<HashMap<String, Vec<JobDesc>>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM profiles ORDER BY cmd_hash",
+            DESC_COLUMNS
+        ))?;
+
+        let mut ret: HashMap<String, Vec<JobDesc>> = HashMap::new();
+
+        for desc in stmt.query_map([], desc_from_row)? {
+            let desc = desc?;
+            ret.entry(desc.command.clone()).or_default().push(desc);
+        }
+
+        Ok(ret)
+    }
+
+    /// Distinct commands stored in the database
+    pub(crate) fn commands(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT DISTINCT command FROM profiles")?;
+        let ret = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(ret)
+    }
+}
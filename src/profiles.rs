@@ -3,14 +3,19 @@ use clap::builder::Str;
 use md5::Digest;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::de::value;
+use serde::Serialize;
+use serde_binary::binary_stream;
 
 use super::proxywireprotocol::{JobDesc, JobProfile};
 use crate::extrap::ExtrapModel;
-use crate::proxy_common::{check_prefix_dir, list_files_with_ext_in, ProxyErr};
-use std::collections::HashMap;
+#[cfg(feature = "sqlite")]
+use crate::profiledb::ProfileDb;
+use crate::proxy_common::{check_prefix_dir, list_files_with_ext_in, DirWatcher, ProxyErr};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Mutex, RwLock};
 use std::{any, fs};
 
@@ -18,19 +23,85 @@ use anyhow::Result;
 
 use crate::extrap::ExtrapEval;
 
+/// Failed parses before a profile file is moved to quarantine
+const QUARANTINE_AFTER: u32 = 3;
+
+/// Suffix of profiles stored in binary (serde_binary little endian as traces)
+const BINARY_PROFILE_SUFFIX: &str = ".profile.bin";
+
+/// Relative difference under which values are equal when a regression is
+/// checked, as metrics of identical runs may differ in their last digits
+#[allow(unused)]
+const REGRESSION_TOLERANCE: f64 = 1e-9;
+
+/// A metric of a profile compared to the previous runs of its command
+#[derive(Serialize)]
+pub(crate) struct MetricRegression {
+    pub metric: String,
+    pub value: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    /// None when all the previous runs have the same value
+    pub zscore: Option<f64>,
+    pub regressed: bool,
+}
+
+/// Outcome of ProfileView::detect_regression
+#[derive(Serialize)]
+pub(crate) struct RegressionReport {
+    pub jobid: String,
+    pub command: String,
+    pub size: i32,
+    /// Number of previous profiles compared with
+    pub baseline: usize,
+    pub threshold: f64,
+    pub regressed: bool,
+    pub metrics: Vec<MetricRegression>,
+}
+
 pub(crate) struct ProfileView {
     profdir: PathBuf,
     profiles: RwLock<HashMap<String, JobProfile>>,
     models: Mutex<HashMap<String, ExtrapEval>>,
+    /// Failed parse attempts per profile file
+    parse_failures: Mutex<HashMap<String, u32>>,
+    /// Profile files already loaded (or written) by this view
+    loaded_files: Mutex<HashSet<String>>,
+    /// Number of profile files loaded by refresh_profiles
+    processed: AtomicU64,
+    /// Save profiles in binary instead of JSON (both are read)
+    binary: bool,
+    /// When set profiles are stored in this database instead of .profile files
+    #[cfg(feature = "sqlite")]
+    db: Option<ProfileDb>,
 }
 
 impl ProfileView {
     pub(crate) fn _get_profile(path: &String) -> Result<JobProfile, Box<dyn Error>> {
-        let file = fs::File::open(path)?;
+        if path.ends_with(BINARY_PROFILE_SUFFIX) {
+            let data = fs::read(path)?;
+            return Ok(serde_binary::from_slice(
+                &data,
+                binary_stream::Endian::Little,
+            )?);
+        }
+
+        let file = BufReader::new(fs::File::open(path)?);
         let content: JobProfile = serde_json::from_reader(file)?;
         Ok(content)
     }
 
+    /// JSON (.profile) and binary (.profile.bin) profiles in dir
+    fn list_profile_files(dir: &PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut ret = list_files_with_ext_in(dir, "profile")?;
+        ret.extend(
+            list_files_with_ext_in(dir, "bin")?
+                .into_iter()
+                .filter(|f| f.ends_with(BINARY_PROFILE_SUFFIX)),
+        );
+        Ok(ret)
+    }
+
     fn extrap_filename(&self, command: &str) -> (Option<PathBuf>, String) {
         let digest = md5::compute(command);
         let mut path = self.profdir.clone();
@@ -50,15 +121,32 @@ impl ProfileView {
             if ret.add_duration()? {
                 self.generate_extrap_model(&ret.desc)?;
             }
+            ret.derive();
+            return Ok(ret);
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            let mut ret = db
+                .get(jobid)?
+                .ok_or_else(|| ProxyErr::newboxed(format!("No profile for job {}", jobid)))?;
+            if ret.add_duration()? {
+                self.generate_extrap_model(&ret.desc)?;
+            }
+            ret.derive();
             return Ok(ret);
         }
 
         let mut path = self.profdir.clone();
-        path.push(format!("{}.profile", jobid));
+        path.push(format!("{}{}", jobid, BINARY_PROFILE_SUFFIX));
+        if !path.is_file() {
+            path.set_file_name(format!("{}.profile", jobid));
+        }
         let mut ret = ProfileView::_get_profile(&path.to_string_lossy().to_string())?;
         if ret.add_duration()? {
             self.generate_extrap_model(&ret.desc)?;
         }
+        ret.derive();
         Ok(ret)
     }
 
@@ -87,19 +175,67 @@ impl ProfileView {
         Ok(data)
     }
 
+    /// Was the profile of a job saved (possibly not loaded yet)
+    pub(crate) fn has_profile(&self, jobid: &str) -> bool {
+        if self.profiles.read().unwrap().contains_key(jobid) {
+            return true;
+        }
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            return matches!(db.get(jobid), Ok(Some(_)));
+        }
+
+        [
+            format!("{}{}", jobid, BINARY_PROFILE_SUFFIX),
+            format!("{}.profile", jobid),
+        ]
+        .iter()
+        .any(|name| self.profdir.join(name).is_file())
+    }
+
+    /// Check that the profile directory is still available
+    pub(crate) fn storage_available(&self) -> bool {
+        self.profdir.is_dir()
+    }
+
     pub(crate) fn refresh_profiles(&self) -> Result<(), Box<dyn Error>> {
         /* Load profiles and existing extra-p models */
 
-        let ret = list_files_with_ext_in(&self.profdir, "profile")?;
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            /* Profiles stay in the database only models are loaded */
+            let mut model_ht = self.models.lock().unwrap();
+            for cmd in db.commands()? {
+                if let (Some(extrap_model), hash) = self.extrap_filename(&cmd) {
+                    if !model_ht.contains_key(&hash) {
+                        model_ht.insert(hash, ExtrapEval::new(extrap_model)?);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        let ret = Self::list_profile_files(&self.profdir)?;
         let mut ht = self.profiles.write().unwrap();
         let mut model_ht = self.models.lock().unwrap();
+        let mut loaded = self.loaded_files.lock().unwrap();
 
         for p in ret.iter() {
-            if !ht.contains_key(p) {
-                let content = Self::_get_profile(p)?;
+            if !loaded.contains(p) {
+                let content = match Self::_get_profile(p) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        /* Skip corrupted files (crashed proxy) so that others load */
+                        self.parse_failed(p, e.as_ref());
+                        continue;
+                    }
+                };
                 let extrap_model = self.extrap_filename(&content.desc.command);
 
                 ht.insert(content.desc.jobid.clone(), content);
+                loaded.insert(p.to_string());
+                self.processed.fetch_add(1, Ordering::Relaxed);
 
                 if let (Some(extrap_model), hash) = extrap_model {
                     if extrap_model.is_file() && !model_ht.contains_key(&hash) {
@@ -112,7 +248,101 @@ impl ProfileView {
         Ok(())
     }
 
+    /// Watcher of the profile directory, None when profiles are in a database
+    pub(crate) fn watcher(&self) -> Result<Option<DirWatcher>, ProxyErr> {
+        #[cfg(feature = "sqlite")]
+        if self.db.is_some() {
+            return Ok(None);
+        }
+
+        DirWatcher::new(&self.profdir).map(Some)
+    }
+
+    /// Profile files waiting to be loaded (always 0 with a database)
+    pub(crate) fn pending_files(&self) -> usize {
+        #[cfg(feature = "sqlite")]
+        if self.db.is_some() {
+            return 0;
+        }
+
+        let loaded = self.loaded_files.lock().unwrap();
+
+        Self::list_profile_files(&self.profdir)
+            .map(|files| files.iter().filter(|f| !loaded.contains(*f)).count())
+            .unwrap_or(0)
+    }
+
+    /// Number of profile files loaded since the start
+    pub(crate) fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    fn quarantine_dir(&self) -> PathBuf {
+        let mut dir = self.profdir.clone();
+        dir.push("quarantine");
+        dir
+    }
+
+    /// Count a failed parse and move the file to quarantine after QUARANTINE_AFTER
+    fn parse_failed(&self, path: &String, e: &dyn Error) {
+        let mut failures = self.parse_failures.lock().unwrap();
+        let count = failures.entry(path.to_string()).or_insert(0);
+        *count += 1;
+
+        log::warn!(
+            "Failed to load profile {} ({}/{}) : {}",
+            path,
+            count,
+            QUARANTINE_AFTER,
+            e
+        );
+
+        if *count < QUARANTINE_AFTER {
+            return;
+        }
+
+        let dir = self.quarantine_dir();
+        let src = PathBuf::from(path);
+
+        let res = fs::create_dir_all(&dir).and_then(|_| {
+            let mut dest = dir.clone();
+            dest.push(src.file_name().unwrap_or_default());
+            fs::rename(&src, dest)
+        });
+
+        match res {
+            Ok(_) => {
+                log::error!(
+                    "Moved corrupted profile {} to {}",
+                    path,
+                    dir.to_string_lossy()
+                );
+                failures.remove(path);
+            }
+            Err(e) => log::error!("Failed to quarantine {} : {}", path, e),
+        }
+    }
+
+    /// Files moved to quarantine as they could not be parsed
+    pub(crate) fn quarantined(&self) -> Vec<String> {
+        let dir = self.quarantine_dir();
+
+        if !dir.is_dir() {
+            return Vec::new();
+        }
+
+        Self::list_profile_files(&dir).unwrap_or_default()
+    }
+
     pub(crate) fn gather_by_command(&self) -> HashMap<String, Vec<JobDesc>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            return db.gather_by_command().unwrap_or_else(|e| {
+                log::error!("Failed to list profiles by command : {}", e);
+                HashMap::new()
+            });
+        }
+
         let mut ret: HashMap<String, Vec<JobDesc>> = HashMap::new();
 
         let ht = self.profiles.read().unwrap();
@@ -126,6 +356,14 @@ impl ProfileView {
     }
 
     pub(crate) fn filter_by_command(&self, cmd: &String) -> Vec<JobDesc> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            return db.descs_by_command(cmd).unwrap_or_else(|e| {
+                log::error!("Failed to list profiles for {} : {}", cmd, e);
+                Vec::new()
+            });
+        }
+
         self.profiles
             .read()
             .unwrap()
@@ -140,8 +378,50 @@ impl ProfileView {
             .collect()
     }
 
+    fn node_profiles_path(&self, jobid: &str) -> PathBuf {
+        let mut path = self.profdir.clone();
+        path.push(format!("{}.nodes", jobid));
+        path
+    }
+
+    /// Store the profile of each node of a job next to its merged profile
+    pub(crate) fn save_node_profiles(
+        &self,
+        desc: &JobDesc,
+        nodes: &HashMap<String, JobProfile>,
+    ) -> Result<(), Box<dyn Error>> {
+        let file = fs::File::create(self.node_profiles_path(&desc.jobid))?;
+        serde_json::to_writer(file, nodes)?;
+        Ok(())
+    }
+
+    pub(crate) fn get_node_profiles(
+        &self,
+        jobid: &str,
+    ) -> Result<HashMap<String, JobProfile>, Box<dyn Error>> {
+        let path = self.node_profiles_path(jobid);
+
+        if !path.is_file() {
+            return Err(ProxyErr::newboxed(format!(
+                "No per node profiles for job {}",
+                jobid
+            )));
+        }
+
+        let file = fs::File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
     #[allow(unused)]
     pub(crate) fn get_profile_list(&self) -> Vec<JobDesc> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            return db.descs().unwrap_or_else(|e| {
+                log::error!("Failed to list profiles : {}", e);
+                Vec::new()
+            });
+        }
+
         self.profiles
             .read()
             .unwrap()
@@ -150,54 +430,46 @@ impl ProfileView {
             .collect()
     }
 
-    pub(crate) fn extrap_model_list(&self, desc: &JobDesc) -> Result<Vec<(String, String, f64)>> {
-        let cmd_hash = md5::compute(&desc.command);
-        let hash = format!("{:x}", cmd_hash);
+    /// Run f on the Extra-P model of a command, models are loaded on demand
+    fn with_extrap_model<T, F>(&self, command: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ExtrapEval) -> Result<T>,
+    {
+        let hash = format!("{:x}", md5::compute(command));
+
+        /* Try to load models too */
+        if self.models.lock().unwrap().get(&hash).is_none() {
+            let _ = self.refresh_profiles();
+        }
 
         if let Some(m) = self.models.lock().unwrap().get_mut(&hash) {
-            Ok(m.models()?)
+            f(m)
         } else {
             Err(anyhow!("Failed to retrieve an extra-p model for {}", hash))
         }
     }
 
+    /// Metric, equation and RSS of each model of a command
+    pub(crate) fn extrap_model_list(&self, command: &str) -> Result<Vec<(String, String, f64)>> {
+        self.with_extrap_model(command, |m| m.models())
+    }
+
     pub(crate) fn extrap_model_eval(
         &self,
-        desc: &JobDesc,
+        command: &str,
         metric: String,
         size: f64,
     ) -> Result<(f64, f64)> {
-        let cmd_hash = md5::compute(&desc.command);
-        let hash = format!("{:x}", cmd_hash);
-
-        if let Some(m) = self.models.lock().unwrap().get_mut(&hash) {
-            let val = m.evaluate(&metric, size)?;
-            Ok((size, val))
-        } else {
-            Err(anyhow!("Failed to retrieve an extra-p model for {}", hash))
-        }
+        self.with_extrap_model(command, |m| Ok((size, m.evaluate(&metric, size)?)))
     }
 
     pub(crate) fn extrap_model_plot(
         &self,
-        desc: &JobDesc,
+        command: &str,
         metric: String,
         points: &[f64],
     ) -> Result<Vec<(f64, f64)>> {
-        let cmd_hash = md5::compute(&desc.command);
-        let hash = format!("{:x}", cmd_hash);
-
-        /* Try to load models too */
-        if self.models.lock().unwrap().get(&hash).is_none() {
-            let _ = self.refresh_profiles();
-        }
-
-        if let Some(m) = self.models.lock().unwrap().get_mut(&hash) {
-            let vals = m.plot(&metric, points)?;
-            Ok(vals)
-        } else {
-            Err(anyhow!("Failed to retrieve an extra-p model for {}", hash))
-        }
+        self.with_extrap_model(command, |m| m.plot(&metric, points))
     }
 
     pub(crate) fn generate_profile_points(
@@ -233,6 +505,99 @@ impl ProfileView {
         ))
     }
 
+    /// Compare the metrics of a profile with the previous runs of the same
+    /// command at the same size, a metric regressed when its z-score is
+    /// beyond the threshold (either way as the metric meaning is unknown)
+    pub(crate) fn detect_regression(
+        &self,
+        profile: &JobProfile,
+        metric: Option<&str>,
+        threshold: f64,
+    ) -> Result<RegressionReport> {
+        let desc = &profile.desc;
+
+        let baseline: Vec<JobProfile> = self
+            .filter_by_command(&desc.command)
+            .par_iter()
+            .filter(|d| {
+                d.jobid != desc.jobid && d.size == desc.size && d.start_time < desc.start_time
+            })
+            .filter_map(|d| self.get_profile(&d.jobid).ok())
+            .collect();
+
+        if baseline.len() < 2 {
+            return Err(anyhow!(
+                "At least two previous runs of {} at size {} are needed, found {}",
+                desc.command,
+                desc.size,
+                baseline.len()
+            ));
+        }
+
+        let mut history: HashMap<&str, Vec<f64>> = HashMap::new();
+        for p in baseline.iter() {
+            for c in p.counters.iter() {
+                history.entry(&c.name).or_default().push(c.ctype.value());
+            }
+        }
+
+        let mut metrics: Vec<MetricRegression> = Vec::new();
+
+        for c in profile.counters.iter() {
+            if metric.is_some_and(|m| m != c.name) {
+                continue;
+            }
+
+            let values = match history.get(c.name.as_str()) {
+                Some(v) if v.len() >= 2 => v,
+                _ => continue,
+            };
+
+            let n = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / n;
+            let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            let stddev = variance.sqrt();
+
+            let value = c.ctype.value();
+
+            /* Without spread any change beyond rounding noise is an outlier */
+            let (zscore, regressed) = if stddev > REGRESSION_TOLERANCE * mean.abs() {
+                let z = (value - mean) / stddev;
+                (Some(z), z.abs() > threshold)
+            } else {
+                let scale = value.abs().max(mean.abs());
+                (None, (value - mean).abs() > REGRESSION_TOLERANCE * scale)
+            };
+
+            metrics.push(MetricRegression {
+                metric: c.name.to_string(),
+                value,
+                mean,
+                stddev,
+                zscore,
+                regressed,
+            });
+        }
+
+        if let Some(m) = metric {
+            if metrics.is_empty() {
+                return Err(anyhow!("No history for {} in previous runs", m));
+            }
+        }
+
+        metrics.sort_by(|a, b| a.metric.cmp(&b.metric));
+
+        Ok(RegressionReport {
+            jobid: desc.jobid.to_string(),
+            command: desc.command.to_string(),
+            size: desc.size,
+            baseline: baseline.len(),
+            threshold,
+            regressed: metrics.iter().any(|m| m.regressed),
+            metrics,
+        })
+    }
+
     pub(crate) fn generate_extrap_model_for_profiles(
         &self,
         profiles: Vec<JobProfile>,
@@ -257,6 +622,65 @@ impl ProfileView {
         Ok(())
     }
 
+    /// Extra-P JSON lines for several commands (and those starting with prefix)
+    /// returns the commands in the order of their `command` parameter
+    pub(crate) fn extrap_bundle(
+        &self,
+        commands: &[String],
+        prefix: Option<&str>,
+    ) -> Result<(Vec<String>, String), Box<dyn Error>> {
+        let mut commands: Vec<String> = commands.to_vec();
+
+        if let Some(prefix) = prefix {
+            commands.extend(
+                self.gather_by_command()
+                    .into_keys()
+                    .filter(|c| c.starts_with(prefix)),
+            );
+        }
+
+        commands.sort();
+        commands.dedup();
+
+        let mut bundled: Vec<String> = Vec::new();
+        let mut models: Vec<ExtrapModel> = Vec::new();
+
+        for cmd in commands {
+            let profiles: Vec<JobProfile> = self
+                .filter_by_command(&cmd)
+                .iter()
+                .filter_map(|v| self.get_profile(&v.jobid).ok())
+                .collect();
+
+            if profiles.is_empty() {
+                log::warn!("No profile for {}, it is not bundled", cmd);
+                continue;
+            }
+
+            let model = ExtrapModel::new(profiles);
+
+            let sizes: HashSet<i32> = model.sizes().into_iter().collect();
+            if sizes.len() < 2 {
+                log::warn!(
+                    "{} has {} distinct size(s), its model will be degenerate",
+                    cmd,
+                    sizes.len()
+                );
+            }
+
+            bundled.push(cmd);
+            models.push(model);
+        }
+
+        if models.is_empty() {
+            return Err(ProxyErr::newboxed("No profile matches the given commands"));
+        }
+
+        let jsonl = ExtrapModel::bundle(models).to_jsonl_string()?;
+
+        Ok((bundled, jsonl))
+    }
+
     pub(crate) fn generate_extrap_model(&self, desc: &JobDesc) -> Result<(), Box<dyn Error>> {
         let gather_by_cmd = self.gather_by_command();
 
@@ -278,26 +702,49 @@ impl ProfileView {
         mut snap: JobProfile,
         desc: &JobDesc,
     ) -> Result<(), Box<dyn Error>> {
+        // Nan / Infinite values seralize to null and cannot be parsed back
+        // This is why we make this pass on all values to ensure we do not store
+        // invalid data
+        snap.counters.iter_mut().for_each(|c| c.clean());
+
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db {
+            log::debug!("Saving profile for {} in database", desc.jobid);
+            db.save(&snap)?;
+            self.generate_extrap_model(desc)?;
+            return Ok(());
+        }
+
         let mut target_dir = self.profdir.clone();
 
-        let fname = format!("{}.profile", desc.jobid);
+        let fname = if self.binary {
+            format!("{}{}", desc.jobid, BINARY_PROFILE_SUFFIX)
+        } else {
+            format!("{}.profile", desc.jobid)
+        };
 
         target_dir.push(fname);
 
+        /* The profile is known, do not load it back */
+        self.loaded_files
+            .lock()
+            .unwrap()
+            .insert(target_dir.to_string_lossy().to_string());
+
         log::debug!(
             "Saving profile for {} in {}",
             desc.jobid,
             target_dir.to_str().unwrap_or("")
         );
 
-        // Nan / Infinite values seralize to null and cannot be parsed back
-        // This is why we make this pass on all values to ensure we do not store
-        // invalid data
-        snap.counters.iter_mut().for_each(|c| c.clean());
-
-        let file = fs::File::create(target_dir)?;
-
-        serde_json::to_writer(file, &snap)?;
+        if self.binary {
+            let data = serde_binary::to_vec(&snap, binary_stream::Endian::Little)?;
+            fs::write(target_dir, data)?;
+        } else {
+            let mut file = BufWriter::new(fs::File::create(target_dir)?);
+            serde_json::to_writer(&mut file, &snap)?;
+            file.flush()?;
+        }
 
         self.profiles
             .write()
@@ -309,13 +756,38 @@ impl ProfileView {
         Ok(())
     }
 
-    pub(crate) fn new(profdir: &PathBuf) -> Result<ProfileView, Box<dyn Error>> {
+    /// Profiles are stored as files in profdir unless a database is given
+    /// (Extra-P models always go to profdir)
+    pub(crate) fn new(
+        profdir: &PathBuf,
+        database: Option<&Path>,
+        binary: bool,
+    ) -> Result<ProfileView, Box<dyn Error>> {
         let profdir = check_prefix_dir(profdir, "profiles")?;
 
+        #[cfg(feature = "sqlite")]
+        let db = match database {
+            Some(path) => Some(ProfileDb::open(path)?),
+            None => None,
+        };
+
+        #[cfg(not(feature = "sqlite"))]
+        if database.is_some() {
+            return Err(ProxyErr::newboxed(
+                "Profile database requires the 'sqlite' feature",
+            ));
+        }
+
         let ret = ProfileView {
             profdir,
             profiles: RwLock::new(HashMap::new()),
             models: Mutex::new(HashMap::new()),
+            parse_failures: Mutex::new(HashMap::new()),
+            loaded_files: Mutex::new(HashSet::new()),
+            processed: AtomicU64::new(0),
+            binary,
+            #[cfg(feature = "sqlite")]
+            db,
         };
 
         ret.refresh_profiles()?;
@@ -323,3 +795,176 @@ impl ProfileView {
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxywireprotocol::{CounterSnapshot, CounterType};
+
+    #[test]
+    fn corrupted_profiles_are_quarantined() {
+        let prefix = std::env::temp_dir().join(format!("proxy-quarantine-{}", std::process::id()));
+        let profdir = prefix.join("profiles");
+        fs::create_dir_all(&profdir).unwrap();
+
+        let mut desc = JobDesc::new();
+        desc.jobid = "ok".to_string();
+        let profile = JobProfile {
+            desc,
+            counters: Vec::new(),
+        };
+        fs::write(
+            profdir.join("ok.profile"),
+            serde_json::to_string(&profile).unwrap(),
+        )
+        .unwrap();
+        /* Truncated by a crashed proxy */
+        fs::write(profdir.join("bad.profile"), "{\"desc\": {").unwrap();
+
+        /* The first attempt is made when the view is created */
+        let view = ProfileView::new(&prefix, None, false).unwrap();
+        assert_eq!(view.processed(), 1);
+        assert!(view.has_profile("ok"));
+
+        for _ in 1..QUARANTINE_AFTER {
+            assert_eq!(view.pending_files(), 1);
+            assert!(view.quarantined().is_empty());
+            view.refresh_profiles().unwrap();
+        }
+
+        assert_eq!(view.pending_files(), 0);
+        let quarantined = view.quarantined();
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].ends_with("bad.profile"));
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn constant_baselines_ignore_rounding_noise() {
+        let prefix = std::env::temp_dir().join(format!("proxy-regression-{}", std::process::id()));
+        let profdir = prefix.join("profiles");
+        fs::create_dir_all(&profdir).unwrap();
+
+        let profile = |jobid: &str, start_time: u64, ratio: f64, calls: f64| {
+            let mut desc = JobDesc::new();
+            desc.jobid = jobid.to_string();
+            desc.command = "./solver".to_string();
+            desc.size = 4;
+            desc.start_time = start_time;
+            desc.end_time = start_time + 1;
+            let counters = [("ratio", ratio), ("calls", calls)]
+                .into_iter()
+                .map(|(name, value)| CounterSnapshot {
+                    name: name.to_string(),
+                    doc: String::new(),
+                    ctype: CounterType::Counter { ts: 0, value },
+                })
+                .collect();
+            JobProfile { desc, counters }
+        };
+
+        for i in 0..3 {
+            let p = profile(&format!("{}", i), i, 0.3, 0.0);
+            fs::write(
+                profdir.join(format!("{}.profile", i)),
+                serde_json::to_string(&p).unwrap(),
+            )
+            .unwrap();
+        }
+
+        let view = ProfileView::new(&prefix, None, false).unwrap();
+        let regressed = |ratio: f64, calls: f64| {
+            view.detect_regression(&profile("new", 10, ratio, calls), None, 3.0)
+                .unwrap()
+                .metrics
+                .iter()
+                .map(|m| (m.metric.to_string(), m.regressed))
+                .collect::<HashMap<String, bool>>()
+        };
+
+        /* 0.1 + 0.2 is 0.30000000000000004 */
+        let same = regressed(0.1 + 0.2, 0.0);
+        assert!(!same["ratio"] && !same["calls"]);
+
+        let changed = regressed(0.31, 1.0);
+        assert!(changed["ratio"] && changed["calls"]);
+
+        fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    /// cargo test --release -- --ignored bench_ --nocapture
+    #[test]
+    #[ignore]
+    fn bench_profile_formats() {
+        const PROFILES: usize = 200;
+        const COUNTERS: usize = 2000;
+
+        let counters: Vec<CounterSnapshot> = (0..COUNTERS)
+            .map(|i| CounterSnapshot {
+                name: format!("func__solver_{}", i),
+                doc: format!("Number of calls to solver_{}", i),
+                ctype: CounterType::Counter {
+                    ts: 1700000000000 + i as u64,
+                    value: i as f64 * 1.5,
+                },
+            })
+            .collect();
+
+        for binary in [false, true] {
+            let prefix = std::env::temp_dir().join(format!(
+                "proxy-profbench-{}-{}",
+                std::process::id(),
+                binary
+            ));
+            let profdir = prefix.join("profiles");
+            fs::create_dir_all(&profdir).unwrap();
+
+            let start = std::time::Instant::now();
+            let mut size = 0;
+
+            for i in 0..PROFILES {
+                let mut desc = JobDesc::new();
+                desc.jobid = format!("{}", i);
+                let profile = JobProfile {
+                    desc,
+                    counters: counters.clone(),
+                };
+
+                let (data, name) = if binary {
+                    (
+                        serde_binary::to_vec(&profile, binary_stream::Endian::Little).unwrap(),
+                        format!("{}{}", i, BINARY_PROFILE_SUFFIX),
+                    )
+                } else {
+                    (
+                        serde_json::to_vec(&profile).unwrap(),
+                        format!("{}.profile", i),
+                    )
+                };
+                size += data.len();
+                fs::write(profdir.join(name), data).unwrap();
+            }
+
+            let written = start.elapsed();
+
+            /* Loading is what the aggregator does for each new file */
+            let start = std::time::Instant::now();
+            let view = ProfileView::new(&prefix, None, binary).unwrap();
+            let loaded = start.elapsed();
+            assert_eq!(view.processed(), PROFILES as u64);
+
+            println!(
+                "{}: {} profiles of {} counters, {} MB written in {:?}, loaded in {:?}",
+                if binary { "binary" } else { "json" },
+                PROFILES,
+                COUNTERS,
+                size / (1024 * 1024),
+                written,
+                loaded
+            );
+
+            fs::remove_dir_all(&prefix).unwrap();
+        }
+    }
+}
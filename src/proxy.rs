@@ -1,15 +1,23 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::Read;
+use std::fs::Permissions;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-use crate::proxy_common::unix_ts;
-use crate::proxywireprotocol::JobDesc;
+use crate::proxy_common::{unix_ts, unix_ts_us};
+use crate::proxywireprotocol::{
+    metric_matches, CounterSnapshot, CounterType, JobDesc, ProxyResponse, ShmSlot,
+};
+use crate::shm::ShmSource;
 
-use super::exporter::{Exporter, ExporterFactory};
-use super::proxy_common::ProxyErr;
+use super::exporter::{Exporter, ExporterFactory, JobAnnotation};
+use super::proxy_common::{unix_peer_uid, unix_socket_listen, ProxyErr};
 
 use super::proxywireprotocol::ProxyCommand;
 
@@ -20,34 +28,126 @@ use super::proxywireprotocol::ProxyCommand;
 pub(crate) struct UnixProxy {
     listener: UnixListener,
     factory: Arc<ExporterFactory>,
+    /// Maximum number of clients handled at once (0 = unlimited)
+    max_clients: usize,
+    /// Number of clients currently handled
+    clients: Arc<AtomicUsize>,
 }
 
 struct PerClientState {
     factory: Arc<ExporterFactory>,
     job_exporter: Option<Arc<Exporter>>,
     job_desc: Option<JobDesc>,
+    /// Shared memory counters of the client
+    shm: Option<ShmSource>,
+    /// Unix ts (ms) of the last read of the shared counters
+    last_shm_poll: u64,
+    /// Uid of the client process (SO_PEERCRED), its segment must belong to it
+    peer_uid: Option<u32>,
+    /// Doc of the counters described by the client
+    docs: HashMap<String, String>,
 }
 
 impl UnixProxy {
+    /// Values of the client job (or of the main exporter) matching a Get pattern
+    fn get_values(exporter: &Exporter, name: &str) -> ProxyResponse {
+        match exporter.counters(true) {
+            Ok(counters) => {
+                let values: Vec<CounterSnapshot> = counters
+                    .into_iter()
+                    .filter(|c| metric_matches(name, &c.name))
+                    .collect();
+
+                if values.is_empty() && !name.ends_with('*') {
+                    ProxyResponse::Error(format!("No such metric {}", name))
+                } else {
+                    ProxyResponse::Values(values)
+                }
+            }
+            Err(e) => ProxyResponse::Error(e.to_string()),
+        }
+    }
+
+    /// Accumulate the increments of the shared memory counters since the last poll
+    fn poll_shm(per_client_state: &mut PerClientState) -> Result<(), Box<dyn Error>> {
+        let values = match per_client_state.shm.as_mut() {
+            Some(shm) => shm.poll(),
+            None => return Ok(()),
+        };
+
+        let ts = unix_ts_us();
+
+        for (name, value) in values {
+            let doc = per_client_state.docs.get(&name).map(String::as_str);
+            per_client_state.factory.accumulate(
+                name.as_str(),
+                doc.unwrap_or_default(),
+                CounterType::Counter { ts, value },
+                per_client_state.job_exporter.clone(),
+                per_client_state.job_desc.as_ref().map(|d| d.jobid.as_str()),
+            )?;
+        }
+
+        per_client_state.last_shm_poll = unix_ts();
+
+        Ok(())
+    }
+
+    fn register_shm(
+        per_client_state: &mut PerClientState,
+        path: String,
+        slots: Vec<ShmSlot>,
+    ) -> Result<(), Box<dyn Error>> {
+        let known = per_client_state
+            .shm
+            .as_ref()
+            .map(|shm| shm.path() == Path::new(&path))
+            .unwrap_or(false);
+
+        if !known {
+            let uid = per_client_state
+                .peer_uid
+                .ok_or_else(|| ProxyErr::new("Unknown client credentials"))?;
+            log::info!("Reading shared counters from {}", path);
+            per_client_state.shm = Some(ShmSource::open(Path::new(&path), uid)?);
+        }
+
+        if let Some(shm) = per_client_state.shm.as_mut() {
+            for s in slots {
+                shm.register(s.name, s.slot)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a command, some of them (Get) expect a response
     fn handle_command(
         per_client_state: &mut PerClientState,
         command: ProxyCommand,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<Option<ProxyResponse>, Box<dyn Error>> {
         log::debug!("{:?}", command);
         match command {
             ProxyCommand::Desc(desc) => {
+                per_client_state
+                    .docs
+                    .insert(desc.name.to_string(), desc.doc.to_string());
                 per_client_state.factory.push(
                     desc.name.as_str(),
                     desc.doc.as_str(),
                     desc.ctype.clone(),
                     per_client_state.job_exporter.clone(),
+                    per_client_state.job_desc.as_ref().map(|d| d.jobid.as_str()),
                 )?;
             }
             ProxyCommand::Value(value) => {
+                let doc = per_client_state.docs.get(&value.name).map(String::as_str);
                 per_client_state.factory.accumulate(
                     value.name.as_str(),
+                    doc.unwrap_or_default(),
                     value.value,
                     per_client_state.job_exporter.clone(),
+                    per_client_state.job_desc.as_ref().map(|d| d.jobid.as_str()),
                 )?;
             }
             ProxyCommand::JobDesc(d) => {
@@ -61,8 +161,64 @@ impl UnixProxy {
                     }
                 }
             }
+            ProxyCommand::JobEnd { jobid, end_time } => {
+                /* Shared counters are not flushed by the client, read them a last time */
+                UnixProxy::poll_shm(per_client_state)?;
+
+                /* Relax now with the client end time, the socket close then has nothing to do */
+                match per_client_state.job_desc.take() {
+                    Some(mut desc) if !desc.jobid.is_empty() && desc.jobid == jobid => {
+                        desc.end_time = end_time;
+                        per_client_state.job_exporter = None;
+                        per_client_state.factory.relax_job(&desc)?;
+                    }
+                    desc => {
+                        log::warn!("Client ended job {} it did not describe", jobid);
+                        per_client_state.job_desc = desc;
+                    }
+                }
+            }
+            ProxyCommand::Get { name } => {
+                /* Shared counters may be more recent than the exporter */
+                UnixProxy::poll_shm(per_client_state)?;
+                let exporter = per_client_state
+                    .job_exporter
+                    .clone()
+                    .unwrap_or_else(|| per_client_state.factory.get_main());
+                return Ok(Some(UnixProxy::get_values(&exporter, &name)));
+            }
+            ProxyCommand::GetNode { name } => {
+                UnixProxy::poll_shm(per_client_state)?;
+                let exporter = per_client_state.factory.get_node();
+                return Ok(Some(UnixProxy::get_values(&exporter, &name)));
+            }
+            ProxyCommand::ShmRegister { path, slots } => {
+                /* The client waits for the answer and falls back to the socket on error */
+                let response = match UnixProxy::register_shm(per_client_state, path, slots) {
+                    Ok(_) => ProxyResponse::Values(Vec::new()),
+                    Err(e) => {
+                        log::warn!("Failed to register shared counters : {}", e);
+                        ProxyResponse::Error(e.to_string())
+                    }
+                };
+                return Ok(Some(response));
+            }
+            ProxyCommand::Annotation { ts, label } => {
+                let job = match &per_client_state.job_desc {
+                    Some(desc) if !desc.jobid.is_empty() => desc.jobid.to_string(),
+                    _ => "main".to_string(),
+                };
+
+                /* The client waits for the answer, errors are not fatal to the connection */
+                let annotation = JobAnnotation { job, ts, label };
+                let response = match per_client_state.factory.annotate(annotation) {
+                    Ok(_) => ProxyResponse::Values(Vec::new()),
+                    Err(e) => ProxyResponse::Error(e.to_string()),
+                };
+                return Ok(Some(response));
+            }
         }
-        Ok(())
+        Ok(None)
     }
 
     fn handle_client(
@@ -75,11 +231,27 @@ impl UnixProxy {
             factory: factory.clone(),
             job_exporter: None,
             job_desc: None,
+            shm: None,
+            last_shm_poll: 0,
+            peer_uid: unix_peer_uid(&stream)
+                .map_err(|e| log::warn!("Failed to get client credentials : {}", e))
+                .ok(),
+            docs: HashMap::new(),
         };
 
+        let mut shm_timeout = false;
+
         loop {
             let mut buff: [u8; 1024] = [0; 1024];
-            let len = stream.read(&mut buff)?;
+            let len = match stream.read(&mut buff) {
+                Ok(len) => len,
+                /* Read timeouts are only set to poll the shared counters */
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    UnixProxy::poll_shm(&mut per_client_state)?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
 
             if len == 0 {
                 break;
@@ -88,15 +260,42 @@ impl UnixProxy {
             for c in buff.iter().take(len) {
                 if *c == 0 {
                     /* Full command */
-                    let cmd: ProxyCommand = serde_json::from_slice(&received_data)?;
-                    UnixProxy::handle_command(&mut per_client_state, cmd)?;
+                    let res = match serde_json::from_slice::<ProxyCommand>(&received_data) {
+                        Ok(cmd) => UnixProxy::handle_command(&mut per_client_state, cmd),
+                        Err(e) => Err(e.into()),
+                    };
+                    factory.count_command(res.is_ok());
+
+                    /* Responses are framed as commands (JSON and a null byte) */
+                    if let Some(response) = res? {
+                        serde_json::to_writer(&mut stream, &response)?;
+                        stream.write_all(&[0_u8])?;
+                    }
+
                     received_data.clear();
                 } else {
                     received_data.push(*c);
                 }
             }
+
+            if per_client_state.shm.is_some() {
+                let period = *factory.period.read().unwrap();
+
+                /* Wake up every period even if the client is silent */
+                if !shm_timeout {
+                    stream.set_read_timeout(Some(Duration::from_millis(period.max(1))))?;
+                    shm_timeout = true;
+                }
+
+                if unix_ts() - per_client_state.last_shm_poll >= period {
+                    UnixProxy::poll_shm(&mut per_client_state)?;
+                }
+            }
         }
 
+        UnixProxy::poll_shm(&mut per_client_state)?;
+
+        /* Fallback for clients which did not send JobEnd */
         if let Some(mut desc) = per_client_state.job_desc {
             if !desc.jobid.is_empty() {
                 /* We set the end Unix TS each time we relax */
@@ -114,16 +313,36 @@ impl UnixProxy {
                 Ok(stream) => {
                     log::debug!("New connection");
 
+                    /* Take a slot, refuse the client when all are taken */
+                    let current = self.clients.fetch_add(1, Ordering::SeqCst);
+
+                    if self.max_clients > 0 && current >= self.max_clients {
+                        self.clients.fetch_sub(1, Ordering::SeqCst);
+                        log::warn!(
+                            "Refusing UNIX client, {} clients are already connected",
+                            current
+                        );
+                        self.factory.client_refused();
+                        drop(stream);
+                        continue;
+                    }
+
                     let factory = self.factory.clone();
+                    let clients = self.clients.clone();
 
                     // Handle the connection in a new thread.
-                    thread::spawn(move || match UnixProxy::handle_client(factory, stream) {
-                        Ok(_) => {
-                            log::debug!("Client left");
-                        }
-                        Err(e) => {
-                            log::error!("Proxy server closing on client : {}", e.to_string());
+                    thread::spawn(move || {
+                        factory.client_connected(1);
+                        match UnixProxy::handle_client(factory.clone(), stream) {
+                            Ok(_) => {
+                                log::debug!("Client left");
+                            }
+                            Err(e) => {
+                                log::error!("Proxy server closing on client : {}", e.to_string());
+                            }
                         }
+                        factory.client_connected(-1);
+                        clients.fetch_sub(1, Ordering::SeqCst);
                     });
                 }
                 Err(err) => {
@@ -137,18 +356,32 @@ impl UnixProxy {
 
     pub(crate) fn new(
         socket_path: String,
+        socket_mode: u32,
+        max_clients: usize,
+        backlog: i32,
         factory: Arc<ExporterFactory>,
     ) -> Result<UnixProxy, Box<dyn Error>> {
+        /* Abstract sockets vanish with the process, nothing to clean */
+        let is_file = !socket_path.starts_with('@');
         let path = Path::new(&socket_path);
 
-        if path.exists() {
+        if is_file && path.exists() {
             std::fs::remove_file(path)
                 .or(Err(ProxyErr::new("Failed to remove previous proxy file")))?;
         }
 
-        let listener = UnixListener::bind(path)?;
+        let listener = unix_socket_listen(&socket_path, backlog)?;
 
-        let proxy = UnixProxy { listener, factory };
+        if is_file {
+            std::fs::set_permissions(path, Permissions::from_mode(socket_mode))?;
+        }
+
+        let proxy = UnixProxy {
+            listener,
+            factory,
+            max_clients,
+            clients: Arc::new(AtomicUsize::new(0)),
+        };
 
         log::info!("UNIX proxy listening on {}", socket_path);
 
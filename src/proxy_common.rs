@@ -1,7 +1,18 @@
+use serde::de::DeserializeOwned;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::ffi::CString;
 use std::ffi::OsStr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, fs};
-use std::{error::Error, path::PathBuf};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
 
 /*******************
  * IMPLEMENT ERROR *
@@ -80,6 +91,15 @@ pub fn unix_ts_us() -> u64 {
         .as_micros() as u64
 }
 
+#[allow(unused)]
+pub fn unix_ts_ns() -> u64 {
+    let current_time = SystemTime::now();
+    current_time
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos() as u64
+}
+
 #[allow(unused)]
 pub(crate) fn list_files_with_ext_in(
     path: &PathBuf,
@@ -119,6 +139,98 @@ pub(crate) fn create_dir_or_fail(path: &PathBuf) -> Result<(), ProxyErr> {
     Ok(())
 }
 
+/// Expand a leading ~ and the $VAR or ${VAR} environment variables of a path
+/// unset variables are left as is with a warning
+#[allow(unused)]
+pub(crate) fn expand_path(path: &str) -> String {
+    let mut ret = String::new();
+
+    let mut rest = match path.strip_prefix('~') {
+        Some(r) if r.is_empty() || r.starts_with('/') => match dirs::home_dir() {
+            Some(home) => {
+                ret += &home.to_string_lossy();
+                r
+            }
+            None => path,
+        },
+        _ => path,
+    };
+
+    while let Some(idx) = rest.find('$') {
+        ret += &rest[..idx];
+        let after = &rest[idx + 1..];
+
+        let (name, literal, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (
+                    &braced[..end],
+                    &rest[idx..idx + end + 3],
+                    &braced[end + 1..],
+                ),
+                None => ("", &rest[idx..], ""),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &rest[idx..idx + end + 1], &after[end..])
+            }
+        };
+
+        match env::var(name) {
+            Ok(v) if !name.is_empty() => ret += &v,
+            _ => {
+                if !name.is_empty() {
+                    log::warn!("{} is not set, it is kept as is in {}", name, path);
+                }
+                ret += literal;
+            }
+        }
+
+        rest = next;
+    }
+
+    ret + rest
+}
+
+/// Default root directory of the proxy, PROXY_PROFILE_DIR or ~/.proxyprofiles
+#[allow(unused)]
+pub(crate) fn default_profile_prefix() -> Result<PathBuf, ProxyErr> {
+    if let Ok(dir) = env::var("PROXY_PROFILE_DIR") {
+        if !dir.is_empty() {
+            return Ok(PathBuf::from(expand_path(&dir)));
+        }
+    }
+
+    match dirs::home_dir() {
+        Some(mut d) => {
+            d.push(".proxyprofiles");
+            Ok(d)
+        }
+        None => Err(ProxyErr::new(
+            "No home directory, set PROXY_PROFILE_DIR or pass a profile directory",
+        )),
+    }
+}
+
+/// Make sure files can be created in dir
+#[allow(unused)]
+pub(crate) fn check_dir_writable(dir: &Path) -> Result<(), ProxyErr> {
+    let probe = dir.join(format!(".proxy_write_test.{}", std::process::id()));
+
+    if let Err(e) = fs::File::create(&probe) {
+        return Err(ProxyErr::new(format!(
+            "Directory {} is not writable : {}",
+            dir.to_str().unwrap_or(""),
+            e
+        )));
+    }
+
+    let _ = fs::remove_file(&probe);
+
+    Ok(())
+}
+
 #[allow(unused)]
 pub(crate) fn check_prefix_dir(prefix: &PathBuf, dirname: &str) -> Result<PathBuf, ProxyErr> {
     // Main directory
@@ -140,6 +252,8 @@ pub(crate) fn check_prefix_dir(prefix: &PathBuf, dirname: &str) -> Result<PathBu
         create_dir_or_fail(&target_dir)?;
     }
 
+    check_dir_writable(&target_dir)?;
+
     Ok(target_dir)
 }
 
@@ -150,9 +264,107 @@ pub(crate) fn hostname() -> String {
     strh.to_string()
 }
 
+/// Token used to authenticate against proxies started with --auth-token
+#[allow(unused)]
+pub(crate) fn get_proxy_auth_token() -> Option<String> {
+    env::var("PROXY_AUTH_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+/// Attach the bearer token (if any) to an outgoing request
+#[allow(unused)]
+pub(crate) fn with_proxy_auth(
+    req: reqwest::blocking::RequestBuilder,
+) -> reqwest::blocking::RequestBuilder {
+    match get_proxy_auth_token() {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
+
+/// Connect and read timeout of requests between proxies
+#[allow(unused)]
+pub(crate) const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest JSON body accepted from another proxy
+#[allow(unused)]
+pub(crate) const PEER_MAX_BODY: u64 = 128 * 1024 * 1024;
+
+/// Header of /job responses carrying the hostname of the proxy
+#[allow(unused)]
+pub(crate) const PROXY_HOST_HEADER: &str = "X-Proxy-Host";
+
+/// HTTP client for requests to other proxies, none may hang forever
+#[allow(unused)]
+pub(crate) fn peer_client(timeout: Duration) -> Result<reqwest::blocking::Client, ProxyErr> {
+    reqwest::blocking::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| ProxyErr::new(format!("Failed to create HTTP client : {}", e)))
+}
+
+/// Decode a JSON response refusing bodies larger than max bytes
+#[allow(unused)]
+pub(crate) fn read_json_capped<T: DeserializeOwned>(
+    response: reqwest::blocking::Response,
+    max: u64,
+) -> Result<T, ProxyErr> {
+    let url = response.url().to_string();
+    let too_large = || ProxyErr::new(format!("Response of {} is larger than {} bytes", url, max));
+
+    if response.content_length().is_some_and(|len| len > max) {
+        return Err(too_large());
+    }
+
+    /* The announced length may be missing (or wrong) read one byte more to check */
+    let mut body: Vec<u8> = Vec::new();
+    response
+        .take(max + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| ProxyErr::new(format!("Failed to read response of {} : {}", url, e)))?;
+
+    if body.len() as u64 > max {
+        return Err(too_large());
+    }
+
+    serde_json::from_slice(&body)
+        .map_err(|e| ProxyErr::new(format!("Bad JSON from {} : {}", url, e)))
+}
+
+/// URL of a proxy from its address, plain HTTP is assumed unless
+/// the address carries a scheme (TLS proxies advertise https://)
+#[allow(unused)]
+pub(crate) fn proxy_url(addr: &str) -> String {
+    if addr.starts_with("http://") || addr.starts_with("https://") {
+        addr.to_string()
+    } else {
+        format!("http://{}", addr)
+    }
+}
+
+/// URL of another proxy of the tree, addresses without a scheme take the
+/// one of reference (our own URL, https:// when TLS is configured)
+#[allow(unused)]
+pub(crate) fn peer_url(addr: &str, reference: &str) -> String {
+    if addr.contains("://") || !reference.starts_with("https://") {
+        proxy_url(addr)
+    } else {
+        format!("https://{}", addr)
+    }
+}
+
 #[allow(unused)]
 pub(crate) fn is_url_live(url: &str, html: bool) -> Result<(), Box<dyn Error>> {
-    let client = reqwest::blocking::Client::new();
+    is_url_live_with(&peer_client(PEER_TIMEOUT)?, url, html)
+}
+
+/// Same as is_url_live with a given client (and its timeouts)
+#[allow(unused)]
+pub(crate) fn is_url_live_with(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    html: bool,
+) -> Result<(), Box<dyn Error>> {
     let response = client.get(url).send()?;
 
     if response.status().is_success() {
@@ -195,6 +407,145 @@ pub fn get_proxy_path() -> String {
     format!("/tmp/metric-proxy-{}.socket", uid)
 }
 
+/// Socket paths starting with '@' designate a Linux abstract-namespace socket
+#[allow(unused)]
+pub(crate) fn abstract_socket_addr(socket_path: &str) -> Option<std::io::Result<SocketAddr>> {
+    socket_path
+        .strip_prefix('@')
+        .map(|name| SocketAddr::from_abstract_name(name.as_bytes()))
+}
+
+/// Listen on a UNIX socket (abstract for '@' paths) queuing up to
+/// backlog pending connections (std always uses 128)
+#[allow(unused)]
+pub(crate) fn unix_socket_listen(socket_path: &str, backlog: i32) -> std::io::Result<UnixListener> {
+    /* Abstract names are given to bind with a leading NUL byte */
+    let path = match socket_path.strip_prefix('@') {
+        Some(name) => format!("\0{}", name),
+        None => socket_path.to_string(),
+    };
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(backlog)?;
+
+    Ok(UnixListener::from(OwnedFd::from(socket)))
+}
+
+/// Uid of the process at the other end of a UNIX socket (SO_PEERCRED)
+#[allow(unused)]
+pub(crate) fn unix_peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(cred.uid)
+}
+
+/// Wakes up when files are written to or moved into a directory (inotify)
+#[allow(unused)]
+pub(crate) struct DirWatcher {
+    fd: OwnedFd,
+}
+
+#[allow(unused)]
+impl DirWatcher {
+    pub(crate) fn new(dir: &Path) -> Result<DirWatcher, ProxyErr> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+
+        if fd < 0 {
+            return Err(ProxyErr::new(format!(
+                "Failed to create an inotify instance : {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let cdir = CString::new(dir.as_os_str().as_bytes()).map_err(ProxyErr::new)?;
+
+        /* Files are only seen once complete: closed after writing or renamed in */
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                fd.as_raw_fd(),
+                cdir.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+            )
+        };
+
+        if wd < 0 {
+            return Err(ProxyErr::new(format!(
+                "Failed to watch {} : {}",
+                dir.to_string_lossy(),
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Ok(DirWatcher { fd })
+    }
+
+    /// Wait up to timeout for changes, returns true if some happened
+    /// (the pending events are consumed as the caller rescans the directory)
+    pub(crate) fn wait(&self, timeout: Duration) -> Result<bool, ProxyErr> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout) };
+
+        if ret < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(ProxyErr::new(format!("Failed to poll inotify : {}", e)));
+        }
+
+        if ret == 0 {
+            return Ok(false);
+        }
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len <= 0 {
+                break;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[allow(unused)]
+pub(crate) fn unix_socket_connect(socket_path: &str) -> std::io::Result<UnixStream> {
+    match abstract_socket_addr(socket_path) {
+        Some(addr) => UnixStream::connect_addr(&addr?),
+        None => UnixStream::connect(socket_path),
+    }
+}
+
 #[allow(unused)]
 pub fn parse_bool(sbool: &str) -> bool {
     matches!(sbool, "1" | "true")
@@ -264,3 +615,365 @@ pub fn getppid() -> Result<u32, Box<dyn Error>> {
         "Could not find PPID entry in /proc/self/status",
     ))
 }
+
+/// Statistics of the current process read from /proc/self
+#[allow(unused)]
+pub(crate) struct ProcessStats {
+    pub rss_bytes: f64,
+    pub virtual_bytes: f64,
+    pub threads: f64,
+    pub open_fds: f64,
+    pub voluntary_ctxt_switches: f64,
+    pub nonvoluntary_ctxt_switches: f64,
+    pub cpu_user_seconds: f64,
+    pub cpu_system_seconds: f64,
+}
+
+#[allow(unused)]
+impl ProcessStats {
+    /// Get a "Key: value" field from /proc/self/status
+    #[cfg(target_os = "linux")]
+    fn status_field(status: &str, key: &str) -> f64 {
+        for l in status.lines() {
+            if let Some(v) = l.strip_prefix(key).and_then(|v| v.strip_prefix(':')) {
+                let mut it = v.split_whitespace();
+                let value = it.next().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+                /* Memory fields are in kB */
+                return match it.next() {
+                    Some("kB") => value * 1024.0,
+                    _ => value,
+                };
+            }
+        }
+        0.0
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn read() -> Result<ProcessStats, ProxyErr> {
+        let status = fs::read_to_string("/proc/self/status").map_err(ProxyErr::new)?;
+        let stat = fs::read_to_string("/proc/self/stat").map_err(ProxyErr::new)?;
+
+        /* The command name may contain spaces, fields start after the closing parenthesis */
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .map(|(_, f)| f.split_whitespace().collect())
+            .unwrap_or_default();
+
+        if fields.len() < 13 {
+            return Err(ProxyErr::new("Failed to parse /proc/self/stat"));
+        }
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let ticks = if ticks > 0.0 { ticks } else { 100.0 };
+
+        /* utime and stime are the 14th and 15th fields */
+        let utime = fields[11].parse::<f64>().unwrap_or(0.0);
+        let stime = fields[12].parse::<f64>().unwrap_or(0.0);
+
+        let open_fds = fs::read_dir("/proc/self/fd")
+            .map(|d| d.count() as f64)
+            .unwrap_or(0.0);
+
+        Ok(ProcessStats {
+            rss_bytes: ProcessStats::status_field(&status, "VmRSS"),
+            virtual_bytes: ProcessStats::status_field(&status, "VmSize"),
+            threads: ProcessStats::status_field(&status, "Threads"),
+            open_fds,
+            voluntary_ctxt_switches: ProcessStats::status_field(&status, "voluntary_ctxt_switches"),
+            nonvoluntary_ctxt_switches: ProcessStats::status_field(
+                &status,
+                "nonvoluntary_ctxt_switches",
+            ),
+            cpu_user_seconds: utime / ticks,
+            cpu_system_seconds: stime / ticks,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn read() -> Result<ProcessStats, ProxyErr> {
+        Err(ProxyErr::new(
+            "Process statistics are only available on Linux",
+        ))
+    }
+
+    /// List of (name, doc, value) without prefix
+    pub(crate) fn values(&self) -> Vec<(&'static str, &'static str, f64)> {
+        vec![
+            (
+                "rss_bytes",
+                "Resident set size of the process in bytes",
+                self.rss_bytes,
+            ),
+            (
+                "virtual_bytes",
+                "Virtual memory size of the process in bytes",
+                self.virtual_bytes,
+            ),
+            ("threads", "Number of threads in the process", self.threads),
+            ("open_fds", "Number of open file descriptors", self.open_fds),
+            (
+                "voluntary_ctxt_switches",
+                "Number of voluntary context switches",
+                self.voluntary_ctxt_switches,
+            ),
+            (
+                "nonvoluntary_ctxt_switches",
+                "Number of involuntary context switches",
+                self.nonvoluntary_ctxt_switches,
+            ),
+            (
+                "cpu_user_seconds",
+                "User CPU time of the process in seconds",
+                self.cpu_user_seconds,
+            ),
+            (
+                "cpu_system_seconds",
+                "System CPU time of the process in seconds",
+                self.cpu_system_seconds,
+            ),
+        ]
+    }
+}
+
+/// Resource usage of the cgroup of the current process (the job on most
+/// batch systems), read from cgroup v2 or the v1 memory and cpuacct controllers
+#[allow(unused)]
+#[derive(Default)]
+pub(crate) struct CgroupStats {
+    pub memory_bytes: Option<f64>,
+    pub cpu_usage_seconds: Option<f64>,
+    pub cpu_user_seconds: Option<f64>,
+    pub cpu_system_seconds: Option<f64>,
+    pub pids: Option<f64>,
+}
+
+#[allow(unused)]
+impl CgroupStats {
+    const ROOT: &'static str = "/sys/fs/cgroup";
+
+    fn read_value(path: &str) -> Option<f64> {
+        fs::read_to_string(path).ok()?.trim().parse::<f64>().ok()
+    }
+
+    /// Get a "key value" field from a stat file
+    fn stat_field(stat: &str, key: &str) -> Option<f64> {
+        stat.lines()
+            .filter_map(|l| l.split_once(' '))
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, v)| v.trim().parse::<f64>().ok())
+    }
+
+    /// Path of the cgroup of the process for a v1 controller, "" for v2
+    fn own_cgroup(cgroups: &str, controller: &str) -> Option<String> {
+        cgroups.lines().find_map(|l| {
+            let mut it = l.splitn(3, ':');
+            let (_, controllers, path) = (it.next()?, it.next()?, it.next()?);
+            controllers
+                .split(',')
+                .any(|c| c == controller)
+                .then(|| path.to_string())
+        })
+    }
+
+    fn read_v2(path: &str) -> Option<CgroupStats> {
+        let dir = format!("{}{}", CgroupStats::ROOT, path);
+        let cpu = fs::read_to_string(format!("{}/cpu.stat", dir)).unwrap_or_default();
+
+        let ret = CgroupStats {
+            memory_bytes: CgroupStats::read_value(&format!("{}/memory.current", dir)),
+            cpu_usage_seconds: CgroupStats::stat_field(&cpu, "usage_usec").map(|v| v / 1e6),
+            cpu_user_seconds: CgroupStats::stat_field(&cpu, "user_usec").map(|v| v / 1e6),
+            cpu_system_seconds: CgroupStats::stat_field(&cpu, "system_usec").map(|v| v / 1e6),
+            pids: CgroupStats::read_value(&format!("{}/pids.current", dir)),
+        };
+
+        ret.has_values().then_some(ret)
+    }
+
+    fn read_v1(cgroups: &str) -> Option<CgroupStats> {
+        let mut ret = CgroupStats::default();
+
+        if let Some(path) = CgroupStats::own_cgroup(cgroups, "memory") {
+            ret.memory_bytes = CgroupStats::read_value(&format!(
+                "{}/memory{}/memory.usage_in_bytes",
+                CgroupStats::ROOT,
+                path
+            ));
+        }
+
+        if let Some(path) = CgroupStats::own_cgroup(cgroups, "cpuacct") {
+            /* The controller is often mounted with cpu as cpu,cpuacct */
+            for mount in ["cpuacct", "cpu,cpuacct"] {
+                let dir = format!("{}/{}{}", CgroupStats::ROOT, mount, path);
+                let usage = match CgroupStats::read_value(&format!("{}/cpuacct.usage", dir)) {
+                    Some(v) => v,
+                    None => continue,
+                };
+
+                let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+                let ticks = if ticks > 0.0 { ticks } else { 100.0 };
+                let stat = fs::read_to_string(format!("{}/cpuacct.stat", dir)).unwrap_or_default();
+
+                ret.cpu_usage_seconds = Some(usage / 1e9);
+                ret.cpu_user_seconds = CgroupStats::stat_field(&stat, "user").map(|v| v / ticks);
+                ret.cpu_system_seconds =
+                    CgroupStats::stat_field(&stat, "system").map(|v| v / ticks);
+                break;
+            }
+        }
+
+        if let Some(path) = CgroupStats::own_cgroup(cgroups, "pids") {
+            ret.pids = CgroupStats::read_value(&format!(
+                "{}/pids{}/pids.current",
+                CgroupStats::ROOT,
+                path
+            ));
+        }
+
+        ret.has_values().then_some(ret)
+    }
+
+    fn has_values(&self) -> bool {
+        !self.values().is_empty()
+    }
+
+    pub(crate) fn read() -> Result<CgroupStats, ProxyErr> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup")
+            .map_err(|e| ProxyErr::new(format!("No cgroup information : {}", e)))?;
+
+        /* The unified hierarchy is the 0:: entry */
+        let v2 = cgroups
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .and_then(CgroupStats::read_v2);
+
+        v2.or_else(|| CgroupStats::read_v1(&cgroups))
+            .ok_or_else(|| ProxyErr::new("No readable cgroup for this process"))
+    }
+
+    /// List of (name, doc, value) without prefix, only the values available
+    pub(crate) fn values(&self) -> Vec<(&'static str, &'static str, f64)> {
+        [
+            (
+                "memory_bytes",
+                "Memory used by the cgroup of the job in bytes",
+                self.memory_bytes,
+            ),
+            (
+                "cpu_usage_seconds",
+                "CPU time used by the cgroup of the job in seconds",
+                self.cpu_usage_seconds,
+            ),
+            (
+                "cpu_user_seconds",
+                "User CPU time used by the cgroup of the job in seconds",
+                self.cpu_user_seconds,
+            ),
+            (
+                "cpu_system_seconds",
+                "System CPU time used by the cgroup of the job in seconds",
+                self.cpu_system_seconds,
+            ),
+            (
+                "pids",
+                "Number of processes in the cgroup of the job",
+                self.pids,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, doc, value)| value.map(|v| (name, doc, v)))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_socket_rebinds_after_drop() {
+        let name = format!("@proxy-rebind-{}", std::process::id());
+        let listener = unix_socket_listen(&name, 16).unwrap();
+        let addr = abstract_socket_addr(&name).unwrap().unwrap();
+        let client = UnixStream::connect_addr(&addr).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        drop((client, server, listener));
+        unix_socket_listen(&name, 16).unwrap();
+
+        /* File sockets leave a stale file the proxy removes before binding */
+        let path = std::env::temp_dir().join(format!("proxy-rebind-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let listener = unix_socket_listen(path, 16).unwrap();
+        let client = UnixStream::connect(path).unwrap();
+        drop((client, listener));
+        std::fs::remove_file(path).unwrap();
+        unix_socket_listen(path, 16).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn peer_urls_follow_our_scheme() {
+        assert_eq!(peer_url("root:1", "node:1"), "http://root:1");
+        assert_eq!(peer_url("root:1", "https://node:1"), "https://root:1");
+        assert_eq!(peer_url("http://root:1", "https://node:1"), "http://root:1");
+        assert_eq!(peer_url("https://root:1", "node:1"), "https://root:1");
+    }
+
+    #[test]
+    fn set_variables_are_expanded() {
+        let var = format!("PROXY_TEST_SCRATCH_{}", std::process::id());
+        env::set_var(&var, "/scratch/u1");
+
+        assert_eq!(expand_path(&format!("${}/proxy", var)), "/scratch/u1/proxy");
+        assert_eq!(
+            expand_path(&format!("${{{}}}_x/proxy", var)),
+            "/scratch/u1_x/proxy"
+        );
+
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            expand_path("~/.proxyprofiles"),
+            format!("{}/.proxyprofiles", home.to_string_lossy())
+        );
+        assert_eq!(expand_path("/a/~b"), "/a/~b");
+
+        env::remove_var(&var);
+    }
+
+    #[test]
+    fn unset_variables_are_kept() {
+        let var = format!("PROXY_TEST_UNSET_{}", std::process::id());
+        env::remove_var(&var);
+
+        for path in [
+            format!("${}/proxy", var),
+            format!("${{{}}}/proxy", var),
+            "/a/${unterminated".to_string(),
+            "/a/$".to_string(),
+            "/a/$/b".to_string(),
+        ] {
+            assert_eq!(expand_path(&path), path);
+        }
+    }
+
+    #[test]
+    fn dir_watcher_sees_written_files() {
+        let dir = std::env::temp_dir().join(format!("proxy-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let watcher = DirWatcher::new(&dir).unwrap();
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+
+        fs::write(dir.join("a.profile"), "{}").unwrap();
+        assert!(watcher.wait(Duration::from_secs(5)).unwrap());
+
+        /* Events were consumed by the previous wait */
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+
+        fs::write(dir.join("b.tmp"), "{}").unwrap();
+        fs::rename(dir.join("b.tmp"), dir.join("b.profile")).unwrap();
+        assert!(watcher.wait(Duration::from_secs(5)).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
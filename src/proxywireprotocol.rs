@@ -1,10 +1,14 @@
 use crate::proxy_common::unix_ts;
 use crate::proxy_common::unix_ts_us;
+use crate::proxy_common::with_proxy_auth;
 use crate::proxy_common::ProxyErr;
+use crate::proxy_common::{peer_client, read_json_capped, PEER_MAX_BODY, PEER_TIMEOUT};
 
 use serde::{Deserialize, Serialize, Serializer};
+use std::collections::VecDeque;
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use std::{collections::HashMap, env, error::Error};
 
@@ -15,6 +19,46 @@ fn ser_f64_nan_as_zero<S: Serializer>(v: &f64, s: S) -> Result<S::Ok, S::Error>
     s.serialize_f64(if v.is_nan() || v.is_infinite() { 0.0 } else { *v })
 }
 
+/// Significant digits of values in the Prometheus output (0 = shortest exact)
+static METRIC_PRECISION: AtomicUsize = AtomicUsize::new(6);
+
+#[allow(unused)]
+pub(crate) fn set_metric_precision(precision: usize) {
+    METRIC_PRECISION.store(precision, Ordering::Relaxed);
+}
+
+/// Format a value for the Prometheus text format
+///
+/// Non finite values follow the spec (NaN, +Inf, -Inf), integers are
+/// kept exact and other values are rounded to METRIC_PRECISION digits.
+#[allow(unused)]
+pub(crate) fn format_metric_value(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+
+    if value.is_infinite() {
+        return if value > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+
+    let precision = METRIC_PRECISION.load(Ordering::Relaxed);
+
+    if precision == 0 || value.fract() == 0.0 {
+        return format!("{}", value);
+    }
+
+    let magnitude = value.abs().log10().floor() as i32;
+    let decimals = precision as i32 - 1 - magnitude;
+
+    if decimals <= 0 {
+        let scale = 10_f64.powi(-decimals);
+        return format!("{}", (value / scale).round() * scale);
+    }
+
+    let ret = format!("{:.*}", decimals as usize, value);
+    ret.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum CounterType {
     Counter {
@@ -30,6 +74,17 @@ pub enum CounterType {
         #[serde(serialize_with = "ser_f64_nan_as_zero")]
         total: f64,
     },
+    /// Last value set with the time (us) it was set at
+    LastValue {
+        ts: u64,
+        #[serde(serialize_with = "ser_f64_nan_as_zero")]
+        value: f64,
+    },
+    /// Counter which may decrease (and go negative), clients send increments
+    UpDown {
+        ts: u64,
+        value: f64,
+    },
 }
 
 impl fmt::Display for CounterType {
@@ -47,13 +102,19 @@ impl fmt::Display for CounterType {
                 write!(
                     f,
                     "{} (Min: {}, Max : {}, Hits: {}, Total : {}) GAUGE",
-                    total / hits,
+                    self.value(),
                     min,
                     max,
                     hits,
                     total
                 )
             }
+            CounterType::LastValue { ts, value } => {
+                write!(f, "{} {} LASTVALUE", ts, value)
+            }
+            CounterType::UpDown { ts, value } => {
+                write!(f, "{} {} UPDOWN", ts, value)
+            }
         }
     }
 }
@@ -68,6 +129,8 @@ impl CounterType {
                 hits: _,
                 total: _,
             } => unix_ts_us(),
+            Self::LastValue { ts, value: _ } => *ts,
+            Self::UpDown { ts, value: _ } => *ts,
         }
     }
 
@@ -86,6 +149,27 @@ impl CounterType {
         }
     }
 
+    #[allow(unused)]
+    pub fn newlastvalue() -> CounterType {
+        Self::LastValue { ts: 0, value: 0.0 }
+    }
+
+    #[allow(unused)]
+    pub fn newupdown() -> CounterType {
+        Self::UpDown { ts: 0, value: 0.0 }
+    }
+
+    /// Name of the kind of counter (as in /metrics/meta)
+    #[allow(unused)]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CounterType::Counter { .. } => "counter",
+            CounterType::Gauge { .. } => "gauge",
+            CounterType::LastValue { .. } => "lastvalue",
+            CounterType::UpDown { .. } => "updown",
+        }
+    }
+
     #[allow(unused)]
     pub fn clean_nan(&mut self) {
         match self {
@@ -113,6 +197,11 @@ impl CounterType {
                     *total = 0.0;
                 }
             }
+            Self::LastValue { ts: _, value } | Self::UpDown { ts: _, value } => {
+                if value.is_infinite() || value.is_nan() {
+                    *value = 0.0;
+                }
+            }
         }
     }
 
@@ -126,6 +215,8 @@ impl CounterType {
                 hits,
                 total: _,
             } => *hits != 0.0,
+            /* A value set (or brought back) to 0 is still data */
+            Self::LastValue { ts, value: _ } | Self::UpDown { ts, value: _ } => *ts != 0,
         }
     }
 
@@ -138,22 +229,47 @@ impl CounterType {
                 max: _,
                 hits,
                 total,
-            } => *total / *hits,
+            } => {
+                /* A gauge without observations has no average */
+                if *hits == 0.0 {
+                    0.0
+                } else {
+                    *total / *hits
+                }
+            }
+            Self::LastValue { ts: _, value } => *value,
+            Self::UpDown { ts: _, value } => *value,
         }
     }
 
     fn serialize(&self, name: &String) -> String {
         match self {
             Self::Counter { ts, value } => {
-                format!("{} {} {}\n", name, ts, value)
+                format!("{} {} {}\n", name, ts, format_metric_value(*value))
             }
-            Self::Gauge {
-                min: _,
-                max: _,
-                hits,
-                total,
-            } => {
-                format!("{} {}\n", name, total / hits,)
+            Self::Gauge { .. } => {
+                format!("{} {}\n", name, format_metric_value(self.value()))
+            }
+            /* Not monotonic, exposed without the counter timestamp like a gauge */
+            Self::LastValue { ts: _, value } | Self::UpDown { ts: _, value } => {
+                format!("{} {}\n", name, format_metric_value(*value))
+            }
+        }
+    }
+
+    /// Same as serialize but appends an OpenMetrics exemplar
+    /// pointing to the trace on counter lines
+    fn serialize_with_exemplar(&self, name: &String, trace_id: &str, trace_ts: f64) -> String {
+        match self {
+            Self::Counter { ts, value } => {
+                let value = format_metric_value(*value);
+                format!(
+                    "{} {} {} # {{trace_id=\"{}\"}} {} {}\n",
+                    name, ts, value, trace_id, value, trace_ts
+                )
+            }
+            Self::Gauge { .. } | Self::LastValue { .. } | Self::UpDown { .. } => {
+                self.serialize(name)
             }
         }
     }
@@ -168,7 +284,8 @@ impl CounterType {
                         ts: sts,
                         value: svalue,
                     } => {
-                        *sts = (*ts + *sts) / 2;
+                        /* The newest sample gives the timestamp */
+                        *sts = (*sts).max(*ts);
                         *svalue += *value;
                         Ok(())
                     }
@@ -198,6 +315,36 @@ impl CounterType {
                     _ => unreachable!(),
                 }
             }
+            CounterType::LastValue { ts, value } => {
+                /* The newest sample wins whichever node it comes from */
+                match self {
+                    CounterType::LastValue {
+                        ts: sts,
+                        value: svalue,
+                    } => {
+                        if *ts >= *sts {
+                            *sts = *ts;
+                            *svalue = *value;
+                        }
+                        Ok(())
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            CounterType::UpDown { ts, value } => {
+                /* Increments add up whatever their sign */
+                match self {
+                    CounterType::UpDown {
+                        ts: sts,
+                        value: svalue,
+                    } => {
+                        *sts = (*sts).max(*ts);
+                        *svalue += *value;
+                        Ok(())
+                    }
+                    _ => unreachable!(),
+                }
+            }
         }
     }
 
@@ -212,7 +359,8 @@ impl CounterType {
                         ts: sts,
                         value: svalue,
                     } => {
-                        *sts = (*ts + *sts) / 2;
+                        /* The newest sample gives the timestamp */
+                        *sts = (*sts).max(*ts);
                         *svalue += *value;
                         Ok(())
                     }
@@ -242,6 +390,36 @@ impl CounterType {
                     _ => unreachable!(),
                 }
             }
+            CounterType::LastValue { ts, value } => {
+                /* The newest sample wins whichever node it comes from */
+                match self {
+                    CounterType::LastValue {
+                        ts: sts,
+                        value: svalue,
+                    } => {
+                        if *ts >= *sts {
+                            *sts = *ts;
+                            *svalue = *value;
+                        }
+                        Ok(())
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            CounterType::UpDown { ts, value } => {
+                /* Increments add up whatever their sign */
+                match self {
+                    CounterType::UpDown {
+                        ts: sts,
+                        value: svalue,
+                    } => {
+                        *sts = (*sts).max(*ts);
+                        *svalue += *value;
+                        Ok(())
+                    }
+                    _ => unreachable!(),
+                }
+            }
         }
     }
 
@@ -265,28 +443,49 @@ impl CounterType {
                 }
             }
             CounterType::Gauge {
-                min,
-                max,
+                min: _,
+                max: _,
                 hits,
                 total,
             } => {
-                /* Here we sum the values and keep min and max accordingly */
+                /* Only the accumulation fields have a delta, min and max
+                are carried forward from the current value as they cannot be
+                un-merged from the previous one */
                 match self {
                     CounterType::Gauge {
-                        min: smin,
-                        max: smax,
+                        min: _,
+                        max: _,
                         hits: shits,
                         total: stotal,
                     } => {
-                        *smin = min_f64(*smin, *min);
-                        *smax = max_f64(*smax, *max);
                         *shits -= hits;
                         *stotal -= total;
+                        /* No new observation (or a reset) means no data */
+                        if *shits <= 0.0 {
+                            *shits = 0.0;
+                            *stotal = 0.0;
+                        }
                         Ok(())
                     }
                     _ => unreachable!(),
                 }
             }
+            /* A last value has no delta, the current one is kept */
+            CounterType::LastValue { .. } => Ok(()),
+            /* A decrease is a legitimate delta, not a reset */
+            CounterType::UpDown { ts, value } => match self {
+                CounterType::UpDown {
+                    ts: sts,
+                    value: svalue,
+                } => {
+                    if *sts > *ts {
+                        *sts -= ts;
+                    }
+                    *svalue -= *value;
+                    Ok(())
+                }
+                _ => unreachable!(),
+            },
         }
     }
 
@@ -294,6 +493,8 @@ impl CounterType {
         match (&self, &other) {
             (CounterType::Gauge { .. }, CounterType::Gauge { .. }) => Ok(()),
             (CounterType::Counter { .. }, CounterType::Counter { .. }) => Ok(()),
+            (CounterType::LastValue { .. }, CounterType::LastValue { .. }) => Ok(()),
+            (CounterType::UpDown { .. }, CounterType::UpDown { .. }) => Ok(()),
             _ => Err(ProxyErr::new(format!(
                 "Both instances are not of the same variant {:?} and {:?}",
                 self, other
@@ -310,16 +511,27 @@ pub(crate) enum AlarmOperator {
     Less(f64),
     #[allow(unused)]
     More(f64),
+    #[allow(unused)]
+    LessEqual(f64),
+    #[allow(unused)]
+    GreaterEqual(f64),
+    #[allow(unused)]
+    NotEqual(f64),
 }
 
 impl AlarmOperator {
     fn apply(&self, val: &CounterType) -> bool {
-        let value: f64 = val.value();
+        self.apply_value(val.value())
+    }
 
+    fn apply_value(&self, value: f64) -> bool {
         match self {
             Self::Equal(v) => *v == value,
             Self::Less(v) => *v > value,
             Self::More(v) => *v < value,
+            Self::LessEqual(v) => value <= *v,
+            Self::GreaterEqual(v) => value >= *v,
+            Self::NotEqual(v) => *v != value,
         }
     }
 }
@@ -330,6 +542,81 @@ impl fmt::Display for AlarmOperator {
             Self::Equal(v) => write!(f, "= {}", *v),
             Self::Less(v) => write!(f, "< {}", *v),
             Self::More(v) => write!(f, "> {}", *v),
+            Self::LessEqual(v) => write!(f, "<= {}", *v),
+            Self::GreaterEqual(v) => write!(f, ">= {}", *v),
+            Self::NotEqual(v) => write!(f, "!= {}", *v),
+        }
+    }
+}
+
+/// How the values of an alarm window are reduced
+#[derive(Clone, Debug)]
+#[allow(unused)]
+pub(crate) enum AlarmAggregation {
+    Mean,
+    /// Nearest-rank percentile in (0, 100]
+    Percentile(f64),
+}
+
+/// Alarm evaluated over the values of the last seconds instead of the current one
+#[derive(Clone, Debug)]
+#[allow(unused)]
+pub(crate) struct AlarmWindow {
+    seconds: u64,
+    aggregation: AlarmAggregation,
+}
+
+impl AlarmWindow {
+    /// Aggregation is "mean" or "pNN" (as p95)
+    #[allow(unused)]
+    pub(crate) fn new(seconds: u64, aggregation: &str) -> Result<AlarmWindow, ProxyErr> {
+        let aggregation = match aggregation {
+            "mean" => AlarmAggregation::Mean,
+            p => match p.strip_prefix('p').and_then(|v| v.parse::<f64>().ok()) {
+                Some(v) if v > 0.0 && v <= 100.0 => AlarmAggregation::Percentile(v),
+                _ => {
+                    return Err(ProxyErr::new(format!(
+                        "Bad alarm aggregation {} expected mean or pNN (as p95)",
+                        aggregation
+                    )))
+                }
+            },
+        };
+
+        if seconds == 0 {
+            return Err(ProxyErr::new("Alarm window must be at least one second"));
+        }
+
+        Ok(AlarmWindow {
+            seconds,
+            aggregation,
+        })
+    }
+
+    fn reduce(&self, samples: &VecDeque<(u64, f64)>) -> f64 {
+        if samples.is_empty() {
+            return 0.0;
+        }
+
+        match self.aggregation {
+            AlarmAggregation::Mean => {
+                samples.iter().map(|(_, v)| v).sum::<f64>() / samples.len() as f64
+            }
+            AlarmAggregation::Percentile(p) => {
+                let mut values: Vec<f64> = samples.iter().map(|(_, v)| *v).collect();
+                values.sort_by(|a, b| a.total_cmp(b));
+                let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+                values[rank.clamp(1, values.len()) - 1]
+            }
+        }
+    }
+}
+
+impl fmt::Display for AlarmWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.aggregation {
+            AlarmAggregation::Mean => write!(f, "mean over {}s", self.seconds),
+            AlarmAggregation::Percentile(p) => write!(f, "p{} over {}s", p, self.seconds),
         }
     }
 }
@@ -344,21 +631,37 @@ pub(crate) struct ValueAlarmTrigger {
     pub(crate) pretty: String,
 }
 
+/// A change of state of an alarm
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct AlarmEvent {
+    /// Unix timestamp in ms
+    pub(crate) ts: u64,
+    pub(crate) active: bool,
+    pub(crate) value: f64,
+}
+
 pub(crate) struct ValueAlarm {
     name: String,
     counter: Arc<RwLock<CounterSnapshot>>,
     op: AlarmOperator,
+    /// State seen by the last check
+    active: Mutex<bool>,
+    /// Last transitions, oldest first
+    history: Mutex<VecDeque<AlarmEvent>>,
+    history_len: usize,
+    /// Evaluate the aggregate of recent values instead of the current one
+    window: Option<AlarmWindow>,
+    /// Values (ts in ms) seen by check within the window, oldest first
+    samples: Mutex<VecDeque<(u64, f64)>>,
 }
 
 impl fmt::Display for ValueAlarm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} : {} {}",
-            self.name,
-            self.counter.read().unwrap(),
-            self.op
-        )
+        write!(f, "{} : {}", self.name, self.counter.read().unwrap())?;
+        if let Some(window) = &self.window {
+            write!(f, " {}", window)?;
+        }
+        write!(f, " {}", self.op)
     }
 }
 
@@ -369,14 +672,18 @@ impl ValueAlarm {
         counter: Arc<RwLock<CounterSnapshot>>,
         op: String,
         val: f64,
+        history_len: usize,
     ) -> Result<ValueAlarm, ProxyErr> {
         let alop = match op.as_str() {
             "=" => AlarmOperator::Equal(val),
             "<" => AlarmOperator::Less(val),
             ">" => AlarmOperator::More(val),
+            "<=" => AlarmOperator::LessEqual(val),
+            ">=" => AlarmOperator::GreaterEqual(val),
+            "!=" => AlarmOperator::NotEqual(val),
             _ => {
                 return Err(ProxyErr::new(format!(
-                    "No operator for {} only has = < and >",
+                    "No operator for {} only has = != < <= > and >=",
                     op
                 )));
             }
@@ -386,15 +693,46 @@ impl ValueAlarm {
             name: name.to_string(),
             counter: counter.clone(),
             op: alop,
+            active: Mutex::new(false),
+            history: Mutex::new(VecDeque::new()),
+            history_len,
+            window: None,
+            samples: Mutex::new(VecDeque::new()),
         })
     }
 
+    /// Evaluate the alarm over a trailing window
+    #[allow(unused)]
+    pub(crate) fn with_window(mut self, window: Option<AlarmWindow>) -> ValueAlarm {
+        self.window = window;
+        self
+    }
+
+    /// Value the operator is applied to, the aggregate of the window if any
+    fn observe(&self, value: f64) -> f64 {
+        let window = match &self.window {
+            Some(w) => w,
+            None => return value,
+        };
+
+        let now = unix_ts();
+        let mut samples = self.samples.lock().unwrap();
+
+        samples.push_back((now, value));
+        let deadline = now.saturating_sub(window.seconds * 1000);
+        samples.retain(|(ts, _)| *ts >= deadline);
+
+        window.reduce(&samples)
+    }
+
     #[allow(unused)]
     pub(crate) fn as_trigger(&self, active: Option<bool>) -> ValueAlarmTrigger {
         let cnt_locked = self.counter.read().unwrap();
 
         let is_active = match active {
             Some(v) => v,
+            /* Windowed alarms keep the state of their last check */
+            None if self.window.is_some() => *self.active.lock().unwrap(),
             None => self.op.apply(&self.counter.read().unwrap().ctype),
         };
 
@@ -408,14 +746,41 @@ impl ValueAlarm {
         }
     }
 
+    /// Check the alarm recording a transition if its state changed
     #[allow(unused)]
     pub(crate) fn check(&self) -> Option<ValueAlarmTrigger> {
-        if self.op.apply(&self.counter.read().unwrap().ctype) {
+        let value = self.observe(self.counter.read().unwrap().ctype.value());
+        let is_active = self.op.apply_value(value);
+
+        let mut active = self.active.lock().unwrap();
+
+        if *active != is_active {
+            *active = is_active;
+
+            if self.history_len > 0 {
+                let mut history = self.history.lock().unwrap();
+                if history.len() >= self.history_len {
+                    history.pop_front();
+                }
+                history.push_back(AlarmEvent {
+                    ts: unix_ts(),
+                    active: is_active,
+                    value,
+                });
+            }
+        }
+
+        if is_active {
             Some(self.as_trigger(Some(true)))
         } else {
             None
         }
     }
+
+    #[allow(unused)]
+    pub(crate) fn history(&self) -> Vec<AlarmEvent> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -447,6 +812,8 @@ impl CounterValue {
                 hits: 0.0,
                 total: 0.0,
             },
+            CounterType::LastValue { ts: _, value } => CounterType::LastValue { ts: 0, value },
+            CounterType::UpDown { .. } => CounterType::UpDown { ts: 0, value: 0.0 },
         };
     }
 
@@ -461,6 +828,11 @@ impl CounterValue {
                 hits,
                 total,
             } => {}
+            /* The time of the last set is kept */
+            CounterType::LastValue { .. } => {}
+            CounterType::UpDown { ts, value: _ } => {
+                *ts = to_set_ts;
+            }
         }
         self
     }
@@ -571,6 +943,54 @@ pub(crate) enum ProxyCommand {
     Desc(ValueDesc),
     Value(CounterValue),
     JobDesc(JobDesc),
+    /// Sent by the client on release to end the job explicitly
+    JobEnd {
+        jobid: String,
+        end_time: u64,
+    },
+    /// Read back the values of the job (or main) exporter,
+    /// a trailing '*' in the name matches any suffix
+    Get {
+        name: String,
+    },
+    /// Same as Get on the values aggregated on the node (all its jobs)
+    GetNode {
+        name: String,
+    },
+    /// Counters of the client published in a shared memory segment (see shm.rs)
+    /// the proxy reads them every period, sent again when slots are added
+    ShmRegister {
+        path: String,
+        slots: Vec<ShmSlot>,
+    },
+    /// Label to record in the trace of the job, ts is a unix timestamp in us
+    Annotation {
+        ts: u64,
+        label: String,
+    },
+}
+
+/// Name of the counter held in a slot of a shared memory segment
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ShmSlot {
+    pub(crate) name: String,
+    pub(crate) slot: usize,
+}
+
+/// Reply of the proxy to a `ProxyCommand::Get`, `GetNode` or `ShmRegister`
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum ProxyResponse {
+    Values(Vec<CounterSnapshot>),
+    Error(String),
+}
+
+/// Does a metric name match a Get pattern (exact or prefix*)
+#[allow(unused)]
+pub(crate) fn metric_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -602,6 +1022,37 @@ pub fn max_f64(a: f64, b: f64) -> f64 {
     }
 }
 
+/// Append the `{k="v"}` label suffix to a metric name
+#[allow(unused)]
+fn label_pair(key: &str, value: &str) -> String {
+    format!("{}=\"{}\"", key, value.replace('"', "\\\""))
+}
+
+pub fn labeled_name(name: String, attributes: &[(String, String)]) -> String {
+    let attrs: Vec<String> = attributes.iter().map(|(k, v)| label_pair(k, v)).collect();
+    match attrs.len() {
+        0 => name,
+        _ => format!("{}{{{}}}", name, attrs.join(",")),
+    }
+}
+
+/// Append a label to a name which may already have some
+#[allow(unused)]
+pub(crate) fn add_label(name: &str, key: &str, value: &str) -> String {
+    match name.strip_suffix('}') {
+        Some(base) if base.ends_with('{') => format!("{}{}}}", base, label_pair(key, value)),
+        Some(base) if base.contains('{') => format!("{},{}}}", base, label_pair(key, value)),
+        _ => labeled_name(name.to_string(), &[(key.to_string(), value.to_string())]),
+    }
+}
+
+/// Does the name carry the given label
+#[allow(unused)]
+pub(crate) fn has_label(name: &str, key: &str, value: &str) -> bool {
+    let pair = label_pair(key, value);
+    name.contains(&format!("{{{}", pair)) || name.contains(&format!(",{}", pair))
+}
+
 impl CounterSnapshot {
     #[allow(unused)]
     pub fn new(
@@ -610,14 +1061,7 @@ impl CounterSnapshot {
         doc: String,
         value: CounterType,
     ) -> CounterSnapshot {
-        let attrs: Vec<String> = attributes
-            .iter()
-            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('"', "\\\"")))
-            .collect();
-        let name = match attrs.len() {
-            0 => name,
-            _ => format!("{}{{{}}}", name, attrs.join(",")),
-        };
+        let name = labeled_name(name, attributes);
 
         CounterSnapshot {
             name,
@@ -641,6 +1085,12 @@ impl CounterSnapshot {
         self.ctype.serialize(&self.name)
     }
 
+    #[allow(unused)]
+    pub fn serialize_with_exemplar(&self, trace_id: &str, trace_ts: f64) -> String {
+        self.ctype
+            .serialize_with_exemplar(&self.name, trace_id, trace_ts)
+    }
+
     pub fn merge(&mut self, other: &CounterSnapshot) -> Result<(), ProxyErr> {
         self.ctype.merge(&other.ctype)
     }
@@ -664,25 +1114,99 @@ impl CounterSnapshot {
 
     #[allow(unused)]
     pub(crate) fn float_value(&self) -> f64 {
-        match self.ctype {
-            CounterType::Counter { ts: _, value } => value,
-            CounterType::Gauge {
-                min: _,
-                max: _,
-                hits,
-                total,
-            } => total / hits,
-        }
+        self.ctype.value()
     }
 }
 
+/// Difference of a metric between two profiles
+/// values are None when the metric is missing on one side
+#[derive(Serialize, Debug)]
+pub(crate) struct MetricDiff {
+    pub(crate) name: String,
+    pub(crate) a: Option<f64>,
+    pub(crate) b: Option<f64>,
+    /// b - a
+    pub(crate) delta: Option<f64>,
+    /// Change from a to b in percent of a
+    pub(crate) percent: Option<f64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct JobProfile {
     pub(crate) desc: JobDesc,
     pub(crate) counters: Vec<CounterSnapshot>,
 }
 
+/// Prefix of the metrics computed from a profile (not measured)
+#[allow(unused)]
+const DERIVED_PREFIX: &str = "derived_";
+
+/// Counters of metric_proxy_init and metric_proxy_release, looked up by
+/// name to know if a job completed so they are never prefixed
+#[allow(unused)]
+pub(crate) const JOB_LIFECYCLE_METRICS: [&str; 2] = ["has_started", "has_finished"];
+
+/// Counters with these suffixes hold bytes and get a throughput
+#[allow(unused)]
+const BYTE_SUFFIXES: [&str; 2] = ["_bytes", "_bytes_total"];
+
 impl JobProfile {
+    /// Insert derived_* metrics computed from the job duration:
+    /// the walltime in seconds and the throughput of byte counters
+    #[allow(unused)]
+    pub(crate) fn derive(&mut self) {
+        /* Previous derivations are replaced */
+        self.counters
+            .retain(|c| !c.name.starts_with(DERIVED_PREFIX));
+
+        /* Running jobs have no end time yet */
+        if self.desc.end_time <= self.desc.start_time {
+            return;
+        }
+
+        let walltime = (self.desc.end_time - self.desc.start_time) as f64 / 1000.0;
+
+        let mut derived = vec![CounterSnapshot::new(
+            format!("{}job_walltime_seconds", DERIVED_PREFIX),
+            &[],
+            "Duration of the job in seconds (derived from its start and end time)".to_string(),
+            CounterType::Gauge {
+                min: walltime,
+                max: walltime,
+                hits: 1.0,
+                total: walltime,
+            },
+        )];
+
+        for c in self.counters.iter() {
+            if let CounterType::Counter { value, .. } = c.ctype {
+                let (base, labels) = match c.name.find('{') {
+                    Some(idx) => c.name.split_at(idx),
+                    None => (c.name.as_str(), ""),
+                };
+
+                if !BYTE_SUFFIXES.iter().any(|s| base.ends_with(s)) {
+                    continue;
+                }
+
+                let base = base.strip_suffix("_total").unwrap_or(base);
+
+                derived.push(CounterSnapshot {
+                    name: format!("{}{}_per_second{}", DERIVED_PREFIX, base, labels),
+                    doc: format!("Average throughput of {} over the job (derived)", c.name),
+                    ctype: CounterType::Gauge {
+                        min: value / walltime,
+                        max: value / walltime,
+                        hits: 1.0,
+                        total: value / walltime,
+                    },
+                });
+            }
+        }
+
+        self.counters.append(&mut derived);
+    }
+
     pub(crate) fn add_duration(&mut self) -> Result<bool, ProxyErr> {
         for c in self.counters.iter() {
             if c.name == "walltime" {
@@ -744,6 +1268,8 @@ impl JobProfile {
                     *max = f64::MIN;
                 }
                 CounterType::Counter { ts: _, value: _ } => {}
+                CounterType::LastValue { ts: _, value: _ } => {}
+                CounterType::UpDown { ts: _, value: _ } => {}
             }
         }
 
@@ -794,8 +1320,56 @@ impl JobProfile {
         None
     }
 
+    /// Compare this profile (a) to another one (b) metric by metric
+    #[allow(unused)]
+    pub(crate) fn diff(&self, other: &JobProfile) -> Vec<MetricDiff> {
+        let mine: HashMap<&String, f64> = self
+            .counters
+            .iter()
+            .map(|v| (&v.name, v.ctype.value()))
+            .collect();
+        let theirs: HashMap<&String, f64> = other
+            .counters
+            .iter()
+            .map(|v| (&v.name, v.ctype.value()))
+            .collect();
+
+        let mut names: Vec<&String> = mine.keys().chain(theirs.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let a = mine.get(name).cloned();
+                let b = theirs.get(name).cloned();
+
+                let (delta, percent) = match (a, b) {
+                    (Some(a), Some(b)) => {
+                        let percent = if a != 0.0 {
+                            Some((b - a) * 100.0 / a.abs())
+                        } else {
+                            None
+                        };
+                        (Some(b - a), percent)
+                    }
+                    _ => (None, None),
+                };
+
+                MetricDiff {
+                    name: name.to_string(),
+                    a,
+                    b,
+                    delta,
+                    percent,
+                }
+            })
+            .collect()
+    }
+
     pub(crate) fn did_complete(&self) -> bool {
-        if let (Some(start), Some(end)) = (self.get("has_started"), self.get("has_finished")) {
+        let [started, finished] = JOB_LIFECYCLE_METRICS;
+        if let (Some(start), Some(end)) = (self.get(started), self.get(finished)) {
             return (start.value().value.value() != 0.0) && (end.value().value.value() != 0.0);
         }
 
@@ -816,11 +1390,11 @@ pub(crate) struct ApiResponse {
 impl ApiResponse {
     #[allow(unused)]
     pub fn query(url: &String) -> Result<ApiResponse, Box<dyn Error>> {
-        let client = reqwest::blocking::Client::new();
-        let response = client.get(url).send()?;
+        let client = peer_client(PEER_TIMEOUT)?;
+        let response = with_proxy_auth(client.get(url)).send()?;
 
         if response.status().is_success() {
-            let resp: ApiResponse = response.json()?;
+            let resp: ApiResponse = read_json_capped(response, PEER_MAX_BODY)?;
             Ok(resp)
         } else {
             Err(ProxyErr::newboxed(
@@ -835,3 +1409,189 @@ impl ApiResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge_profile(min: f64, max: f64, hits: f64, total: f64) -> JobProfile {
+        JobProfile {
+            desc: JobDesc::new(),
+            counters: vec![CounterSnapshot {
+                name: "load".to_string(),
+                doc: String::new(),
+                ctype: CounterType::Gauge {
+                    min,
+                    max,
+                    hits,
+                    total,
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn metric_values_follow_the_text_format() {
+        /* Default precision of 6 significant digits */
+        assert_eq!(format_metric_value(100.0 / 3.0), "33.3333");
+        assert_eq!(format_metric_value(0.000123456789), "0.000123457");
+        assert_eq!(format_metric_value(2.5), "2.5");
+
+        /* Large counters are exact and never in scientific notation */
+        assert_eq!(format_metric_value(123456789012.0), "123456789012");
+        assert_eq!(format_metric_value(1e17), "100000000000000000");
+        assert_eq!(format_metric_value(1234567.891), "1234570");
+
+        assert_eq!(format_metric_value(f64::NAN), "NaN");
+        assert_eq!(format_metric_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_metric_value(f64::NEG_INFINITY), "-Inf");
+
+        /* A gauge never observed */
+        let gauge = CounterType::Gauge {
+            min: 0.0,
+            max: 0.0,
+            hits: 0.0,
+            total: 0.0,
+        };
+        assert_eq!(gauge.serialize(&"load".to_string()), "load 0\n");
+
+        let gauge = CounterType::Gauge {
+            min: f64::NAN,
+            max: f64::NAN,
+            hits: 1.0,
+            total: f64::NAN,
+        };
+        assert_eq!(gauge.serialize(&"load".to_string()), "load NaN\n");
+
+        let counter = CounterType::Counter {
+            ts: 12,
+            value: 9007199254740992.0,
+        };
+        assert_eq!(
+            counter.serialize(&"calls".to_string()),
+            "calls 12 9007199254740992\n"
+        );
+    }
+
+    #[test]
+    fn gauge_deltas_stay_finite() {
+        /* Two new observations of 4 and 6 since the previous snapshot */
+        let mut current = gauge_profile(1.0, 6.0, 12.0, 40.0);
+        current
+            .substract(&gauge_profile(1.0, 9.0, 10.0, 30.0))
+            .unwrap();
+
+        match current.counters[0].ctype {
+            CounterType::Gauge {
+                min,
+                max,
+                hits,
+                total,
+            } => {
+                assert_eq!((min, max), (1.0, 6.0));
+                assert_eq!((hits, total), (2.0, 10.0));
+            }
+            _ => panic!("Not a gauge"),
+        }
+        assert_eq!(current.counters[0].ctype.value(), 5.0);
+
+        /* Nothing observed or the source was reset */
+        for previous in [
+            gauge_profile(1.0, 6.0, 12.0, 40.0),
+            gauge_profile(1.0, 6.0, 15.0, 40.0000001),
+        ] {
+            let mut current = gauge_profile(1.0, 6.0, 12.0, 40.0);
+            current.substract(&previous).unwrap();
+
+            let value = current.counters[0].ctype.value();
+            assert!(value.is_finite());
+            assert_eq!(value, 0.0);
+            assert!(!current.counters[0].ctype.hasdata());
+        }
+    }
+
+    #[test]
+    fn alarm_operators_include_the_boundary() {
+        let load = Arc::new(RwLock::new(
+            gauge_profile(5.0, 5.0, 1.0, 5.0).counters.remove(0),
+        ));
+
+        let cases = [
+            ("=", [false, true, false]),
+            ("!=", [true, false, true]),
+            ("<", [false, false, true]),
+            ("<=", [false, true, true]),
+            (">", [true, false, false]),
+            (">=", [true, true, false]),
+        ];
+
+        for (op, expected) in cases {
+            for (threshold, active) in [4.0, 5.0, 6.0].into_iter().zip(expected) {
+                let alarm = ValueAlarm::new(
+                    &"load".to_string(),
+                    load.clone(),
+                    op.to_string(),
+                    threshold,
+                    0,
+                )
+                .unwrap();
+                assert_eq!(alarm.check().is_some(), active, "5 {} {}", op, threshold);
+                assert_eq!(alarm.as_trigger(None).active, active);
+                assert_eq!(alarm.op.to_string(), format!("{} {}", op, threshold));
+            }
+        }
+
+        assert!(ValueAlarm::new(&"load".to_string(), load, "=>".to_string(), 5.0, 0).is_err());
+    }
+
+    #[test]
+    fn merged_counters_keep_the_newest_timestamp() {
+        for (first, second) in [(10, 20), (20, 10)] {
+            let mut merged = CounterType::Counter {
+                ts: first,
+                value: 1.0,
+            };
+            merged
+                .merge(&CounterType::Counter {
+                    ts: second,
+                    value: 2.0,
+                })
+                .unwrap();
+            assert_eq!(merged.ts(), 20);
+            assert_eq!(merged.value(), 3.0);
+
+            let mut set = CounterType::Counter {
+                ts: first,
+                value: 1.0,
+            };
+            set.set(&CounterType::Counter {
+                ts: second,
+                value: 2.0,
+            })
+            .unwrap();
+            assert_eq!(set.ts(), 20);
+        }
+    }
+
+    #[test]
+    fn last_values_keep_the_newest_sample() {
+        let newest = CounterType::LastValue { ts: 20, value: 7.5 };
+        let oldest = CounterType::LastValue { ts: 10, value: 2.0 };
+
+        for (first, second) in [(&newest, &oldest), (&oldest, &newest)] {
+            let mut merged = first.clone();
+            merged.merge(second).unwrap();
+            assert_eq!((merged.ts(), merged.value()), (20, 7.5));
+
+            let mut set = first.clone();
+            set.set(second).unwrap();
+            assert_eq!((set.ts(), set.value()), (20, 7.5));
+        }
+
+        /* The raw value without timestamp nor aggregation */
+        assert_eq!(newest.serialize(&"depth".to_string()), "depth 7.5\n");
+
+        let mut gauge = CounterType::newgauge();
+        assert!(gauge.merge(&newest).is_err());
+    }
+}
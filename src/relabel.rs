@@ -0,0 +1,193 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::proxy_common::ProxyErr;
+
+/*****************
+ * METRIC RENAME *
+ *****************/
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+enum RelabelAction {
+    /// Rename the metric using `replace` ($1 refers to captures)
+    #[default]
+    Replace,
+    /// Discard the metric
+    Drop,
+}
+
+/// A rule as written in the relabel configuration file
+#[derive(Deserialize)]
+struct RelabelRuleDef {
+    #[serde(rename = "match")]
+    pattern: String,
+    #[serde(default)]
+    replace: String,
+    #[serde(default)]
+    action: RelabelAction,
+}
+
+struct RelabelRule {
+    pattern: Regex,
+    replace: String,
+    action: RelabelAction,
+}
+
+/// Names remembered by the cache, it is emptied when full
+const RELABEL_CACHE_MAX: usize = 65536;
+
+/// Ordered list of rules normalizing client metric names
+/// the first matching rule wins, unmatched names are kept
+///
+/// The configuration is a JSON array such as:
+/// [{"match": "^MPI_Bytes_sent$", "replace": "mpi_bytes"},
+///  {"match": "^debug_", "action": "drop"}]
+#[derive(Default)]
+pub(crate) struct Relabeler {
+    rules: Vec<RelabelRule>,
+    /// Result per input name as the rules are applied on each value
+    cache: RwLock<HashMap<String, Option<String>>>,
+}
+
+impl Relabeler {
+    #[allow(unused)]
+    pub(crate) fn from_file(path: &Path) -> Result<Relabeler, ProxyErr> {
+        let data = std::fs::read_to_string(path).map_err(|e| {
+            ProxyErr::new(format!(
+                "Failed to read relabel config {} : {}",
+                path.to_string_lossy(),
+                e
+            ))
+        })?;
+
+        Relabeler::from_json(&data)
+    }
+
+    fn from_json(data: &str) -> Result<Relabeler, ProxyErr> {
+        let defs: Vec<RelabelRuleDef> = serde_json::from_str(data)
+            .map_err(|e| ProxyErr::new(format!("Bad relabel config : {}", e)))?;
+
+        let mut rules: Vec<RelabelRule> = Vec::new();
+
+        for d in defs {
+            let pattern = Regex::new(&d.pattern)
+                .map_err(|e| ProxyErr::new(format!("Bad relabel rule {} : {}", d.pattern, e)))?;
+            if d.action == RelabelAction::Replace && d.replace.is_empty() {
+                return Err(ProxyErr::new(format!(
+                    "Relabel rule {} has no replace, use the drop action to discard metrics",
+                    d.pattern
+                )));
+            }
+            log::info!("Relabel rule {} {:?} {}", d.pattern, d.action, d.replace);
+            rules.push(RelabelRule {
+                pattern,
+                replace: d.replace,
+                action: d.action,
+            });
+        }
+
+        Ok(Relabeler {
+            rules,
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Name of the metric after relabeling, None if it is dropped
+    pub(crate) fn apply(&self, name: &str) -> Option<String> {
+        if self.rules.is_empty() {
+            return Some(name.to_string());
+        }
+
+        if let Some(cached) = self.cache.read().unwrap().get(name) {
+            return cached.clone();
+        }
+
+        let ret = match self.rules.iter().find(|r| r.pattern.is_match(name)) {
+            Some(rule) => match rule.action {
+                RelabelAction::Replace => {
+                    let renamed = rule.pattern.replace(name, rule.replace.as_str());
+                    /* Captures may all be empty, a metric needs a name */
+                    if renamed.is_empty() {
+                        log::warn!("Relabeling {} gives an empty name, keeping it", name);
+                        Some(name.to_string())
+                    } else {
+                        Some(renamed.to_string())
+                    }
+                }
+                RelabelAction::Drop => None,
+            },
+            None => Some(name.to_string()),
+        };
+
+        let mut cache = self.cache.write().unwrap();
+
+        /* Names may embed labels (ranks, paths), do not grow forever */
+        if cache.len() >= RELABEL_CACHE_MAX {
+            cache.clear();
+        }
+
+        cache.insert(name.to_string(), ret.clone());
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relabel_never_gives_empty_names() {
+        assert!(Relabeler::from_json(r#"[{"match": "^debug_"}]"#).is_err());
+
+        let relabel =
+            Relabeler::from_json(r#"[{"match": "^mpi_(.*)$", "replace": "$1"}]"#).unwrap();
+        assert_eq!(relabel.apply("mpi_bytes").as_deref(), Some("bytes"));
+        assert_eq!(relabel.apply("mpi_").as_deref(), Some("mpi_"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let relabel = Relabeler::from_json(
+            r#"[{"match": "^MPI_Bytes_sent$", "replace": "mpi_bytes"},
+                {"match": "^debug_", "action": "drop"},
+                {"match": "^debug_calls$", "replace": "calls"}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            relabel.apply("MPI_Bytes_sent").as_deref(),
+            Some("mpi_bytes")
+        );
+        assert_eq!(relabel.apply("debug_calls"), None);
+        /* Now from the cache */
+        assert_eq!(relabel.apply("debug_calls"), None);
+
+        /* Names matching no rule pass through, partial matches do not rename */
+        assert_eq!(relabel.apply("calls").as_deref(), Some("calls"));
+        assert_eq!(
+            relabel.apply("MPI_Bytes_sent_total").as_deref(),
+            Some("MPI_Bytes_sent_total")
+        );
+        assert_eq!(
+            Relabeler::default().apply("debug_calls").as_deref(),
+            Some("debug_calls")
+        );
+    }
+
+    #[test]
+    fn relabel_cache_is_bounded() {
+        let relabel = Relabeler::from_json(r#"[{"match": "^x", "action": "drop"}]"#).unwrap();
+
+        for i in 0..RELABEL_CACHE_MAX + 10 {
+            relabel.apply(&format!("calls{{rank=\"{}\"}}", i));
+        }
+
+        assert!(relabel.cache.read().unwrap().len() <= RELABEL_CACHE_MAX);
+        assert_eq!(relabel.apply("x_calls"), None);
+    }
+}
@@ -1,20 +1,22 @@
 use crate::exporter::Exporter;
-use crate::proxy_common::{self, is_url_live, unix_ts};
+use crate::proxy_common::{self, is_url_live, is_url_live_with, unix_ts};
 use crate::proxy_common::{unix_ts_us, ProxyErr};
-use crate::proxywireprotocol::{CounterSnapshot, CounterType, JobDesc, JobProfile};
+use crate::proxywireprotocol::{add_label, CounterSnapshot, CounterType, JobDesc, JobProfile};
 use crate::trace::{Trace, TraceView};
 use crate::ExporterFactory;
 use crate::ftio::FtioClient;
 use core::fmt;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder};
 use serde::Serialize;
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::write;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::sync::Arc;
 use std::vec;
 
-use crate::systemmetrics::SystemMetrics;
+use crate::systemmetrics::{SystemMetrics, SystemMetricsLevel};
 
 enum ScraperType {
     Proxy,
@@ -56,7 +58,23 @@ pub struct ProxyScraper {
     factory: Option<Arc<ExporterFactory>>,
     period: u64,
     last_scrape: u64,
+    /// Unix ts (ms) of the last successful scrape (0 = never)
+    last_success: u64,
+    /// Offset in ms of the scrape within its period
+    phase: u64,
     ttype: ScraperType,
+    /// HTTP client for network scrapes (built once with timeouts)
+    client: Option<Client>,
+    /// Number of consecutive failed scrapes
+    failures: u32,
+    /// Last raw value and reset offset of scraped Prometheus counters
+    counter_resets: HashMap<String, (f64, f64)>,
+    /// Merge Prometheus gauge samples over intervals of this many ms (0 = overwrite)
+    gauge_interval: u64,
+    /// Index of the current gauge interval
+    gauge_slot: u64,
+    /// Scrapes are skipped while paused (see /join/pause)
+    paused: bool,
 }
 
 #[derive(Serialize)]
@@ -65,6 +83,19 @@ pub struct ProxyScraperSnapshot {
     ttype: String,
     period: u64,
     last_scrape: u64,
+    paused: bool,
+}
+
+/// Random phase in [0, period) seeded from the target and the current time
+fn scrape_phase(target_url: &str, period: u64) -> u64 {
+    if period == 0 {
+        return 0;
+    }
+
+    let mut hasher = RandomState::new().build_hasher();
+    target_url.hash(&mut hasher);
+    unix_ts_us().hash(&mut hasher);
+    hasher.finish() % period
 }
 
 impl ProxyScraper {
@@ -73,7 +104,7 @@ impl ProxyScraper {
             return Ok((
                 target_url.to_string(),
                 ScraperType::SystemMetrics {
-                    sys: Box::new(SystemMetrics::new()),
+                    sys: Box::new(SystemMetrics::new(SystemMetricsLevel::Full)),
                 },
             ));
         }
@@ -111,13 +142,57 @@ impl ProxyScraper {
     ) -> Result<ProxyScraper, ProxyErr> {
         let (url, ttype) = ProxyScraper::detect_type(target_url)?;
         log::info!("Creating a scrapper to {} for a period of {}", url, period);
+        let client = Client::builder()
+            .connect_timeout(factory.scrape_timeout())
+            .timeout(factory.scrape_timeout())
+            .build()
+            .map_err(|e| ProxyErr::new(e.to_string()))?;
+        /* Network targets are spread so that equal periods do not fire together */
+        let phase = if factory.scrape_jitter {
+            scrape_phase(&url, period)
+        } else {
+            0
+        };
         Ok(ProxyScraper {
             target_url: url,
             state: HashMap::new(),
             factory: Some(factory),
             period,
             last_scrape: 0,
+            last_success: 0,
+            phase,
             ttype,
+            client: Some(client),
+            failures: 0,
+            counter_resets: HashMap::new(),
+            gauge_interval: 0,
+            gauge_slot: 0,
+            paused: false,
+        })
+    }
+
+    pub(crate) fn newsystem(
+        level: SystemMetricsLevel,
+        period: u64,
+        factory: Arc<ExporterFactory>,
+    ) -> Result<ProxyScraper, ProxyErr> {
+        Ok(ProxyScraper {
+            target_url: "/system".to_string(),
+            state: HashMap::new(),
+            factory: Some(factory),
+            period,
+            last_scrape: 0,
+            last_success: 0,
+            phase: 0,
+            ttype: ScraperType::SystemMetrics {
+                sys: Box::new(SystemMetrics::new(level)),
+            },
+            client: None,
+            failures: 0,
+            counter_resets: HashMap::new(),
+            gauge_interval: 0,
+            gauge_slot: 0,
+            paused: false,
         })
     }
 
@@ -131,7 +206,15 @@ impl ProxyScraper {
             factory: None,
             period: proxy_common::get_proxy_period(),
             last_scrape: 0,
+            last_success: 0,
+            phase: 0,
             ttype: ScraperType::Trace { exporter, trace },
+            client: None,
+            failures: 0,
+            counter_resets: HashMap::new(),
+            gauge_interval: 0,
+            gauge_slot: 0,
+            paused: false,
         })
     }
 
@@ -146,11 +229,19 @@ impl ProxyScraper {
             factory: None,
             period: 10000,
             last_scrape: 0,
+            last_success: 0,
+            phase: 0,
             ttype: ScraperType::Ftio {
                 traces,
                 jobid: jobid.to_string(),
                 ftio_client,
             },
+            client: None,
+            failures: 0,
+            counter_resets: HashMap::new(),
+            gauge_interval: 0,
+            gauge_slot: 0,
+            paused: false,
         })
     }
 
@@ -161,6 +252,23 @@ impl ProxyScraper {
             ttype: self.ttype.to_string(),
             period: self.period,
             last_scrape: self.last_scrape / 1000,
+            paused: self.paused,
+        }
+    }
+
+    /// Stop or restart scraping the target, its state is kept meanwhile
+    pub(crate) fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Last success (ms) and state of network scrapes, None for local ones
+    pub(crate) fn status(&self) -> Option<(u64, bool)> {
+        match &self.ttype {
+            ScraperType::Proxy | ScraperType::Prometheus => Some((
+                self.last_success,
+                self.last_success > 0 && self.failures == 0,
+            )),
+            _ => None,
         }
     }
 
@@ -175,16 +283,101 @@ impl ProxyScraper {
         }
     }
 
+    /// Host (and port) of the scraped target
+    fn source_name(&self) -> String {
+        match url::Url::parse(&self.target_url) {
+            Ok(url) => match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_string(),
+                _ => self.target_url.to_string(),
+            },
+            Err(_) => self.target_url.to_string(),
+        }
+    }
+
+    /// Client of network scrapes
+    fn client(&self) -> Result<&Client, ProxyErr> {
+        self.client
+            .as_ref()
+            .ok_or_else(|| ProxyErr::new("Scrape has no HTTP client"))
+    }
+
+    /// GET of the target, the timeout follows the factory settings
+    fn get_target(&self) -> Result<RequestBuilder, ProxyErr> {
+        let mut req = proxy_common::with_proxy_auth(self.client()?.get(&self.target_url));
+
+        if let Some(factory) = &self.factory {
+            req = req.timeout(factory.scrape_timeout());
+        }
+
+        Ok(req)
+    }
+
+    /// Scrapes fire once per period at `phase` ms into the period
+    fn due(&self) -> bool {
+        let now = unix_ts();
+
+        if self.phase == 0 || self.period == 0 {
+            return now - self.last_scrape >= self.period;
+        }
+
+        let slot = |ts: u64| ts.saturating_sub(self.phase) / self.period;
+        slot(now) > slot(self.last_scrape)
+    }
+
+    /// Accumulate scraped gauges as observations over `interval` ms
+    /// (min/max/average restart at each interval) instead of overwriting them
+    pub(crate) fn set_gauge_interval(&mut self, interval: u64) {
+        self.gauge_interval = interval;
+    }
+
+    /// Check that an evicted network target answers again
+    pub(crate) fn probe(&self) -> Result<(), Box<dyn Error>> {
+        let client = self.client()?;
+
+        match &self.ttype {
+            ScraperType::Proxy => {
+                let base = self
+                    .target_url
+                    .strip_suffix("/job")
+                    .unwrap_or(&self.target_url);
+                is_url_live_with(client, &format!("{}/is_admire_proxy.html", base), true)
+            }
+            ScraperType::Prometheus => is_url_live_with(client, &self.target_url, false),
+            _ => Err(ProxyErr::newboxed("Only network scrapes can be probed")),
+        }
+    }
+
+    /// Scrape again on next loop after a successful probe
+    pub(crate) fn reinstate(&mut self) {
+        self.failures = 0;
+        self.last_scrape = 0;
+    }
+
+    /// Record a failed scrape, it is retried on next period
+    /// and the number of consecutive failures is returned
+    pub(crate) fn failed(&mut self) -> u32 {
+        self.failures += 1;
+        self.last_scrape = unix_ts();
+        self.failures
+    }
+
     fn scrape_proxy(&mut self) -> Result<(), Box<dyn Error>> {
         let mut deleted: Vec<JobDesc> = Vec::new();
 
-        let client = Client::new();
-        let response = client.get(&self.target_url).send()?;
+        let response = self.get_target()?.send()?;
+
+        let reported_host: Option<String> = response
+            .headers()
+            .get(proxy_common::PROXY_HOST_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
 
         // Check if the response was successful (status code 200 OK)
         if response.status().is_success() {
             // Deserialize the JSON response into your data structure
-            let mut profiles: Vec<JobProfile> = response.json()?;
+            let mut profiles: Vec<JobProfile> =
+                proxy_common::read_json_capped(response, proxy_common::PEER_MAX_BODY)?;
             let new_keys: HashSet<String> = profiles.iter().map(|v| v.desc.jobid.clone()).collect();
 
             /* First detect if a job has left */
@@ -212,6 +405,12 @@ impl ProxyScraper {
                 unreachable!("Proxy scrapes should have a factory");
             };
 
+            let source_host = if factory.label_source_host() {
+                Some(reported_host.unwrap_or_else(|| self.reported_node(&profiles)))
+            } else {
+                None
+            };
+
             for p in profiles.iter_mut() {
                 log::trace!("Scraping {} from {}", p.desc.jobid, self.target_url);
                 let cur: JobProfile;
@@ -227,6 +426,12 @@ impl ProxyScraper {
 
                 if let Some(exporter) = factory.resolve_by_id(&p.desc.jobid) {
                     for cnt in p.counters.iter() {
+                        let labeled = source_host.as_ref().map(|host| CounterSnapshot {
+                            name: add_label(&cnt.name, "source_host", host),
+                            ..cnt.clone()
+                        });
+                        let cnt = labeled.as_ref().unwrap_or(cnt);
+
                         exporter.push(cnt)?;
                         exporter.accumulate(cnt, true)?;
                     }
@@ -234,6 +439,8 @@ impl ProxyScraper {
                     return Err(ProxyErr::newboxed("No such JobID"));
                 }
 
+                factory.node_profile(&self.source_name(), &cur);
+
                 /* Now insert the non-substracted for next call state */
                 self.state.insert(p.desc.jobid.to_string(), cur);
             }
@@ -244,26 +451,168 @@ impl ProxyScraper {
         Ok(())
     }
 
-    fn prometheus_sample_name(s: &prometheus_parse::Sample) -> String {
-        let mut name = s.metric.to_string();
+    /// Hostname of a child proxy which does not report it, taken from its
+    /// node job (see ExporterFactory::new) or from the scraped URL
+    fn reported_node(&self, profiles: &[JobProfile]) -> String {
+        let nodes: Vec<&JobProfile> = profiles
+            .iter()
+            .filter(|p| p.desc.jobid.starts_with("Node: "))
+            .collect();
+
+        match nodes.as_slice() {
+            [node] => node.desc.nodelist.to_string(),
+            _ => url::Url::parse(&self.target_url)
+                .ok()
+                .and_then(|u| u.host_str().map(|h| h.to_string()))
+                .unwrap_or_else(|| self.source_name()),
+        }
+    }
+
+    fn prometheus_sample_name(
+        metric: &str,
+        s: &prometheus_parse::Sample,
+        extra: Option<(&str, f64)>,
+    ) -> String {
+        let mut labels: Vec<String> = Vec::new();
 
         if !s.labels.is_empty() {
-            name = format!("{}{{{}\"}}", name, s.labels);
+            labels.push(s.labels.to_string());
+        }
+
+        /* Bucket bounds and quantiles are kept as labels */
+        if let Some((label, value)) = extra {
+            let value = if value.is_infinite() && value > 0.0 {
+                "+Inf".to_string()
+            } else {
+                value.to_string()
+            };
+            labels.push(format!("{}=\"{}\"", label, value));
         }
 
-        name
+        if labels.is_empty() {
+            metric.to_string()
+        } else {
+            format!("{}{{{}}}", metric, labels.join(","))
+        }
+    }
+
+    fn prometheus_counter(name: String, value: f64, doc: &str) -> CounterSnapshot {
+        CounterSnapshot {
+            name,
+            ctype: CounterType::Counter {
+                ts: proxy_common::unix_ts_us(),
+                value,
+            },
+            doc: doc.to_string(),
+        }
+    }
+
+    fn prometheus_gauge(name: String, value: f64, doc: &str) -> CounterSnapshot {
+        CounterSnapshot {
+            name,
+            ctype: CounterType::Gauge {
+                min: value,
+                max: value,
+                hits: 1.0,
+                total: value,
+            },
+            doc: doc.to_string(),
+        }
+    }
+
+    fn prometheus_sample_to_counters(
+        v: &prometheus_parse::Sample,
+        doc: &str,
+    ) -> Vec<CounterSnapshot> {
+        match &v.value {
+            prometheus_parse::Value::Counter(value) => vec![ProxyScraper::prometheus_counter(
+                ProxyScraper::prometheus_sample_name(&v.metric, v, None),
+                *value,
+                doc,
+            )],
+            prometheus_parse::Value::Gauge(value) => vec![ProxyScraper::prometheus_gauge(
+                ProxyScraper::prometheus_sample_name(&v.metric, v, None),
+                *value,
+                doc,
+            )],
+            /* Buckets are cumulative counts and thus aggregate as counters */
+            prometheus_parse::Value::Histogram(buckets) => buckets
+                .iter()
+                .map(|b| {
+                    ProxyScraper::prometheus_counter(
+                        ProxyScraper::prometheus_sample_name(
+                            &format!("{}_bucket", v.metric),
+                            v,
+                            Some(("le", b.less_than)),
+                        ),
+                        b.count,
+                        doc,
+                    )
+                })
+                .collect(),
+            prometheus_parse::Value::Summary(quantiles) => quantiles
+                .iter()
+                .map(|q| {
+                    ProxyScraper::prometheus_gauge(
+                        ProxyScraper::prometheus_sample_name(
+                            &v.metric,
+                            v,
+                            Some(("quantile", q.quantile)),
+                        ),
+                        q.count,
+                        doc,
+                    )
+                })
+                .collect(),
+            /* The _sum and _count series of histograms and summaries are not typed */
+            prometheus_parse::Value::Untyped(value)
+                if v.metric.ends_with("_sum") || v.metric.ends_with("_count") =>
+            {
+                vec![ProxyScraper::prometheus_counter(
+                    ProxyScraper::prometheus_sample_name(&v.metric, v, None),
+                    *value,
+                    doc,
+                )]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Keep scraped counters monotonic across target restarts, when a counter
+    /// goes down its previous value is added to all following samples
+    fn handle_counter_reset(
+        resets: &mut HashMap<String, (f64, f64)>,
+        snapshot: &mut CounterSnapshot,
+    ) {
+        if let CounterType::Counter { value, .. } = &mut snapshot.ctype {
+            let (last, offset) = resets
+                .entry(snapshot.name.to_string())
+                .or_insert((0.0, 0.0));
+
+            if *value < *last {
+                log::debug!(
+                    "Counter {} was reset ({} < {})",
+                    snapshot.name,
+                    *value,
+                    *last
+                );
+                *offset += *last;
+            }
+
+            *last = *value;
+            *value += *offset;
+        }
     }
 
     fn scrape_prometheus(&mut self) -> Result<(), Box<dyn Error>> {
-        let client = Client::new();
-        let response = client.get(&self.target_url).send()?;
+        let response = self.get_target()?.send()?;
         let data = response.text()?;
 
         let lines: Vec<_> = data.lines().map(|s| Ok(s.to_string())).collect();
         let metrics = prometheus_parse::Scrape::parse(lines.into_iter())?;
 
         let factory = if let Some(factory) = &self.factory {
-            factory
+            factory.clone()
         } else {
             unreachable!("Proxy scrapes should have a factory");
         };
@@ -275,41 +624,52 @@ impl ProxyScraper {
 
         if let Ok(mut locals) = factory.get_local_job_exporters() {
             target_exporters.append(&mut locals);
+            self.push_prometheus_samples(metrics, &target_exporters)?;
+        }
 
-            for v in metrics.samples {
-                let doc: String = metrics
-                    .docs
-                    .get(&v.metric)
-                    .unwrap_or(&"".to_string())
-                    .clone();
-
-                let entry: Option<CounterSnapshot> = match v.value {
-                    prometheus_parse::Value::Counter(value) => Some(CounterSnapshot {
-                        name: ProxyScraper::prometheus_sample_name(&v),
-                        ctype: CounterType::Counter {
-                            ts: proxy_common::unix_ts_us(),
-                            value,
-                        },
-                        doc,
-                    }),
-                    prometheus_parse::Value::Gauge(value) => Some(CounterSnapshot {
-                        name: ProxyScraper::prometheus_sample_name(&v),
-                        ctype: CounterType::Gauge {
-                            min: 0.0,
-                            max: 0.0,
-                            hits: 1.0,
-                            total: value,
-                        },
-                        doc,
-                    }),
-                    _ => None,
-                };
-
-                for e in target_exporters.iter() {
-                    if let Some(m) = entry.clone() {
-                        e.push(&m)?;
-                        e.accumulate(&m, false)?;
+        Ok(())
+    }
+
+    /// Push scraped Prometheus samples to the exporters
+    fn push_prometheus_samples(
+        &mut self,
+        metrics: prometheus_parse::Scrape,
+        target_exporters: &[Arc<Exporter>],
+    ) -> Result<(), Box<dyn Error>> {
+        /* Gauges are merged until the next interval where their range restarts */
+        let merge_gauges = if let Some(slot) = unix_ts().checked_div(self.gauge_interval) {
+            let same_interval = slot == self.gauge_slot;
+            self.gauge_slot = slot;
+            same_interval
+        } else {
+            false
+        };
+
+        for v in metrics.samples {
+            let doc: String = metrics
+                .docs
+                .get(&v.metric)
+                .unwrap_or(&"".to_string())
+                .clone();
+
+            let mut entries = ProxyScraper::prometheus_sample_to_counters(&v, &doc);
+
+            for m in entries.iter_mut() {
+                ProxyScraper::handle_counter_reset(&mut self.counter_resets, m);
+            }
+
+            for e in target_exporters.iter() {
+                for m in entries.iter() {
+                    let merge = merge_gauges && matches!(m.ctype, CounterType::Gauge { .. });
+
+                    if merge && e.get(&m.name).is_err() {
+                        /* First observation of this gauge */
+                        e.push(m)?;
+                        continue;
                     }
+
+                    e.push(m)?;
+                    e.accumulate(m, merge)?;
                 }
             }
         }
@@ -331,7 +691,14 @@ impl ProxyScraper {
             unreachable!("Proxy scrapes should have a factory");
         };
 
-        let metrics = sys.scrape()?;
+        let metrics: Vec<CounterSnapshot> = sys
+            .scrape()?
+            .into_iter()
+            .map(|mut m| {
+                m.name = factory.metric_name(&m.name, true);
+                m
+            })
+            .collect();
 
         // We push in MAIN, NODE and All exporters which may generate profiles
         // THese exporters are the one attached locally and thus bound to
@@ -371,13 +738,14 @@ impl ProxyScraper {
     }
 
     fn scrape_ftio(&mut self, traces: Arc<TraceView>, jobid: String, ftio_client: Arc<FtioClient>) -> Result<(), Box<dyn Error>> {
-        traces.generate_ftio_model(&jobid, ftio_client)?;
+        /* Models are computed by the FTIO worker not to block the scrapes */
+        traces.request_ftio_model(&jobid, ftio_client);
         Ok(())
     }
 
     pub(crate) fn scrape(&mut self) -> Result<(), Box<dyn Error>> {
-        if unix_ts() - self.last_scrape < self.period {
-            /* Not to be scraped yet */
+        if self.paused || !self.due() {
+            /* Paused or not to be scraped yet */
             return Ok(());
         }
 
@@ -402,7 +770,157 @@ impl ProxyScraper {
         }
 
         self.last_scrape = unix_ts();
+        self.last_success = self.last_scrape;
+        self.failures = 0;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTOGRAM_TEXT: &str = r#"# HELP req_seconds Request latency
+# TYPE req_seconds histogram
+req_seconds_bucket{method="get",le="0.1"} 3
+req_seconds_bucket{method="get",le="1"} 5
+req_seconds_bucket{method="get",le="+Inf"} 6
+req_seconds_sum{method="get"} 2.5
+req_seconds_count{method="get"} 6
+# HELP rpc_seconds RPC latency
+# TYPE rpc_seconds summary
+rpc_seconds{quantile="0.5"} 0.2
+rpc_seconds{quantile="0.99"} 0.9
+rpc_seconds_sum 10
+rpc_seconds_count 40
+"#;
+
+    fn prometheus_scraper() -> ProxyScraper {
+        ProxyScraper {
+            target_url: "http://localhost:9100/metrics".to_string(),
+            state: HashMap::new(),
+            factory: None,
+            period: 1000,
+            last_scrape: 0,
+            last_success: 0,
+            phase: 0,
+            ttype: ScraperType::Prometheus,
+            client: None,
+            failures: 0,
+            counter_resets: HashMap::new(),
+            gauge_interval: 0,
+            gauge_slot: 0,
+            paused: false,
+        }
+    }
+
+    fn parse(text: &str) -> prometheus_parse::Scrape {
+        prometheus_parse::Scrape::parse(text.lines().map(|s| Ok(s.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn gauges_are_merged_over_the_interval() {
+        let samples = ["4", "10", "1"].map(|v| format!("# TYPE load gauge\nload {}\n", v));
+
+        /* One hour intervals, the three scrapes fall in the same one */
+        let mut scraper = prometheus_scraper();
+        scraper.set_gauge_interval(3600 * 1000);
+        let exporters = [Arc::new(Exporter::new())];
+        for text in samples.iter() {
+            scraper
+                .push_prometheus_samples(parse(text), &exporters)
+                .unwrap();
+        }
+
+        let load = exporters[0].get(&"load".to_string()).unwrap();
+        match load.read().unwrap().ctype {
+            CounterType::Gauge {
+                min,
+                max,
+                hits,
+                total,
+            } => {
+                assert_eq!((min, max), (1.0, 10.0));
+                assert_eq!((hits, total), (3.0, 15.0));
+            }
+            _ => panic!("Not a gauge"),
+        }
+
+        /* Without an interval the last sample wins */
+        let mut scraper = prometheus_scraper();
+        let exporters = [Arc::new(Exporter::new())];
+        for text in samples.iter() {
+            scraper
+                .push_prometheus_samples(parse(text), &exporters)
+                .unwrap();
+        }
+
+        let load = exporters[0].get(&"load".to_string()).unwrap();
+        assert_eq!(load.read().unwrap().ctype.value(), 1.0);
+    }
+
+    #[test]
+    fn counter_resets_keep_counters_monotonic() {
+        let mut resets: HashMap<String, (f64, f64)> = HashMap::new();
+
+        /* The target restarts after 10, then again after 4 */
+        let values: Vec<f64> = [5.0, 10.0, 2.0, 4.0, 1.0, 3.0]
+            .iter()
+            .map(|v| {
+                let mut snap = ProxyScraper::prometheus_counter("requests".to_string(), *v, "");
+                ProxyScraper::handle_counter_reset(&mut resets, &mut snap);
+                snap.ctype.value()
+            })
+            .collect();
+        assert_eq!(values, vec![5.0, 10.0, 12.0, 14.0, 15.0, 17.0]);
+
+        /* Gauges go down freely */
+        let mut gauge = ProxyScraper::prometheus_gauge("load".to_string(), 1.0, "");
+        resets.insert("load".to_string(), (5.0, 0.0));
+        ProxyScraper::handle_counter_reset(&mut resets, &mut gauge);
+        assert_eq!(gauge.ctype.value(), 1.0);
+    }
+
+    #[test]
+    fn histograms_and_summaries_are_scraped() {
+        let lines = HISTOGRAM_TEXT.lines().map(|s| Ok(s.to_string()));
+        let metrics = prometheus_parse::Scrape::parse(lines).unwrap();
+
+        let counters: HashMap<String, CounterType> = metrics
+            .samples
+            .iter()
+            .flat_map(|s| ProxyScraper::prometheus_sample_to_counters(s, "doc"))
+            .map(|c| (c.name, c.ctype))
+            .collect();
+        assert_eq!(counters.len(), 9);
+
+        /* Buckets, sums and counts are cumulative */
+        for (name, value) in [
+            ("req_seconds_bucket{method=\"get\",le=\"0.1\"}", 3.0),
+            ("req_seconds_bucket{method=\"get\",le=\"1\"}", 5.0),
+            ("req_seconds_bucket{method=\"get\",le=\"+Inf\"}", 6.0),
+            ("req_seconds_sum{method=\"get\"}", 2.5),
+            ("req_seconds_count{method=\"get\"}", 6.0),
+            ("rpc_seconds_sum", 10.0),
+            ("rpc_seconds_count", 40.0),
+        ] {
+            assert!(
+                matches!(counters[name], CounterType::Counter { value: v, .. } if v == value),
+                "{}",
+                name
+            );
+        }
+
+        for (name, value) in [
+            ("rpc_seconds{quantile=\"0.5\"}", 0.2),
+            ("rpc_seconds{quantile=\"0.99\"}", 0.9),
+        ] {
+            assert!(
+                matches!(counters[name], CounterType::Gauge { total, .. } if total == value),
+                "{}",
+                name
+            );
+        }
+    }
+}
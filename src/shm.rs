@@ -0,0 +1,350 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::proxy_common::ProxyErr;
+
+/**************************
+ * SHARED MEMORY COUNTERS *
+ **************************/
+
+/*
+ * A segment is a file mapped by the client (read-write) and the proxy (read-only):
+ *
+ * | magic u64 | version u32 | slots u32 | slot 0 f64 | slot 1 f64 | ... |
+ *
+ * All fields are native endian (both sides run on the same node) and each slot
+ * holds the total of a counter since the client started as the bits of an f64.
+ * Names are not stored in the segment, the client sends ProxyCommand::ShmRegister
+ * with the name of each slot when it allocates it.
+ *
+ * Atomicity: slots are 8 bytes aligned and only accessed with 64 bits atomics,
+ * the client updates them with a compare and swap loop and the proxy with plain
+ * atomic loads. Each value read is consistent but two slots read in a row are
+ * not a snapshot of the same instant, a scrape may see a part of the updates
+ * done in parallel which are then seen on the next scrape.
+ */
+
+const SHM_MAGIC: u64 = u64::from_ne_bytes(*b"PROXYSHM");
+const SHM_VERSION: u32 = 1;
+const SHM_HEADER: usize = 16;
+
+/// Number of slots of a client segment when PROXY_SHM_SLOTS is not set
+#[allow(unused)]
+pub(crate) const SHM_DEFAULT_SLOTS: usize = 4096;
+
+pub(crate) struct ShmSegment {
+    path: PathBuf,
+    ptr: *mut libc::c_void,
+    len: usize,
+    slots: usize,
+    /// Next free slot (client side)
+    next: AtomicUsize,
+    /// The creator removes the file when dropped
+    owner: bool,
+}
+
+/* Slots are only accessed through atomics */
+unsafe impl Send for ShmSegment {}
+unsafe impl Sync for ShmSegment {}
+
+impl ShmSegment {
+    fn map(file: &File, len: usize, writable: bool) -> Result<*mut libc::c_void, ProxyErr> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(ProxyErr::new(format!(
+                "Failed to map shared counters : {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Ok(ptr)
+    }
+
+    /// Create the segment of a client with room for slots counters
+    #[allow(unused)]
+    pub(crate) fn create(path: &Path, slots: usize) -> Result<ShmSegment, Box<dyn Error>> {
+        let len = SHM_HEADER + slots * 8;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+
+        let ptr = ShmSegment::map(&file, len, true)?;
+
+        unsafe {
+            let header = ptr as *mut u8;
+            (header.add(8) as *mut u32).write(SHM_VERSION);
+            (header.add(12) as *mut u32).write(slots as u32);
+            /* The magic goes last, the proxy checks it first */
+            (*(ptr as *const AtomicU64)).store(SHM_MAGIC, Ordering::Release);
+        }
+
+        Ok(ShmSegment {
+            path: path.to_path_buf(),
+            ptr,
+            len,
+            slots,
+            next: AtomicUsize::new(0),
+            owner: true,
+        })
+    }
+
+    /// Map the segment of a client read-only, it must be a regular file
+    /// owned by the uid of the client (a FIFO would block, a device or a
+    /// file of another user would be read on their behalf)
+    #[allow(unused)]
+    pub(crate) fn open(path: &Path, owner_uid: u32) -> Result<ShmSegment, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+            .open(path)?;
+
+        /* Checked on the opened file (fstat) not to race with a rename */
+        let meta = file.metadata()?;
+
+        if !meta.file_type().is_file() {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is not a regular file",
+                path.to_string_lossy()
+            )));
+        }
+
+        if meta.uid() != owner_uid {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is owned by uid {} and not by the client (uid {})",
+                path.to_string_lossy(),
+                meta.uid(),
+                owner_uid
+            )));
+        }
+
+        let len = meta.len() as usize;
+
+        if len < SHM_HEADER {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is too small for shared counters",
+                path.to_string_lossy()
+            )));
+        }
+
+        let ptr = ShmSegment::map(&file, len, false)?;
+
+        let mut segment = ShmSegment {
+            path: path.to_path_buf(),
+            ptr,
+            len,
+            slots: 0,
+            next: AtomicUsize::new(0),
+            owner: false,
+        };
+
+        let (magic, version, slots) = unsafe {
+            let header = ptr as *const u8;
+            (
+                (*(ptr as *const AtomicU64)).load(Ordering::Acquire),
+                (header.add(8) as *const u32).read(),
+                (header.add(12) as *const u32).read() as usize,
+            )
+        };
+
+        if magic != SHM_MAGIC || version != SHM_VERSION {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is not a version {} counter segment",
+                path.to_string_lossy(),
+                SHM_VERSION
+            )));
+        }
+
+        if SHM_HEADER + slots * 8 > len {
+            return Err(ProxyErr::newboxed(format!(
+                "{} is truncated ({} slots in {} bytes)",
+                path.to_string_lossy(),
+                slots,
+                len
+            )));
+        }
+
+        segment.slots = slots;
+
+        Ok(segment)
+    }
+
+    #[allow(unused)]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn slot(&self, idx: usize) -> Option<&AtomicU64> {
+        if idx >= self.slots {
+            return None;
+        }
+        unsafe {
+            let p = (self.ptr as *const u8).add(SHM_HEADER + idx * 8);
+            Some(&*(p as *const AtomicU64))
+        }
+    }
+
+    /// Take a free slot, None when the segment is full
+    #[allow(unused)]
+    pub(crate) fn alloc(&self) -> Option<usize> {
+        let idx = self.next.fetch_add(1, Ordering::SeqCst);
+        if idx < self.slots {
+            Some(idx)
+        } else {
+            self.next.store(self.slots, Ordering::SeqCst);
+            None
+        }
+    }
+
+    /// Add value to a slot (client side)
+    #[allow(unused)]
+    pub(crate) fn add(&self, idx: usize, value: f64) {
+        if let Some(slot) = self.slot(idx) {
+            let mut current = slot.load(Ordering::Relaxed);
+            loop {
+                let new = (f64::from_bits(current) + value).to_bits();
+                match slot.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => break,
+                    Err(v) => current = v,
+                }
+            }
+        }
+    }
+
+    /// Current total of a slot
+    #[allow(unused)]
+    pub(crate) fn read(&self, idx: usize) -> Option<f64> {
+        self.slot(idx)
+            .map(|s| f64::from_bits(s.load(Ordering::Relaxed)))
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+        if self.owner {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Counters of a client segment as followed by the proxy
+#[allow(unused)]
+pub(crate) struct ShmSource {
+    segment: ShmSegment,
+    /// Name, slot and total seen on the last poll
+    slots: Vec<(String, usize, f64)>,
+}
+
+#[allow(unused)]
+impl ShmSource {
+    pub(crate) fn open(path: &Path, owner_uid: u32) -> Result<ShmSource, Box<dyn Error>> {
+        Ok(ShmSource {
+            segment: ShmSegment::open(path, owner_uid)?,
+            slots: Vec::new(),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.segment.path()
+    }
+
+    pub(crate) fn register(&mut self, name: String, idx: usize) -> Result<(), ProxyErr> {
+        if self.segment.read(idx).is_none() {
+            return Err(ProxyErr::new(format!(
+                "Slot {} of {} is out of the segment",
+                idx, name
+            )));
+        }
+
+        /* Increments done before the registration are reported on the first poll */
+        self.slots.push((name, idx, 0.0));
+
+        Ok(())
+    }
+
+    /// Increments of each counter since the previous poll
+    pub(crate) fn poll(&mut self) -> Vec<(String, f64)> {
+        let mut ret = Vec::new();
+
+        for (name, idx, last) in self.slots.iter_mut() {
+            if let Some(total) = self.segment.read(*idx) {
+                if total != *last {
+                    ret.push((name.clone(), total - *last));
+                    *last = total;
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn tmp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proxy-shm-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn open_checks_owner_and_size() {
+        let path = tmp_path("owner");
+        let uid = unsafe { libc::getuid() };
+
+        let segment = ShmSegment::create(&path, 4).unwrap();
+        let slot = segment.alloc().unwrap();
+        segment.add(slot, 2.5);
+
+        let source = ShmSegment::open(&path, uid).unwrap();
+        assert_eq!(source.read(slot), Some(2.5));
+        assert!(ShmSegment::open(&path, uid.wrapping_add(1)).is_err());
+
+        /* A header announcing more slots than the file holds */
+        let small = tmp_path("small");
+        std::fs::write(&small, [0u8; 4]).unwrap();
+        assert!(ShmSegment::open(&small, uid).is_err());
+        std::fs::remove_file(&small).unwrap();
+    }
+
+    #[test]
+    fn open_refuses_fifo() {
+        let path = tmp_path("fifo");
+        let cpath = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+
+        /* Opening must neither block nor map the FIFO */
+        assert!(ShmSegment::open(&path, unsafe { libc::getuid() }).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
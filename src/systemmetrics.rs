@@ -1,23 +1,40 @@
 use sysinfo::{ComponentExt, CpuExt, DiskExt, NetworkExt, System, SystemExt};
 
 use crate::{
-    proxy_common::{unix_ts, unix_ts_us, ProxyErr},
+    proxy_common::{unix_ts, unix_ts_us, ProcessStats, ProxyErr},
     proxywireprotocol::{CounterSnapshot, CounterType},
 };
 
+/// Amount of node-level metrics collected by the system scraper
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum SystemMetricsLevel {
+    /// Do not scrape system metrics
+    None,
+    /// Only aggregated CPU and memory metrics
+    Basic,
+    /// All metrics including disks, network, temperatures and per-core CPU
+    Full,
+}
+
 pub struct SystemMetrics {
     sys: System,
     last_scrape: f64,
+    level: SystemMetricsLevel,
 }
 
 impl SystemMetrics {
-    pub fn new() -> SystemMetrics {
+    pub fn new(level: SystemMetricsLevel) -> SystemMetrics {
         SystemMetrics {
             sys: System::new_all(),
             last_scrape: unix_ts() as f64 / 1000.0,
+            level,
         }
     }
 
+    fn enabled(&self, level: SystemMetricsLevel) -> bool {
+        self.level >= level
+    }
+
     fn scrape_disks(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
         for d in self.sys.disks() {
             let attrs: Vec<(String, String)> = vec![
@@ -35,7 +52,7 @@ impl SystemMetrics {
 
             let total_space: f64 = d.total_space() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_disk_size_bytes".to_string(),
+                "disk_size_bytes".to_string(),
                 attrs.as_slice(),
                 "Total size in bytes of the given device".to_string(),
                 CounterType::Gauge {
@@ -48,7 +65,7 @@ impl SystemMetrics {
 
             let free_space = d.available_space() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_disk_free_size_bytes".to_string(),
+                "disk_free_size_bytes".to_string(),
                 attrs.as_slice(),
                 "Total remaining size in bytes of the given device".to_string(),
                 CounterType::Gauge {
@@ -61,7 +78,7 @@ impl SystemMetrics {
 
             let used_space = total_space - free_space;
             counters.push(CounterSnapshot::new(
-                "proxy_disk_used_size_bytes".to_string(),
+                "disk_used_size_bytes".to_string(),
                 attrs.as_slice(),
                 "Total used size in bytes of the given device".to_string(),
                 CounterType::Gauge {
@@ -74,7 +91,7 @@ impl SystemMetrics {
 
             let disk_usage = (used_space * 100.0) / total_space;
             counters.push(CounterSnapshot::new(
-                "proxy_disk_usage_percent".to_string(),
+                "disk_usage_percent".to_string(),
                 attrs.as_slice(),
                 "Total used percentage of the given device".to_string(),
                 CounterType::Gauge {
@@ -99,7 +116,7 @@ impl SystemMetrics {
             if now != self.last_scrape {
                 let transmitted = data.transmitted() as f64 / (now - self.last_scrape);
                 counters.push(CounterSnapshot::new(
-                    "proxy_network_transmit_bandwidth_bytes".to_string(),
+                    "network_transmit_bandwidth_bytes".to_string(),
                     attrs.as_slice(),
                     "Outgoing Bandwidth during the refresh interval on the given device"
                         .to_string(),
@@ -113,7 +130,7 @@ impl SystemMetrics {
 
                 let received = data.received() as f64 / (now - self.last_scrape);
                 counters.push(CounterSnapshot::new(
-                    "proxy_network_receive_bandwidth_bytes".to_string(),
+                    "network_receive_bandwidth_bytes".to_string(),
                     attrs.as_slice(),
                     "Incoming Bandwidth during the refresh interval on the given device"
                         .to_string(),
@@ -127,7 +144,7 @@ impl SystemMetrics {
 
                 let transmitted = data.packets_transmitted() as f64 / (now - self.last_scrape);
                 counters.push(CounterSnapshot::new(
-                    "proxy_network_transmit_packet_rate".to_string(),
+                    "network_transmit_packet_rate".to_string(),
                     attrs.as_slice(),
                     "Outgoing packet rate during the refresh interval on the given device"
                         .to_string(),
@@ -141,7 +158,7 @@ impl SystemMetrics {
 
                 let received = data.packets_received() as f64 / (now - self.last_scrape);
                 counters.push(CounterSnapshot::new(
-                    "proxy_network_receive_packet_rate".to_string(),
+                    "network_receive_packet_rate".to_string(),
                     attrs.as_slice(),
                     "Incoming packet rate during the refresh interval on the given device"
                         .to_string(),
@@ -156,7 +173,7 @@ impl SystemMetrics {
 
             let transmitted = data.total_transmitted() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_transmit_bytes_total".to_string(),
+                "network_transmit_bytes_total".to_string(),
                 attrs.as_slice(),
                 "Total number of bytes sent on the given device".to_string(),
                 CounterType::Counter {
@@ -167,7 +184,7 @@ impl SystemMetrics {
 
             let received = data.total_received() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_receive_bytes_total".to_string(),
+                "network_receive_bytes_total".to_string(),
                 attrs.as_slice(),
                 "Total number of bytes received on the given device".to_string(),
                 CounterType::Counter {
@@ -178,7 +195,7 @@ impl SystemMetrics {
 
             let transmitted = data.total_packets_transmitted() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_transmit_packets_total".to_string(),
+                "network_transmit_packets_total".to_string(),
                 attrs.as_slice(),
                 "Total number of packets sent on the given device".to_string(),
                 CounterType::Counter {
@@ -189,7 +206,7 @@ impl SystemMetrics {
 
             let received = data.total_packets_received() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_receive_packets_total".to_string(),
+                "network_receive_packets_total".to_string(),
                 attrs.as_slice(),
                 "Total number of packets received on the given device".to_string(),
                 CounterType::Counter {
@@ -200,7 +217,7 @@ impl SystemMetrics {
 
             let transmitted = data.total_errors_on_transmitted() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_transmit_packets_error_total".to_string(),
+                "network_transmit_packets_error_total".to_string(),
                 attrs.as_slice(),
                 "Total number of erroneous packets sent on the given device".to_string(),
                 CounterType::Counter {
@@ -211,7 +228,7 @@ impl SystemMetrics {
 
             let received = data.total_errors_on_received() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_network_receive_packets_error_total".to_string(),
+                "network_receive_packets_error_total".to_string(),
                 attrs.as_slice(),
                 "Total number of erroneous  packets received on the given device".to_string(),
                 CounterType::Counter {
@@ -231,7 +248,7 @@ impl SystemMetrics {
 
             let curtemp = c.temperature() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_component_temperature_celcius".to_string(),
+                "component_temperature_celcius".to_string(),
                 attrs.as_slice(),
                 "Current temperature in celcius for the given component".to_string(),
                 CounterType::Gauge {
@@ -245,7 +262,7 @@ impl SystemMetrics {
             /*
                        let temp = c.max() as f64;
                        counters.push(CounterSnapshot::new(
-                           "proxy_component_max_seen_temperature_celcius".to_string(),
+                           "component_max_seen_temperature_celcius".to_string(),
                            attrs.as_slice(),
                            "Maximum temperature seen in the past in celcius for the given component"
                                .to_string(),
@@ -259,7 +276,7 @@ impl SystemMetrics {
             */
             if let Some(temp) = c.critical() {
                 counters.push(CounterSnapshot::new(
-                    "proxy_component_critical_temperature_celcius".to_string(),
+                    "component_critical_temperature_celcius".to_string(),
                     attrs.as_slice(),
                     "Critical temperature in celcius for the given component".to_string(),
                     CounterType::Gauge {
@@ -273,7 +290,7 @@ impl SystemMetrics {
                 let crit = if curtemp >= temp as f64 { 1 } else { 0 };
 
                 counters.push(CounterSnapshot::new(
-                    "proxy_component_critical_temperature".to_string(),
+                    "component_critical_temperature".to_string(),
                     attrs.as_slice(),
                     "A boolean indicating if the component reached a critical temperature"
                         .to_string(),
@@ -293,7 +310,7 @@ impl SystemMetrics {
     fn scrape_memory(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
         let total_mem = self.sys.total_memory() as f64;
         counters.push(CounterSnapshot::new(
-            "proxy_memory_total_bytes".to_string(),
+            "memory_total_bytes".to_string(),
             &[],
             "Total memory on the system in bytes".to_string(),
             CounterType::Gauge {
@@ -306,7 +323,7 @@ impl SystemMetrics {
 
         let used_mem = self.sys.used_memory() as f64;
         counters.push(CounterSnapshot::new(
-            "proxy_memory_used_bytes".to_string(),
+            "memory_used_bytes".to_string(),
             &[],
             "Total memory on the system in bytes".to_string(),
             CounterType::Gauge {
@@ -319,7 +336,7 @@ impl SystemMetrics {
 
         let usedpct = used_mem * 100.0 / total_mem;
         counters.push(CounterSnapshot::new(
-            "proxy_memory_used_percent".to_string(),
+            "memory_used_percent".to_string(),
             &[],
             "Total memory usage on the system in percent".to_string(),
             CounterType::Gauge {
@@ -332,7 +349,7 @@ impl SystemMetrics {
 
         let total_swp = self.sys.total_memory() as f64;
         counters.push(CounterSnapshot::new(
-            "proxy_swap_total_bytes".to_string(),
+            "swap_total_bytes".to_string(),
             &[],
             "Total swap size on the system in bytes".to_string(),
             CounterType::Gauge {
@@ -345,7 +362,7 @@ impl SystemMetrics {
 
         let used_swp = self.sys.used_swap() as f64;
         counters.push(CounterSnapshot::new(
-            "proxy_swap_used_bytes".to_string(),
+            "swap_used_bytes".to_string(),
             &[],
             "Total used swap on the system in bytes".to_string(),
             CounterType::Gauge {
@@ -358,7 +375,7 @@ impl SystemMetrics {
 
         let usedpct = used_swp * 100.0 / total_swp;
         counters.push(CounterSnapshot::new(
-            "proxy_memory_swap_used_percent".to_string(),
+            "memory_swap_used_percent".to_string(),
             &[],
             "Total swap usage on the system in percent".to_string(),
             CounterType::Gauge {
@@ -401,7 +418,7 @@ impl SystemMetrics {
             ),
         ];
         counters.push(CounterSnapshot::new(
-            "proxy_scrape_total".to_string(),
+            "scrape_total".to_string(),
             attrs.as_slice(),
             "Number of scrapes for proxy instance".to_string(),
             CounterType::Counter {
@@ -418,6 +435,14 @@ impl SystemMetrics {
         let mut total_load: f64 = 0.0;
 
         for c in cpus {
+            let load = c.cpu_usage() as f64;
+            total_load += load;
+
+            /* Per core values are only emitted in full mode */
+            if !self.enabled(SystemMetricsLevel::Full) {
+                continue;
+            }
+
             let attrs: Vec<(String, String)> = vec![
                 ("name".to_string(), c.name().to_string()),
                 ("vendor".to_string(), c.vendor_id().to_string()),
@@ -425,7 +450,7 @@ impl SystemMetrics {
             ];
             let freq = c.frequency() as f64;
             counters.push(CounterSnapshot::new(
-                "proxy_cpu_frequency_ghz".to_string(),
+                "cpu_frequency_ghz".to_string(),
                 attrs.as_slice(),
                 "Current frequency of the given CPU".to_string(),
                 CounterType::Gauge {
@@ -435,10 +460,8 @@ impl SystemMetrics {
                     total: freq,
                 },
             ));
-            let load = c.cpu_usage() as f64;
-            total_load += load;
             counters.push(CounterSnapshot::new(
-                "proxy_cpu_usage_percent".to_string(),
+                "cpu_usage_percent".to_string(),
                 attrs.as_slice(),
                 "Current load in percent of the given CPU".to_string(),
                 CounterType::Gauge {
@@ -451,7 +474,7 @@ impl SystemMetrics {
         }
         let cpucnt: f64 = cpus.len() as f64;
         counters.push(CounterSnapshot::new(
-            "proxy_cpu_total".to_string(),
+            "cpu_total".to_string(),
             &[],
             "Number of tracked CPUs by individual proxies".to_string(),
             CounterType::Gauge {
@@ -464,7 +487,7 @@ impl SystemMetrics {
 
         let avg_load: f64 = total_load / cpucnt;
         counters.push(CounterSnapshot::new(
-            "proxy_cpu_load_average_percent".to_string(),
+            "cpu_load_average_percent".to_string(),
             &[],
             "Average load on all the CPUs".to_string(),
             CounterType::Gauge {
@@ -477,28 +500,64 @@ impl SystemMetrics {
         Ok(())
     }
 
+    /// Statistics of the proxy process itself
+    fn scrape_process(&self, counters: &mut Vec<CounterSnapshot>) -> Result<(), ProxyErr> {
+        let stats = match ProcessStats::read() {
+            Ok(s) => s,
+            Err(e) => {
+                log::debug!("No process statistics : {}", e);
+                return Ok(());
+            }
+        };
+
+        for (name, doc, value) in stats.values() {
+            counters.push(CounterSnapshot::new(
+                format!("process_{}", name),
+                &[],
+                doc.to_string(),
+                CounterType::Gauge {
+                    min: value,
+                    max: value,
+                    hits: 1.0,
+                    total: value,
+                },
+            ));
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn scrape(&mut self) -> Result<Vec<CounterSnapshot>, ProxyErr> {
         let mut ret: Vec<CounterSnapshot> = Vec::new();
 
-        self.sys.refresh_disks_list();
-        self.sys.refresh_disks();
-        self.scrape_disks(&mut ret)?;
+        if self.enabled(SystemMetricsLevel::Full) {
+            self.sys.refresh_disks_list();
+            self.sys.refresh_disks();
+            self.scrape_disks(&mut ret)?;
 
-        self.sys.refresh_networks_list();
-        self.sys.refresh_networks();
-        self.scrape_network_cards(&mut ret)?;
+            self.sys.refresh_networks_list();
+            self.sys.refresh_networks();
+            self.scrape_network_cards(&mut ret)?;
 
-        self.sys.refresh_components_list();
-        self.sys.refresh_components();
-        self.scrape_temperatures(&mut ret)?;
+            self.sys.refresh_components_list();
+            self.sys.refresh_components();
+            self.scrape_temperatures(&mut ret)?;
+        }
 
-        self.sys.refresh_memory();
-        self.scrape_memory(&mut ret)?;
+        if self.enabled(SystemMetricsLevel::Basic) {
+            self.sys.refresh_memory();
+            self.scrape_memory(&mut ret)?;
+        }
 
-        self.scrape_system_info(&mut ret)?;
+        if self.enabled(SystemMetricsLevel::Full) {
+            self.scrape_system_info(&mut ret)?;
+        }
 
-        self.sys.refresh_cpu();
-        self.scrape_cpu(&mut ret)?;
+        if self.enabled(SystemMetricsLevel::Basic) {
+            self.sys.refresh_cpu();
+            self.scrape_cpu(&mut ret)?;
+            self.scrape_process(&mut ret)?;
+        }
 
         /* Flag the last scrape TS */
         self.last_scrape = unix_ts() as f64 / 1000.0;
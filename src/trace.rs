@@ -1,15 +1,19 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::process::{Command, ExitStatus, Stdio};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
-    fs::{remove_file, File, OpenOptions},
+    fs::{self, remove_file, File, OpenOptions},
     io::Seek,
     os::unix::prelude::FileExt,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use rayon::{
@@ -18,13 +22,16 @@ use rayon::{
 };
 use serde::{Deserialize, Serialize};
 use serde_binary::binary_stream;
+use serde_json::json;
 
 use zmq::{self, Context};
 
-use crate::ftio::FtioClient;
+use crate::ftio::{FtioClient, FtioCommand};
 use crate::{
     exporter::ExporterFactory,
-    proxy_common::{check_prefix_dir, list_files_with_ext_in, unix_ts, ProxyErr},
+    proxy_common::{
+        check_prefix_dir, list_files_with_ext_in, unix_ts, unix_ts_ns, unix_ts_us, ProxyErr,
+    },
     proxywireprotocol::{max_f64, min_f64, CounterSnapshot, CounterType, JobDesc, JobProfile},
 };
 
@@ -34,20 +41,116 @@ use crate::proxy_common::offset_time_serie;
 /**********************
  * JSON TRACE SUPPORT *
  **********************/
+
+/// Exponential moving average of a serie, alpha must be in (0,1]
+pub fn ema_time_serie(data: &[(f64, f64)], alpha: f64) -> Vec<(f64, f64)> {
+    let mut ret: Vec<(f64, f64)> = Vec::with_capacity(data.len());
+
+    for (ts, v) in data {
+        let smoothed = match ret.last() {
+            Some((_, prev)) => alpha * v + (1.0 - alpha) * prev,
+            None => *v,
+        };
+        ret.push((*ts, smoothed));
+    }
+
+    ret
+}
+
+/// Check that a smoothing factor is usable by ema_time_serie
+pub fn check_ema_alpha(alpha: f64) -> Result<(), ProxyErr> {
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(())
+    } else {
+        Err(ProxyErr::new(format!(
+            "Smoothing factor {} is not in (0,1]",
+            alpha
+        )))
+    }
+}
+
+/// Indices kept by the largest-triangle-three-buckets algorithm
+fn lttb_indices(series: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let len = series.len();
+
+    if n < 3 || len <= n {
+        return (0..len).collect();
+    }
+
+    /* First and last points are always kept, others are picked per bucket */
+    let every = (len - 2) as f64 / (n - 2) as f64;
+    let mut ret: Vec<usize> = Vec::with_capacity(n);
+    let mut a = 0;
+    ret.push(a);
+
+    for i in 0..(n - 2) {
+        /* Average of the next bucket is the third point of the triangle */
+        let avg_start = ((i + 1) as f64 * every) as usize + 1;
+        let avg_end = (((i + 2) as f64 * every) as usize + 1).min(len);
+        let (avg_x, avg_y) = if avg_start < avg_end {
+            let next = &series[avg_start..avg_end];
+            let count = next.len() as f64;
+            (
+                next.iter().map(|(x, _)| x).sum::<f64>() / count,
+                next.iter().map(|(_, y)| y).sum::<f64>() / count,
+            )
+        } else {
+            series[len - 1]
+        };
+
+        let range_start = (i as f64 * every) as usize + 1;
+        let range_end = (((i + 1) as f64 * every) as usize + 1).min(len - 1);
+        let (ax, ay) = series[a];
+
+        let mut max_area = -1.0;
+        for (j, (x, y)) in series.iter().enumerate().take(range_end).skip(range_start) {
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs() * 0.5;
+            if area > max_area {
+                max_area = area;
+                a = j;
+            }
+        }
+
+        ret.push(a);
+    }
+
+    ret.push(len - 1);
+
+    ret
+}
+
+/// Downsample a serie to n points with the largest-triangle-three-buckets
+/// algorithm which preserves its visual shape (n < 3 keeps all points)
+pub(crate) fn lttb(series: &[(f64, f64)], n: usize) -> Vec<(f64, f64)> {
+    lttb_indices(series, n)
+        .into_iter()
+        .map(|i| series[i])
+        .collect()
+}
+
 #[derive(Serialize)]
 pub struct TraceExport {
     pub infos: TraceInfo,
     pub metrics: HashMap<String, Vec<(f64, f64)>>,
+    /// User annotations (ts, label) on the same time axis as the metrics
+    pub annotations: Vec<(f64, String)>,
 }
 
 impl TraceExport {
-    pub fn new(infos: TraceInfo, traces: &TraceView) -> Result<TraceExport, Box<dyn Error>> {
+    /// Export a trace, series longer than max_points are downsampled
+    /// and their derivative is then computed on the downsampled serie
+    pub fn new(
+        infos: TraceInfo,
+        traces: &TraceView,
+        max_points: Option<usize>,
+    ) -> Result<TraceExport, Box<dyn Error>> {
         let mut ret = TraceExport {
             infos,
             metrics: HashMap::new(),
+            annotations: Vec::new(),
         };
 
-        ret.load(traces)?;
+        ret.load(traces, max_points)?;
 
         Ok(ret)
     }
@@ -57,9 +160,14 @@ impl TraceExport {
         Ok(())
     }
 
-    fn load(&mut self, traces: &TraceView) -> Result<(), Box<dyn Error>> {
+    fn load(
+        &mut self,
+        traces: &TraceView,
+        max_points: Option<usize>,
+    ) -> Result<(), Box<dyn Error>> {
         let metrics = traces.metrics(&self.infos.desc.jobid)?;
-        let full_data = traces.full_read(&self.infos.desc.jobid)?;
+        /* Exports cover the whole history not only the in-memory window */
+        let full_data = traces.history_read(&self.infos.desc.jobid)?;
 
         let mut offset: Option<f64> = None;
 
@@ -79,6 +187,9 @@ impl TraceExport {
 
         let offset = offset.unwrap_or(0.0);
 
+        /* Exports (and FTIO) are in seconds whatever the unit of the trace */
+        let unit = full_data.unit;
+
         // Define a type alias for the inner tuple
         type MetricTuple = (f64, f64);
 
@@ -103,6 +214,12 @@ impl TraceExport {
 
                 /* Fix temporal offset */
                 offset_time_serie(&mut data, offset);
+                data.iter_mut()
+                    .for_each(|(ts, _)| *ts = unit.to_seconds(*ts));
+
+                if let Some(n) = max_points {
+                    data = lttb(&data, n);
+                }
 
                 /* Derivate the data  */
                 let deriv = derivate_time_serie(&data);
@@ -116,6 +233,12 @@ impl TraceExport {
             self.set(format!("deriv__{}", m), deriv)?;
         }
 
+        self.annotations = full_data
+            .annotations
+            .iter()
+            .map(|(ts, label)| (unit.to_seconds(ts - offset), label.clone()))
+            .collect();
+
         Ok(())
     }
 }
@@ -138,6 +261,46 @@ pub(crate) struct TraceCounterMetadata {
     pub(crate) doc: String,
 }
 
+/// Unit of the timestamps (ts) of the frames of a trace
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum TraceTimeUnit {
+    /// Seconds with a millisecond resolution (traces without a TimeUnit frame)
+    #[default]
+    Seconds,
+    /// Nanoseconds since the epoch (an f64 keeps them to ~256 ns)
+    Nanoseconds,
+}
+
+impl TraceTimeUnit {
+    /// Current time in this unit
+    fn now(&self) -> f64 {
+        match self {
+            TraceTimeUnit::Seconds => unix_ts() as f64 / 1000.0,
+            TraceTimeUnit::Nanoseconds => unix_ts_ns() as f64,
+        }
+    }
+
+    pub(crate) fn to_seconds(self, ts: f64) -> f64 {
+        match self {
+            TraceTimeUnit::Seconds => ts,
+            TraceTimeUnit::Nanoseconds => ts / 1e9,
+        }
+    }
+
+    /// Timestamp in ms as used in JobDesc
+    fn to_ms(self, ts: f64) -> u64 {
+        (self.to_seconds(ts) * 1000.0) as u64
+    }
+
+    /// Convert a unix timestamp in us (as sent by clients) to this unit
+    fn us_to_unit(self, ts: u64) -> f64 {
+        match self {
+            TraceTimeUnit::Seconds => ts as f64 / 1e6,
+            TraceTimeUnit::Nanoseconds => ts as f64 * 1000.0,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) enum TraceFrame {
     Desc {
@@ -152,6 +315,22 @@ pub(crate) enum TraceFrame {
         ts: f64,
         counters: Vec<TraceCounter>,
     },
+    /// Counters which changed since the previous counters frame (see --trace-delta)
+    /// they are materialized as Counters frames when read
+    DeltaCounters {
+        ts: f64,
+        counters: Vec<TraceCounter>,
+    },
+    /// Follows the Desc frame when timestamps are not in seconds
+    TimeUnit {
+        ts: f64,
+        unit: TraceTimeUnit,
+    },
+    /// Label set by the application at a given time
+    Annotation {
+        ts: f64,
+        label: String,
+    },
 }
 
 impl TraceFrame {
@@ -160,6 +339,9 @@ impl TraceFrame {
             TraceFrame::Desc { ts, desc: _ } => ts,
             TraceFrame::CounterMetadata { ts, metadata: _ } => ts,
             TraceFrame::Counters { ts, counters: _ } => ts,
+            TraceFrame::DeltaCounters { ts, counters: _ } => ts,
+            TraceFrame::TimeUnit { ts, unit: _ } => ts,
+            TraceFrame::Annotation { ts, label: _ } => ts,
         }
     }
 
@@ -183,7 +365,7 @@ impl TraceFrame {
                                 id: v.id,
                                 value: CounterType::Counter { ts, value },
                             },
-                            CounterType::Gauge { .. } => unreachable!(),
+                            _ => unreachable!(),
                         },
                         CounterType::Gauge {
                             min,
@@ -205,7 +387,33 @@ impl TraceFrame {
                                     total: total + total2,
                                 },
                             },
-                            CounterType::Counter { .. } => unreachable!(),
+                            _ => unreachable!(),
+                        },
+                        CounterType::LastValue { ts, value } => match prev.value {
+                            /* Keep the newest value of the frame */
+                            CounterType::LastValue {
+                                ts: ts2,
+                                value: value2,
+                            } => TraceCounter {
+                                id: v.id,
+                                value: if ts >= ts2 {
+                                    CounterType::LastValue { ts, value }
+                                } else {
+                                    CounterType::LastValue {
+                                        ts: ts2,
+                                        value: value2,
+                                    }
+                                },
+                            },
+                            _ => unreachable!(),
+                        },
+                        /* The value of the newest frame like a counter */
+                        CounterType::UpDown { ts, value } => match prev.value {
+                            CounterType::UpDown { .. } => TraceCounter {
+                                id: v.id,
+                                value: CounterType::UpDown { ts, value },
+                            },
+                            _ => unreachable!(),
                         },
                     }
                 } else {
@@ -225,7 +433,8 @@ impl TraceFrame {
                     ts: tsb,
                     counters: countersb,
                 } => Ok(TraceFrame::Counters {
-                    ts: (*tsb + ts) / 2.0,
+                    /* Values are the ones of the newest frame, so is the timestamp */
+                    ts: max_f64(*tsb, ts),
                     counters: TraceFrame::mergecounters(counters, countersb),
                 }),
                 _ => unreachable!("This function must take a counter"),
@@ -238,6 +447,69 @@ impl TraceFrame {
         matches!(self, TraceFrame::Counters { .. })
     }
 
+    /// Values of a Counters frame by counter id
+    fn counter_values(&self) -> HashMap<u64, CounterType> {
+        match self {
+            TraceFrame::Counters { ts: _, counters } => {
+                counters.iter().map(|c| (c.id, c.value.clone())).collect()
+            }
+            _ => HashMap::new(),
+        }
+    }
+
+    /// Delta encoding of a Counters frame given the values of the previous one
+    /// the frame is kept whole if a counter of the previous one is missing
+    fn delta(&self, last: &HashMap<u64, CounterType>) -> TraceFrame {
+        let (ts, counters) = match self {
+            TraceFrame::Counters { ts, counters } => (ts, counters),
+            _ => return self.clone(),
+        };
+
+        let ids: HashSet<u64> = counters.iter().map(|c| c.id).collect();
+
+        if !last.keys().all(|id| ids.contains(id)) {
+            return self.clone();
+        }
+
+        TraceFrame::DeltaCounters {
+            ts: *ts,
+            counters: counters
+                .iter()
+                .filter(|c| last.get(&c.id) != Some(&c.value))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Delta frames become full Counters frames completed with the values
+    /// of the previous counters frame, last is updated with the counters frames
+    fn materialize(self, last: &mut HashMap<u64, CounterType>) -> TraceFrame {
+        let frame = match self {
+            TraceFrame::DeltaCounters { ts, counters } => {
+                for c in counters {
+                    last.insert(c.id, c.value);
+                }
+
+                let mut counters: Vec<TraceCounter> = last
+                    .iter()
+                    .map(|(id, value)| TraceCounter {
+                        id: *id,
+                        value: value.clone(),
+                    })
+                    .collect();
+                counters.sort_by_key(|c| c.id);
+                TraceFrame::Counters { ts, counters }
+            }
+            frame => frame,
+        };
+
+        if frame.is_counters() {
+            *last = frame.counter_values();
+        }
+
+        frame
+    }
+
     #[allow(unused)]
     fn is_desc(&self) -> bool {
         matches!(self, TraceFrame::Desc { .. })
@@ -246,20 +518,41 @@ impl TraceFrame {
     fn is_metadata(&self) -> bool {
         matches!(self, TraceFrame::CounterMetadata { .. })
     }
+
+    fn is_annotation(&self) -> bool {
+        matches!(self, TraceFrame::Annotation { .. })
+    }
 }
 
+/// Counters of the seconds spent in a function (callees included), they are
+/// written by the client on function exit as func__ counts calls on entry
+/// (-finstrument-functions with libproxyclient)
+#[allow(unused)]
+const FUNC_TIME_PREFIX: &str = "func_time__";
+
 #[derive(Clone)]
 pub(crate) struct TraceData {
     pub(crate) counters: HashMap<String, TraceCounterMetadata>,
     pub(crate) desc: TraceFrame,
     pub(crate) frames: Vec<TraceFrame>,
     pub(crate) series: HashMap<u64, Vec<(f64, CounterType)>>,
+    /// Maximum number of samples kept in memory per serie (0 = unbounded)
+    /// the full history remains on disk
+    pub(crate) window: usize,
+    /// Unit of the timestamps of the frames and series
+    pub(crate) unit: TraceTimeUnit,
+    /// Annotations (ts, label) in the order they were written
+    pub(crate) annotations: Vec<(f64, String)>,
+    /// Values of the last counters frame, delta frames are applied to them
+    last_counters: HashMap<u64, CounterType>,
 }
 
 impl TraceData {
     fn clear(&mut self) {
         self.counters.clear();
         self.series.clear();
+        self.annotations.clear();
+        self.last_counters.clear();
         self.frames = Vec::new();
     }
 
@@ -268,10 +561,22 @@ impl TraceData {
             let counter_vec = self.series.entry(c.id).or_default();
 
             counter_vec.push((ts, c.value.clone()));
+
+            /* Evict the oldest samples beyond the retention window */
+            if self.window != 0 && counter_vec.len() > self.window {
+                let excess = counter_vec.len() - self.window;
+                counter_vec.drain(0..excess);
+            }
         }
     }
 
     fn append_data(&mut self, frames: &mut Vec<TraceFrame>) {
+        /* Only full frames are kept in memory */
+        let mut frames: Vec<TraceFrame> = frames
+            .drain(..)
+            .map(|f| f.materialize(&mut self.last_counters))
+            .collect();
+
         for frame in frames.iter() {
             match frame {
                 TraceFrame::Desc { ts: _, desc: _ } => {
@@ -284,25 +589,37 @@ impl TraceData {
                 TraceFrame::Counters { ts, counters } => {
                     self.push_counters(*ts, counters);
                 }
+                /* Materialized above */
+                TraceFrame::DeltaCounters { .. } => {}
+                TraceFrame::TimeUnit { ts: _, unit } => {
+                    self.unit = *unit;
+                }
+                TraceFrame::Annotation { ts, label } => {
+                    self.annotations.push((*ts, label.clone()));
+                }
             }
         }
 
-        self.frames.append(frames);
+        self.frames.append(&mut frames);
     }
 
     #[allow(unused)]
-    fn new(desc: TraceFrame, frames: &mut Vec<TraceFrame>) -> TraceData {
-        let mut ret = TraceData::empty(&desc);
+    fn new(desc: TraceFrame, frames: &mut Vec<TraceFrame>, window: usize) -> TraceData {
+        let mut ret = TraceData::empty(&desc, window);
         ret.append_data(frames);
         ret
     }
 
-    fn empty(desc: &TraceFrame) -> TraceData {
+    fn empty(desc: &TraceFrame, window: usize) -> TraceData {
         TraceData {
             counters: HashMap::new(),
             desc: desc.clone(),
             frames: Vec::new(),
             series: HashMap::new(),
+            window,
+            unit: TraceTimeUnit::default(),
+            annotations: Vec::new(),
+            last_counters: HashMap::new(),
         }
     }
 
@@ -310,8 +627,104 @@ impl TraceData {
         let mut v = vec![frame];
         self.append_data(&mut v);
     }
+
+    /// Chrome Trace Event JSON (see ui.perfetto.dev or chrome://tracing)
+    ///
+    /// The time spent in functions (func_time__ counters) gives a duration
+    /// event per sampling interval starting with the interval, of the length
+    /// of the time spent in it. Other counters are counter events and
+    /// annotations instant events. Timestamps are us since the first sample.
+    #[allow(unused)]
+    pub(crate) fn to_chrome_trace(&self, jobid: &str) -> serde_json::Value {
+        let us = |ts: f64| self.unit.to_seconds(ts) * 1e6;
+
+        let offset = self
+            .series
+            .values()
+            .filter_map(|s| s.first().map(|(ts, _)| *ts))
+            .chain(self.annotations.iter().map(|(ts, _)| *ts))
+            .fold(f64::INFINITY, f64::min);
+        let offset = if offset.is_finite() { us(offset) } else { 0.0 };
+
+        let mut events: Vec<serde_json::Value> = vec![json!({
+            "name": "process_name",
+            "ph": "M",
+            "pid": 1,
+            "args": {"name": jobid},
+        })];
+
+        let mut names: Vec<(&String, u64)> = self.counters.iter().map(|(n, m)| (n, m.id)).collect();
+        names.sort();
+
+        for (tid, (name, id)) in names.into_iter().enumerate() {
+            let serie = match self.series.get(&id) {
+                Some(s) => TraceView::to_time_serie(s),
+                None => continue,
+            };
+
+            let func = match name.strip_prefix(FUNC_TIME_PREFIX) {
+                Some(f) => f,
+                None => {
+                    events.extend(serie.iter().map(|(ts, value)| {
+                        json!({
+                            "name": name,
+                            "ph": "C",
+                            "ts": us(*ts) - offset,
+                            "pid": 1,
+                            "args": {"value": value},
+                        })
+                    }));
+                    continue;
+                }
+            };
+
+            for w in serie.windows(2) {
+                let ((start, before), (end, after)) = (w[0], w[1]);
+                let spent = (after - before) * 1e6;
+                if spent <= 0.0 {
+                    continue;
+                }
+
+                events.push(json!({
+                    "name": func,
+                    "ph": "X",
+                    "ts": us(start) - offset,
+                    "dur": spent.min(us(end) - us(start)),
+                    "pid": 1,
+                    "tid": tid + 1,
+                }));
+            }
+        }
+
+        events.extend(self.annotations.iter().map(|(ts, label)| {
+            json!({
+                "name": label,
+                "ph": "i",
+                "s": "g",
+                "ts": us(*ts) - offset,
+                "pid": 1,
+            })
+        }));
+
+        json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        })
+    }
 }
 
+/// Traces starting with this magic have a copy of the length of each frame
+/// after it, so that the last frame is found from the end of the file.
+/// Traces without it (older ones) are scanned from the start and keep
+/// this framing until they are folded.
+const TRACE_MAGIC: &[u8; 8] = b"PXTRACE2";
+
+/// Number of times a trace was folded since the proxy started
+static TRACE_FOLDS: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes of trace files removed by TraceView::gc since the proxy started
+static TRACE_RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
 /// This is the trace state main handle to a trace
 /// when writing to it and when reading from it
 /// The trace is read lazily only and the
@@ -324,14 +737,24 @@ struct TraceState {
     size: u64,
     /// Maximum size of the trace
     max_size: usize,
-    /// Timestamp of the last write to the trace
+    /// Timestamp of the last write to the trace (in unit)
     lastwrite: f64,
+    /// Unit of the timestamps of the frames
+    unit: TraceTimeUnit,
     /// Path of the trace
     path: PathBuf,
+    /// Write only the counters which changed since the previous frame
+    delta: bool,
+    /// Frames are followed by their length (file starts with TRACE_MAGIC)
+    footer: bool,
 
     /// Current counter identifier of the trace
     current_counter_id: u64,
 
+    /// Bumped on each fold, starts at the opening time (us) so that
+    /// read tokens of a previous run of the proxy are stale as well
+    generation: u64,
+
     /// Read state
     trace_data: TraceData,
 }
@@ -354,9 +777,20 @@ impl TraceState {
         Ok(fd.metadata()?.len())
     }
 
+    /// Offset of the first frame and presence of frame footers
+    fn framing(fd: &File) -> Result<(u64, bool), Box<dyn Error>> {
+        let mut magic: [u8; 8] = [0; 8];
+
+        match fd.read_at(&mut magic, 0)? {
+            8 if &magic == TRACE_MAGIC => Ok((TRACE_MAGIC.len() as u64, true)),
+            _ => Ok((0, false)),
+        }
+    }
+
     fn desc_from_file(path: &PathBuf) -> Result<JobDesc, Box<dyn Error>> {
         let mut fd = File::open(path)?;
-        let (data, _) = Self::read_frame_at(&mut fd, 0)?;
+        let (start, footer) = Self::framing(&fd)?;
+        let (data, _) = Self::read_frame_at(&mut fd, start, footer)?;
 
         if let Some(frame) = data {
             return Ok(frame.desc()?);
@@ -371,9 +805,41 @@ impl TraceState {
         TraceState::desc_from_file(&self.path)
     }
 
+    /// Unit of the timestamps, given by the frame following the desc (if any)
+    fn unit_from_file(path: &PathBuf) -> Result<TraceTimeUnit, Box<dyn Error>> {
+        let mut fd = File::open(path)?;
+        let (start, footer) = Self::framing(&fd)?;
+        let (_, off) = Self::read_frame_at(&mut fd, start, footer)?;
+
+        match Self::read_frame_at(&mut fd, off, footer)? {
+            (Some(TraceFrame::TimeUnit { ts: _, unit }), _) => Ok(unit),
+            _ => Ok(TraceTimeUnit::Seconds),
+        }
+    }
+
     fn offset_of_last_frame_start(fd: &mut File) -> Result<u64, Box<dyn Error>> {
         let total_size = fd.metadata()?.len();
-        let mut offset = 0;
+        let (start, footer) = Self::framing(fd)?;
+
+        /* The footer gives the start of the last frame at once */
+        if footer {
+            if total_size <= start {
+                return Ok(start);
+            }
+
+            let mut size: [u8; 8] = [0; 8];
+            fd.read_exact_at(&mut size, total_size - 8)?;
+            let size = u64::from_le_bytes(size);
+
+            return total_size
+                .checked_sub(size + 16)
+                .filter(|off| *off >= start)
+                .ok_or_else(|| -> Box<dyn Error> {
+                    ProxyErr::newboxed("Bad frame length at the end of the trace")
+                });
+        }
+
+        let mut offset = start;
         loop {
             let mut size: [u8; 8] = [0; 8];
             fd.read_exact_at(&mut size, offset).unwrap();
@@ -404,19 +870,26 @@ impl TraceState {
             /* This is an empty frame */
             return Ok(None);
         }
-        let (data, _) = Self::read_frame_at(&mut fd, off)?;
+        let (data, _) = Self::read_frame_at(&mut fd, off, self.footer)?;
         Ok(data)
     }
 
-    fn read_frame_at(fd: &mut File, off: u64) -> Result<(Option<TraceFrame>, u64), Box<dyn Error>> {
+    fn read_frame_at(
+        fd: &mut File,
+        off: u64,
+        footer: bool,
+    ) -> Result<(Option<TraceFrame>, u64), Box<dyn Error>> {
         let mut data: Vec<u8> = Vec::new();
         let mut current_offset = off;
 
         /* We expect an 8 bytes integer at the start */
         let mut len_data: [u8; 8] = [0; 8];
-        if fd.read_at(&mut len_data, current_offset)? == 0 {
+        match fd.read_at(&mut len_data, current_offset)? {
             /* EOF */
-            return Ok((None, current_offset));
+            0 => return Ok((None, current_offset)),
+            /* Length is still being written */
+            1..=7 => return Ok((None, off)),
+            _ => {}
         }
         current_offset += 8;
 
@@ -432,6 +905,11 @@ impl TraceState {
             let mut buff: [u8; 1024] = [0; 1024];
             let len = fd.read_at(&mut buff[..block_size], current_offset)?;
 
+            if len == 0 {
+                /* Frame is still being written, read it again later */
+                return Ok((None, off));
+            }
+
             for c in buff.iter().take(len) {
                 current_offset += 1;
                 left_to_read -= 1;
@@ -439,6 +917,15 @@ impl TraceState {
                 data.push(*c);
 
                 if left_to_read == 0 {
+                    if footer {
+                        let mut len_data: [u8; 8] = [0; 8];
+                        if fd.read_at(&mut len_data, current_offset)? < 8 {
+                            /* Footer is still being written */
+                            return Ok((None, off));
+                        }
+                        current_offset += 8;
+                    }
+
                     let frame: TraceFrame =
                         serde_binary::from_slice(&data, binary_stream::Endian::Little)?;
                     return Ok((Some(frame), current_offset));
@@ -465,7 +952,7 @@ impl TraceState {
                     .insert(c.name.to_string(), metadata.clone());
 
                 let frame = TraceFrame::CounterMetadata {
-                    ts: unix_ts() as f64 / 1000.0,
+                    ts: self.unit.now(),
                     metadata,
                 };
 
@@ -483,27 +970,41 @@ impl TraceState {
         None
     }
 
-    fn do_write_frame(fd: &mut File, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
+    fn do_write_frame(
+        fd: &mut File,
+        frame: &TraceFrame,
+        footer: bool,
+    ) -> Result<(), Box<dyn Error>> {
         let buff: Vec<u8> = serde_binary::to_vec(&frame, binary_stream::Endian::Little)?;
 
-        // First write length
+        // Length, frame and length again (footer) in a single write
         let len: u64 = buff.len() as u64;
         let len = len.to_le_bytes();
 
-        let endoff = fd.stream_position()?;
-        fd.write_all_at(&len, endoff)?;
+        let mut data: Vec<u8> = Vec::with_capacity(buff.len() + 16);
+        data.extend_from_slice(&len);
+        data.extend_from_slice(&buff);
+        if footer {
+            data.extend_from_slice(&len);
+        }
 
-        // And then write buff
         let endoff = fd.stream_position()?;
-        fd.write_at(&buff, endoff)?;
+        fd.write_all_at(&data, endoff)?;
 
         Ok(())
     }
 
+    /// The magic is the first thing written to new traces
+    fn write_magic(fd: &mut File) -> Result<(), Box<dyn Error>> {
+        let endoff = fd.stream_position()?;
+        fd.write_all_at(TRACE_MAGIC, endoff)?;
+        Ok(())
+    }
+
     fn write_frame(&mut self, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
         let mut fd = self.open(false)?;
 
-        Self::do_write_frame(&mut fd, frame)?;
+        Self::do_write_frame(&mut fd, frame, self.footer)?;
 
         self.size = fd.metadata()?.len();
 
@@ -518,10 +1019,10 @@ impl TraceState {
         let mut fd = self.open(false)?;
 
         for f in frames.iter() {
-            Self::do_write_frame(&mut fd, f)?;
+            Self::do_write_frame(&mut fd, f, self.footer)?;
         }
 
-        self.lastwrite = unix_ts() as f64 / 1000.0;
+        self.lastwrite = self.unit.now();
         self.size = fd.metadata()?.len();
 
         Ok(())
@@ -538,6 +1039,15 @@ impl TraceState {
             .cloned()
             .collect();
 
+        /* Annotations are kept as is, only counters are folded */
+        let mut annotations: Vec<TraceFrame> = self
+            .trace_data
+            .frames
+            .iter()
+            .filter(|v| v.is_annotation())
+            .cloned()
+            .collect();
+
         let counters: Vec<TraceFrame> = self
             .trace_data
             .frames
@@ -561,28 +1071,51 @@ impl TraceState {
         /* Now rewrite it all */
         remove_file(&self.path)?;
 
-        /* Just recreate the file */
-        let fd = self.open(true);
+        /* Just recreate the file, older traces get footers */
+        let mut fd = self.open(true)?;
+        Self::write_magic(&mut fd)?;
+        self.footer = true;
         drop(fd);
 
         /* Desc first */
         self.write_frame(&desc)?;
 
+        if let Some(unit) = self.unit_frame() {
+            self.write_frame(&unit)?;
+        }
+
         /* Then all metadata */
         for v in meta.iter() {
             self.write_frame(v)?;
         }
 
-        /* And counters */
-        for v in newcounters.iter() {
+        /* Annotations */
+        for v in annotations.iter() {
             self.write_frame(v)?;
         }
 
+        /* And counters, delta frames are computed again over the folded ones */
+        let mut last: HashMap<u64, CounterType> = HashMap::new();
+
+        for v in newcounters.iter() {
+            if self.delta {
+                self.write_frame(&v.delta(&last))?;
+                last = v.counter_values();
+            } else {
+                self.write_frame(v)?;
+            }
+        }
+
         /* Update in memory state */
         self.trace_data.clear();
         self.trace_data.append_data(&mut meta);
+        self.trace_data.append_data(&mut annotations);
         self.trace_data.append_data(&mut newcounters);
 
+        /* Offsets into the previous file are meaningless now */
+        self.generation += 1;
+        TRACE_FOLDS.fetch_add(1, Ordering::SeqCst);
+
         Ok(())
     }
 
@@ -601,12 +1134,16 @@ impl TraceState {
             })
             .collect();
 
-        let ts = unix_ts() as f64 / 1000.0; //counters.first().map(|v| v.value.ts()).unwrap_or(unix_ts());
+        let ts = self.unit.now(); //counters.first().map(|v| v.value.ts()).unwrap_or(unix_ts());
 
         let frame = TraceFrame::Counters { ts, counters };
 
         /* Add to file */
-        self.write_frame(&frame)?;
+        if self.delta {
+            self.write_frame(&frame.delta(&self.trace_data.last_counters))?;
+        } else {
+            self.write_frame(&frame)?;
+        }
         /* Add to in-memory state */
         self.trace_data.push(frame);
 
@@ -618,56 +1155,180 @@ impl TraceState {
         Ok(false)
     }
 
+    /// Append an annotation, ts is a unix timestamp in us
+    fn annotate(&mut self, ts: u64, label: String) -> Result<(), Box<dyn Error>> {
+        let frame = TraceFrame::Annotation {
+            ts: self.unit.us_to_unit(ts),
+            label,
+        };
+
+        self.write_frame(&frame)?;
+        self.trace_data.push(frame);
+
+        Ok(())
+    }
+
     fn read_all(&mut self) -> Result<Vec<TraceFrame>, Box<dyn Error>> {
+        /* First frame is the desc */
+        let (frames, _) = self.read_from(0)?;
+        Ok(frames)
+    }
+
+    /// Read the frames starting at a frame boundary and return them
+    /// with the offset following the last complete frame, delta frames
+    /// are returned materialized against the counters preceding them
+    pub(crate) fn read_from(
+        &mut self,
+        offset: u64,
+    ) -> Result<(Vec<TraceFrame>, u64), Box<dyn Error>> {
+        let (frames, end) = self.read_raw_from(offset)?;
+
+        let mut last: HashMap<u64, CounterType> = HashMap::new();
+
+        let mut fd = self.open(false)?;
+        let (start, footer) = Self::framing(&fd)?;
+
+        /* Deltas past the offset need the counters written before it */
+        if offset > start
+            && frames
+                .iter()
+                .any(|f| matches!(f, TraceFrame::DeltaCounters { .. }))
+        {
+            let mut current_offset = start;
+
+            while current_offset < offset {
+                let (frame, next) = Self::read_frame_at(&mut fd, current_offset, footer)?;
+                match frame {
+                    Some(f) => {
+                        f.materialize(&mut last);
+                    }
+                    None => break,
+                }
+                current_offset = next;
+            }
+        }
+
+        let frames = frames
+            .into_iter()
+            .map(|f| f.materialize(&mut last))
+            .collect();
+
+        Ok((frames, end))
+    }
+
+    /// Incremental read from a token returned by a previous call ("0" reads
+    /// from the start), tokens from before a fold of the trace are rejected
+    #[allow(unused)]
+    fn read_since(&mut self, token: &str) -> Result<(Vec<TraceFrame>, String), Box<dyn Error>> {
+        let offset = if token == "0" {
+            0
+        } else {
+            token
+                .split_once('.')
+                .filter(|(generation, _)| {
+                    u64::from_str_radix(generation, 16).ok() == Some(self.generation)
+                })
+                .and_then(|(_, offset)| u64::from_str_radix(offset, 16).ok())
+                .ok_or_else(|| {
+                    ProxyErr::new(format!(
+                        "Stale or invalid token {}, the trace may have been folded, read again from 0",
+                        token
+                    ))
+                })?
+        };
+
+        let (frames, offset) = self.read_from(offset)?;
+
+        Ok((frames, format!("{:x}.{:x}", self.generation, offset)))
+    }
+
+    /// Frames as stored on disk from a frame boundary
+    fn read_raw_from(&mut self, offset: u64) -> Result<(Vec<TraceFrame>, u64), Box<dyn Error>> {
         let mut frames = Vec::new();
 
         let mut fd = self.open(false)?;
 
-        /* First frame is the desc */
-        let mut current_offset: u64 = 0;
+        if offset > fd.metadata()?.len() {
+            /* The trace was folded (rewritten) since this offset */
+            return Err(ProxyErr::newboxed(format!(
+                "Offset {} is past the end of the trace",
+                offset
+            )));
+        }
+
+        /* Offset 0 is the start of the trace, after the magic */
+        let (start, footer) = Self::framing(&fd)?;
+        let mut current_offset: u64 = offset.max(start);
         let mut frame: Option<TraceFrame>;
 
         loop {
-            (frame, current_offset) = Self::read_frame_at(&mut fd, current_offset)?;
+            (frame, current_offset) = Self::read_frame_at(&mut fd, current_offset, footer)?;
 
-            if frame.is_none() {
-                return Ok(frames);
+            match frame {
+                Some(f) => frames.push(f),
+                None => return Ok((frames, current_offset)),
             }
+        }
+    }
 
-            /* Full frame */
-            frames.push(frame.unwrap());
+    /// Frame recording the unit, seconds (the legacy unit) have none
+    fn unit_frame(&self) -> Option<TraceFrame> {
+        match self.unit {
+            TraceTimeUnit::Seconds => None,
+            unit => Some(TraceFrame::TimeUnit {
+                ts: unit.now(),
+                unit,
+            }),
         }
     }
 
-    fn new(path: &Path, job: &JobDesc, max_size: usize) -> Result<TraceState, Box<dyn Error>> {
+    fn new(
+        path: &Path,
+        job: &JobDesc,
+        max_size: usize,
+        window: usize,
+        unit: TraceTimeUnit,
+        delta: bool,
+    ) -> Result<TraceState, Box<dyn Error>> {
         // First thing save the jobdesc
         let desc = TraceFrame::Desc {
-            ts: unix_ts() as f64 / 1000.0,
+            ts: unit.now(),
             desc: job.clone(),
         };
 
-        let ret = TraceState {
+        let mut ret = TraceState {
             loaded: true, // Trace is new thus already loaded
             size: 0,
             max_size,
             lastwrite: 0.0,
+            unit,
             path: path.to_path_buf(),
+            delta,
+            footer: true,
             current_counter_id: 0,
-            trace_data: TraceData::empty(&desc),
+            generation: unix_ts_us(),
+            trace_data: TraceData::empty(&desc, window),
         };
+        ret.trace_data.unit = unit;
 
         let mut fd = ret.open(true)?;
 
-        TraceState::do_write_frame(&mut fd, &desc)?;
+        TraceState::write_magic(&mut fd)?;
+        TraceState::do_write_frame(&mut fd, &desc, ret.footer)?;
+
+        if let Some(frame) = ret.unit_frame() {
+            TraceState::do_write_frame(&mut fd, &frame, ret.footer)?;
+        }
 
         Ok(ret)
     }
 
-    fn from(path: &Path, max_size: usize) -> Result<TraceState, Box<dyn Error>> {
+    fn from(path: &Path, max_size: usize, window: usize) -> Result<TraceState, Box<dyn Error>> {
         let desc = TraceState::desc_from_file(&path.to_path_buf())?;
+        let unit = TraceState::unit_from_file(&path.to_path_buf())?;
 
         let desc = TraceFrame::Desc {
-            ts: unix_ts() as f64,
+            ts: unit.now(),
             desc: desc.clone(),
         };
 
@@ -676,10 +1337,15 @@ impl TraceState {
             size: 0,
             max_size,
             lastwrite: 0.0,
+            unit,
             path: path.to_path_buf(),
+            delta: false,
+            footer: TraceState::framing(&File::open(path)?)?.1,
             current_counter_id: 0,
-            trace_data: TraceData::empty(&desc),
+            generation: unix_ts_us(),
+            trace_data: TraceData::empty(&desc, window),
         };
+        ret.trace_data.unit = unit;
 
         let lastframe = ret.read_last()?;
 
@@ -702,6 +1368,29 @@ impl TraceState {
         Ok(())
     }
 
+    /// Prepare a trace read from disk for new frames, counter
+    /// ids resume after the largest one of the metadata frames
+    fn resume(&mut self, max_size: usize, delta: bool) -> Result<(), Box<dyn Error>> {
+        self.load()?;
+        self.max_size = max_size;
+        self.delta = delta;
+        self.current_counter_id = self
+            .trace_data
+            .counters
+            .values()
+            .map(|c| c.id + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.current_counter_id);
+        Ok(())
+    }
+
+    /// Read back the whole trace from disk ignoring the retention window
+    fn read_history(&mut self) -> Result<TraceData, Box<dyn Error>> {
+        let mut frames = self.read_all()?;
+        Ok(TraceData::new(self.trace_data.desc.clone(), &mut frames, 0))
+    }
+
     fn metrics(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
         self.load()?;
         Ok(self.trace_data.counters.keys().cloned().collect())
@@ -715,13 +1404,13 @@ pub(crate) struct Trace {
 }
 
 impl Trace {
-    fn new_from_file(file: &String) -> Result<Trace, Box<dyn Error>> {
+    fn new_from_file(file: &String, window: usize) -> Result<Trace, Box<dyn Error>> {
         let path = Path::new(&file);
-        let mut state = TraceState::from(path, 0)?;
+        let mut state = TraceState::from(path, 0, window)?;
         let mut desc = state.desc()?;
         /* Assume end time is the last profile write ~1 sec exact */
         if state.lastwrite != 0.0 {
-            desc.end_time = state.lastwrite as u64;
+            desc.end_time = state.unit.to_ms(state.lastwrite);
         }
         Ok(Trace {
             desc,
@@ -736,7 +1425,14 @@ impl Trace {
         path
     }
 
-    fn new(prefix: &Path, desc: &JobDesc, max_size: usize) -> Result<Trace, Box<dyn Error>> {
+    fn new(
+        prefix: &Path,
+        desc: &JobDesc,
+        max_size: usize,
+        window: usize,
+        unit: TraceTimeUnit,
+        delta: bool,
+    ) -> Result<Trace, Box<dyn Error>> {
         let path = Trace::name(prefix, desc);
         if path.exists() {
             return Err(ProxyErr::newboxed(format!(
@@ -745,7 +1441,7 @@ impl Trace {
             )));
         }
 
-        let state = TraceState::new(&path, desc, max_size)?;
+        let state = TraceState::new(&path, desc, max_size, window, unit, delta)?;
 
         Ok(Trace {
             desc: desc.clone(),
@@ -754,6 +1450,11 @@ impl Trace {
         })
     }
 
+    /// Append to a trace already on disk (proxy restarted during the job)
+    fn resume(&self, max_size: usize, delta: bool) -> Result<(), Box<dyn Error>> {
+        self.state.lock().unwrap().resume(max_size, delta)
+    }
+
     pub(crate) fn desc(&self) -> &JobDesc {
         &self.desc
     }
@@ -767,6 +1468,12 @@ impl Trace {
             .to_string()
     }
 
+    /// Timestamp in seconds of the last frame written to the trace
+    pub(crate) fn lastwrite(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        state.unit.to_seconds(state.lastwrite)
+    }
+
     pub(crate) fn push(
         &self,
         profile: JobProfile,
@@ -786,13 +1493,45 @@ impl Trace {
 
         Ok(sampling)
     }
+
+    /// Tag the trace with a label at ts (unix timestamp in us)
+    pub(crate) fn annotate(&self, ts: u64, label: String) -> Result<(), Box<dyn Error>> {
+        if *self.done.read().unwrap() {
+            return Err(ProxyErr::newboxed("Job is done"));
+        }
+
+        self.state.lock().unwrap().annotate(ts, label)
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct TraceInfo {
     pub desc: JobDesc,
     pub size: u64,
+    /// Unix timestamp in seconds of the last write
     pub lastwrite: u64,
+    /// Unit of the timestamps of the trace series
+    pub unit: TraceTimeUnit,
+}
+
+/// Disk usage of all the traces (see /trace/stats)
+#[derive(Serialize)]
+pub(crate) struct TraceStats {
+    pub traces: usize,
+    pub total_bytes: u64,
+    pub folds: u64,
+    /// Bytes of trace files removed by the retention policy
+    pub reclaimed_bytes: u64,
+    /// Success and duration of the last FTIO run if any
+    pub ftio_last: Option<(bool, Duration)>,
+}
+
+/// Frames appended to a trace after a given offset (see /trace/read?since=)
+#[derive(Serialize)]
+pub(crate) struct TraceTail {
+    frames: Vec<TraceFrame>,
+    /// Token to pass as `since` on the next read
+    token: String,
 }
 
 #[derive(Serialize)]
@@ -807,7 +1546,8 @@ impl TraceInfo {
         TraceInfo {
             desc: trace.desc.clone(),
             size: infos.size,
-            lastwrite: infos.lastwrite as u64,
+            lastwrite: infos.unit.to_seconds(infos.lastwrite) as u64,
+            unit: infos.unit,
         }
     }
 }
@@ -850,10 +1590,36 @@ impl FtioModelStorage {
     }
 }
 
+/// Job and FTIO client of a run queued for the FTIO worker
+type FtioRequest = (String, Arc<FtioClient>);
+
+/// Exit status and outputs of the FTIO command
+struct FtioOutput {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
 pub(crate) struct TraceView {
     prefix: PathBuf,
+    /// Number of samples per serie kept in memory (0 = unbounded)
+    series_window: usize,
+    /// Unit of the timestamps of new traces
+    time_unit: TraceTimeUnit,
+    /// Delta encode the counters frames written
+    delta: bool,
     traces: RwLock<HashMap<String, Arc<Trace>>>,
     freq_models: RwLock<HashMap<String, FtioModelStorage>>,
+    /// Fallback FTIO command (server unreachable)
+    ftio_command: FtioCommand,
+    /// Jobs with an FTIO run in progress
+    ftio_inflight: Mutex<HashSet<String>>,
+    /// Jobs waiting for the FTIO worker
+    ftio_queued: Mutex<HashSet<String>>,
+    /// Queue of the FTIO worker thread, started on first request
+    ftio_queue: Mutex<Option<Sender<FtioRequest>>>,
+    /// Success and duration of the last FTIO run
+    ftio_last: Mutex<Option<(bool, Duration)>>,
 }
 
 impl TraceView {
@@ -890,15 +1656,76 @@ impl TraceView {
         }
     }
 
+    pub(crate) fn stats(&self) -> TraceStats {
+        let traces = self.traces.read().unwrap();
+
+        TraceStats {
+            traces: traces.len(),
+            total_bytes: traces.values().map(|t| t.state.lock().unwrap().size).sum(),
+            folds: TRACE_FOLDS.load(Ordering::SeqCst),
+            reclaimed_bytes: TRACE_RECLAIMED.load(Ordering::SeqCst),
+            ftio_last: *self.ftio_last.lock().unwrap(),
+        }
+    }
+
+    /// Remove the traces not written for retention seconds unless keep
+    /// returns true for their jobid, returns the number of bytes reclaimed
+    pub(crate) fn gc<F>(&self, retention: u64, keep: F) -> u64
+    where
+        F: Fn(&str) -> bool,
+    {
+        let now = unix_ts() as f64 / 1000.0;
+
+        /* Candidates are selected without the lock as keep may take other locks */
+        let candidates: Vec<(String, f64)> = self
+            .traces
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(jobid, t)| (jobid.to_string(), t.lastwrite()))
+            .collect();
+
+        let expired: Vec<String> = candidates
+            .into_iter()
+            .filter(|(jobid, lastwrite)| now - lastwrite >= retention as f64 && !keep(jobid))
+            .map(|(jobid, _)| jobid)
+            .collect();
+
+        let mut reclaimed: u64 = 0;
+
+        for jobid in expired {
+            let trace = match self.traces.write().unwrap().remove(&jobid) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            let path = trace.path();
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+            match remove_file(&path) {
+                Ok(_) => {
+                    log::info!("Removed trace {} ({} bytes)", path, size);
+                    reclaimed += size;
+                }
+                Err(e) => log::error!("Failed to remove trace {} : {}", path, e),
+            }
+        }
+
+        TRACE_RECLAIMED.fetch_add(reclaimed, Ordering::SeqCst);
+
+        reclaimed
+    }
+
     fn load_existing_traces(
         prefix: &PathBuf,
+        series_window: usize,
     ) -> Result<HashMap<String, Arc<Trace>>, Box<dyn Error>> {
         let mut ret: HashMap<String, Arc<Trace>> = HashMap::new();
 
         let files = list_files_with_ext_in(prefix, "trace")?;
 
         for f in files.iter() {
-            match Trace::new_from_file(f) {
+            match Trace::new_from_file(f, series_window) {
                 Ok(t) => {
                     ret.insert(t.desc.jobid.to_string(), Arc::new(t));
                 }
@@ -938,7 +1765,7 @@ impl TraceView {
 
     #[allow(unused)]
     pub(crate) fn infos(&self, jobid: &String) -> Result<TraceInfo, ProxyErr> {
-        let trace = self.read(jobid, None)?;
+        let trace = self.read(jobid, None, None, None)?;
         Ok(trace.info)
     }
 
@@ -968,11 +1795,38 @@ impl TraceView {
         Err(ProxyErr::new(format!("No such trace with jobid {}", jobid)))
     }
 
+    /// Same as `full_read` but reloads all the samples from the
+    /// trace file instead of returning the in-memory window
+    pub(crate) fn history_read(&self, jobid: &String) -> Result<TraceData, ProxyErr> {
+        let ht = self.traces.read().unwrap();
+
+        if let Some(trace) = ht.get(jobid) {
+            if let Ok(mut locked_trace) = trace.state.lock() {
+                return Ok(locked_trace.read_history()?);
+            } else {
+                unreachable!("Failed to acquire trace lock");
+            }
+        }
+
+        Err(ProxyErr::new(format!("No such trace with jobid {}", jobid)))
+    }
+
+    /// Full history of a trace in the Chrome Trace Event format
+    pub(crate) fn chrome_trace(&self, jobid: &String) -> Result<serde_json::Value, ProxyErr> {
+        Ok(self.history_read(jobid)?.to_chrome_trace(jobid))
+    }
+
     pub(crate) fn read(
         &self,
         jobid: &String,
         metric_name: Option<String>,
+        smooth: Option<f64>,
+        max_points: Option<usize>,
     ) -> Result<TraceRead, ProxyErr> {
+        if let Some(alpha) = smooth {
+            check_ema_alpha(alpha)?;
+        }
+
         let ht = self.traces.read().unwrap();
 
         if let Some(trace) = ht.get(jobid) {
@@ -1008,6 +1862,20 @@ impl TraceView {
                 unreachable!();
             };
 
+            let time_serie = match smooth {
+                Some(alpha) => TraceView::smooth_time_serie(&time_serie, alpha),
+                None => time_serie,
+            };
+
+            /* Downsampling comes last so that smoothing sees all samples */
+            let time_serie = match max_points {
+                Some(n) => lttb_indices(&TraceView::to_time_serie(&time_serie), n)
+                    .into_iter()
+                    .map(|i| time_serie[i].clone())
+                    .collect(),
+                None => time_serie,
+            };
+
             return Ok(TraceRead {
                 info: TraceInfo::new(trace),
                 time_serie,
@@ -1017,6 +1885,18 @@ impl TraceView {
         Err(ProxyErr::new(format!("No such trace id {}", jobid)))
     }
 
+    /// Frames written to the trace since the given byte offset
+    pub(crate) fn read_since(&self, jobid: &String, token: &str) -> Result<TraceTail, ProxyErr> {
+        let ht = self.traces.read().unwrap();
+
+        if let Some(trace) = ht.get(jobid) {
+            let (frames, token) = trace.state.lock().unwrap().read_since(token)?;
+            return Ok(TraceTail { frames, token });
+        }
+
+        Err(ProxyErr::new(format!("No such trace id {}", jobid)))
+    }
+
     pub(crate) fn to_time_serie(time_serie: &[(f64, CounterType)]) -> Vec<(f64, f64)> {
         let mut ret: Vec<(f64, f64)> = Vec::new();
 
@@ -1029,15 +1909,51 @@ impl TraceView {
                     hits: _,
                     total: _,
                 } => ret.push((*ts, c.value())),
+                CounterType::LastValue { ts: _, value } => ret.push((*ts, *value)),
+                CounterType::UpDown { ts: _, value } => ret.push((*ts, *value)),
             }
         }
 
         ret
     }
 
+    /// Apply an EMA to a serie keeping the counter types
+    /// gauges are returned as a single hit of the smoothed value
+    fn smooth_time_serie(time_serie: &[(f64, CounterType)], alpha: f64) -> Vec<(f64, CounterType)> {
+        let smoothed = ema_time_serie(&TraceView::to_time_serie(time_serie), alpha);
+
+        time_serie
+            .iter()
+            .zip(smoothed)
+            .map(|((ts, c), (_, v))| {
+                let c = match c {
+                    CounterType::Counter { ts: cnt_ts, .. } => CounterType::Counter {
+                        ts: *cnt_ts,
+                        value: v,
+                    },
+                    CounterType::Gauge { min, max, .. } => CounterType::Gauge {
+                        min: *min,
+                        max: *max,
+                        hits: 1.0,
+                        total: v,
+                    },
+                    CounterType::LastValue { ts: val_ts, .. } => CounterType::LastValue {
+                        ts: *val_ts,
+                        value: v,
+                    },
+                    CounterType::UpDown { ts: cnt_ts, .. } => CounterType::UpDown {
+                        ts: *cnt_ts,
+                        value: v,
+                    },
+                };
+                (*ts, c)
+            })
+            .collect()
+    }
+
     #[allow(unused)]
     pub(crate) fn plot(&self, jobid: &String, filter: String) -> Result<Vec<(f64, f64)>, ProxyErr> {
-        let trace = self.read(jobid, Some(filter))?;
+        let trace = self.read(jobid, Some(filter), None, None)?;
         let ret = TraceView::to_time_serie(&trace.time_serie);
         Ok(ret)
     }
@@ -1050,9 +1966,32 @@ impl TraceView {
         let mut ht = self.traces.write().unwrap();
 
         let trace = match ht.get(&jobdesc.jobid) {
-            Some(v) => v.clone(),
+            Some(v) => {
+                /* Traces found at startup are read only until resumed */
+                v.resume(max_size, self.delta)?;
+                v.clone()
+            }
             None => {
-                let trace = Trace::new(&self.prefix, jobdesc, max_size)?;
+                let path = Trace::name(&self.prefix, jobdesc);
+
+                let trace = if path.exists() {
+                    log::info!("Appending to existing trace {}", path.to_string_lossy());
+                    let trace = Trace::new_from_file(
+                        &path.to_string_lossy().to_string(),
+                        self.series_window,
+                    )?;
+                    trace.resume(max_size, self.delta)?;
+                    trace
+                } else {
+                    Trace::new(
+                        &self.prefix,
+                        jobdesc,
+                        max_size,
+                        self.series_window,
+                        self.time_unit,
+                        self.delta,
+                    )?
+                };
                 let ret = Arc::new(trace);
                 ht.insert(jobdesc.jobid.to_string(), ret.clone());
                 ret
@@ -1062,16 +2001,84 @@ impl TraceView {
         Ok(trace)
     }
 
-    pub(crate) fn export(&self, jobid: &String) -> Result<TraceExport, Box<dyn Error>> {
-        TraceExport::new(self.infos(jobid)?, self)
+    pub(crate) fn export(
+        &self,
+        jobid: &String,
+        max_points: Option<usize>,
+    ) -> Result<TraceExport, Box<dyn Error>> {
+        TraceExport::new(self.infos(jobid)?, self, max_points)
     }
 
+    /// Queue an FTIO run of a job on the worker thread
+    /// nothing is done if one is already queued for the job
+    pub(crate) fn request_ftio_model(
+        self: &Arc<Self>,
+        jobid: &String,
+        ftio_client: Arc<FtioClient>,
+    ) {
+        if !self.ftio_queued.lock().unwrap().insert(jobid.to_string()) {
+            return;
+        }
+
+        let mut queue = self.ftio_queue.lock().unwrap();
+
+        let tx = queue.get_or_insert_with(|| {
+            let (tx, rx) = channel();
+            let view = Arc::downgrade(self);
+            thread::spawn(move || TraceView::run_ftio_worker(view, rx));
+            tx
+        });
+
+        if tx.send((jobid.to_string(), ftio_client)).is_err() {
+            self.ftio_queued.lock().unwrap().remove(jobid);
+        }
+    }
+
+    /// Mainloop of the FTIO worker, runs the queued jobs one at a time
+    fn run_ftio_worker(view: Weak<TraceView>, rx: Receiver<FtioRequest>) {
+        for (jobid, ftio_client) in rx.iter() {
+            let view = match view.upgrade() {
+                Some(v) => v,
+                None => return,
+            };
+
+            view.ftio_queued.lock().unwrap().remove(&jobid);
+
+            if let Err(e) = view.generate_ftio_model(&jobid, ftio_client) {
+                log::warn!("Failed to generate FTIO model of {} : {}", jobid, e);
+            }
+        }
+    }
+
+    /// Run FTIO on a job and wait for the models
+    /// fails if a run is already in progress for the job
     pub(crate) fn generate_ftio_model(
         &self,
         jobid: &String,
         ftio_client: Arc<FtioClient>,
     ) -> Result<(), Box<dyn Error>> {
-        let export = self.export(jobid)?;
+        if !self.ftio_inflight.lock().unwrap().insert(jobid.to_string()) {
+            return Err(ProxyErr::newboxed(format!(
+                "FTIO is already running for {}",
+                jobid
+            )));
+        }
+
+        let start = Instant::now();
+        let ret = self.do_generate_ftio_model(jobid, ftio_client);
+        *self.ftio_last.lock().unwrap() = Some((ret.is_ok(), start.elapsed()));
+
+        self.ftio_inflight.lock().unwrap().remove(jobid);
+
+        ret
+    }
+
+    fn do_generate_ftio_model(
+        &self,
+        jobid: &String,
+        ftio_client: Arc<FtioClient>,
+    ) -> Result<(), Box<dyn Error>> {
+        let export = self.export(jobid, None)?;
 
         if let Ok(ftio_result) = ftio_client.send_receive(export) {
             match rmp_serde::from_slice::<Vec<FtioModel>>(&ftio_result) {
@@ -1099,34 +2106,90 @@ impl TraceView {
         Ok(())
     }
 
-    fn generate_fallback_ftio_model(&self, jobid: &String) -> Result<(), Box<dyn Error>> {
-        which::which("admire_proxy_invoke_ftio")?;
-
-        let export = self.export(jobid)?;
+    /// Run the FTIO command with input on stdin, it is killed after its timeout
+    fn run_ftio_command(&self, input: String) -> Result<FtioOutput, Box<dyn Error>> {
+        let cmd = &self.ftio_command;
 
-        let mut cmd = Command::new("admire_proxy_invoke_ftio")
-            .arg("-n")
-            .arg("10")
+        let mut child = Command::new(&cmd.path)
+            .args(&cmd.args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()?;
 
-        let mut child_stdin = cmd.stdin.take().unwrap();
+        /* Pipes are served by threads so that a stalled child cannot block us */
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || stdin.write_all(input.as_bytes()));
 
-        child_stdin
-            .write_all(serde_json::to_string(&export)?.as_bytes())
-            .unwrap();
-        drop(child_stdin);
+        let mut stdout = child.stdout.take().unwrap();
+        let out = thread::spawn(move || {
+            let mut data: Vec<u8> = Vec::new();
+            stdout.read_to_end(&mut data).map(|_| data)
+        });
+
+        let mut stderr = child.stderr.take().unwrap();
+        let err = thread::spawn(move || {
+            let mut data: Vec<u8> = Vec::new();
+            stderr.read_to_end(&mut data).map(|_| data)
+        });
+
+        let start = Instant::now();
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if start.elapsed() > cmd.timeout {
+                child.kill()?;
+                child.wait()?;
+                return Err(ProxyErr::newboxed(format!(
+                    "{} killed after {} s",
+                    cmd.path,
+                    cmd.timeout.as_secs()
+                )));
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        };
 
-        let output = cmd.wait_with_output()?;
+        let stdout = out
+            .join()
+            .map_err(|_| ProxyErr::new("FTIO stdout reader panicked"))??;
+        let stderr = err
+            .join()
+            .map_err(|_| ProxyErr::new("FTIO stderr reader panicked"))??;
+
+        Ok(FtioOutput {
+            status,
+            stdout,
+            stderr,
+        })
+    }
 
-        let output_str = String::from_utf8_lossy(&output.stdout);
+    fn generate_fallback_ftio_model(&self, jobid: &String) -> Result<(), Box<dyn Error>> {
+        which::which(&self.ftio_command.path).map_err(|_| {
+            ProxyErr::new(format!(
+                "FTIO server is unreachable and {} is not in PATH",
+                self.ftio_command.path
+            ))
+        })?;
+
+        let export = self.export(jobid, None)?;
+
+        let FtioOutput {
+            status,
+            stdout,
+            stderr,
+        } = self.run_ftio_command(serde_json::to_string(&export)?)?;
+
+        let output_str = String::from_utf8_lossy(&stdout);
         let json_start = output_str
             .find('[')
             .ok_or("JSON array not found in FTIO output")?;
         let json_part = &output_str[json_start..];
 
-        if output.status.success() {
+        if status.success() {
             match serde_json::from_slice::<Vec<FtioModel>>(json_part.as_bytes()) {
                 Ok(models) => {
                     if let Ok(job_model_ht) = self.freq_models.write().as_mut() {
@@ -1152,10 +2215,89 @@ impl TraceView {
 
         Err(ProxyErr::newboxed(format!(
             "FTIO command failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+            String::from_utf8_lossy(&stderr)
         )))
     }
 
+    #[allow(unused)]
+    /// Path of the raw trace file of a job
+    pub(crate) fn trace_file(&self, jobid: &String) -> Result<PathBuf, ProxyErr> {
+        if let Some(trace) = self.traces.read().unwrap().get(jobid) {
+            Ok(trace.state.lock().unwrap().path.clone())
+        } else {
+            Err(ProxyErr::new("No such jobid"))
+        }
+    }
+
+    #[allow(unused)]
+    /// Register a raw trace file (as sent by /trace/download) in the view
+    /// the trace is considered complete and is never written to
+    /// Import a raw trace file of at most max_size bytes
+    pub(crate) fn import(
+        &self,
+        data: &mut dyn Read,
+        max_size: u64,
+    ) -> Result<JobDesc, Box<dyn Error>> {
+        let mut tmp = self.prefix.clone();
+        tmp.push(format!(".upload-{}.tmp", unix_ts_us()));
+
+        let res = self.import_from(&tmp, data, max_size);
+
+        if tmp.exists() {
+            remove_file(&tmp)?;
+        }
+
+        res
+    }
+
+    fn import_from(
+        &self,
+        tmp: &PathBuf,
+        data: &mut dyn Read,
+        max_size: u64,
+    ) -> Result<JobDesc, Box<dyn Error>> {
+        /* One more byte tells an oversized upload */
+        if io::copy(&mut data.take(max_size + 1), &mut File::create(tmp)?)? > max_size {
+            return Err(ProxyErr::newboxed(format!(
+                "Trace is larger than {} bytes",
+                max_size
+            )));
+        }
+
+        /* Fails if the first frame is not a description */
+        let desc = TraceState::desc_from_file(tmp)?;
+
+        /* The jobid becomes a file name, do not let it escape the prefix */
+        if desc.jobid.is_empty()
+            || desc.jobid.contains('/')
+            || desc.jobid.contains('\0')
+            || desc.jobid.starts_with('.')
+        {
+            return Err(ProxyErr::newboxed(format!(
+                "Refusing trace with jobid '{}'",
+                desc.jobid
+            )));
+        }
+
+        let mut ht = self.traces.write().unwrap();
+        let path = Trace::name(&self.prefix, &desc);
+
+        if ht.contains_key(&desc.jobid) || path.exists() {
+            return Err(ProxyErr::newboxed(format!(
+                "A trace already exists for job {}",
+                desc.jobid
+            )));
+        }
+
+        std::fs::rename(tmp, &path)?;
+
+        let trace = Trace::new_from_file(&path.to_string_lossy().to_string(), self.series_window)?;
+        *trace.done.write().unwrap() = true;
+        ht.insert(desc.jobid.to_string(), Arc::new(trace));
+
+        Ok(desc)
+    }
+
     pub(crate) fn done(&self, job: &JobDesc) -> Result<(), Box<dyn Error>> {
         if let Some(j) = self.traces.write().unwrap().get_mut(&job.jobid) {
             *j.done.write().unwrap() = true;
@@ -1165,14 +2307,333 @@ impl TraceView {
         Ok(())
     }
 
-    pub(crate) fn new(prefix: &PathBuf) -> Result<TraceView, Box<dyn Error>> {
+    pub(crate) fn new(
+        prefix: &PathBuf,
+        series_window: usize,
+        time_unit: TraceTimeUnit,
+        delta: bool,
+    ) -> Result<TraceView, Box<dyn Error>> {
         let prefix = check_prefix_dir(prefix, "traces")?;
-        let traces = RwLock::new(Self::load_existing_traces(&prefix)?);
+        let traces = RwLock::new(Self::load_existing_traces(&prefix, series_window)?);
         let freq_models = RwLock::new(HashMap::new());
         Ok(TraceView {
             prefix,
+            series_window,
+            time_unit,
+            delta,
             traces,
             freq_models,
+            ftio_command: FtioCommand::default(),
+            ftio_inflight: Mutex::new(HashSet::new()),
+            ftio_queued: Mutex::new(HashSet::new()),
+            ftio_queue: Mutex::new(None),
+            ftio_last: Mutex::new(None),
         })
     }
+
+    /// Command used when the FTIO server is unreachable
+    pub(crate) fn with_ftio_command(mut self, command: FtioCommand) -> TraceView {
+        self.ftio_command = command;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proxy-trace-{}-{}", std::process::id(), name))
+    }
+
+    fn snapshot(name: &str, value: f64) -> CounterSnapshot {
+        CounterSnapshot {
+            name: name.to_string(),
+            doc: String::new(),
+            ctype: CounterType::Counter { ts: 0, value },
+        }
+    }
+
+    /// Counters frames read back from a trace written with or without deltas
+    fn write_trace(name: &str, delta: bool) -> (TraceState, u64) {
+        let path = tmp_path(name);
+        let mut state = TraceState::new(
+            &path,
+            &JobDesc::new(),
+            usize::MAX,
+            0,
+            TraceTimeUnit::Seconds,
+            delta,
+        )
+        .unwrap();
+
+        state
+            .push(vec![snapshot("a", 1.0), snapshot("b", 5.0)])
+            .unwrap();
+        let (_, mid) = state.read_from(0).unwrap();
+
+        for i in 2..5 {
+            state
+                .push(vec![snapshot("a", i as f64), snapshot("b", 5.0)])
+                .unwrap();
+        }
+
+        (state, mid)
+    }
+
+    fn counters(frames: Vec<TraceFrame>) -> Vec<Vec<(u64, f64)>> {
+        frames
+            .into_iter()
+            .filter_map(|f| match f {
+                TraceFrame::Counters { ts: _, counters } => {
+                    Some(counters.iter().map(|c| (c.id, c.value.value())).collect())
+                }
+                TraceFrame::DeltaCounters { .. } => panic!("Delta frame was not materialized"),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_frames_round_trip() {
+        let (mut full, full_mid) = write_trace("full", false);
+        let (mut delta, delta_mid) = write_trace("delta", true);
+
+        let expected = counters(full.read_from(0).unwrap().0);
+        assert_eq!(expected.len(), 4);
+        assert_eq!(counters(delta.read_from(0).unwrap().0), expected);
+
+        /* Reading from the middle needs the counters preceding the offset */
+        assert_eq!(
+            counters(delta.read_from(delta_mid).unwrap().0),
+            counters(full.read_from(full_mid).unwrap().0)
+        );
+
+        /* The whole trace is loaded back as full frames */
+        delta.loaded = false;
+        delta.load().unwrap();
+        assert_eq!(delta.trace_data.series.get(&0).unwrap().len(), 4);
+        let (_, b) = delta.trace_data.series[&1].last().unwrap();
+        assert_eq!(b.value(), 5.0);
+
+        remove_file(tmp_path("full")).unwrap();
+        remove_file(tmp_path("delta")).unwrap();
+    }
+
+    #[test]
+    fn annotations_round_trip() {
+        for (unit, expected_ts) in [
+            (TraceTimeUnit::Seconds, 1700000000.25),
+            (TraceTimeUnit::Nanoseconds, 1700000000250000000.0),
+        ] {
+            let path = tmp_path(&format!("annotations-{:?}", unit));
+            let mut state =
+                TraceState::new(&path, &JobDesc::new(), usize::MAX, 0, unit, false).unwrap();
+
+            state.push(vec![snapshot("a", 1.0)]).unwrap();
+            state
+                .annotate(1700000000250000, "checkpoint".to_string())
+                .unwrap();
+            state.push(vec![snapshot("a", 2.0)]).unwrap();
+            state.push(vec![snapshot("a", 3.0)]).unwrap();
+
+            let annotations = |frames: Vec<TraceFrame>| {
+                frames
+                    .into_iter()
+                    .filter_map(|f| match f {
+                        TraceFrame::Annotation { ts, label } => Some((ts, label)),
+                        _ => None,
+                    })
+                    .collect::<Vec<(f64, String)>>()
+            };
+            let expected = vec![(expected_ts, "checkpoint".to_string())];
+
+            assert_eq!(annotations(state.read_from(0).unwrap().0), expected);
+            state.loaded = false;
+            state.load().unwrap();
+            assert_eq!(state.trace_data.annotations, expected);
+
+            /* Folding only merges the counters frames */
+            state.fold().unwrap();
+            assert_eq!(annotations(state.read_from(0).unwrap().0), expected);
+            state.loaded = false;
+            state.load().unwrap();
+            assert_eq!(state.trace_data.annotations, expected);
+
+            remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn fold_makes_read_tokens_stale() {
+        let (mut state, _) = write_trace("tokens", false);
+
+        let (frames, token) = state.read_since("0").unwrap();
+        assert_eq!(counters(frames).len(), 4);
+
+        state
+            .push(vec![snapshot("a", 5.0), snapshot("b", 5.0)])
+            .unwrap();
+        let (frames, token) = state.read_since(&token).unwrap();
+        assert_eq!(counters(frames).len(), 1);
+
+        state.fold().unwrap();
+        assert!(state.read_since(&token).is_err());
+        assert!(state.read_since("garbage").is_err());
+
+        let (frames, _) = state.read_since("0").unwrap();
+        assert!(!counters(frames).is_empty());
+
+        remove_file(tmp_path("tokens")).unwrap();
+    }
+
+    #[test]
+    fn traces_resume_after_a_restart() {
+        let prefix = tmp_path("restart");
+        let mut desc = JobDesc::new();
+        desc.jobid = "restart".to_string();
+
+        let profile = |counters: Vec<CounterSnapshot>| JobProfile {
+            desc: desc.clone(),
+            counters,
+        };
+
+        {
+            let view = TraceView::new(&prefix, 0, TraceTimeUnit::Seconds, false).unwrap();
+            let trace = view.get(&desc, usize::MAX).unwrap();
+            trace
+                .push(profile(vec![snapshot("a", 1.0), snapshot("b", 2.0)]), 1)
+                .unwrap();
+        }
+
+        /* The proxy restarted, the trace found on disk is appended to */
+        let view = TraceView::new(&prefix, 0, TraceTimeUnit::Seconds, false).unwrap();
+        let trace = view.get(&desc, usize::MAX).unwrap();
+        trace
+            .push(profile(vec![snapshot("a", 3.0), snapshot("c", 4.0)]), 1)
+            .unwrap();
+
+        let data = view.history_read(&desc.jobid).unwrap();
+        let ids: HashSet<u64> = data.counters.values().map(|m| m.id).collect();
+        assert_eq!(data.counters.len(), 3);
+        assert_eq!(ids.len(), 3);
+
+        let a = data.series[&data.counters["a"].id]
+            .iter()
+            .map(|(_, v)| v.value())
+            .collect::<Vec<f64>>();
+        assert_eq!(a, vec![1.0, 3.0]);
+        assert_eq!(data.series[&data.counters["c"].id].len(), 1);
+
+        std::fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    #[test]
+    fn function_time_gives_duration_events() {
+        let desc = TraceFrame::Desc {
+            ts: 0.0,
+            desc: JobDesc::new(),
+        };
+        let mut data = TraceData::empty(&desc, 0);
+
+        for (id, name) in [(0, "func_time__solve"), (1, "func__solve")] {
+            data.push(TraceFrame::CounterMetadata {
+                ts: 10.0,
+                metadata: TraceCounterMetadata {
+                    id,
+                    name: name.to_string(),
+                    doc: String::new(),
+                },
+            });
+        }
+
+        for (ts, spent, calls) in [(10.0, 0.0, 0.0), (11.0, 0.25, 3.0), (12.0, 0.25, 3.0)] {
+            data.push(TraceFrame::Counters {
+                ts,
+                counters: vec![
+                    TraceCounter {
+                        id: 0,
+                        value: CounterType::Counter {
+                            ts: 0,
+                            value: spent,
+                        },
+                    },
+                    TraceCounter {
+                        id: 1,
+                        value: CounterType::Counter {
+                            ts: 0,
+                            value: calls,
+                        },
+                    },
+                ],
+            });
+        }
+        data.push(TraceFrame::Annotation {
+            ts: 11.5,
+            label: "checkpoint".to_string(),
+        });
+
+        let trace = data.to_chrome_trace("1");
+        let events = trace["traceEvents"].as_array().unwrap();
+        let phase = |ph: &str| {
+            events
+                .iter()
+                .filter(|e| e["ph"] == ph)
+                .collect::<Vec<&serde_json::Value>>()
+        };
+
+        /* No time was spent in the second interval */
+        let durations = phase("X");
+        assert_eq!(durations.len(), 1);
+        assert_eq!(durations[0]["name"], "solve");
+        assert_eq!(durations[0]["ts"], 0.0);
+        assert_eq!(durations[0]["dur"], 250000.0);
+
+        let counts = phase("C");
+        assert_eq!(counts.len(), 3);
+        assert!(counts.iter().all(|e| e["name"] == "func__solve"));
+
+        let instants = phase("i");
+        assert_eq!(instants.len(), 1);
+        assert_eq!(instants[0]["ts"], 1500000.0);
+    }
+
+    #[test]
+    fn folded_frames_keep_the_newest_time() {
+        let frame = |ts: f64, value: f64| TraceFrame::Counters {
+            ts,
+            counters: vec![TraceCounter {
+                id: 0,
+                value: CounterType::Counter {
+                    ts: ts as u64,
+                    value,
+                },
+            }],
+        };
+
+        let folded = frame(10.0, 1.0).sum(&frame(20.0, 3.0)).unwrap();
+        match folded {
+            TraceFrame::Counters { ts, counters } => {
+                assert_eq!(ts, 20.0);
+                assert_eq!(counters[0].value.ts(), 20);
+                assert_eq!(counters[0].value.value(), 3.0);
+            }
+            _ => panic!("Not a counters frame"),
+        }
+    }
+
+    #[test]
+    fn folded_last_values_keep_the_newest_sample() {
+        /* A last value older than the one of the previous frame is dropped */
+        let last = |ts: u64, value: f64| {
+            vec![TraceCounter {
+                id: 0,
+                value: CounterType::LastValue { ts, value },
+            }]
+        };
+        let merged = TraceFrame::mergecounters(last(20, 7.5), &last(10, 2.0));
+        assert_eq!((merged[0].value.ts(), merged[0].value.value()), (20, 7.5));
+        let merged = TraceFrame::mergecounters(last(10, 2.0), &last(20, 7.5));
+        assert_eq!((merged[0].value.ts(), merged[0].value.value()), (20, 7.5));
+    }
 }
@@ -23,13 +23,20 @@ use colored::Colorize;
 use std::fs::File;
 use std::io::Write;
 
+mod alarmtemplates;
 mod exporter;
 mod extrap;
 mod ftio;
+#[cfg(feature = "otlp")]
+mod otlp;
+#[cfg(feature = "sqlite")]
+mod profiledb;
 mod profiles;
+mod relabel;
 mod scrapper;
 mod systemmetrics;
-use exporter::ExporterFactory;
+use exporter::{ExporterFactory, ExporterFactoryOptions};
+use systemmetrics::SystemMetricsLevel;
 
 use rayon::iter::*;
 
@@ -38,7 +45,7 @@ use crate::{exporter::NoInstrumentation, proxy_common::offset_time_serie, trace:
 #[derive(Parser)]
 struct Cli {
     #[arg(short, long)]
-    /// Path to the profile directory (default ~/.proxyprofile/)
+    /// Path to the profile directory (default $PROXY_PROFILE_DIR or ~/.proxyprofiles/)
     profile_path: Option<PathBuf>,
     /// List traces only
     #[arg(short, long, default_value_t = false)]
@@ -58,6 +65,9 @@ struct Cli {
     /// Export a model for the given job(s)
     #[arg(short, long, default_value_t = false)]
     gen_model: bool,
+    /// Downsample exported series to at most this many points (LTTB)
+    #[arg(short, long)]
+    max_points: Option<usize>,
 }
 
 struct TraceExporter {
@@ -66,7 +76,13 @@ struct TraceExporter {
 
 impl TraceExporter {
     fn new(path: &Path) -> Result<TraceExporter, ProxyErr> {
-        let factory = ExporterFactory::new(path.to_path_buf(), false, 1024 * 1024 * 32, 1000, 2, Arc::new(NoInstrumentation))?;
+        let options = ExporterFactoryOptions {
+            aggregate: false,
+            system_metrics: SystemMetricsLevel::None,
+            ..Default::default()
+        };
+        let factory =
+            ExporterFactory::new(path.to_path_buf(), options, Arc::new(NoInstrumentation))?;
         Ok(TraceExporter { factory })
     }
 
@@ -81,7 +97,12 @@ impl TraceExporter {
         Ok(())
     }
 
-    fn export(&self, from: &String, to: &Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    fn export(
+        &self,
+        from: &String,
+        to: &Option<PathBuf>,
+        max_points: Option<usize>,
+    ) -> Result<(), Box<dyn Error>> {
         /* Get infos */
         let infos = self.factory.trace_store.infos(from)?;
 
@@ -108,7 +129,7 @@ impl TraceExporter {
 
         let file = File::create(output)?;
 
-        let export = TraceExport::new(infos, &self.factory.trace_store)?;
+        let export = TraceExport::new(infos, &self.factory.trace_store, max_points)?;
 
         serde_json::to_writer(file, &export)?;
 
@@ -151,9 +172,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let profile_dir = if let Some(p) = args.profile_path {
         p.clone()
     } else {
-        let mut profile_prefix = dirs::home_dir().unwrap();
-        profile_prefix.push(".proxyprofiles");
-        profile_prefix.to_path_buf()
+        proxy_common::default_profile_prefix()?
     };
 
     if !profile_dir.is_dir() {
@@ -194,7 +213,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     jobs.par_iter().for_each(|j| {
         if args.export_trace {
-            if let Err(e) = tv.export(j, &args.output) {
+            if let Err(e) = tv.export(j, &args.output, args.max_points) {
                 println!("Failed to generate trace for {} : {}", j, e);
             }
         }
@@ -1,22 +1,34 @@
-use crate::proxy_common::{self, gen_range, ProxyErr};
-use crate::proxywireprotocol::{self, ApiResponse, CounterSnapshot, CounterType, JobProfile};
+use crate::exporter::{CounterUpdate, JobAnnotation};
+use crate::proxy_common::{self, gen_range, ProxyErr, PROXY_HOST_HEADER};
+use crate::proxywireprotocol::{
+    self, AlarmWindow, ApiResponse, CounterSnapshot, CounterType, JobProfile,
+};
 use crate::{
     exporter::{Exporter, ExporterFactory},
-    proxy_common::{concat_slices, derivate_time_serie, hostname, parse_bool},
+    proxy_common::{concat_slices, derivate_time_serie, hostname, parse_bool, proxy_url},
 };
 
 use colored::Colorize;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use retry::{delay::Fixed, retry, OperationResult};
 use rouille::input::json::JsonError;
-use rouille::{Request, Response};
-use serde::Deserialize;
+use rouille::{Request, Response, ResponseBody};
+use serde::{Deserialize, Serialize};
 use static_files::Resource;
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Instant;
 
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use crate::squeue;
+use crate::systemmetrics::SystemMetricsLevel;
+use clap::ValueEnum;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
@@ -24,6 +36,15 @@ include!(concat!(env!("OUT_DIR"), "/generated.rs"));
  * WEBSERVER *
  *************/
 
+/// An edge of the proxy tree as returned by /topo
+#[derive(Serialize)]
+struct TopoEdge {
+    parent: String,
+    child: String,
+    /// Number of children of the parent
+    refcount: u32,
+}
+
 struct ClientPivot {
     url: String,
     refcount: u32,
@@ -41,14 +62,21 @@ impl ClientPivot {
         }
     }
 
+    /// Map a child to this pivot, a child already mapped is not
+    /// added (nor counted) twice as it would be scraped twice
     fn mapto(&mut self, child_url: String) {
+        if self.child.contains(&child_url) {
+            return;
+        }
         self.refcount += 1;
         self.child.push(child_url);
     }
 
     fn removefrom(&mut self, child_url: &String) {
-        self.refcount -= 1;
-        self.child.retain(|x| x != child_url);
+        if self.child.contains(child_url) {
+            self.refcount -= 1;
+            self.child.retain(|x| x != child_url);
+        }
     }
 
     #[allow(unused)]
@@ -81,11 +109,39 @@ impl ClientPivot {
     }
 }
 
+/// A /metrics text output is cached per exporter and query parameters
+#[derive(PartialEq, Eq, Hash)]
+struct MetricsCacheKey {
+    /// Empty for the main exporter
+    jobid: String,
+    exemplars: bool,
+    include_empty: bool,
+}
+
+struct MetricsCacheEntry {
+    at: Instant,
+    text: String,
+}
+
 pub(crate) struct Web {
     port: u32,
+    /// Address the server listens on (url() still reports the hostname)
+    bind: IpAddr,
+    /// PEM certificate and key when serving HTTPS
+    tls: Option<(Vec<u8>, Vec<u8>)>,
     factory: Arc<ExporterFactory>,
     static_files: HashMap<String, Resource>,
     known_client: Mutex<Vec<ClientPivot>>,
+    auth_token: Option<String>,
+    auth_read: bool,
+    /// UNIX socket of the proxy, only reported in /config
+    unix_path: String,
+    /// Last /metrics/global result and when it was computed (ms)
+    global_cache: Mutex<Option<(u64, Vec<CounterSnapshot>)>>,
+    /// How long a /metrics text output is served from the cache (zero = no cache)
+    metrics_cache_ttl: Duration,
+    /// Text /metrics outputs
+    metrics_cache: Mutex<HashMap<MetricsCacheKey, MetricsCacheEntry>>,
 }
 
 enum WebResponse {
@@ -98,6 +154,8 @@ enum WebResponse {
     #[allow(unused)]
     Redirect302(String),
     Native(Response),
+    /// Unbounded response which must not be buffered
+    Stream(Response),
     NoSuchDoc(),
 }
 
@@ -127,20 +185,128 @@ impl WebResponse {
             WebResponse::NoSuchDoc() => Response::empty_404(),
             WebResponse::Redirect302(url) => Response::redirect_302(url),
             WebResponse::Native(response) => response,
+            WebResponse::Stream(response) => response,
+        }
+    }
+
+    /// Serialize the response and compress it when the client
+    /// advertises support for it using the Accept-Encoding header
+    /// only textual payloads (prometheus text, JSON) are compressed
+    fn serialize_for(self: WebResponse, req: &Request) -> Response {
+        match self {
+            /* Compression would wait for the end of the stream */
+            WebResponse::Stream(response) => response,
+            resp => rouille::content_encoding::apply(req, resp.serialize()),
+        }
+    }
+}
+
+/// Delay after which an empty line is sent to detect closed streams
+const STREAM_HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// Attempts to bind the web port (500ms apart) while it is still in use
+const WEB_BIND_ATTEMPTS: usize = 20;
+
+/// Request handler of the web server, shared by the bind attempts
+type WebHandler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Error of the last bind attempt
+type BindError = retry::Error<Box<dyn std::error::Error + Send + Sync>>;
+
+/// Reader turning counter updates into NDJSON lines for /stream
+/// rouille sends it using chunked transfer until the client leaves
+struct CounterStream {
+    rx: Receiver<CounterUpdate>,
+    /// Coalescing window, only the last value of a counter is sent
+    interval: Duration,
+    pending: Vec<u8>,
+}
+
+impl CounterStream {
+    fn fill(&mut self) -> std::io::Result<()> {
+        let first = match self.rx.recv_timeout(STREAM_HEARTBEAT) {
+            Ok(u) => u,
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending.push(b'\n');
+                return Ok(());
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let mut order: Vec<(String, String)> = Vec::new();
+        let mut batch: HashMap<(String, String), CounterUpdate> = HashMap::new();
+        let deadline = Instant::now() + self.interval;
+        let mut next = Some(first);
+
+        while let Some(u) = next {
+            let key = (u.job.to_string(), u.counter.name.to_string());
+            if batch.insert(key.clone(), u).is_none() {
+                order.push(key);
+            }
+
+            next = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => self.rx.recv_timeout(left).ok(),
+                _ => self.rx.try_recv().ok(),
+            };
+
+            /* Do not starve the client on very fast counters */
+            if order.len() >= 4096 {
+                break;
+            }
+        }
+
+        for key in order {
+            if let Some(u) = batch.get(&key) {
+                serde_json::to_writer(&mut self.pending, u)?;
+                self.pending.push(b'\n');
+            }
         }
+
+        Ok(())
+    }
+}
+
+impl Read for CounterStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            self.fill()?;
+        }
+
+        /* Nothing after a fill means the proxy closed the stream */
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+
+        Ok(len)
     }
 }
 
 impl Web {
-    pub(crate) fn new(port: u32, factory: Arc<ExporterFactory>) -> Web {
+    pub(crate) fn new(
+        port: u32,
+        bind: IpAddr,
+        tls: Option<(Vec<u8>, Vec<u8>)>,
+        factory: Arc<ExporterFactory>,
+        auth_token: Option<String>,
+        auth_read: bool,
+        unix_path: String,
+    ) -> Web {
         let web = Web {
             port,
+            bind,
+            tls,
             factory,
             static_files: generate()
                 .into_iter()
                 .map(|(k, v)| (k.to_string(), v))
                 .collect(),
             known_client: Mutex::new(Vec::new()),
+            auth_token,
+            auth_read,
+            unix_path,
+            global_cache: Mutex::new(None),
+            metrics_cache_ttl: Duration::ZERO,
+            metrics_cache: Mutex::new(HashMap::new()),
         };
         /* Add myself in the URLs */
         web.known_client
@@ -150,8 +316,20 @@ impl Web {
         web
     }
 
+    /// Serve the text /metrics output from a cache refreshed every ttl
+    pub(crate) fn with_metrics_cache(mut self, ttl: Duration) -> Web {
+        self.metrics_cache_ttl = ttl;
+        self
+    }
+
+    /// Address advertised to other proxies, the scheme is
+    /// only given for TLS as plain HTTP is the default
     pub(crate) fn url(&self) -> String {
-        format!("{}:{}", hostname(), self.port)
+        if self.tls.is_some() {
+            format!("https://{}:{}", hostname(), self.port)
+        } else {
+            format!("{}:{}", hostname(), self.port)
+        }
     }
 
     fn default_doc() -> String {
@@ -244,7 +422,7 @@ impl Web {
         let value = value.unwrap();
 
         let snap = CounterSnapshot {
-            name: key,
+            name: self.factory.metric_name(&key, false),
             doc: "".to_string(),
             ctype: CounterType::Counter {
                 ts: proxy_common::unix_ts_us() as u64,
@@ -252,8 +430,14 @@ impl Web {
             },
         };
 
+        let name = snap.name.to_string();
+
         match self.factory.get_main().set(snap) {
-            Ok(_) => WebResponse::Success("set".to_string()),
+            Ok(_) => {
+                self.factory
+                    .notify_update("main", &self.factory.get_main(), &name);
+                WebResponse::Success("set".to_string())
+            }
             Err(e) => WebResponse::BadReq(e.to_string()),
         }
     }
@@ -274,7 +458,7 @@ impl Web {
         let value = value.unwrap();
 
         let snap = CounterSnapshot {
-            name: key,
+            name: self.factory.metric_name(&key, false),
             doc: "".to_string(),
             ctype: CounterType::Counter {
                 ts: proxy_common::unix_ts_us() as u64,
@@ -283,7 +467,11 @@ impl Web {
         };
 
         match self.factory.get_main().accumulate(&snap, false) {
-            Ok(_) => WebResponse::Success("inc".to_string()),
+            Ok(_) => {
+                self.factory
+                    .notify_update("main", &self.factory.get_main(), &snap.name);
+                WebResponse::Success("inc".to_string())
+            }
             Err(e) => WebResponse::BadReq(e.to_string()),
         }
     }
@@ -302,7 +490,7 @@ impl Web {
         let key = key.unwrap();
 
         let snap = CounterSnapshot {
-            name: key,
+            name: self.factory.metric_name(&key, false),
             doc,
             ctype: CounterType::newcounter(),
         };
@@ -313,23 +501,270 @@ impl Web {
         }
     }
 
-    fn serialize_exporter(exporter: &Arc<Exporter>) -> WebResponse {
-        match exporter.serialize() {
+    /// Annotation forwarded by a child proxy without a trace for the job
+    fn handle_annotate(&self, req: &Request) -> WebResponse {
+        let annotation: Result<JobAnnotation, JsonError> = rouille::input::json_input(req);
+
+        match annotation {
+            Ok(annotation) => match self.factory.annotate(annotation) {
+                Ok(_) => WebResponse::Success("annotate".to_string()),
+                Err(e) => WebResponse::BadReq(e.to_string()),
+            },
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    /// Does the client accept application/json
+    fn accepts_json(req: &Request) -> bool {
+        req.header("Accept")
+            .map(|a| {
+                a.split(',')
+                    .any(|t| t.split(';').next().unwrap_or("").trim() == "application/json")
+            })
+            .unwrap_or(false)
+    }
+
+    fn serialize_exporter(
+        exporter: &Arc<Exporter>,
+        exemplars: bool,
+        json: bool,
+        full: bool,
+    ) -> WebResponse {
+        if json {
+            return match exporter.counters(full) {
+                Ok(c) => WebResponse::Native(Response::json(&c)),
+                Err(e) => WebResponse::BadReq(e.to_string()),
+            };
+        }
+
+        match exporter.serialize_full(exemplars, full) {
             Ok(v) => WebResponse::Text(v),
             Err(e) => WebResponse::BadReq(e.to_string()),
         }
     }
 
-    fn handle_metrics(&self, req: &Request) -> WebResponse {
-        if let Some(jobid) = req.get_param("job") {
-            if let Some(exporter) = self.factory.resolve_by_id(&jobid) {
-                Web::serialize_exporter(&exporter)
-            } else {
-                WebResponse::BadReq(format!("No such jobid {}", jobid))
+    /// Text exposition of an exporter cached for --metrics-cache-ms
+    /// the X-Cache header tells if the output was served from the cache
+    fn cached_metrics(
+        &self,
+        jobid: &str,
+        exporter: &Arc<Exporter>,
+        exemplars: bool,
+        full: bool,
+    ) -> WebResponse {
+        if self.metrics_cache_ttl.is_zero() {
+            return Web::serialize_exporter(exporter, exemplars, false, full);
+        }
+
+        let key = MetricsCacheKey {
+            jobid: jobid.to_string(),
+            exemplars,
+            include_empty: full,
+        };
+
+        if let Some(entry) = self.metrics_cache.lock().unwrap().get(&key) {
+            if entry.at.elapsed() < self.metrics_cache_ttl {
+                return WebResponse::Native(
+                    Response::text(entry.text.clone()).with_additional_header("X-Cache", "HIT"),
+                );
             }
+        }
+
+        /* Serialized without the lock, concurrent misses may both serialize */
+        let text = match exporter.serialize_full(exemplars, full) {
+            Ok(v) => v,
+            Err(e) => return WebResponse::BadReq(e.to_string()),
+        };
+
+        let ttl = self.metrics_cache_ttl;
+        let mut cache = self.metrics_cache.lock().unwrap();
+        /* Drop the entries of ended jobs */
+        cache.retain(|_, entry| entry.at.elapsed() < ttl);
+        cache.insert(
+            key,
+            MetricsCacheEntry {
+                at: Instant::now(),
+                text: text.clone(),
+            },
+        );
+
+        WebResponse::Native(Response::text(text).with_additional_header("X-Cache", "MISS"))
+    }
+
+    fn handle_metrics(&self, req: &Request) -> WebResponse {
+        /* Exemplars are opt-in as not all scrapers parse them */
+        let exemplars = match req.get_param("exemplars") {
+            Some(e) => parse_bool(e.as_str()),
+            None => false,
+        };
+
+        /* Text exposition unless JSON is explicitly requested */
+        let json = Web::accepts_json(req);
+
+        /* Declared metrics without data (counters at 0, gauges never set) are
+        listed in text and skipped in JSON unless include_empty says otherwise */
+        let full = match req.get_param("include_empty") {
+            Some(e) => parse_bool(e.as_str()),
+            None => !json,
+        };
+
+        let (jobid, exporter) = match req.get_param("job") {
+            Some(jobid) => match self.factory.resolve_by_id(&jobid) {
+                Some(e) => (jobid, e),
+                None => return WebResponse::BadReq(format!("No such jobid {}", jobid)),
+            },
+            None => (String::new(), self.factory.get_main()),
+        };
+
+        if json {
+            Web::serialize_exporter(&exporter, exemplars, json, full)
         } else {
-            Web::serialize_exporter(&self.factory.get_main())
+            self.cached_metrics(&jobid, &exporter, exemplars, full)
+        }
+    }
+
+    /// Metrics changed since the token passed as since (all without it)
+    /// with the token to pass on the next call
+    fn handle_metrics_delta(&self, req: &Request) -> WebResponse {
+        let exporter = match req.get_param("job") {
+            Some(jobid) => match self.factory.resolve_by_id(&jobid) {
+                Some(e) => e,
+                None => return WebResponse::BadReq(format!("No such jobid {}", jobid)),
+            },
+            None => self.factory.get_main(),
+        };
+
+        let since = req.get_param("since").unwrap_or_default();
+
+        WebResponse::Native(Response::json(&exporter.delta(&since)))
+    }
+
+    fn handle_metrics_meta(&self, req: &Request) -> WebResponse {
+        let exporter = match req.get_param("job") {
+            Some(jobid) => match self.factory.resolve_by_id(&jobid) {
+                Some(e) => e,
+                None => return WebResponse::BadReq(format!("No such jobid {}", jobid)),
+            },
+            None => self.factory.get_main(),
+        };
+
+        WebResponse::Native(Response::json(&exporter.meta()))
+    }
+
+    /// Children of this proxy, from its proxy scrapes and (on the root) the pivot list
+    fn child_proxies(&self) -> Vec<String> {
+        let me = proxy_url(&self.url());
+
+        let mut ret: Vec<String> = self
+            .factory
+            .child_proxies()
+            .iter()
+            .map(|c| proxy_url(c))
+            .collect();
+
+        for c in self.known_client.lock().unwrap().iter() {
+            if proxy_url(&c.url) == me {
+                for child in c.child.iter().map(|c| proxy_url(c)) {
+                    if !ret.contains(&child) {
+                        ret.push(child);
+                    }
+                }
+            }
+        }
+
+        ret.retain(|c| *c != me);
+        ret
+    }
+
+    /// Node exporter of this proxy merged with the /metrics/global of its children
+    /// (main already holds what was scraped from the children)
+    fn global_counters(&self) -> Vec<CounterSnapshot> {
+        let period = *self.factory.period.read().unwrap();
+
+        /* Cached for a period so that a query does not storm the tree */
+        if let Some((ts, counters)) = self.global_cache.lock().unwrap().as_ref() {
+            if proxy_common::unix_ts() - ts < period {
+                return counters.clone();
+            }
+        }
+
+        let client = reqwest::blocking::Client::builder()
+            .connect_timeout(self.factory.scrape_timeout())
+            .timeout(self.factory.scrape_timeout())
+            .build();
+
+        let children: Vec<Vec<CounterSnapshot>> = match &client {
+            Ok(client) => self
+                .child_proxies()
+                .into_par_iter()
+                .filter_map(|child| {
+                    let url = format!("{}/metrics/global", child);
+                    let resp = proxy_common::with_proxy_auth(client.get(&url))
+                        .header("Accept", "application/json")
+                        .send()
+                        .and_then(|r| r.error_for_status())
+                        .map_err(ProxyErr::new)
+                        .and_then(|r| {
+                            proxy_common::read_json_capped::<Vec<CounterSnapshot>>(
+                                r,
+                                proxy_common::PEER_MAX_BODY,
+                            )
+                        });
+                    match resp {
+                        Ok(c) => Some(c),
+                        Err(e) => {
+                            log::warn!("Failed to get global metrics of {} : {}", child, e);
+                            None
+                        }
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("Failed to create HTTP client : {}", e);
+                Vec::new()
+            }
+        };
+
+        let mut merged: HashMap<String, CounterSnapshot> = HashMap::new();
+
+        let local = self.factory.get_node().counters(false).unwrap_or_default();
+
+        for c in local.into_iter().chain(children.into_iter().flatten()) {
+            match merged.get_mut(&c.name) {
+                Some(existing) => {
+                    if let Err(e) = existing.merge(&c) {
+                        log::warn!("Failed to merge {} : {}", c.name, e);
+                    }
+                }
+                None => {
+                    merged.insert(c.name.to_string(), c);
+                }
+            }
+        }
+
+        let ret: Vec<CounterSnapshot> = merged.into_values().collect();
+
+        *self.global_cache.lock().unwrap() = Some((proxy_common::unix_ts(), ret.clone()));
+
+        ret
+    }
+
+    fn handle_metrics_global(&self, req: &Request) -> WebResponse {
+        let counters = self.global_counters();
+
+        if Web::accepts_json(req) {
+            return WebResponse::Native(Response::json(&counters));
         }
+
+        let exporter = Arc::new(Exporter::new());
+
+        for c in counters.iter() {
+            if let Err(e) = exporter.push(c) {
+                return WebResponse::BadReq(e.to_string());
+            }
+        }
+
+        Web::serialize_exporter(&exporter, false, false, true)
     }
 
     fn handle_queue(&self, _req: &Request) -> WebResponse {
@@ -369,10 +804,57 @@ impl Web {
         WebResponse::Native(Response::json(&traces))
     }
 
+    /// Parse the optional max_points parameter used to downsample traces
+    fn max_points_param(req: &Request) -> Result<Option<usize>, WebResponse> {
+        match req.get_param("max_points").map(|v| v.parse::<usize>()) {
+            Some(Ok(n)) if n < 3 => Err(WebResponse::BadReq(
+                "max_points must be at least 3".to_string(),
+            )),
+            Some(Ok(n)) => Ok(Some(n)),
+            Some(Err(e)) => Err(WebResponse::BadReq(format!(
+                "Bad max_points parameter: {}",
+                e
+            ))),
+            None => Ok(None),
+        }
+    }
+
     fn handle_traceread(&self, req: &Request) -> WebResponse {
+        /* Incremental read of the raw frames */
+        if let Some(since) = req.get_param("since") {
+            return match req.get_param("job") {
+                Some(jobid) => match self.factory.trace_store.read_since(&jobid, &since) {
+                    Ok(tail) => WebResponse::Native(Response::json(&tail)),
+                    Err(e) => WebResponse::BadReq(format!("Failed to read trace {}", e)),
+                },
+                None => WebResponse::BadReq("No job GET parameter passed".to_string()),
+            };
+        }
+
         let filter = req.get_param("filter");
+
+        let smooth = match req.get_param("smooth").map(|v| v.parse::<f64>()) {
+            Some(Ok(alpha)) => {
+                if let Err(e) = crate::trace::check_ema_alpha(alpha) {
+                    return WebResponse::BadReq(e.to_string());
+                }
+                Some(alpha)
+            }
+            Some(Err(e)) => return WebResponse::BadReq(format!("Bad smooth parameter: {}", e)),
+            None => None,
+        };
+
+        let max_points = match Web::max_points_param(req) {
+            Ok(n) => n,
+            Err(resp) => return resp,
+        };
+
         if let Some(jobid) = req.get_param("job") {
-            match self.factory.trace_store.read(&jobid, filter) {
+            match self
+                .factory
+                .trace_store
+                .read(&jobid, filter, smooth, max_points)
+            {
                 Ok(data) => {
                     return WebResponse::Native(Response::json(&data));
                 }
@@ -398,6 +880,47 @@ impl Web {
         WebResponse::BadReq("No job GET parameter passed".to_string())
     }
 
+    fn handle_tracedownload(&self, req: &Request) -> WebResponse {
+        if let Some(jobid) = req.get_param("job") {
+            let file = self
+                .factory
+                .trace_store
+                .trace_file(&jobid)
+                .map_err(|e| e.to_string())
+                .and_then(|path| std::fs::File::open(path).map_err(|e| e.to_string()));
+
+            return match file {
+                Ok(fd) => WebResponse::Native(
+                    Response::from_file("application/octet-stream", fd).with_additional_header(
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}.trace\"", jobid),
+                    ),
+                ),
+                Err(e) => WebResponse::BadReq(format!("Failed to open trace of {} : {}", jobid, e)),
+            };
+        }
+        WebResponse::BadReq("No job GET parameter passed".to_string())
+    }
+
+    fn handle_traceupload(&self, req: &Request) -> WebResponse {
+        if req.method() != "POST" {
+            return WebResponse::BadReq("Traces must be uploaded with POST".to_string());
+        }
+
+        let mut data = match req.data() {
+            Some(d) => d,
+            None => return WebResponse::BadReq("No trace data in request".to_string()),
+        };
+
+        /* A trace is folded once past --max-trace-size, leave room for its last frames */
+        let max_size = 2 * self.factory.max_trace_size() as u64;
+
+        match self.factory.trace_store.import(&mut data, max_size) {
+            Ok(desc) => WebResponse::Success(format!("Imported trace of {}", desc.jobid)),
+            Err(e) => WebResponse::BadReq(format!("Failed to import trace : {}", e)),
+        }
+    }
+
     fn handle_tracesize(&self, req: &Request) -> WebResponse {
         if let Some(jobid) = req.get_param("jobid") {
             match self.factory.trace_store.get_trace_sizes(&jobid) {
@@ -412,6 +935,42 @@ impl Web {
         WebResponse::BadReq("No job GET parameter passed".to_string())
     }
 
+    fn handle_tracegc(&self, req: &Request) -> WebResponse {
+        if req.method() != "POST" {
+            return WebResponse::BadReq("Trace GC must be triggered with POST".to_string());
+        }
+
+        let reclaimed = match self.factory.trace_gc() {
+            Some(r) => r,
+            None => {
+                return WebResponse::BadReq("Traces are kept as --trace-retention is 0".to_string())
+            }
+        };
+
+        WebResponse::Native(Response::json(
+            &serde_json::json!({ "reclaimed_bytes": reclaimed }),
+        ))
+    }
+
+    /// Trace in the Chrome Trace Event format, to open in ui.perfetto.dev
+    fn handle_trace_perfetto(&self, req: &Request) -> WebResponse {
+        if let Some(jobid) = req.get_param("job") {
+            return match self.factory.trace_store.chrome_trace(&jobid) {
+                Ok(trace) => WebResponse::Native(Response::json(&trace).with_additional_header(
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}.perfetto.json\"", jobid),
+                )),
+                Err(e) => WebResponse::BadReq(e.to_string()),
+            };
+        }
+        WebResponse::BadReq("No job GET parameter passed".to_string())
+    }
+
+    fn handle_tracestats(&self, _req: &Request) -> WebResponse {
+        let stats = self.factory.trace_store.stats();
+        WebResponse::Native(Response::json(&stats))
+    }
+
     fn handle_traceplot(&self, req: &Request) -> WebResponse {
         #[derive(Deserialize)]
         struct Plotdef {
@@ -471,6 +1030,20 @@ impl Web {
         WebResponse::Native(Response::json(&scrapes))
     }
 
+    /// Pause or resume the scrape of a target (to=host:port)
+    fn handle_join_pause(&self, req: &Request, paused: bool) -> WebResponse {
+        let to = match req.get_param("to") {
+            Some(to) => to,
+            None => return WebResponse::BadReq("No to parameter passed".to_string()),
+        };
+
+        match self.factory.set_scrape_paused(&to, paused) {
+            Ok(key) if paused => WebResponse::Success(format!("Paused scraping {}", key)),
+            Ok(key) => WebResponse::Success(format!("Resumed scraping {}", key)),
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
     fn handle_join(&self, req: &Request) -> WebResponse {
         let to = req.get_param("to");
 
@@ -480,7 +1053,7 @@ impl Web {
 
         let to = to.unwrap();
 
-        if to.contains("http") {
+        if to.contains("http://") {
             return WebResponse::BadReq(
                 "To should not be an URL (with http://) but host:port".to_string(),
             );
@@ -491,7 +1064,20 @@ impl Web {
             None => 1000,
         };
 
-        if let Err(e) = ExporterFactory::add_scrape(self.factory.clone(), &to, period) {
+        /* Optional interval in ms over which scraped gauges are merged */
+        let gauge_interval: u64 = match req.get_param("gauge_interval") {
+            Some(e) => match e.parse::<u64>() {
+                Ok(v) => v,
+                Err(e) => {
+                    return WebResponse::BadReq(format!("Bad gauge_interval parameter: {}", e))
+                }
+            },
+            None => 0,
+        };
+
+        if let Err(e) =
+            ExporterFactory::add_scrape(self.factory.clone(), &to, period, gauge_interval)
+        {
             return WebResponse::BadReq(format!("Failed to add {} for scraping : {}", to, e));
         }
 
@@ -507,7 +1093,7 @@ impl Web {
 
         let to = to.unwrap();
 
-        if to.contains("http") {
+        if to.contains("http://") {
             return WebResponse::BadReq(
                 "To should not be an URL (with http://) but host:port".to_string(),
             );
@@ -529,7 +1115,7 @@ impl Web {
             let period = if i < periods.len() { periods[i] } else { 1000 };
 
             if let Err(e) =
-                ExporterFactory::add_scrape(self.factory.clone(), &target.to_string(), period)
+                ExporterFactory::add_scrape(self.factory.clone(), &target.to_string(), period, 0)
             {
                 return WebResponse::BadReq(format!(
                     "Failed to add {} for scraping : {}",
@@ -638,7 +1224,7 @@ impl Web {
 
         let from: String = from.unwrap().clone();
 
-        if from.contains("http") {
+        if from.contains("http://") {
             return WebResponse::BadReq(
                 "From should not be an URL (with http://) but host:port".to_string(),
             );
@@ -648,6 +1234,11 @@ impl Web {
 
         let mut clients = self.known_client.lock().unwrap();
 
+        /* A node may pivot again (retry or several roots), keep its current parent */
+        if let Some(parent) = clients.iter().find(|c| c.child.contains(&from)) {
+            return WebResponse::Success(parent.url.to_string());
+        }
+
         // Find the target parent node
 
         let target_url = self.get_target_node(&mut clients, max_branches);
@@ -672,25 +1263,37 @@ impl Web {
             resp = WebResponse::BadReq("Did not match any server".to_string());
         }
 
-        let mut new_client = ClientPivot::new(from);
-        new_client.set_depth(depth);
-
-        clients.push(new_client);
+        if let Some(known) = clients.iter_mut().find(|c| c.url == from) {
+            known.set_depth(depth);
+        } else {
+            let mut new_client = ClientPivot::new(from);
+            new_client.set_depth(depth);
+            clients.push(new_client);
+        }
 
         resp
     }
 
     fn handle_topo(&self, _req: &Request) -> WebResponse {
-        let mut resp: Vec<(String, String)> = Vec::new();
+        let mut resp: Vec<TopoEdge> = Vec::new();
 
         for c in self.known_client.lock().unwrap().iter() {
             for t in &c.child {
-                resp.push((c.url.clone(), t.clone()))
+                resp.push(TopoEdge {
+                    parent: c.url.clone(),
+                    child: t.clone(),
+                    refcount: c.refcount,
+                })
             }
         }
 
+        /* A lone proxy is its own root */
         if resp.is_empty() {
-            resp.push((self.url().to_string(), self.url().to_string()));
+            resp.push(TopoEdge {
+                parent: self.url().to_string(),
+                child: self.url().to_string(),
+                refcount: 0,
+            });
         }
 
         WebResponse::Native(Response::json(&resp))
@@ -729,8 +1332,9 @@ impl Web {
                         {
                             clients[parent_pos].removefrom(&replacement_url);
                             let req_url = format!(
-                                "http://{}/disconnect?target={}",
-                                clients[parent_pos].url, replacement_url
+                                "{}/disconnect?target={}",
+                                proxy_url(&clients[parent_pos].url),
+                                replacement_url
                             );
                             let _resp = ApiResponse::query(&req_url);
                         }
@@ -750,15 +1354,17 @@ impl Web {
                     let children_param = children.join("&");
                     let mut periods: Vec<String> = Vec::new();
                     for child in &children {
-                        let period_url = format!("http://{}/period", child);
+                        let period_url = format!("{}/period", proxy_url(child));
                         let resp = ApiResponse::query(&period_url);
                         periods.push(if let Ok(r) = resp { r.operation } else { "1000".to_string() });
                     }
                     let periods_param = periods.join("&");
 
                     let req_url = format!(
-                        "http://{}/join/multiple?to={}&period={}",
-                        replacement_url, children_param, periods_param
+                        "{}/join/multiple?to={}&period={}",
+                        proxy_url(&replacement_url),
+                        children_param,
+                        periods_param
                     );
                     let _resp = ApiResponse::query(&req_url);
                 }
@@ -771,7 +1377,7 @@ impl Web {
             return WebResponse::Success("Unresponsive node removed!".to_string());
         }
 
-        let period_url = format!("http://{}/period", replacement_url);
+        let period_url = format!("{}/period", proxy_url(&replacement_url));
         let period = ApiResponse::query(&period_url)
             .map(|r| r.operation)
             .unwrap_or_else(|_| "1000".to_string());
@@ -784,7 +1390,7 @@ impl Web {
             return WebResponse::BadReq("No from parameter passed".to_string());
         }
         let from_url: String = from_url.unwrap();
-        if from_url.contains("http") {
+        if from_url.contains("http://") {
             return WebResponse::BadReq(
                 "From should not be an URL (with http://) but host:port".to_string(),
             );
@@ -795,7 +1401,7 @@ impl Web {
             return WebResponse::BadReq("No target parameter passed".to_string());
         }
         let target_url: String = target_url.unwrap();
-        if target_url.contains("http") {
+        if target_url.contains("http://") {
             return WebResponse::BadReq(
                 "Target should not be an URL (with http://) but host:port".to_string(),
             );
@@ -828,7 +1434,7 @@ impl Web {
         };
 
         // Disconnect the leaving node from its parent's scrape list
-        let disconnect_url = format!("http://{}/disconnect?target={}", parent_url, from);
+        let disconnect_url = format!("{}/disconnect?target={}", proxy_url(&parent_url), from);
         let _ = ApiResponse::query(&disconnect_url);
 
         // Repair the TBON: find a replacement and wire it in
@@ -841,8 +1447,10 @@ impl Web {
                 let replacement = parts[0];
                 let period = parts[1];
                 let join_url = format!(
-                    "http://{}/join?to={}&period={}",
-                    parent_url, replacement, period
+                    "{}/join?to={}&period={}",
+                    proxy_url(&parent_url),
+                    replacement,
+                    period
                 );
                 let _ = ApiResponse::query(&join_url);
                 log::info!(
@@ -864,8 +1472,8 @@ impl Web {
 
         let mut target_url: String = target_url.unwrap().clone();
 
-        if !target_url.contains("http") {
-            target_url = format!("http://{}/job", target_url);
+        if !target_url.contains("://") {
+            target_url = format!("{}/job", proxy_url(&target_url));
         }
         if let Err(e) = ExporterFactory::remove_scrape(self.factory.clone(), &target_url) {
             return WebResponse::BadReq(format!("Failed to remove {}: {}", target_url, e));
@@ -874,6 +1482,151 @@ impl Web {
         WebResponse::Success(format!("Removed {} from scraping", target_url))
     }
 
+    /// Live counter updates as NDJSON, optionally for one job and coalesced over interval ms
+    fn handle_stream(&self, req: &Request) -> WebResponse {
+        let interval = match req.get_param("interval").map(|v| v.parse::<u64>()) {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => return WebResponse::BadReq(format!("Bad interval: {}", e)),
+            None => 0,
+        };
+
+        let rx = match self.factory.subscribe(req.get_param("job")) {
+            Ok(rx) => rx,
+            Err(e) => {
+                return WebResponse::Native(
+                    Response::text(format!("{}\n", e)).with_status_code(503),
+                )
+            }
+        };
+
+        let stream = CounterStream {
+            rx,
+            interval: Duration::from_millis(interval),
+            pending: Vec::new(),
+        };
+
+        WebResponse::Stream(Response {
+            status_code: 200,
+            headers: vec![("Content-Type".into(), "application/x-ndjson".into())],
+            data: ResponseBody::from_reader(stream),
+            upgrade: None,
+        })
+    }
+
+    /// Effective settings, only those in CONFIG_MUTABLE can be POSTed
+    fn config(&self) -> serde_json::Value {
+        let system_metrics = self
+            .factory
+            .system_metrics()
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default();
+
+        serde_json::json!({
+            "unix_socket": self.unix_path,
+            "bind": self.bind.to_string(),
+            "port": self.port,
+            "tls": self.tls.is_some(),
+            "period": *self.factory.period.read().unwrap(),
+            "branches": self.factory.branches,
+            "aggregate": self.factory.is_aggregator(),
+            "metric_prefix": self.factory.metric_prefix(),
+            "scrape_timeout_ms": self.factory.scrape_timeout().as_millis() as u64,
+            "metric_ttl": self.factory.metric_ttl(),
+            "system_metrics": system_metrics,
+        })
+    }
+
+    fn handle_config(&self, req: &Request) -> WebResponse {
+        if req.method() != "POST" {
+            return WebResponse::Native(Response::json(&self.config()));
+        }
+
+        let changes: serde_json::Map<String, serde_json::Value> =
+            match rouille::input::json_input(req) {
+                Ok(c) => c,
+                Err(e) => return WebResponse::BadReq(e.to_string()),
+            };
+
+        let current = self.config();
+
+        let mut ttl: Option<u64> = None;
+        let mut timeout: Option<Duration> = None;
+        let mut level: Option<SystemMetricsLevel> = None;
+
+        /* Check everything first so that a bad request changes nothing */
+        for (key, value) in changes.iter() {
+            match key.as_str() {
+                "metric_ttl" => match value.as_u64() {
+                    Some(v) => ttl = Some(v),
+                    None => {
+                        return WebResponse::BadReq(
+                            "metric_ttl must be a number of seconds".to_string(),
+                        )
+                    }
+                },
+                "scrape_timeout_ms" => match value.as_u64() {
+                    Some(v) if v > 0 => timeout = Some(Duration::from_millis(v)),
+                    _ => {
+                        return WebResponse::BadReq(
+                            "scrape_timeout_ms must be a positive number of ms".to_string(),
+                        )
+                    }
+                },
+                "system_metrics" => match value
+                    .as_str()
+                    .map(|v| SystemMetricsLevel::from_str(v, true))
+                {
+                    Some(Ok(l)) => level = Some(l),
+                    _ => {
+                        return WebResponse::BadReq(
+                            "system_metrics must be one of none, basic or full".to_string(),
+                        )
+                    }
+                },
+                k if current.get(k).is_some() => {
+                    return WebResponse::BadReq(format!(
+                        "{} cannot be changed at runtime, restart the proxy to modify it",
+                        k
+                    ))
+                }
+                k => return WebResponse::BadReq(format!("No such setting {}", k)),
+            }
+        }
+
+        if let Some(ttl) = ttl {
+            self.factory.set_metric_ttl(ttl);
+        }
+
+        if let Some(timeout) = timeout {
+            self.factory.set_scrape_timeout(timeout);
+        }
+
+        if let Some(level) = level {
+            if let Err(e) = ExporterFactory::set_system_metrics(self.factory.clone(), level) {
+                return WebResponse::BadReq(e.to_string());
+            }
+        }
+
+        WebResponse::Native(Response::json(&self.config()))
+    }
+
+    /// Liveness, answers as long as the webserver runs
+    fn handle_healthz(&self, _req: &Request) -> WebResponse {
+        WebResponse::Native(Response::json(&serde_json::json!({"status": "ok"})))
+    }
+
+    /// Readiness, 503 until a scrape pass succeeded and profiles can be stored
+    fn handle_readyz(&self, _req: &Request) -> WebResponse {
+        if self.factory.is_ready() {
+            WebResponse::Native(Response::json(&serde_json::json!({"status": "ok"})))
+        } else {
+            WebResponse::Native(
+                Response::json(&serde_json::json!({"status": "starting"})).with_status_code(503),
+            )
+        }
+    }
+
     // Get period of the Exporter Factory
     fn handle_period(&self, _req: &Request) -> WebResponse {
         println!("Handling period request");
@@ -889,12 +1642,71 @@ impl Web {
                 Err(e) => WebResponse::BadReq(e.to_string()),
             }
         } else {
-            /* For all we skip null values to be faster */
-            let all = self.factory.profiles(false);
-            WebResponse::Native(Response::json(&all))
+            let command = req.get_param("command");
+            let partition = req.get_param("partition");
+            let cluster = req.get_param("cluster");
+
+            /* For all we skip null values to be faster, filters are ANDed */
+            let all = self.factory.profiles_filtered(false, |desc| {
+                let command_ok = match &command {
+                    Some(c) => desc.command.contains(c.as_str()),
+                    None => true,
+                };
+                let partition_ok = match &partition {
+                    Some(p) => &desc.partition == p,
+                    None => true,
+                };
+                let cluster_ok = match &cluster {
+                    Some(c) => &desc.cluster == c,
+                    None => true,
+                };
+                command_ok && partition_ok && cluster_ok
+            });
+            /* Lets the scraping proxies label metrics with their origin (--label-source-host) */
+            WebResponse::Native(
+                Response::json(&all)
+                    .with_additional_header(PROXY_HOST_HEADER, proxy_common::hostname()),
+            )
+        }
+    }
+
+    fn handle_job_pernode(&self, req: &Request) -> WebResponse {
+        let jobid = match req.get_param("job") {
+            Some(jobid) => jobid,
+            None => return WebResponse::BadReq("A 'job' GET parameter is required".to_string()),
+        };
+
+        match self.factory.node_profiles_of(&jobid) {
+            Ok(nodes) => WebResponse::Native(Response::json(&nodes)),
+            Err(e) => WebResponse::BadReq(e.to_string()),
         }
     }
 
+    fn handle_job_diff(&self, req: &Request) -> WebResponse {
+        let (a, b) = match (req.get_param("a"), req.get_param("b")) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                return WebResponse::BadReq(
+                    "Both 'a' and 'b' GET parameters are required".to_string(),
+                );
+            }
+        };
+
+        let prof_a = if let Some(prof) = self.job_id_to_profile(&a) {
+            prof
+        } else {
+            return WebResponse::BadReq(format!("No such jobid {}", a));
+        };
+
+        let prof_b = if let Some(prof) = self.job_id_to_profile(&b) {
+            prof
+        } else {
+            return WebResponse::BadReq(format!("No such jobid {}", b));
+        };
+
+        WebResponse::Native(Response::json(&prof_a.diff(&prof_b)))
+    }
+
     fn handle_joblist(&self, _req: &Request) -> WebResponse {
         let jobs = self.factory.list_jobs();
 
@@ -905,7 +1717,8 @@ impl Web {
     }
 
     fn handle_alarms(&self, _req: &Request) -> WebResponse {
-        let trigerred_alarms = self.factory.check_alarms();
+        /* Alarms are sampled by the factory loop, polling must not skew windows */
+        let trigerred_alarms = self.factory.active_alarms();
         WebResponse::Native(Response::json(&trigerred_alarms))
     }
 
@@ -917,18 +1730,32 @@ impl Web {
             metric: String,
             operation: String,
             value: f64,
+            /// Seconds of the trailing window (0 = current value)
+            #[serde(default)]
+            window: u64,
+            /// mean (default) or pNN over the window
+            aggregation: Option<String>,
         }
 
         let al: Result<AlarmDef, JsonError> = rouille::input::json_input(req);
 
         match al {
             Ok(def) => {
+                let window = match def.window {
+                    0 => None,
+                    s => match AlarmWindow::new(s, def.aggregation.as_deref().unwrap_or("mean")) {
+                        Ok(w) => Some(w),
+                        Err(e) => return WebResponse::BadReq(e.to_string()),
+                    },
+                };
+
                 match self.factory.add_alarm(
                     def.name,
                     def.target,
                     def.metric,
                     def.operation,
                     def.value,
+                    window,
                 ) {
                     Ok(_) => WebResponse::Success("alarm registered".to_string()),
                     Err(e) => WebResponse::BadReq(e.to_string()),
@@ -972,6 +1799,16 @@ impl Web {
         }
     }
 
+    fn handle_alarm_history(&self, req: &Request) -> WebResponse {
+        if let Some(jobid) = req.get_param("job") {
+            return match self.factory.alarm_history(&jobid) {
+                Ok(h) => WebResponse::Native(Response::json(&h)),
+                Err(e) => WebResponse::BadReq(e.to_string()),
+            };
+        }
+        WebResponse::BadReq("No job GET parameter passed".to_string())
+    }
+
     fn handle_list_alarms(&self, _: &Request) -> WebResponse {
         let alarms = self.factory.list_alarms();
         WebResponse::Native(Response::json(&alarms))
@@ -1098,7 +1935,7 @@ impl Web {
                             Err(e) => return badreq(&format!("Invalid FTIO args JSON: {}", e)),
                         };
 
-                    let export = match self.factory.trace_store.export(&jobid) {
+                    let export = match self.factory.trace_store.export(&jobid, None) {
                         Ok(v) => v,
                         Err(_) => return badreq("Could not export trace data"),
                     };
@@ -1142,6 +1979,40 @@ impl Web {
         );
     }
 
+    fn handle_ftio_generate(&self, req: &Request) -> WebResponse {
+        if req.method() != "POST" {
+            return WebResponse::BadReq("FTIO models must be generated with POST".to_string());
+        }
+
+        let jobid = match req.get_param("job") {
+            Some(j) => j,
+            None => return WebResponse::BadReq("No job GET parameter passed".to_string()),
+        };
+
+        if let Err(e) = self
+            .factory
+            .trace_store
+            .generate_ftio_model(&jobid, self.factory.ftio_client.clone())
+        {
+            return WebResponse::BadReq(format!("Failed to generate FTIO model : {}", e));
+        }
+
+        match self.factory.trace_store.get_job_freq_model(jobid) {
+            Some(models) => WebResponse::Native(Response::json(&models)),
+            None => WebResponse::BadReq("FTIO did not produce any model".to_string()),
+        }
+    }
+
+    fn handle_ftio_get(&self, req: &Request) -> WebResponse {
+        match req.get_param("job") {
+            Some(jobid) => match self.factory.trace_store.get_job_freq_model(jobid) {
+                Some(models) => WebResponse::Native(Response::json(&models)),
+                None => WebResponse::BadReq("No FTIO model for this job".to_string()),
+            },
+            None => WebResponse::BadReq("No job GET parameter passed".to_string()),
+        }
+    }
+
     fn handle_ftio_logs(&self, _req: &Request) -> WebResponse {
         let logs = self.factory.ftio_client.get_logs();
         WebResponse::Native(Response::json(&logs))
@@ -1183,7 +2054,12 @@ impl Web {
                 return WebResponse::BadReq("No such jobid".to_string());
             };
 
-            if let Ok(model) = self.factory.profile_store.extrap_model_list(&prof.desc) {
+            let model = self
+                .factory
+                .profile_store
+                .extrap_model_list(&prof.desc.command);
+
+            if let Ok(model) = model {
                 return WebResponse::Native(Response::json(&model));
             }
             return WebResponse::BadReq(format!("Failed to get {}", jobid));
@@ -1221,8 +2097,13 @@ impl Web {
     }
 
     fn handle_get_json_trace(&self, req: &Request) -> WebResponse {
+        let max_points = match Web::max_points_param(req) {
+            Ok(n) => n,
+            Err(resp) => return resp,
+        };
+
         if let Some(jobid) = req.get_param("jobid") {
-            if let Ok(jsontrace) = self.factory.trace_store.export(&jobid) {
+            if let Ok(jsontrace) = self.factory.trace_store.export(&jobid, max_points) {
                 return WebResponse::Native(Response::json(&jsontrace));
             }
             return WebResponse::BadReq(format!("Failed to get {}", jobid));
@@ -1321,13 +2202,155 @@ impl Web {
         match self
             .factory
             .profile_store
-            .extrap_model_plot(&prof.desc, metric, &range)
+            .extrap_model_plot(&prof.desc.command, metric, &range)
         {
             Ok(model) => return WebResponse::Native(Response::json(&model)),
             Err(e) => WebResponse::BadReq(format!("Failed to plot {}", e)),
         }
     }
 
+    /// Extra-P JSON lines of several commands (repeated 'command' and/or a 'prefix')
+    /// samples carry a 'command' parameter indexing the X-Extrap-Commands header
+    fn handle_extrap_bundle(&self, req: &Request) -> WebResponse {
+        let commands: Vec<String> = url::form_urlencoded::parse(req.raw_query_string().as_bytes())
+            .filter(|(k, _)| k == "command")
+            .map(|(_, v)| v.to_string())
+            .collect();
+        let prefix = req.get_param("prefix");
+
+        if commands.is_empty() && prefix.is_none() {
+            return WebResponse::BadReq(
+                "'command' GET parameters or a 'prefix' are required".to_string(),
+            );
+        }
+
+        match self
+            .factory
+            .profile_store
+            .extrap_bundle(&commands, prefix.as_deref())
+        {
+            Ok((commands, jsonl)) => {
+                let commands = serde_json::to_string(&commands).unwrap_or_default();
+                WebResponse::Native(
+                    Response::text(jsonl).with_additional_header("X-Extrap-Commands", commands),
+                )
+            }
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    fn handle_command_model(&self, req: &Request) -> WebResponse {
+        #[derive(Serialize)]
+        struct MetricModel {
+            metric: String,
+            equation: String,
+            rss: f64,
+        }
+
+        let command = match req.get_param("command") {
+            Some(c) => c,
+            None => {
+                return WebResponse::BadReq("A 'command' GET parameter is required".to_string())
+            }
+        };
+
+        match self.factory.profile_store.extrap_model_list(&command) {
+            Ok(models) => {
+                let models: Vec<MetricModel> = models
+                    .into_iter()
+                    .map(|(metric, equation, rss)| MetricModel {
+                        metric,
+                        equation,
+                        rss,
+                    })
+                    .collect();
+                WebResponse::Native(Response::json(&models))
+            }
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    /// Command and metric GET parameters of the model endpoints
+    fn model_params(req: &Request) -> Result<(String, String), WebResponse> {
+        match (req.get_param("command"), req.get_param("metric")) {
+            (Some(command), Some(metric)) => Ok((command, metric)),
+            _ => Err(WebResponse::BadReq(
+                "Both 'command' and 'metric' GET parameters are required".to_string(),
+            )),
+        }
+    }
+
+    /// Float GET parameter, default is used when missing
+    fn float_param(req: &Request, name: &str, default: Option<f64>) -> Result<f64, WebResponse> {
+        match (req.get_param(name), default) {
+            (Some(v), _) => v
+                .parse::<f64>()
+                .map_err(|e| WebResponse::BadReq(format!("Failed to parse '{}' : {}", name, e))),
+            (None, Some(d)) => Ok(d),
+            (None, None) => Err(WebResponse::BadReq(format!(
+                "A '{}' GET parameter is required",
+                name
+            ))),
+        }
+    }
+
+    fn handle_command_model_eval(&self, req: &Request) -> WebResponse {
+        let (command, metric) = match Web::model_params(req) {
+            Ok(v) => v,
+            Err(e) => return e,
+        };
+
+        let size = match Web::float_param(req, "size", None) {
+            Ok(v) => v,
+            Err(e) => return e,
+        };
+
+        match self
+            .factory
+            .profile_store
+            .extrap_model_eval(&command, metric, size)
+        {
+            Ok((size, value)) => WebResponse::Native(Response::json(&serde_json::json!({
+                "size": size,
+                "value": value
+            }))),
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    fn handle_command_model_plot(&self, req: &Request) -> WebResponse {
+        let (command, metric) = match Web::model_params(req) {
+            Ok(v) => v,
+            Err(e) => return e,
+        };
+
+        let (from, to, points) = match (
+            Web::float_param(req, "from", None),
+            Web::float_param(req, "to", None),
+            Web::float_param(req, "points", Some(100.0)),
+        ) {
+            (Ok(from), Ok(to), Ok(points)) => (from, to, points as usize),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e,
+        };
+
+        if to < from || points < 2 {
+            return WebResponse::BadReq("Expected from <= to and at least 2 points".to_string());
+        }
+
+        /* Evenly spaced sizes including both bounds */
+        let step = (to - from) / (points - 1) as f64;
+        let range: Vec<f64> = (0..points).map(|i| from + step * i as f64).collect();
+
+        match self
+            .factory
+            .profile_store
+            .extrap_model_plot(&command, metric, &range)
+        {
+            Ok(curve) => WebResponse::Native(Response::json(&curve)),
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
     fn handle_profile_points(&self, req: &Request) -> WebResponse {
         if let Some(jobid) = req.get_param("jobid") {
             let prof = if let Some(prof) = self.job_id_to_profile(&jobid) {
@@ -1349,6 +2372,40 @@ impl Web {
         WebResponse::BadReq("A GET parameter for a reference jobid must be passed".to_string())
     }
 
+    fn handle_profile_regression(&self, req: &Request) -> WebResponse {
+        let jobid = match req.get_param("jobid") {
+            Some(j) => j,
+            None => return WebResponse::BadReq("A jobid parameter must be passed".to_string()),
+        };
+
+        let threshold = match req.get_param("threshold").map(|t| t.parse::<f64>()) {
+            None => 3.0,
+            Some(Ok(t)) if t > 0.0 => t,
+            _ => return WebResponse::BadReq("threshold must be a positive number".to_string()),
+        };
+
+        let prof = match self.job_id_to_profile(&jobid) {
+            Some(p) => p,
+            None => return WebResponse::BadReq("No such jobid".to_string()),
+        };
+
+        let metric = req.get_param("metric");
+
+        match self
+            .factory
+            .profile_store
+            .detect_regression(&prof, metric.as_deref(), threshold)
+        {
+            Ok(report) => WebResponse::Native(Response::json(&report)),
+            Err(e) => WebResponse::BadReq(e.to_string()),
+        }
+    }
+
+    fn handle_list_quarantine(&self, _: &Request) -> WebResponse {
+        let files = self.factory.profile_store.quarantined();
+        WebResponse::Native(Response::json(&files))
+    }
+
     fn handle_list_profiles_per_cmd(&self, _: &Request) -> WebResponse {
         let prof = self.factory.profile_store.gather_by_command();
         WebResponse::Native(Response::json(&prof))
@@ -1408,15 +2465,93 @@ impl Web {
         (prefix, resource)
     }
 
+    fn requires_auth(&self, method: &str, prefix: &str, resource: &str) -> bool {
+        match prefix {
+            "set" | "accumulate" | "push" | "annotate" | "join" | "remove" | "leave"
+            | "disconnect" => true,
+            "alarms" => matches!(resource, "add" | "del"),
+            "ftio" => resource == "generate",
+            "trace" => resource == "upload" || resource == "gc" || self.auth_read,
+            "metrics" | "job" | "stream" => self.auth_read,
+            "config" => method == "POST" || self.auth_read,
+            _ => false,
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let token = match &self.auth_token {
+            Some(t) => t,
+            None => return true,
+        };
+
+        let expected = format!("Bearer {}", token);
+        request
+            .header("Authorization")
+            .map(|h| Web::constant_time_eq(h.as_bytes(), expected.as_bytes()))
+            .unwrap_or(false)
+    }
+
+    /// Compare without stopping at the first differing byte so that the
+    /// time taken does not tell how much of the token was guessed
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+
+        a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    fn addr_in_use(err: &(dyn std::error::Error + 'static)) -> bool {
+        err.downcast_ref::<std::io::Error>()
+            .map(|e| e.kind() == std::io::ErrorKind::AddrInUse)
+            .unwrap_or(false)
+    }
+
+    /// Start the HTTP(S) server on addr, retrying while the port is in use
+    fn bind_server<H>(
+        addr: SocketAddr,
+        tls: Option<(Vec<u8>, Vec<u8>)>,
+        handler: H,
+    ) -> Result<rouille::Server<WebHandler>, BindError>
+    where
+        H: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        /* std already sets SO_REUSEADDR, the port can only be busy
+        because the previous proxy did not exit yet after a restart */
+        retry(Fixed::from_millis(500).take(WEB_BIND_ATTEMPTS), || {
+            let handler = handler.clone();
+            let handler: WebHandler = Box::new(move |request: &Request| (*handler)(request));
+
+            let server = match tls.clone() {
+                Some((cert, key)) => rouille::Server::new_ssl(addr, handler, cert, key),
+                None => rouille::Server::new(addr, handler),
+            };
+
+            match server {
+                Ok(s) => OperationResult::Ok(s),
+                Err(e) if Web::addr_in_use(e.as_ref()) => {
+                    log::warn!("{} is in use, retrying", addr);
+                    OperationResult::Retry(e)
+                }
+                Err(e) => OperationResult::Err(e),
+            }
+        })
+    }
+
     pub(crate) fn run_blocking(self) {
-        let hostname = hostname();
         log::info!(
-            "Proxy webserver listening on http://{}:{}",
-            hostname,
-            self.port
+            "Proxy webserver listening on {} (bound to {})",
+            proxy_url(&self.url()),
+            self.bind
         );
 
-        rouille::start_server(format!("0.0.0.0:{}", self.port), move |request| {
+        let factory = self.factory.clone();
+        let addr = SocketAddr::new(self.bind, self.port as u16);
+        let tls = self.tls.clone();
+
+        let handler = move |request: &Request| {
             let url = request.url();
 
             let (prefix, resource) = Web::parse_url(&url);
@@ -1428,14 +2563,33 @@ impl Web {
                 resource.yellow()
             );
 
+            if self.requires_auth(request.method(), prefix.as_str(), resource.as_str())
+                && !self.is_authorized(request)
+            {
+                log::warn!("Rejected unauthenticated request to {}", url);
+                let resp = Response::text("Unauthorized\n")
+                    .with_status_code(401)
+                    .with_additional_header("WWW-Authenticate", "Bearer");
+                return WebResponse::Native(resp).serialize_for(request);
+            }
+
             let resp: WebResponse = match prefix.as_str() {
                 "/" => self.serve_static_file("/index.html"),
                 "set" => self.handle_set(request),
                 "accumulate" => self.handle_accumulate(request),
                 "push" => self.handle_push(request),
-                "metrics" => self.handle_metrics(request),
+                "annotate" => self.handle_annotate(request),
+                "metrics" => match resource.as_str() {
+                    "global" => self.handle_metrics_global(request),
+                    "meta" => self.handle_metrics_meta(request),
+                    "delta" => self.handle_metrics_delta(request),
+                    _ => self.handle_metrics(request),
+                },
+                "stream" => self.handle_stream(request),
                 "job" => match resource.as_str() {
                     "list" => self.handle_joblist(request),
+                    "diff" => self.handle_job_diff(request),
+                    "pernode" => self.handle_job_pernode(request),
                     "" => self.handle_job(request),
                     _ => WebResponse::BadReq(url),
                 },
@@ -1450,7 +2604,12 @@ impl Web {
                     "plot" => self.handle_traceplot(request),
                     "metrics" => self.handle_tracemetrics(request),
                     "size" => self.handle_tracesize(request),
+                    "stats" => self.handle_tracestats(request),
+                    "gc" => self.handle_tracegc(request),
+                    "download" => self.handle_tracedownload(request),
+                    "upload" => self.handle_traceupload(request),
                     "json" => self.handle_get_json_trace(request),
+                    "perfetto" => self.handle_trace_perfetto(request),
                     "ftio" => self.handle_ftio_get_model(request),
                     _ => WebResponse::BadReq(url),
                 },
@@ -1458,8 +2617,20 @@ impl Web {
                     "" => self.handle_list_profiles(request),
                     "get" => self.handle_get_profiles(request),
                     "percmd" => self.handle_list_profiles_per_cmd(request),
+                    "quarantine" => self.handle_list_quarantine(request),
                     "extrap" => self.handle_extrap_get_jsonl(request),
                     "points" => self.handle_profile_points(request),
+                    "regression" => self.handle_profile_regression(request),
+                    "model" => self.handle_command_model(request),
+                    _ => WebResponse::BadReq(url),
+                },
+                "profiles/extrap" => match resource.as_str() {
+                    "bundle" => self.handle_extrap_bundle(request),
+                    _ => WebResponse::BadReq(url),
+                },
+                "profiles/model" => match resource.as_str() {
+                    "eval" => self.handle_command_model_eval(request),
+                    "plot" => self.handle_command_model_plot(request),
                     _ => WebResponse::BadReq(url),
                 },
                 "model" => match resource.as_str() {
@@ -1473,6 +2644,8 @@ impl Web {
                     "modified_args" => self.handle_ftio_modified_args(request),
                     "logs" => self.handle_ftio_logs(request),
                     "port" => self.handle_ftio_port(request),
+                    "generate" => self.handle_ftio_generate(request),
+                    "get" => self.handle_ftio_get(request),
                     _ => WebResponse::BadReq(url),
                 },
                 "pivot" => self.handle_pivot(request),
@@ -1481,23 +2654,272 @@ impl Web {
                     "" => self.handle_join(request),
                     "list" => self.handle_join_list(request),
                     "multiple" => self.handle_join_multiple(request),
+                    "pause" => self.handle_join_pause(request, true),
+                    "resume" => self.handle_join_pause(request, false),
                     _ => WebResponse::BadReq(url),
                 },
                 "remove" => self.handle_remove(request),
                 "leave" => self.handle_leave(request),
                 "disconnect" => self.handle_disconnect(request),
                 "period" => self.handle_period(request),
+                "config" => self.handle_config(request),
+                "healthz" => self.handle_healthz(request),
+                "readyz" => self.handle_readyz(request),
                 "alarms" => match resource.as_str() {
                     "" => self.handle_alarms(request),
                     "add" => self.handle_add_alarms(request),
                     "del" => self.handle_del_alarms(request),
                     "list" => self.handle_list_alarms(request),
+                    "history" => self.handle_alarm_history(request),
                     _ => WebResponse::BadReq(url),
                 },
                 _ => self.serve_static_file(url.as_str()),
             };
 
-            resp.serialize()
+            resp.serialize_for(request)
+        };
+
+        let server = Web::bind_server(addr, tls, handler).expect("Failed to start the webserver");
+
+        /* Stop accepting requests once the factory is shut down */
+        while factory.is_running() {
+            server.poll_timeout(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::{ExporterFactoryOptions, NoInstrumentation};
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+
+    #[test]
+    fn web_port_rebinds_after_drop() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = Web::bind_server(addr, None, |_| Response::text("ok")).unwrap();
+        let addr = server.server_addr();
+
+        /* The server closes the connection, its side of the port is left in TIME_WAIT */
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+            resp
         });
+
+        while !client.is_finished() {
+            server.poll_timeout(Duration::from_millis(10));
+        }
+        assert!(client.join().unwrap().ends_with("ok"));
+        drop(server);
+
+        /* tiny_http closes the listener from its accept thread shortly after the drop */
+        let start = Instant::now();
+        Web::bind_server(addr, None, |_| Response::text("ok")).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    fn header<'a>(resp: &'a Response, name: &str) -> Option<&'a str> {
+        resp.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    fn body(resp: Response) -> Vec<u8> {
+        let mut data = Vec::new();
+        resp.data
+            .into_reader_and_size()
+            .0
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    /// Payload of a gzip member without optional header fields
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!(&data[..4], &[0x1f, 0x8b, 8, 0]);
+        let inflated = miniz_oxide::inflate::decompress_to_vec(&data[10..data.len() - 8]).unwrap();
+
+        let size = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+        assert_eq!(size as usize, inflated.len());
+        inflated
+    }
+
+    #[test]
+    fn responses_are_gzipped_when_accepted() {
+        let text = "# TYPE calls counter\ncalls 1\n".repeat(1000);
+
+        let req = Request::fake_http(
+            "GET",
+            "/metrics",
+            vec![("Accept-Encoding".to_string(), "gzip".to_string())],
+            Vec::new(),
+        );
+        let resp = WebResponse::Text(text.clone()).serialize_for(&req);
+        assert_eq!(header(&resp, "Content-Encoding"), Some("gzip"));
+        let data = body(resp);
+        assert!(data.len() < text.len());
+        assert_eq!(gunzip(&data), text.as_bytes());
+
+        /* Streams are sent as they come */
+        let resp = WebResponse::Stream(Response::text(text.clone())).serialize_for(&req);
+        assert!(header(&resp, "Content-Encoding").is_none());
+
+        let req = Request::fake_http("GET", "/metrics", Vec::new(), Vec::new());
+        let resp = WebResponse::Text(text.clone()).serialize_for(&req);
+        assert!(header(&resp, "Content-Encoding").is_none());
+        assert_eq!(body(resp), text.as_bytes());
+    }
+
+    /// A web server over a factory whose main exporter holds counters metrics
+    fn metrics_web(name: &str, counters: usize, ttl: Duration) -> (Web, PathBuf) {
+        let prefix =
+            std::env::temp_dir().join(format!("proxy-web-{}-{}", std::process::id(), name));
+        let factory = ExporterFactory::new(
+            prefix.clone(),
+            ExporterFactoryOptions {
+                aggregate: false,
+                system_metrics: SystemMetricsLevel::None,
+                ..Default::default()
+            },
+            Arc::new(NoInstrumentation),
+        )
+        .unwrap();
+
+        for i in 0..counters {
+            let snap = CounterSnapshot {
+                name: format!("bench_calls{{rank=\"{}\"}}", i),
+                doc: "Number of calls".to_string(),
+                ctype: CounterType::Counter {
+                    ts: 0,
+                    value: i as f64,
+                },
+            };
+            factory.get_main().push(&snap).unwrap();
+        }
+
+        let web = Web::new(
+            0,
+            IpAddr::from([127, 0, 0, 1]),
+            None,
+            factory,
+            None,
+            false,
+            String::new(),
+        )
+        .with_metrics_cache(ttl);
+
+        (web, prefix)
+    }
+
+    fn x_cache(resp: &WebResponse) -> Option<String> {
+        match resp {
+            WebResponse::Native(r) => header(r, "X-Cache").map(|v| v.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn metrics_are_cached_per_job() {
+        let (web, prefix) = metrics_web("cache", 10, Duration::from_secs(60));
+        let main = web.factory.get_main();
+
+        let first = web.cached_metrics("", &main, false, true);
+        assert_eq!(x_cache(&first).as_deref(), Some("MISS"));
+        let second = web.cached_metrics("", &main, false, true);
+        assert_eq!(x_cache(&second).as_deref(), Some("HIT"));
+
+        /* Other jobs and outputs have their own entries */
+        let job = web.cached_metrics("1", &main, false, true);
+        assert_eq!(x_cache(&job).as_deref(), Some("MISS"));
+        let exemplars = web.cached_metrics("", &main, true, true);
+        assert_eq!(x_cache(&exemplars).as_deref(), Some("MISS"));
+
+        let (uncached, uncached_prefix) = metrics_web("nocache", 10, Duration::ZERO);
+        let resp = uncached.cached_metrics("", &main, false, true);
+        assert!(x_cache(&resp).is_none());
+
+        web.factory.shutdown();
+        uncached.factory.shutdown();
+        std::fs::remove_dir_all(&prefix).unwrap();
+        std::fs::remove_dir_all(&uncached_prefix).unwrap();
+    }
+
+    #[test]
+    fn bearer_token_must_match_exactly() {
+        let (mut web, prefix) = metrics_web("auth", 0, Duration::ZERO);
+        web.auth_token = Some("s3cret".to_string());
+
+        let authorized = |web: &Web, value: Option<&str>| {
+            let headers = value
+                .map(|v| vec![("Authorization".to_string(), v.to_string())])
+                .unwrap_or_default();
+            web.is_authorized(&Request::fake_http("GET", "/metrics", headers, Vec::new()))
+        };
+
+        assert!(authorized(&web, Some("Bearer s3cret")));
+        assert!(!authorized(&web, None));
+        assert!(!authorized(&web, Some("s3cret")));
+        assert!(!authorized(&web, Some("bearer s3cret")));
+        assert!(!authorized(&web, Some("Bearer s3cret ")));
+        assert!(!authorized(&web, Some("Bearer s3cre")));
+        assert!(!authorized(&web, Some("Bearer s3creT")));
+
+        web.auth_token = None;
+        assert!(authorized(&web, None));
+
+        web.factory.shutdown();
+        std::fs::remove_dir_all(&prefix).unwrap();
+    }
+
+    /// CPU time used by the process
+    fn cpu_time() -> Duration {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    }
+
+    /// cargo test --release -- --ignored bench_ --nocapture
+    #[test]
+    #[ignore]
+    fn bench_concurrent_scrapes() {
+        const SCRAPERS: usize = 8;
+        const SCRAPES: usize = 50;
+
+        for ttl in [Duration::ZERO, Duration::from_millis(200)] {
+            let (web, prefix) = metrics_web("bench", 10000, ttl);
+            let main = web.factory.get_main();
+
+            let cpu = cpu_time();
+            let start = Instant::now();
+
+            std::thread::scope(|s| {
+                for _ in 0..SCRAPERS {
+                    s.spawn(|| {
+                        for _ in 0..SCRAPES {
+                            web.cached_metrics("", &main, false, true);
+                        }
+                    });
+                }
+            });
+
+            println!(
+                "cache {:?}: {} scrapes in {:?} ({:?} CPU)",
+                ttl,
+                SCRAPERS * SCRAPES,
+                start.elapsed(),
+                cpu_time() - cpu
+            );
+
+            web.factory.shutdown();
+            std::fs::remove_dir_all(&prefix).unwrap();
+        }
     }
 }